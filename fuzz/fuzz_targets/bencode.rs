@@ -0,0 +1,22 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use matey_fuzz::bencode_parser::{encode, from_reader, parse_bencode, parse_bencode_strict};
+
+fuzz_target!(|data: &[u8]| {
+    // Whatever parses has to come back the same after encoding it again.
+    if let Ok((_, value)) = parse_bencode(data) {
+        let encoded = encode(&value);
+
+        assert_eq!(parse_bencode(&encoded).unwrap().1, value);
+    }
+
+    // Encoding always sorts dict keys, so it has to pass strict parsing too.
+    if let Ok((_, value)) = parse_bencode_strict(data) {
+        let encoded = encode(&value);
+
+        assert_eq!(parse_bencode_strict(&encoded), Ok((&[][..], value)));
+    }
+
+    let _ = from_reader(data);
+});
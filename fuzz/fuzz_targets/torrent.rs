@@ -0,0 +1,19 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use matey_fuzz::{
+    bencode_parser::encode,
+    torrent_parser::{info_hash_only, Torrent},
+};
+
+fuzz_target!(|data: &[u8]| {
+    let _ = info_hash_only(data);
+
+    if let Ok((torrent, _)) = Torrent::try_from_with_warnings(data) {
+        let _ = torrent.info_hash();
+        let _ = torrent.info_hash_v2();
+        let _ = torrent.info.check_consistency();
+        let _ = torrent.info.file_offsets();
+        let _ = encode(&torrent.to_bencode());
+    }
+});
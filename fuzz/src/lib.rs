@@ -0,0 +1,15 @@
+//! matey's parsers, built on their own so they can be fuzzed, since matey
+//! itself is only a binary. Run a target with `cargo +nightly fuzz run
+//! bencode` or `cargo +nightly fuzz run torrent` from matey's root.
+
+#![feature(slice_as_chunks)]
+#![allow(dead_code)]
+
+#[path = "../../src/bencode_parser.rs"]
+pub mod bencode_parser;
+#[path = "../../src/bitfield.rs"]
+pub mod bitfield;
+#[path = "../../src/torrent_parser.rs"]
+pub mod torrent_parser;
+#[path = "../../src/types.rs"]
+pub mod types;
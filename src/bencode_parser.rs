@@ -5,42 +5,602 @@ use nom::{
     bytes::complete::{tag, take, take_while1},
     character::is_digit,
     combinator::{cut, map, map_res},
+    error::{context, ContextError, ErrorKind, FromExternalError},
     multi::many0,
     sequence::{delimited, pair, terminated},
     IResult,
 };
-use snafu::{ensure, ResultExt, Snafu};
-use std::{collections::BTreeMap, num};
+use snafu::{ensure, OptionExt, ResultExt, Snafu};
+use std::{
+    collections::BTreeMap,
+    convert::TryFrom,
+    fmt::{self, Write as _},
+    io::{self, Read, Write},
+    num,
+    ops::Range,
+    str, string,
+};
+
+/// How deeply lists and dicts can be nested inside each other by default.
+/// Parsing recurses for each level, so without a limit, a malicious input
+/// like `llll...` could overflow the stack.
+pub const DEFAULT_MAX_DEPTH: usize = 100;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ParseOptions {
+    /// See [`parse_bencode_strict`].
+    pub strict: bool,
+    pub max_depth: usize,
+}
+
+impl Default for ParseOptions {
+    fn default() -> Self {
+        Self {
+            strict: false,
+            max_depth: DEFAULT_MAX_DEPTH,
+        }
+    }
+}
+
+pub fn parse_bencode(bencode: &[u8]) -> IResult<&[u8], Bencode, BencodeParsingError> {
+    parse_bencode_with(bencode, ParseOptions::default())
+}
+
+/// Like [`parse_bencode`], but the value has to be all there is in
+/// `bencode`, the way a .torrent file is just its dict. Bytes after it
+/// usually mean the file's been corrupted or had something stuck on the end.
+pub fn parse_bencode_exact(bencode: &[u8]) -> Result<Bencode, nom::Err<BencodeParsingError>> {
+    let (rest, value) = parse_bencode(bencode)?;
+    expect_end(bencode, rest)?;
+
+    Ok(value)
+}
+
+/// Checks that there's nothing but whitespace left in `rest` after parsing
+/// a value out of `input`. A trailing newline is let through, since plenty
+/// of tools add one.
+pub fn expect_end(input: &[u8], rest: &[u8]) -> Result<(), nom::Err<BencodeParsingError>> {
+    match rest.iter().position(|b| !b.is_ascii_whitespace()) {
+        Some(position) => Err(nom::Err::Failure(BencodeParsingError::TrailingData {
+            offset: input.len() - rest.len() + position,
+        })),
+        None => Ok(()),
+    }
+}
 
-pub fn parse_bencode(bencode: &[u8]) -> IResult<&[u8], Bencode> {
-    // TODO: return our custom error somehow
+/// Like [`parse_bencode`], but for anything that can be borrowed as bytes,
+/// like a `Vec<u8>`, a `str` or [`Bytes`].
+pub fn parse_bencode_from<B: AsRef<[u8]> + ?Sized>(
+    bencode: &B,
+) -> IResult<&[u8], Bencode, BencodeParsingError> {
+    parse_bencode(bencode.as_ref())
+}
+
+/// Like [`parse_bencode`], but additionally rejects dictionaries whose keys
+/// aren't unique and in ascending order. The info hash is computed over the
+/// raw bytes of the info dict, so a torrent that doesn't follow this will hash
+/// differently from the same torrent re-encoded by another client.
+pub fn parse_bencode_strict(bencode: &[u8]) -> IResult<&[u8], Bencode, BencodeParsingError> {
+    parse_bencode_with(
+        bencode,
+        ParseOptions {
+            strict: true,
+            ..ParseOptions::default()
+        },
+    )
+}
+
+pub fn parse_bencode_with(
+    bencode: &[u8],
+    options: ParseOptions,
+) -> IResult<&[u8], Bencode, BencodeParsingError> {
+    value(bencode, options)
+        .map(|(rest, value)| (rest, value.into()))
+        .map_err(|e| e.map(|e| e.offset_from(bencode)))
+}
+
+/// Like [`parse_bencode`], but byte strings point into `bencode` instead of
+/// being copied out of it. Worth it when only a few fields are actually read,
+/// like when inspecting a torrent with a huge info[pieces].
+pub fn parse_bencode_borrowed(
+    bencode: &[u8],
+) -> IResult<&[u8], BorrowedBencode<'_>, BencodeParsingError> {
+    value(bencode, ParseOptions::default()).map_err(|e| e.map(|e| e.offset_from(bencode)))
+}
+
+/// Where each of a dict's values was in the input it was parsed from, by key.
+pub type Spans = BTreeMap<Bytes, Range<usize>>;
+
+/// Like [`parse_bencode`], but when the value is a dict, also gives where in
+/// `bencode` each of its values is, by key. That's what the info hash needs,
+/// since it has to be taken over the info dict exactly as it was encoded.
+/// Anything other than a dict has no spans.
+pub fn parse_bencode_spanned(
+    bencode: &[u8],
+) -> IResult<&[u8], (Bencode, Spans), BencodeParsingError> {
+    spanned(bencode).map_err(|e| e.map(|e| e.offset_from(bencode)))
+}
+
+fn spanned(bencode: &[u8]) -> IResult<&[u8], (Bencode, Spans), BencodeParsingError> {
+    let options = ParseOptions::default();
+    let offset = |rest: &[u8]| bencode.len() - rest.len();
+
+    let mut rest = match bencode.strip_prefix(b"d") {
+        Some(rest) => rest,
+        None => {
+            return value(bencode, options)
+                .map(|(rest, value)| (rest, (value.into(), BTreeMap::new())))
+        }
+    };
+    let options = nested(bencode, options)?;
+    let (mut dict, mut spans) = (BTreeMap::new(), BTreeMap::new());
+
+    // This goes the same way as dict() does, just keeping track of where each
+    // value was along the way.
+    loop {
+        let (value_start, key) = match string(rest) {
+            Ok(parsed) => parsed,
+            Err(nom::Err::Error(_)) => {
+                let (rest, _) = cut(context("'e' or a dictionary key", tag("e")))(rest)?;
+
+                return Ok((rest, (Bencode::Dict(dict), spans)));
+            }
+            Err(e) => return Err(e),
+        };
+        let (value_end, value) = cut(|i| value(i, options))(value_start)?;
+        let key = Bytes::copy_from_slice(key);
+
+        spans.insert(key.clone(), offset(value_start)..offset(value_end));
+        dict.insert(key, value.into());
+        rest = value_end;
+    }
+}
+
+fn value(
+    bencode: &[u8],
+    options: ParseOptions,
+) -> IResult<&[u8], BorrowedBencode<'_>, BencodeParsingError> {
     alt((
-        map(number, Bencode::Number),
-        map(string, Bencode::ByteString),
-        map(list, Bencode::List),
-        map(dict, Bencode::Dict),
+        number,
+        map(string, BorrowedBencode::ByteString),
+        map(|i| list(i, options), BorrowedBencode::List),
+        map(|i| dict(i, options), BorrowedBencode::Dict),
     ))(bencode)
+    .map_err(|e| match e {
+        // A plain Error means every alternative failed right at the start,
+        // whereas Failures come from further inside and already say what
+        // they were expecting.
+        nom::Err::Error(_) => nom::Err::Error(BencodeParsingError::InvalidBencode {
+            offset: bencode.len(),
+            expected: "the start of a value ('i', 'l', 'd' or a digit)",
+        }),
+        e => e,
+    })
+}
+
+pub fn encode(bencode: &Bencode) -> Vec<u8> {
+    let mut output = Vec::new();
+
+    // Writing to a Vec never fails, so there's no error to propagate here.
+    encode_to(bencode, &mut output).unwrap();
+
+    output
+}
+
+pub fn encode_to<W: Write>(bencode: &Bencode, writer: &mut W) -> io::Result<()> {
+    match bencode {
+        Bencode::Number(number) => write!(writer, "i{}e", number),
+        Bencode::BigNumber(digits) => {
+            writer.write_all(b"i")?;
+            writer.write_all(digits)?;
+            writer.write_all(b"e")
+        }
+        Bencode::ByteString(bytes) => encode_string(bytes, writer),
+        Bencode::List(list) => {
+            writer.write_all(b"l")?;
+
+            for item in list {
+                encode_to(item, writer)?;
+            }
+
+            writer.write_all(b"e")
+        }
+        // BTreeMap iterates in ascending key order, and Bytes compares by its
+        // raw contents, so this is always the canonical ordering required by
+        // the spec.
+        Bencode::Dict(dict) => {
+            writer.write_all(b"d")?;
+
+            for (key, value) in dict {
+                encode_string(key, writer)?;
+                encode_to(value, writer)?;
+            }
+
+            writer.write_all(b"e")
+        }
+    }
+}
+
+/// Renders `bencode` as indented, JSON-like text for debugging. Byte strings
+/// that aren't UTF-8, like piece hashes, are shown as hex between angle
+/// brackets instead.
+pub fn pretty_print(bencode: &Bencode) -> String {
+    let mut output = String::new();
+
+    // Writing to a String never fails either.
+    pretty_print_to(bencode, 0, &mut output).unwrap();
+
+    output
+}
+
+fn pretty_print_to(bencode: &Bencode, indent: usize, output: &mut String) -> fmt::Result {
+    match bencode {
+        Bencode::Number(number) => write!(output, "{}", number),
+        Bencode::BigNumber(digits) => output.write_str(&String::from_utf8_lossy(digits)),
+        Bencode::ByteString(bytes) => pretty_print_string(bytes, output),
+        Bencode::List(list) if list.is_empty() => output.write_str("[]"),
+        Bencode::Dict(dict) if dict.is_empty() => output.write_str("{}"),
+        Bencode::List(list) => {
+            output.write_str("[\n")?;
+
+            for (i, item) in list.iter().enumerate() {
+                write!(output, "{:1$}", "", (indent + 1) * 2)?;
+                pretty_print_to(item, indent + 1, output)?;
+                output.write_str(if i + 1 < list.len() { ",\n" } else { "\n" })?;
+            }
+
+            write!(output, "{:1$}]", "", indent * 2)
+        }
+        Bencode::Dict(dict) => {
+            output.write_str("{\n")?;
+
+            for (i, (key, value)) in dict.iter().enumerate() {
+                write!(output, "{:1$}", "", (indent + 1) * 2)?;
+                pretty_print_string(key, output)?;
+                output.write_str(": ")?;
+                pretty_print_to(value, indent + 1, output)?;
+                output.write_str(if i + 1 < dict.len() { ",\n" } else { "\n" })?;
+            }
+
+            write!(output, "{:1$}}}", "", indent * 2)
+        }
+    }
+}
+
+fn pretty_print_string(bytes: &[u8], output: &mut String) -> fmt::Result {
+    match str::from_utf8(bytes) {
+        Ok(string) => write!(output, "{:?}", string),
+        Err(_) => {
+            output.write_char('<')?;
+
+            for byte in bytes {
+                write!(output, "{:02x}", byte)?;
+            }
+
+            output.write_char('>')
+        }
+    }
+}
+
+fn encode_string<W: Write>(bytes: &[u8], writer: &mut W) -> io::Result<()> {
+    write!(writer, "{}:", bytes.len())?;
+    writer.write_all(bytes)
 }
 
 #[derive(PartialEq, Eq, Debug, Clone, Is)]
 pub enum Bencode {
     Number(i64),
+    /// An integer too big to fit in an `i64`, kept as its digits (and sign)
+    /// since the spec doesn't limit how big one can be.
+    BigNumber(Bytes),
     ByteString(Bytes),
     List(Vec<Bencode>),
+    /// Kept sorted by the keys' raw bytes, so the order keys were inserted
+    /// or parsed in never matters for equality, `Debug`, or encoding.
     Dict(BTreeMap<Bytes, Bencode>),
 }
 
+/// A [`Bencode`] whose byte strings borrow from the input they were parsed
+/// from, as returned by [`parse_bencode_borrowed`].
+#[derive(PartialEq, Eq, Debug, Clone, Is)]
+pub enum BorrowedBencode<'a> {
+    Number(i64),
+    BigNumber(&'a [u8]),
+    ByteString(&'a [u8]),
+    List(Vec<BorrowedBencode<'a>>),
+    Dict(BTreeMap<&'a [u8], BorrowedBencode<'a>>),
+}
+
+impl<'a> BorrowedBencode<'a> {
+    pub fn get(&self, key: &[u8]) -> Option<&BorrowedBencode<'a>> {
+        self.as_dict()?.get(key)
+    }
+
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            Self::Number(number) => Some(*number),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&'a [u8]> {
+        match self {
+            Self::ByteString(bytes) => Some(bytes),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&'a str> {
+        str::from_utf8(self.as_bytes()?).ok()
+    }
+
+    pub fn as_list(&self) -> Option<&[BorrowedBencode<'a>]> {
+        match self {
+            Self::List(list) => Some(list),
+            _ => None,
+        }
+    }
+
+    pub fn as_dict(&self) -> Option<&BTreeMap<&'a [u8], BorrowedBencode<'a>>> {
+        match self {
+            Self::Dict(dict) => Some(dict),
+            _ => None,
+        }
+    }
+}
+
+impl From<BorrowedBencode<'_>> for Bencode {
+    fn from(borrowed: BorrowedBencode<'_>) -> Self {
+        match borrowed {
+            BorrowedBencode::Number(number) => Self::Number(number),
+            BorrowedBencode::BigNumber(digits) => Self::BigNumber(Bytes::copy_from_slice(digits)),
+            BorrowedBencode::ByteString(bytes) => Self::ByteString(Bytes::copy_from_slice(bytes)),
+            BorrowedBencode::List(list) => Self::List(list.into_iter().map(Self::from).collect()),
+            BorrowedBencode::Dict(dict) => Self::Dict(
+                dict.into_iter()
+                    .map(|(key, value)| (Bytes::copy_from_slice(key), Self::from(value)))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+// The consuming accessors come from the Is derive, these are for reading
+// values without taking the tree apart.
+impl Bencode {
+    pub fn get(&self, key: &[u8]) -> Option<&Bencode> {
+        self.as_dict()?.get(key)
+    }
+
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            Self::Number(number) => Some(*number),
+            _ => None,
+        }
+    }
+
+    /// The digits of any integer, however big, for parsing into something
+    /// bigger than an `i64`.
+    pub fn number_bytes(&self) -> Option<Bytes> {
+        match self {
+            Self::Number(number) => Some(Bytes::from(number.to_string())),
+            Self::BigNumber(digits) => Some(digits.clone()),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Self::ByteString(bytes) => Some(bytes),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        str::from_utf8(self.as_bytes()?).ok()
+    }
+
+    pub fn as_list(&self) -> Option<&[Bencode]> {
+        match self {
+            Self::List(list) => Some(list),
+            _ => None,
+        }
+    }
+
+    pub fn as_dict(&self) -> Option<&BTreeMap<Bytes, Bencode>> {
+        match self {
+            Self::Dict(dict) => Some(dict),
+            _ => None,
+        }
+    }
+
+    pub fn kind(&self) -> BencodeKind {
+        match self {
+            Self::Number(_) | Self::BigNumber(_) => BencodeKind::Integer,
+            Self::ByteString(_) => BencodeKind::ByteString,
+            Self::List(_) => BencodeKind::List,
+            Self::Dict(_) => BencodeKind::Dict,
+        }
+    }
+}
+
+/// Which variant a [`Bencode`] is, for saying what was expected or found.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BencodeKind {
+    Integer,
+    ByteString,
+    List,
+    Dict,
+}
+
+impl fmt::Display for BencodeKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Integer => "an integer",
+            Self::ByteString => "a byte string",
+            Self::List => "a list",
+            Self::Dict => "a dictionary",
+        })
+    }
+}
+
+impl TryFrom<Bencode> for i64 {
+    type Error = BencodeConversionError;
+
+    fn try_from(bencode: Bencode) -> Result<Self, Self::Error> {
+        match bencode {
+            Bencode::Number(number) => Ok(number),
+            Bencode::BigNumber(digits) => NumberTooBig { digits }.fail(),
+            other => UnexpectedType {
+                expected: BencodeKind::Integer,
+                found: other.kind(),
+            }
+            .fail(),
+        }
+    }
+}
+
+impl TryFrom<Bencode> for Vec<u8> {
+    type Error = BencodeConversionError;
+
+    fn try_from(bencode: Bencode) -> Result<Self, Self::Error> {
+        match bencode {
+            Bencode::ByteString(bytes) => Ok(bytes.to_vec()),
+            other => UnexpectedType {
+                expected: BencodeKind::ByteString,
+                found: other.kind(),
+            }
+            .fail(),
+        }
+    }
+}
+
+impl TryFrom<Bencode> for String {
+    type Error = BencodeConversionError;
+
+    fn try_from(bencode: Bencode) -> Result<Self, Self::Error> {
+        String::from_utf8(Vec::try_from(bencode)?).context(NotUtf8)
+    }
+}
+
+impl TryFrom<Bencode> for Vec<Bencode> {
+    type Error = BencodeConversionError;
+
+    fn try_from(bencode: Bencode) -> Result<Self, Self::Error> {
+        match bencode {
+            Bencode::List(list) => Ok(list),
+            other => UnexpectedType {
+                expected: BencodeKind::List,
+                found: other.kind(),
+            }
+            .fail(),
+        }
+    }
+}
+
 #[non_exhaustive]
-#[derive(Debug, Snafu)]
+#[derive(Debug, PartialEq, Snafu)]
+pub enum BencodeConversionError {
+    #[snafu(display("Expected {}, found {}", expected, found))]
+    UnexpectedType {
+        expected: BencodeKind,
+        found: BencodeKind,
+    },
+    #[snafu(display("Byte string isn't valid UTF-8: {}", source))]
+    NotUtf8 { source: string::FromUtf8Error },
+    #[snafu(display("Integer {} doesn't fit in an i64", String::from_utf8_lossy(digits)))]
+    NumberTooBig { digits: Bytes },
+}
+
+#[non_exhaustive]
+#[derive(Debug, PartialEq, Snafu)]
 pub enum BencodeParsingError {
-    #[snafu(display("Invalid number: {}", source))]
-    InvalidNumber { source: BencodeNumberParsingError },
-    #[snafu(display("Input is not valid bencode"))]
-    InvalidBencode,
+    #[snafu(display("Invalid number at byte {}: {}", offset, source))]
+    InvalidNumber {
+        offset: usize,
+        source: BencodeNumberParsingError,
+    },
+    #[snafu(display("Expected {} at byte {}", expected, offset))]
+    InvalidBencode {
+        offset: usize,
+        expected: &'static str,
+    },
+    #[snafu(display("Dictionary key {:?} is out of order", String::from_utf8_lossy(key)))]
+    UnsortedKeys { key: Bytes },
+    #[snafu(display(
+        "Dictionary key {:?} appears more than once",
+        String::from_utf8_lossy(key)
+    ))]
+    DuplicateKey { key: Bytes },
+    #[snafu(display("Value at byte {} is nested too deeply", offset))]
+    TooDeep { offset: usize },
+    #[snafu(display("Found more data at byte {} after the end of the value", offset))]
+    TrailingData { offset: usize },
+}
+
+impl BencodeParsingError {
+    /// The individual parsers only ever see the input that's left, so while
+    /// parsing, offsets are actually the number of bytes remaining. This turns
+    /// them into offsets from the start of `input` once we know what that is.
+    fn offset_from(mut self, input: &[u8]) -> Self {
+        match &mut self {
+            Self::InvalidNumber { offset, .. }
+            | Self::InvalidBencode { offset, .. }
+            | Self::TooDeep { offset } => *offset = input.len() - *offset,
+            _ => {}
+        }
+
+        self
+    }
+}
+
+impl nom::error::ParseError<&[u8]> for BencodeParsingError {
+    fn from_error_kind(input: &[u8], _kind: ErrorKind) -> Self {
+        Self::InvalidBencode {
+            offset: input.len(),
+            expected: "valid bencode",
+        }
+    }
+
+    fn append(_input: &[u8], _kind: ErrorKind, other: Self) -> Self {
+        other
+    }
+}
+
+impl ContextError<&[u8]> for BencodeParsingError {
+    fn add_context(_input: &[u8], ctx: &'static str, mut other: Self) -> Self {
+        if let Self::InvalidBencode { expected, .. } = &mut other {
+            *expected = ctx;
+        }
+
+        other
+    }
+}
+
+impl FromExternalError<&[u8], BencodeNumberParsingError> for BencodeParsingError {
+    fn from_external_error(
+        input: &[u8],
+        _kind: ErrorKind,
+        source: BencodeNumberParsingError,
+    ) -> Self {
+        Self::InvalidNumber {
+            offset: input.len(),
+            source,
+        }
+    }
+}
+
+impl FromExternalError<&[u8], num::ParseIntError> for BencodeParsingError {
+    fn from_external_error(input: &[u8], _kind: ErrorKind, _e: num::ParseIntError) -> Self {
+        Self::InvalidBencode {
+            offset: input.len(),
+            expected: "a byte string length that fits in memory",
+        }
+    }
 }
 
 #[non_exhaustive]
-#[derive(Debug, Snafu)]
+#[derive(Debug, PartialEq, Snafu)]
 pub enum BencodeNumberParsingError {
     #[snafu(display("Zero cannot be negative"))]
     NegativeZero,
@@ -48,66 +608,414 @@ pub enum BencodeNumberParsingError {
     LeadingZero,
     #[snafu(display("Numbers cannot be empty"))]
     EmptyNumber,
+    #[snafu(display("Numbers can only contain digits and a leading minus sign"))]
+    InvalidDigit,
+    #[snafu(display("A minus sign can only come right at the start of a number"))]
+    MisplacedSign,
     ParseError {
         source: num::ParseIntError,
     },
 }
 
-fn string(bencode: &[u8]) -> IResult<&[u8], Bytes> {
+fn string(bencode: &[u8]) -> IResult<&[u8], &[u8], BencodeParsingError> {
     let (bencode, num_characters) = terminated(
         map_res(take_while1(is_digit), |bytes| {
             String::from_utf8_lossy(bytes).parse::<usize>()
         }),
-        cut(tag(":")),
+        cut(context("':'", tag(":"))),
     )(bencode)?;
 
-    let (bencode, output_string) = cut(take(num_characters))(bencode)?;
+    let (bencode, output_string) = cut(context(
+        "as many bytes as the byte string's length",
+        take(num_characters),
+    ))(bencode)?;
 
-    Ok((bencode, Bytes::copy_from_slice(output_string)))
+    Ok((bencode, output_string))
 }
 
-fn number(bencode: &[u8]) -> IResult<&[u8], i64> {
+fn number(bencode: &[u8]) -> IResult<&[u8], BorrowedBencode<'_>, BencodeParsingError> {
     delimited(
         tag("i"),
         cut(map_res(
-            take_while1(|c| is_digit(c) || c == b'-'),
-            |bytes| -> Result<_, BencodeNumberParsingError> {
-                let number_str = String::from_utf8_lossy(bytes);
+            context("a digit or '-'", take_while1(|c| is_digit(c) || c == b'-')),
+            parse_integer,
+        )),
+        cut(context("'e'", tag("e"))),
+    )(bencode)
+}
 
-                let number = number_str.parse().context(ParseError)?;
+fn parse_number(bytes: &[u8]) -> Result<i64, BencodeNumberParsingError> {
+    ensure!(!bytes.is_empty(), EmptyNumber);
+    ensure!(
+        bytes.iter().all(|&c| is_digit(c) || c == b'-'),
+        InvalidDigit
+    );
+    ensure!(!bytes[1..].contains(&b'-'), MisplacedSign);
 
-                if bytes[0] == b'-' {
-                    ensure!(number != 0, NegativeZero);
-                    ensure!(bytes[1] != b'0', LeadingZero);
-                }
+    let number_str = String::from_utf8_lossy(bytes);
 
-                ensure!(bytes[0] != b'0' || number_str.len() == 1, LeadingZero);
+    let number = number_str.parse().context(ParseError)?;
 
-                Ok(number)
-            },
-        )),
-        cut(tag("e")),
-    )(bencode)
+    if bytes[0] == b'-' {
+        ensure!(number != 0, NegativeZero);
+        ensure!(bytes[1] != b'0', LeadingZero);
+    }
+
+    ensure!(bytes[0] != b'0' || number_str.len() == 1, LeadingZero);
+
+    Ok(number)
 }
 
-fn list(bencode: &[u8]) -> IResult<&[u8], Vec<Bencode>> {
-    delimited(tag("l"), cut(many0(parse_bencode)), cut(tag("e")))(bencode)
+/// Parses a number like [`parse_number`] does, except that one too big for
+/// an `i64` is kept as it is instead of being an error.
+fn parse_integer(bytes: &[u8]) -> Result<BorrowedBencode<'_>, BencodeNumberParsingError> {
+    match parse_number(bytes) {
+        Ok(number) => Ok(BorrowedBencode::Number(number)),
+        Err(BencodeNumberParsingError::ParseError { source })
+            if matches!(
+                source.kind(),
+                num::IntErrorKind::PosOverflow | num::IntErrorKind::NegOverflow
+            ) =>
+        {
+            // Overflowing means it can't be zero, but it could still have
+            // leading zeroes.
+            let digits = bytes.strip_prefix(b"-").unwrap_or(bytes);
+            ensure!(digits[0] != b'0', LeadingZero);
+
+            Ok(BorrowedBencode::BigNumber(bytes))
+        }
+        Err(e) => Err(e),
+    }
 }
 
-fn dict(bencode: &[u8]) -> IResult<&[u8], BTreeMap<Bytes, Bencode>> {
-    let (bencode, output_tuple_list) = delimited(
-        tag("d"),
-        cut(many0(pair(string, parse_bencode))),
-        cut(tag("e")),
-    )(bencode)?;
+/// Called once a list or dict has started at `bencode`, returning the options
+/// to parse its elements with.
+fn nested(
+    bencode: &[u8],
+    options: ParseOptions,
+) -> Result<ParseOptions, nom::Err<BencodeParsingError>> {
+    match options.max_depth.checked_sub(1) {
+        Some(max_depth) => Ok(ParseOptions {
+            max_depth,
+            ..options
+        }),
+        None => Err(nom::Err::Failure(BencodeParsingError::TooDeep {
+            offset: bencode.len(),
+        })),
+    }
+}
+
+fn list(
+    bencode: &[u8],
+    options: ParseOptions,
+) -> IResult<&[u8], Vec<BorrowedBencode<'_>>, BencodeParsingError> {
+    let (rest, _) = tag("l")(bencode)?;
+    let options = nested(bencode, options)?;
+
+    terminated(
+        cut(many0(move |i| value(i, options))),
+        cut(context("'e' or a list element", tag("e"))),
+    )(rest)
+}
+
+fn dict(
+    bencode: &[u8],
+    options: ParseOptions,
+) -> IResult<&[u8], BTreeMap<&[u8], BorrowedBencode<'_>>, BencodeParsingError> {
+    let (rest, _) = tag("d")(bencode)?;
+    let options = nested(bencode, options)?;
+
+    let (bencode, output_tuple_list) = terminated(
+        cut(many0(pair(string, cut(move |i| value(i, options))))),
+        cut(context("'e' or a dictionary key", tag("e"))),
+    )(rest)?;
+
+    if options.strict {
+        for window in output_tuple_list.windows(2) {
+            let (prev_key, key) = (&window[0].0, &window[1].0);
+
+            if prev_key == key {
+                return Err(nom::Err::Failure(BencodeParsingError::DuplicateKey {
+                    key: Bytes::copy_from_slice(key),
+                }));
+            } else if prev_key > key {
+                return Err(nom::Err::Failure(BencodeParsingError::UnsortedKeys {
+                    key: Bytes::copy_from_slice(key),
+                }));
+            }
+        }
+    }
 
     Ok((bencode, output_tuple_list.into_iter().collect()))
 }
 
+/// Parses a single bencode value by pulling bytes from `reader` as they're
+/// needed, rather than requiring all of the input up front like
+/// [`parse_bencode`] does. Nothing past the end of the value is consumed.
+pub fn from_reader<R: Read>(reader: R) -> Result<Bencode, BencodeReadError> {
+    from_reader_with_max_depth(reader, DEFAULT_MAX_DEPTH)
+}
+
+pub fn from_reader_with_max_depth<R: Read>(
+    reader: R,
+    max_depth: usize,
+) -> Result<Bencode, BencodeReadError> {
+    BencodeReader {
+        reader,
+        peeked: None,
+        depth_left: max_depth,
+    }
+    .value()
+}
+
+struct BencodeReader<R> {
+    reader: R,
+    peeked: Option<u8>,
+    depth_left: usize,
+}
+
+impl<R: Read> BencodeReader<R> {
+    fn peek(&mut self) -> Result<u8, BencodeReadError> {
+        if let Some(byte) = self.peeked {
+            return Ok(byte);
+        }
+
+        let mut buf = [0];
+
+        loop {
+            match self.reader.read(&mut buf) {
+                Ok(0) => return UnexpectedEof.fail(),
+                Ok(_) => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e).context(ReadError),
+            }
+        }
+
+        self.peeked = Some(buf[0]);
+
+        Ok(buf[0])
+    }
+
+    fn next(&mut self) -> Result<u8, BencodeReadError> {
+        let byte = self.peek()?;
+        self.peeked = None;
+
+        Ok(byte)
+    }
+
+    fn take_until(&mut self, end: u8) -> Result<Vec<u8>, BencodeReadError> {
+        let mut bytes = Vec::new();
+
+        loop {
+            match self.next()? {
+                byte if byte == end => return Ok(bytes),
+                byte => bytes.push(byte),
+            }
+        }
+    }
+
+    fn value(&mut self) -> Result<Bencode, BencodeReadError> {
+        Ok(match self.peek()? {
+            b'i' => {
+                self.next()?;
+
+                let digits = self.take_until(b'e')?;
+
+                parse_integer(&digits).context(InvalidReadNumber)?.into()
+            }
+            b'0'..=b'9' => Bencode::ByteString(self.string()?),
+            b'l' => {
+                self.next()?;
+                self.enter()?;
+
+                let mut list = Vec::new();
+
+                while self.peek()? != b'e' {
+                    list.push(self.value()?);
+                }
+
+                self.next()?;
+                self.depth_left += 1;
+
+                Bencode::List(list)
+            }
+            b'd' => {
+                self.next()?;
+                self.enter()?;
+
+                let mut dict = BTreeMap::new();
+
+                while self.peek()? != b'e' {
+                    let key = self.string()?;
+                    dict.insert(key, self.value()?);
+                }
+
+                self.next()?;
+                self.depth_left += 1;
+
+                Bencode::Dict(dict)
+            }
+            byte => UnexpectedByte { byte }.fail()?,
+        })
+    }
+
+    fn enter(&mut self) -> Result<(), BencodeReadError> {
+        self.depth_left = self.depth_left.checked_sub(1).context(ReadTooDeep)?;
+
+        Ok(())
+    }
+
+    fn string(&mut self) -> Result<Bytes, BencodeReadError> {
+        let mut len_bytes = Vec::new();
+
+        while is_digit(self.peek()?) {
+            len_bytes.push(self.next()?);
+        }
+
+        ensure!(
+            !len_bytes.is_empty() && self.next()? == b':',
+            InvalidStringLength
+        );
+
+        let len = String::from_utf8_lossy(&len_bytes)
+            .parse::<u64>()
+            .ok()
+            .context(InvalidStringLength)?;
+
+        // Reading through Take grows the buffer as data actually arrives, so a
+        // bogus length can't make us allocate a huge buffer up front. We just
+        // consumed the ':', so there's nothing peeked that we'd be skipping.
+        let mut data = Vec::new();
+
+        (&mut self.reader)
+            .take(len)
+            .read_to_end(&mut data)
+            .context(ReadError)?;
+
+        ensure!(data.len() as u64 == len, UnexpectedEof);
+
+        Ok(data.into())
+    }
+}
+
+#[derive(Debug, Snafu)]
+pub enum BencodeReadError {
+    #[snafu(display("Couldn't read bencode: {}", source))]
+    ReadError { source: io::Error },
+    #[snafu(display("Input ended in the middle of a bencode value"))]
+    UnexpectedEof,
+    #[snafu(display("Invalid number: {}", source))]
+    InvalidReadNumber { source: BencodeNumberParsingError },
+    #[snafu(display("Invalid byte string length"))]
+    InvalidStringLength,
+    #[snafu(display("Unexpected byte {:?} at the start of a value", *byte as char))]
+    UnexpectedByte { byte: u8 },
+    #[snafu(display("Value is nested too deeply"))]
+    ReadTooDeep,
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
 
+    #[test]
+    fn conversions() {
+        assert_eq!(i64::try_from(Bencode::Number(-3)), Ok(-3));
+        assert_eq!(
+            Vec::<u8>::try_from(Bencode::ByteString(Bytes::from_static(b"\xff"))),
+            Ok(vec![0xff])
+        );
+        assert_eq!(
+            String::try_from(Bencode::ByteString(Bytes::from_static(b"spam"))),
+            Ok("spam".to_string())
+        );
+        assert_eq!(
+            Vec::<Bencode>::try_from(Bencode::List(vec![Bencode::Number(1)])),
+            Ok(vec![Bencode::Number(1)])
+        );
+    }
+
+    #[test]
+    fn mismatched_conversions() {
+        assert_eq!(
+            i64::try_from(Bencode::List(vec![])),
+            Err(BencodeConversionError::UnexpectedType {
+                expected: BencodeKind::Integer,
+                found: BencodeKind::List
+            })
+        );
+        assert_eq!(
+            String::try_from(Bencode::Dict(BTreeMap::new())),
+            Err(BencodeConversionError::UnexpectedType {
+                expected: BencodeKind::ByteString,
+                found: BencodeKind::Dict
+            })
+        );
+        assert_eq!(
+            Vec::<Bencode>::try_from(Bencode::Number(1))
+                .unwrap_err()
+                .to_string(),
+            "Expected a list, found an integer"
+        );
+        assert!(matches!(
+            String::try_from(Bencode::ByteString(Bytes::from_static(b"\xff"))),
+            Err(BencodeConversionError::NotUtf8 { .. })
+        ));
+    }
+
+    fn nested_lists(depth: usize) -> Vec<u8> {
+        [vec![b'l'; depth], vec![b'e'; depth]].concat()
+    }
+
+    #[test]
+    fn max_depth() {
+        let nested = nested_lists(DEFAULT_MAX_DEPTH);
+
+        assert!(parse_bencode(&nested).is_ok());
+        assert!(from_reader(nested.as_slice()).is_ok());
+    }
+
+    #[test]
+    fn too_deep() {
+        assert_eq!(
+            parse_bencode(&nested_lists(DEFAULT_MAX_DEPTH + 1)),
+            Err(nom::Err::Failure(BencodeParsingError::TooDeep {
+                offset: DEFAULT_MAX_DEPTH
+            }))
+        );
+        assert!(matches!(
+            from_reader(nested_lists(DEFAULT_MAX_DEPTH + 1).as_slice()),
+            Err(BencodeReadError::ReadTooDeep)
+        ));
+    }
+
+    #[test]
+    fn far_too_deep() {
+        let nested = [b"d1:a".repeat(100_000), vec![b'e'; 100_000]].concat();
+
+        assert!(matches!(
+            parse_bencode(&nested),
+            Err(nom::Err::Failure(BencodeParsingError::TooDeep { .. }))
+        ));
+        assert!(matches!(
+            from_reader(nested.as_slice()),
+            Err(BencodeReadError::ReadTooDeep)
+        ));
+    }
+
+    #[test]
+    fn configurable_max_depth() {
+        let options = ParseOptions {
+            max_depth: 2,
+            ..ParseOptions::default()
+        };
+
+        assert!(parse_bencode_with(b"llee", options).is_ok());
+        assert!(parse_bencode_with(b"llleee", options).is_err());
+        assert!(from_reader_with_max_depth(b"llee" as &[u8], 2).is_ok());
+        assert!(from_reader_with_max_depth(b"llleee" as &[u8], 2).is_err());
+    }
+
     #[test]
     fn basic_byte_string() {
         assert_eq!(
@@ -244,6 +1152,111 @@ mod test {
         assert!(parse_bencode(b"i-e").is_err());
     }
 
+    #[test]
+    fn number_with_trailing_zero() {
+        assert_eq!(
+            parse_bencode(b"i10e"),
+            Ok((b"" as &[u8], Bencode::Number(10)))
+        );
+    }
+
+    #[test]
+    fn negative_number_with_trailing_zero() {
+        assert_eq!(
+            parse_bencode(b"i-10e"),
+            Ok((b"" as &[u8], Bencode::Number(-10)))
+        );
+    }
+
+    #[test]
+    fn negative_one() {
+        assert_eq!(
+            parse_bencode(b"i-1e"),
+            Ok((b"" as &[u8], Bencode::Number(-1)))
+        );
+    }
+
+    #[test]
+    fn misplaced_sign() {
+        for bytes in &[b"--1" as &[u8], b"1-", b"1-2"] {
+            assert_eq!(
+                parse_number(bytes),
+                Err(BencodeNumberParsingError::MisplacedSign)
+            );
+        }
+
+        assert_eq!(parse_number(b"-1"), Ok(-1));
+        assert!(parse_bencode(b"i--1e").is_err());
+        assert!(parse_bencode(b"i1-e").is_err());
+    }
+
+    #[test]
+    fn number_limits() {
+        assert_eq!(
+            parse_bencode(b"i9223372036854775807e"),
+            Ok((b"" as &[u8], Bencode::Number(i64::MAX)))
+        );
+        assert_eq!(
+            parse_bencode(b"i-9223372036854775808e"),
+            Ok((b"" as &[u8], Bencode::Number(i64::MIN)))
+        );
+    }
+
+    #[test]
+    fn number_overflow() {
+        for bytes in &[b"9223372036854775808" as &[u8], b"-9223372036854775809"] {
+            assert!(matches!(
+                parse_number(bytes),
+                Err(BencodeNumberParsingError::ParseError { .. })
+            ));
+        }
+
+        // Those are only too big for an i64, not for bencode.
+        assert_eq!(
+            parse_bencode(b"i-9223372036854775809e"),
+            Ok((
+                b"" as &[u8],
+                Bencode::BigNumber(Bytes::from_static(b"-9223372036854775809"))
+            ))
+        );
+        assert!(parse_bencode(b"i099999999999999999999e").is_err());
+    }
+
+    #[test]
+    fn big_numbers() {
+        let input = b"d13:creation datei99999999999999999999e4:sizei1024ee";
+        let (_, bencode) = parse_bencode(input).unwrap();
+
+        let creation_date = bencode.get(b"creation date").unwrap();
+
+        assert!(creation_date.as_i64().is_none());
+        assert_eq!(
+            creation_date.number_bytes().unwrap(),
+            b"99999999999999999999" as &[u8]
+        );
+        assert_eq!(
+            String::from_utf8_lossy(&creation_date.number_bytes().unwrap())
+                .parse::<i128>()
+                .unwrap(),
+            99999999999999999999
+        );
+        assert_eq!(
+            bencode.get(b"size").unwrap().number_bytes().unwrap(),
+            b"1024" as &[u8]
+        );
+        assert!(matches!(
+            i64::try_from(creation_date.clone()),
+            Err(BencodeConversionError::NumberTooBig { .. })
+        ));
+        assert_eq!(encode(&bencode), input);
+        assert_eq!(from_reader(&input[..]).unwrap(), bencode);
+    }
+
+    #[test]
+    fn leading_zero_inside_list() {
+        assert!(parse_bencode(b"li03ee").is_err());
+    }
+
     #[test]
     fn basic_list() {
         let bencode_hello = "5:hello";
@@ -346,6 +1359,68 @@ mod test {
         );
     }
 
+    #[test]
+    fn parse_from_anything_bytes() {
+        let expected = Bencode::List(vec![Bencode::Number(1), Bencode::ByteString("a".into())]);
+        let vec = b"li1e1:ae".to_vec();
+
+        assert_eq!(
+            parse_bencode_from(&vec).unwrap(),
+            (&[] as &[u8], expected.clone())
+        );
+        assert_eq!(parse_bencode_from("li1e1:ae").unwrap().1, expected);
+        assert_eq!(
+            parse_bencode_from(&Bytes::from_static(b"li1e1:aeextra"))
+                .unwrap()
+                .0,
+            b"extra"
+        );
+    }
+
+    #[test]
+    fn exact_rejects_trailing_data() {
+        assert_eq!(
+            parse_bencode_exact(b"d1:ai1ee").unwrap(),
+            parse_bencode(b"d1:ai1ee").unwrap().1
+        );
+        assert!(parse_bencode_exact(b"d1:ai1ee\r\n").is_ok());
+        assert!(matches!(
+            parse_bencode_exact(b"d1:ai1eed1:bi2ee"),
+            Err(nom::Err::Failure(BencodeParsingError::TrailingData {
+                offset: 8
+            }))
+        ));
+        assert!(matches!(
+            parse_bencode_exact(b"i1e \n garbage"),
+            Err(nom::Err::Failure(BencodeParsingError::TrailingData {
+                offset: 6
+            }))
+        ));
+    }
+
+    #[test]
+    fn dict_order_doesnt_matter() {
+        let entries = vec![
+            (Bytes::from_static(b"\xff"), Bencode::Number(1)),
+            (Bytes::from_static(b"b"), Bencode::ByteString("two".into())),
+            (Bytes::from_static(b"a"), Bencode::List(vec![])),
+            (Bytes::from_static(b"B"), Bencode::Number(4)),
+        ];
+
+        let forwards = Bencode::Dict(entries.iter().cloned().collect());
+        let backwards = Bencode::Dict(entries.into_iter().rev().collect());
+
+        assert_eq!(forwards, backwards);
+        assert_eq!(format!("{:?}", forwards), format!("{:?}", backwards));
+        assert_eq!(encode(&forwards), encode(&backwards));
+        assert_eq!(encode(&forwards), b"d1:Bi4e1:ale1:b3:two1:\xffi1ee");
+        // Unsorted input is still parsed, just into the same sorted dict.
+        assert_eq!(
+            parse_bencode(b"d1:b3:two1:\xffi1e1:ale1:Bi4ee").unwrap().1,
+            forwards
+        );
+    }
+
     #[test]
     fn empty_dict() {
         assert_eq!(
@@ -389,15 +1464,15 @@ mod test {
         let val_two = "3:baz";
 
         let dict_str = format!("d{}{}{}{}e", key_one, val_one, key_two, val_two);
-        let (_, result_dict) = dict(&dict_str.as_bytes()).unwrap();
+        let (_, result_dict) = dict(dict_str.as_bytes(), ParseOptions::default()).unwrap();
 
         assert_eq!(
             parse_bencode(format!("l{0}{0}e", dict_str).as_bytes()),
             Ok((
                 b"" as &[u8],
                 Bencode::List(vec![
-                    Bencode::Dict(result_dict.clone()),
-                    Bencode::Dict(result_dict)
+                    BorrowedBencode::Dict(result_dict.clone()).into(),
+                    BorrowedBencode::Dict(result_dict).into()
                 ])
             ))
         );
@@ -413,7 +1488,7 @@ mod test {
         let key_one = "3:foo";
         let key_two = "3:bar";
 
-        let (_, result_list) = list(&list_str.as_bytes()).unwrap();
+        let (_, result_list) = list(list_str.as_bytes(), ParseOptions::default()).unwrap();
 
         assert_eq!(
             parse_bencode(format!("d{}{2}{}{2}e", key_one, key_two, list_str).as_bytes()),
@@ -421,8 +1496,11 @@ mod test {
                 b"" as &[u8],
                 Bencode::Dict(
                     vec![
-                        ("foo".into(), Bencode::List(result_list.clone())),
-                        ("bar".into(), Bencode::List(result_list)),
+                        (
+                            "foo".into(),
+                            BorrowedBencode::List(result_list.clone()).into()
+                        ),
+                        ("bar".into(), BorrowedBencode::List(result_list).into()),
                     ]
                     .into_iter()
                     .collect()
@@ -440,7 +1518,8 @@ mod test {
         let val_two = "3:baz";
 
         let nested_dict_str = format!("d{}{}{}{}e", key_one, val_one, key_two, val_two);
-        let (_, result_nested_dict) = dict(&nested_dict_str.as_bytes()).unwrap();
+        let (_, result_nested_dict) =
+            dict(nested_dict_str.as_bytes(), ParseOptions::default()).unwrap();
 
         assert_eq!(
             parse_bencode(format!("d{}{2}{}{2}e", key_one, key_two, nested_dict_str).as_bytes()),
@@ -448,8 +1527,14 @@ mod test {
                 b"" as &[u8],
                 Bencode::Dict(
                     vec![
-                        ("foo".into(), Bencode::Dict(result_nested_dict.clone())),
-                        ("baz".into(), Bencode::Dict(result_nested_dict)),
+                        (
+                            "foo".into(),
+                            BorrowedBencode::Dict(result_nested_dict.clone()).into()
+                        ),
+                        (
+                            "baz".into(),
+                            BorrowedBencode::Dict(result_nested_dict).into()
+                        ),
                     ]
                     .into_iter()
                     .collect()
@@ -457,4 +1542,383 @@ mod test {
             ))
         );
     }
+
+    #[test]
+    fn error_offset_invalid_start() {
+        assert_eq!(
+            parse_bencode(b"x"),
+            Err(nom::Err::Error(BencodeParsingError::InvalidBencode {
+                offset: 0,
+                expected: "the start of a value ('i', 'l', 'd' or a digit)"
+            }))
+        );
+    }
+
+    #[test]
+    fn error_offset_in_list() {
+        assert_eq!(
+            parse_bencode(b"l5:helloi8ex"),
+            Err(nom::Err::Failure(BencodeParsingError::InvalidBencode {
+                offset: 11,
+                expected: "'e' or a list element"
+            }))
+        );
+    }
+
+    #[test]
+    fn error_offset_in_dict() {
+        assert_eq!(
+            parse_bencode(b"d3:fooi8ei9ee"),
+            Err(nom::Err::Failure(BencodeParsingError::InvalidBencode {
+                offset: 9,
+                expected: "'e' or a dictionary key"
+            }))
+        );
+        assert_eq!(
+            parse_bencode(b"d3:foox"),
+            Err(nom::Err::Failure(BencodeParsingError::InvalidBencode {
+                offset: 6,
+                expected: "the start of a value ('i', 'l', 'd' or a digit)"
+            }))
+        );
+    }
+
+    #[test]
+    fn error_offset_string() {
+        assert_eq!(
+            parse_bencode(b"l5a:helloe"),
+            Err(nom::Err::Failure(BencodeParsingError::InvalidBencode {
+                offset: 2,
+                expected: "':'"
+            }))
+        );
+        assert_eq!(
+            parse_bencode(b"l5:worl"),
+            Err(nom::Err::Failure(BencodeParsingError::InvalidBencode {
+                offset: 3,
+                expected: "as many bytes as the byte string's length"
+            }))
+        );
+    }
+
+    #[test]
+    fn error_offset_number() {
+        assert_eq!(
+            parse_bencode(b"li8eie"),
+            Err(nom::Err::Failure(BencodeParsingError::InvalidBencode {
+                offset: 5,
+                expected: "a digit or '-'"
+            }))
+        );
+        assert_eq!(
+            parse_bencode(b"li8ei08e"),
+            Err(nom::Err::Failure(BencodeParsingError::InvalidNumber {
+                offset: 5,
+                source: BencodeNumberParsingError::LeadingZero
+            }))
+        );
+    }
+
+    #[test]
+    fn strict_sorted_dict() {
+        assert_eq!(
+            parse_bencode_strict(b"d3:bar4:spam3:fooi88ee"),
+            parse_bencode(b"d3:bar4:spam3:fooi88ee")
+        );
+    }
+
+    #[test]
+    fn strict_unsorted_keys() {
+        assert_eq!(
+            parse_bencode_strict(b"d3:fooi88e3:bar4:spame"),
+            Err(nom::Err::Failure(BencodeParsingError::UnsortedKeys {
+                key: "bar".into()
+            }))
+        );
+    }
+
+    #[test]
+    fn strict_duplicate_key() {
+        assert_eq!(
+            parse_bencode_strict(b"d3:fooi88e3:fooi89ee"),
+            Err(nom::Err::Failure(BencodeParsingError::DuplicateKey {
+                key: "foo".into()
+            }))
+        );
+    }
+
+    #[test]
+    fn strict_nested_unsorted_keys() {
+        assert_eq!(
+            parse_bencode_strict(b"ld3:fooi88e3:bar4:spamee"),
+            Err(nom::Err::Failure(BencodeParsingError::UnsortedKeys {
+                key: "bar".into()
+            }))
+        );
+    }
+
+    #[test]
+    fn lenient_unsorted_keys() {
+        assert!(parse_bencode(b"d3:fooi88e3:bar4:spame").is_ok());
+    }
+
+    /// Hands out at most a single byte per read, to make sure nothing relies
+    /// on getting more than it asked for.
+    struct OneByteReader<'a>(&'a [u8]);
+
+    impl Read for OneByteReader<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match (self.0.split_first(), buf.first_mut()) {
+                (Some((&byte, rest)), Some(out)) => {
+                    *out = byte;
+                    self.0 = rest;
+
+                    Ok(1)
+                }
+                _ => Ok(0),
+            }
+        }
+    }
+
+    #[test]
+    fn reader_one_byte_at_a_time() {
+        let torrent_bytes = include_bytes!("../tests/fixtures/single_file.torrent");
+
+        assert_eq!(
+            from_reader(OneByteReader(torrent_bytes)).unwrap(),
+            parse_bencode(torrent_bytes).unwrap().1
+        );
+    }
+
+    #[test]
+    fn reader_leaves_trailing_bytes() {
+        let mut input = b"l5:helloi8eeworld" as &[u8];
+
+        assert_eq!(
+            from_reader(&mut input).unwrap(),
+            Bencode::List(vec![
+                Bencode::ByteString("hello".into()),
+                Bencode::Number(8)
+            ])
+        );
+        assert_eq!(input, b"world");
+    }
+
+    #[test]
+    fn reader_eof_mid_string() {
+        assert!(matches!(
+            from_reader(OneByteReader(b"5:hel")),
+            Err(BencodeReadError::UnexpectedEof)
+        ));
+    }
+
+    #[test]
+    fn reader_eof_mid_list() {
+        assert!(matches!(
+            from_reader(OneByteReader(b"l5:helloi8e")),
+            Err(BencodeReadError::UnexpectedEof)
+        ));
+    }
+
+    #[test]
+    fn reader_eof_mid_number() {
+        assert!(matches!(
+            from_reader(OneByteReader(b"i88")),
+            Err(BencodeReadError::UnexpectedEof)
+        ));
+    }
+
+    #[test]
+    fn reader_invalid_number() {
+        assert!(matches!(
+            from_reader(OneByteReader(b"i+8e")),
+            Err(BencodeReadError::InvalidReadNumber {
+                source: BencodeNumberParsingError::InvalidDigit
+            })
+        ));
+        assert!(matches!(
+            from_reader(OneByteReader(b"i-0e")),
+            Err(BencodeReadError::InvalidReadNumber {
+                source: BencodeNumberParsingError::NegativeZero
+            })
+        ));
+    }
+
+    #[test]
+    fn reader_non_string_key() {
+        assert!(matches!(
+            from_reader(OneByteReader(b"di8ei8ee")),
+            Err(BencodeReadError::InvalidStringLength)
+        ));
+    }
+
+    #[test]
+    fn borrowed_parse() {
+        let input = b"d3:bari-3e3:fool4:spam4:eggsee".to_vec();
+        let (_, borrowed) = parse_bencode_borrowed(&input).unwrap();
+
+        let spam = borrowed.get(b"foo").unwrap().as_list().unwrap()[0]
+            .as_bytes()
+            .unwrap();
+
+        assert_eq!(spam, b"spam");
+        // Actually points into the input rather than a copy of it.
+        assert_eq!(spam.as_ptr(), input[18..].as_ptr());
+        assert_eq!(
+            borrowed.get(b"bar").and_then(BorrowedBencode::as_i64),
+            Some(-3)
+        );
+        assert_eq!(Bencode::from(borrowed), parse_bencode(&input).unwrap().1);
+    }
+
+    #[test]
+    fn spanned_parse() {
+        let input = b"d3:bari-3e3:fool4:spam4:eggse4:infod1:ai1eee";
+        let (rest, (bencode, spans)) = parse_bencode_spanned(input).unwrap();
+
+        assert!(rest.is_empty());
+        assert_eq!(bencode, parse_bencode(input).unwrap().1);
+        assert_eq!(
+            spans
+                .iter()
+                .map(|(key, span)| (key.as_ref(), &input[span.clone()]))
+                .collect::<Vec<_>>(),
+            vec![
+                (b"bar" as &[u8], b"i-3e" as &[u8]),
+                (b"foo", b"l4:spam4:eggse"),
+                (b"info", b"d1:ai1ee"),
+            ]
+        );
+
+        // Only the top level gets spans.
+        let (_, (_, spans)) = parse_bencode_spanned(b"li1ee").unwrap();
+
+        assert!(spans.is_empty());
+    }
+
+    #[test]
+    fn spanned_parse_errors() {
+        for input in [b"d3:fooi8ei9ee" as &[u8], b"d3:foox", b"d3:foo", b"l"] {
+            assert_eq!(
+                parse_bencode_spanned(input).map(|(rest, (bencode, _))| (rest, bencode)),
+                parse_bencode(input)
+            );
+        }
+    }
+
+    #[test]
+    fn borrowing_accessors() {
+        let (_, bencode) =
+            parse_bencode(b"d4:infod4:name5:hello6:lengthi88ee4:listli1ei2eee").unwrap();
+
+        let info = bencode.get(b"info").unwrap();
+
+        assert_eq!(info.get(b"name").and_then(Bencode::as_str), Some("hello"));
+        assert_eq!(info.get(b"length").and_then(Bencode::as_i64), Some(88));
+        assert_eq!(
+            bencode.get(b"list").and_then(Bencode::as_list),
+            Some(&[Bencode::Number(1), Bencode::Number(2)] as &[_])
+        );
+
+        // The tree is still intact since none of these consumed it.
+        assert!(bencode.dict().is_some());
+    }
+
+    #[test]
+    fn borrowing_accessors_wrong_variant() {
+        let number = Bencode::Number(88);
+
+        assert_eq!(number.get(b"info"), None);
+        assert_eq!(number.as_str(), None);
+        assert_eq!(number.as_list(), None);
+        assert_eq!(Bencode::ByteString("hello".into()).as_i64(), None);
+        assert_eq!(Bencode::ByteString(vec![0xff, 0xfe].into()).as_str(), None);
+    }
+
+    #[test]
+    fn pretty_print_snapshot() {
+        let bencode = parse_bencode(
+            b"d8:announce20:http://example.com/a4:infod6:lengthi-5e4:name8:tab\there6:pieces4:\xde\xad\xbe\xefe4:listli1el0:deleee5:emptylee",
+        )
+        .unwrap()
+        .1;
+
+        assert_eq!(
+            pretty_print(&bencode),
+            r#"{
+  "announce": "http://example.com/a",
+  "empty": [],
+  "info": {
+    "length": -5,
+    "name": "tab\there",
+    "pieces": <deadbeef>
+  },
+  "list": [
+    1,
+    [
+      "",
+      {},
+      []
+    ]
+  ]
+}"#
+        );
+    }
+
+    #[test]
+    fn pretty_print_scalars() {
+        assert_eq!(pretty_print(&Bencode::Number(88)), "88");
+        assert_eq!(
+            pretty_print(&Bencode::ByteString("\"hi\"".into())),
+            r#""\"hi\"""#
+        );
+        assert_eq!(
+            pretty_print(&Bencode::ByteString(Bytes::from_static(b"\xff\x00"))),
+            "<ff00>"
+        );
+    }
+
+    #[test]
+    fn encode_numbers() {
+        assert_eq!(encode(&Bencode::Number(88)), b"i88e");
+        assert_eq!(encode(&Bencode::Number(0)), b"i0e");
+        assert_eq!(encode(&Bencode::Number(-88)), b"i-88e");
+    }
+
+    #[test]
+    fn encode_byte_strings() {
+        assert_eq!(encode(&Bencode::ByteString("hello".into())), b"5:hello");
+        assert_eq!(encode(&Bencode::ByteString("".into())), b"0:");
+    }
+
+    #[test]
+    fn encode_list() {
+        assert_eq!(
+            encode(&Bencode::List(vec![
+                Bencode::ByteString("hello".into()),
+                Bencode::Number(8)
+            ])),
+            b"l5:helloi8ee"
+        );
+    }
+
+    #[test]
+    fn encode_dict_sorts_keys() {
+        let mut dict = BTreeMap::new();
+
+        dict.insert("foo".into(), Bencode::Number(88));
+        dict.insert("bar".into(), Bencode::ByteString("spam".into()));
+
+        assert_eq!(encode(&Bencode::Dict(dict)), b"d3:bar4:spam3:fooi88ee");
+    }
+
+    #[test]
+    fn encode_round_trip() {
+        let torrent_bytes = include_bytes!("../tests/fixtures/single_file.torrent");
+
+        let (rest, bencode) = parse_bencode(torrent_bytes).unwrap();
+
+        assert!(rest.is_empty());
+        assert_eq!(encode(&bencode), torrent_bytes);
+    }
 }
@@ -0,0 +1,299 @@
+use snafu::{ensure, Snafu};
+use std::ops::{BitAnd, BitOr, Not};
+
+/// Which pieces of a torrent are present, in the same layout as the wire
+/// protocol's bitfield message: the high bit of the first byte is piece 0.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Bitfield {
+    bytes: Vec<u8>,
+    num_pieces: usize,
+}
+
+impl Bitfield {
+    pub fn new(num_pieces: usize) -> Self {
+        Self {
+            bytes: vec![0; byte_len(num_pieces)],
+            num_pieces,
+        }
+    }
+
+    /// Validates a bitfield sent by a peer. It has to be exactly as long as
+    /// needed for `num_pieces`, and any spare bits at the end have to be 0.
+    pub fn from_bytes(bytes: Vec<u8>, num_pieces: usize) -> Result<Self, BitfieldError> {
+        let expected = byte_len(num_pieces);
+
+        ensure!(
+            bytes.len() == expected,
+            InvalidBitfieldLen {
+                len: bytes.len(),
+                expected
+            }
+        );
+
+        if let Some(last) = bytes.last() {
+            ensure!(last & spare_mask(num_pieces) == 0, SpareBitsSet);
+        }
+
+        Ok(Self { bytes, num_pieces })
+    }
+
+    pub fn len(&self) -> usize {
+        self.num_pieces
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.num_pieces == 0
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+
+    /// Returns false for any index past the end rather than panicking, since
+    /// there's no way we could have a piece that doesn't exist.
+    pub fn has(&self, index: usize) -> bool {
+        index < self.num_pieces && self.bytes[index / 8] & mask(index) != 0
+    }
+
+    pub fn set(&mut self, index: usize) {
+        assert!(
+            index < self.num_pieces,
+            "piece index {} out of range for {} pieces",
+            index,
+            self.num_pieces
+        );
+
+        self.bytes[index / 8] |= mask(index);
+    }
+
+    pub fn count_set(&self) -> usize {
+        self.bytes.iter().map(|b| b.count_ones() as usize).sum()
+    }
+
+    pub fn iter_set(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.num_pieces).filter(move |&index| self.has(index))
+    }
+
+    /// Whether every piece is set.
+    pub fn is_complete(&self) -> bool {
+        self.count_set() == self.num_pieces
+    }
+
+    pub fn missing_indices(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.num_pieces).filter(move |&index| !self.has(index))
+    }
+
+    /// The pieces set in this one but not in `other`, like the ones a peer
+    /// has that we don't.
+    pub fn difference(&self, other: &Self) -> Self {
+        self.zip_with(other, |a, b| a & !b)
+    }
+
+    /// Combines two bitfields byte by byte. Both have to be for the same
+    /// number of pieces, since anything else is a bug.
+    fn zip_with(&self, other: &Self, f: impl Fn(u8, u8) -> u8) -> Self {
+        assert_eq!(
+            self.num_pieces, other.num_pieces,
+            "bitfields are for different numbers of pieces"
+        );
+
+        let mut bitfield = Self {
+            bytes: self
+                .bytes
+                .iter()
+                .zip(&other.bytes)
+                .map(|(&a, &b)| f(a, b))
+                .collect(),
+            num_pieces: self.num_pieces,
+        };
+
+        bitfield.clear_spare_bits();
+        bitfield
+    }
+
+    fn clear_spare_bits(&mut self) {
+        let spare_mask = spare_mask(self.num_pieces);
+
+        if let Some(last) = self.bytes.last_mut() {
+            *last &= !spare_mask;
+        }
+    }
+}
+
+impl BitAnd for &Bitfield {
+    type Output = Bitfield;
+
+    fn bitand(self, other: Self) -> Bitfield {
+        self.zip_with(other, |a, b| a & b)
+    }
+}
+
+impl BitAnd for Bitfield {
+    type Output = Bitfield;
+
+    fn bitand(self, other: Self) -> Bitfield {
+        &self & &other
+    }
+}
+
+impl BitOr for &Bitfield {
+    type Output = Bitfield;
+
+    fn bitor(self, other: Self) -> Bitfield {
+        self.zip_with(other, |a, b| a | b)
+    }
+}
+
+impl BitOr for Bitfield {
+    type Output = Bitfield;
+
+    fn bitor(self, other: Self) -> Bitfield {
+        &self | &other
+    }
+}
+
+/// Flips every piece, leaving the spare bits at the end unset.
+impl Not for &Bitfield {
+    type Output = Bitfield;
+
+    fn not(self) -> Bitfield {
+        self.zip_with(self, |a, _| !a)
+    }
+}
+
+impl Not for Bitfield {
+    type Output = Bitfield;
+
+    fn not(self) -> Bitfield {
+        !&self
+    }
+}
+
+fn byte_len(num_pieces: usize) -> usize {
+    num_pieces.div_ceil(8)
+}
+
+fn mask(index: usize) -> u8 {
+    0x80 >> (index % 8)
+}
+
+/// The bits of the last byte that are past the last piece.
+fn spare_mask(num_pieces: usize) -> u8 {
+    let spare_bits = byte_len(num_pieces) * 8 - num_pieces;
+
+    ((1u16 << spare_bits) - 1) as u8
+}
+
+#[derive(Debug, Snafu)]
+pub enum BitfieldError {
+    #[snafu(display("Expected a bitfield of {} bytes, got {}", expected, len))]
+    InvalidBitfieldLen { len: usize, expected: usize },
+    #[snafu(display("Bitfield has spare bits set past the last piece"))]
+    SpareBitsSet,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn boundary_bits() {
+        let mut bitfield = Bitfield::new(17);
+
+        assert_eq!(bitfield.as_bytes().len(), 3);
+
+        bitfield.set(0);
+        bitfield.set(7);
+        bitfield.set(8);
+        bitfield.set(16);
+
+        assert_eq!(bitfield.as_bytes(), [0b1000_0001, 0b1000_0000, 0b1000_0000]);
+        assert!(bitfield.has(0) && bitfield.has(7) && bitfield.has(8) && bitfield.has(16));
+        assert!(!bitfield.has(1) && !bitfield.has(15));
+        assert!(!bitfield.has(17));
+        assert_eq!(bitfield.count_set(), 4);
+        assert_eq!(bitfield.iter_set().collect::<Vec<_>>(), vec![0, 7, 8, 16]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn set_out_of_range() {
+        Bitfield::new(8).set(8);
+    }
+
+    #[test]
+    fn from_bytes_spare_bits() {
+        assert!(Bitfield::from_bytes(vec![0xff, 0b1110_0000], 11).is_ok());
+        assert!(matches!(
+            Bitfield::from_bytes(vec![0xff, 0b1111_0000], 11),
+            Err(BitfieldError::SpareBitsSet)
+        ));
+        assert!(Bitfield::from_bytes(vec![0xff, 0xff], 16).is_ok());
+    }
+
+    fn bitfield(num_pieces: usize, pieces: &[usize]) -> Bitfield {
+        let mut bitfield = Bitfield::new(num_pieces);
+        pieces.iter().for_each(|&index| bitfield.set(index));
+
+        bitfield
+    }
+
+    #[test]
+    fn bitwise_ops() {
+        let peer = bitfield(11, &[0, 3, 8, 10]);
+        let ours = bitfield(11, &[0, 1, 8]);
+
+        assert_eq!(&peer & &ours, bitfield(11, &[0, 8]));
+        assert_eq!(&peer | &ours, bitfield(11, &[0, 1, 3, 8, 10]));
+        assert_eq!(peer.difference(&ours), bitfield(11, &[3, 10]));
+        assert_eq!(peer.clone() & !ours.clone(), peer.difference(&ours));
+        assert_eq!(peer.clone() | ours, bitfield(11, &[0, 1, 3, 8, 10]));
+    }
+
+    #[test]
+    fn not_masks_spare_bits() {
+        let flipped = !bitfield(11, &[0, 3, 8, 10]);
+
+        assert_eq!(flipped.as_bytes(), [0b0110_1111, 0b0100_0000]);
+        assert_eq!(flipped.count_set(), 7);
+        assert!(Bitfield::from_bytes(flipped.clone().into_bytes(), 11).is_ok());
+        assert!((!Bitfield::new(11)).is_complete());
+        assert!((!!Bitfield::new(16)).as_bytes().iter().all(|&b| b == 0));
+        assert!((!Bitfield::new(0)).is_complete());
+    }
+
+    #[test]
+    fn complete_and_missing() {
+        let mut bitfield = bitfield(10, &[0, 2, 3, 4, 5, 6, 7, 8]);
+
+        assert!(!bitfield.is_complete());
+        assert_eq!(bitfield.missing_indices().collect::<Vec<_>>(), vec![1, 9]);
+
+        bitfield.set(1);
+        bitfield.set(9);
+
+        assert!(bitfield.is_complete());
+        assert_eq!(bitfield.missing_indices().next(), None);
+    }
+
+    #[test]
+    #[should_panic]
+    fn mismatched_lengths() {
+        let _ = &Bitfield::new(8) | &Bitfield::new(9);
+    }
+
+    #[test]
+    fn from_bytes_wrong_len() {
+        assert!(matches!(
+            Bitfield::from_bytes(vec![0, 0, 0], 16),
+            Err(BitfieldError::InvalidBitfieldLen {
+                len: 3,
+                expected: 2
+            })
+        ));
+    }
+}
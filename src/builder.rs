@@ -0,0 +1,514 @@
+use super::torrent_parser::{
+    FileAttr, SHA1Hash, Torrent, TorrentFile, TorrentInfo, MAX_PIECE_LEN, MIN_PIECE_LEN,
+};
+use sha1::{Digest, Sha1};
+use snafu::{ensure, OptionExt, ResultExt, Snafu};
+use std::{
+    collections::BTreeMap,
+    convert::TryInto,
+    fs::{self, File},
+    io::{self, Read},
+    mem,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+pub const DEFAULT_PIECE_LEN: u64 = 256 * 1024;
+/// How many pieces are hashed at once when hashing in parallel, which is
+/// also how many are kept in memory.
+const PARALLEL_BATCH_LEN: usize = 64;
+/// [`TorrentBuilder::auto_piece_len`] picks piece lengths that give at most
+/// this many pieces, so between 1000 and 2000 for anything big enough.
+const AUTO_MAX_PIECES: u64 = 2000;
+/// The biggest piece length it'll pick, even though bigger ones are allowed.
+pub const AUTO_MAX_PIECE_LEN: u64 = 16 * 1024 * 1024;
+
+/// Makes a torrent out of a file, or out of every file under a directory.
+/// Files are added in order of their paths, so building from the same
+/// content always gives the same info hash.
+#[derive(Clone, Debug)]
+pub struct TorrentBuilder {
+    path: PathBuf,
+    announce: String,
+    /// `None` to pick one based on how much content there is.
+    piece_len: Option<u64>,
+    private: bool,
+    comment: Option<String>,
+    parallel: bool,
+}
+
+impl TorrentBuilder {
+    pub fn new<P: AsRef<Path>>(path: P, announce: &str) -> Self {
+        Self {
+            path: path.as_ref().to_owned(),
+            announce: announce.to_owned(),
+            piece_len: Some(DEFAULT_PIECE_LEN),
+            private: false,
+            comment: None,
+            parallel: false,
+        }
+    }
+
+    /// This has to be a power of two between [`MIN_PIECE_LEN`] and
+    /// [`MAX_PIECE_LEN`], the same as for parsing.
+    pub fn with_piece_len(mut self, piece_len: u64) -> Self {
+        self.piece_len = Some(piece_len);
+        self
+    }
+
+    /// Picks the piece length with [`TorrentBuilder::piece_len_for`] once
+    /// it's known how big the content is.
+    pub fn auto_piece_len(mut self) -> Self {
+        self.piece_len = None;
+        self
+    }
+
+    /// The smallest piece length that takes no more than about 2000 pieces
+    /// to cover `total_length`, which is what mainline clients go for too.
+    /// Small torrents end up with fewer pieces, and huge ones with more.
+    pub fn piece_len_for(total_length: u64) -> u64 {
+        total_length
+            .div_ceil(AUTO_MAX_PIECES)
+            .next_power_of_two()
+            .clamp(MIN_PIECE_LEN, AUTO_MAX_PIECE_LEN)
+    }
+
+    pub fn with_private(mut self, private: bool) -> Self {
+        self.private = private;
+        self
+    }
+
+    pub fn with_comment(mut self, comment: &str) -> Self {
+        self.comment = Some(comment.to_owned());
+        self
+    }
+
+    /// Hashes pieces on rayon's thread pool rather than as they're read.
+    /// This only does anything with the `rayon` feature, and the pieces come
+    /// out the same either way.
+    pub fn with_parallel(mut self, parallel: bool) -> Self {
+        self.parallel = parallel;
+        self
+    }
+
+    /// Reads through all of the content to hash it, so this takes as long as
+    /// reading every file does. [`Torrent::write_to`] gives the `.torrent`
+    /// file for it.
+    pub fn build(self) -> Result<Torrent, BuildError> {
+        let name = file_name(&self.path)?;
+        let metadata = fs::metadata(&self.path).context(Io { path: &self.path })?;
+        let multi_file = metadata.is_dir();
+
+        let paths = if multi_file {
+            let mut paths = Vec::new();
+            walk(&self.path, &mut paths)?;
+            paths.sort();
+            paths
+        } else {
+            vec![self.path.clone()]
+        };
+
+        let piece_len = match self.piece_len {
+            Some(piece_len) => piece_len,
+            None => {
+                let mut total_length = 0;
+
+                for path in &paths {
+                    total_length += fs::metadata(path).context(Io { path })?.len();
+                }
+
+                Self::piece_len_for(total_length)
+            }
+        };
+
+        ensure!(
+            piece_len.is_power_of_two() && (MIN_PIECE_LEN..=MAX_PIECE_LEN).contains(&piece_len),
+            InvalidPieceLen { piece_len }
+        );
+
+        let mut files = Vec::new();
+        let mut hasher = PieceHasher::new(piece_len, self.parallel);
+
+        for path in paths {
+            let mut file = File::open(&path).context(Io { path: &path })?;
+            let length = hasher.update(&mut file).context(Io { path: &path })?;
+
+            // Single-file torrents have the name as the file's whole path.
+            let relative = match path.strip_prefix(&self.path) {
+                Ok(relative) if multi_file => relative
+                    .iter()
+                    .map(|component| {
+                        component
+                            .to_str()
+                            .map(str::to_owned)
+                            .context(NotUtf8 { path: &path })
+                    })
+                    .collect::<Result<_, _>>()?,
+                _ => PathBuf::from(&name),
+            };
+
+            files.push(TorrentFile {
+                length,
+                path: relative,
+                md5sum: None,
+                pieces_root: None,
+                raw_path: None,
+                attr: FileAttr::default(),
+                symlink_path: None,
+                extra: BTreeMap::new(),
+            });
+        }
+
+        let pieces = hasher.finish();
+
+        ensure!(!pieces.is_empty(), NoContent { path: &self.path });
+
+        let mut torrent = Torrent::new(
+            self.announce,
+            TorrentInfo {
+                name,
+                files,
+                piece_len,
+                pieces,
+                private: self.private,
+                multi_file,
+                meta_version: None,
+                raw_name: None,
+                extra: BTreeMap::new(),
+            },
+        );
+
+        torrent.comment = self.comment;
+        torrent.created_by = Some(concat!("matey/", env!("CARGO_PKG_VERSION")).to_owned());
+        torrent.creation_date = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .ok()
+            .map(|since| since.as_secs() as i64);
+
+        Ok(torrent)
+    }
+}
+
+fn file_name(path: &Path) -> Result<String, BuildError> {
+    // canonicalize makes sure something like "." still has a name.
+    let path = path.canonicalize().context(Io { path })?;
+
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .map(str::to_owned)
+        .context(NotUtf8 { path: &path })
+}
+
+/// Collects every file under `dir`, however deep.
+fn walk(dir: &Path, paths: &mut Vec<PathBuf>) -> Result<(), BuildError> {
+    for entry in fs::read_dir(dir).context(Io { path: dir })? {
+        let path = entry.context(Io { path: dir })?.path();
+
+        if fs::metadata(&path).context(Io { path: &path })?.is_dir() {
+            walk(&path, paths)?;
+        } else {
+            paths.push(path);
+        }
+    }
+
+    Ok(())
+}
+
+/// Pieces run on from one file into the next, so this hashes everything as
+/// one long stream. When hashing in parallel, whole pieces are held onto
+/// until there are enough to keep every thread busy.
+struct PieceHasher {
+    piece_len: u64,
+    parallel: bool,
+    buf: Vec<u8>,
+    pending: Vec<Vec<u8>>,
+    pieces: Vec<SHA1Hash>,
+}
+
+impl PieceHasher {
+    fn new(piece_len: u64, parallel: bool) -> Self {
+        Self {
+            piece_len,
+            parallel,
+            buf: Vec::with_capacity(piece_len as usize),
+            pending: Vec::new(),
+            pieces: Vec::new(),
+        }
+    }
+
+    /// Hashes everything in `reader`, returning how much there was.
+    fn update<R: Read>(&mut self, reader: &mut R) -> io::Result<u64> {
+        let mut read = 0;
+
+        loop {
+            let remaining = self.piece_len - self.buf.len() as u64;
+            let n = reader.take(remaining).read_to_end(&mut self.buf)?;
+
+            if n == 0 {
+                return Ok(read);
+            }
+
+            read += n as u64;
+
+            if self.buf.len() as u64 == self.piece_len {
+                self.hash_piece();
+            }
+        }
+    }
+
+    fn hash_piece(&mut self) {
+        let piece = mem::replace(&mut self.buf, Vec::with_capacity(self.piece_len as usize));
+        self.pending.push(piece);
+
+        let batch_len = if self.parallel { PARALLEL_BATCH_LEN } else { 1 };
+
+        if self.pending.len() >= batch_len {
+            self.hash_pending();
+        }
+    }
+
+    fn hash_pending(&mut self) {
+        let hash = |piece: &Vec<u8>| SHA1Hash(Sha1::digest(piece).as_slice().try_into().unwrap());
+
+        // Collecting keeps the pieces in order however they get hashed.
+        #[cfg(feature = "rayon")]
+        let hashes = if self.parallel {
+            use rayon::prelude::*;
+
+            self.pending.par_iter().map(hash).collect::<Vec<_>>()
+        } else {
+            self.pending.iter().map(hash).collect()
+        };
+
+        #[cfg(not(feature = "rayon"))]
+        let hashes = self.pending.iter().map(hash).collect::<Vec<_>>();
+
+        self.pieces.extend(hashes);
+        self.pending.clear();
+    }
+
+    /// The last piece is whatever's left over.
+    fn finish(mut self) -> Vec<SHA1Hash> {
+        if !self.buf.is_empty() {
+            self.hash_piece();
+        }
+
+        self.hash_pending();
+
+        self.pieces
+    }
+}
+
+#[derive(Debug, Snafu)]
+pub enum BuildError {
+    #[snafu(display("Couldn't read {}: {}", path.display(), source))]
+    Io { path: PathBuf, source: io::Error },
+    #[snafu(display("{} isn't valid UTF-8", path.display()))]
+    NotUtf8 { path: PathBuf },
+    #[snafu(display("There's nothing in {} to make a torrent of", path.display()))]
+    NoContent { path: PathBuf },
+    #[snafu(display(
+        "Piece length {} isn't a power of two between {} and {}",
+        piece_len,
+        MIN_PIECE_LEN,
+        MAX_PIECE_LEN
+    ))]
+    InvalidPieceLen { piece_len: u64 },
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util;
+    use std::convert::TryFrom;
+
+    fn base_dir(name: &str) -> PathBuf {
+        test_util::base_dir("builder", name)
+    }
+
+    #[test]
+    fn build_from_directory() {
+        let base_dir = base_dir("dir");
+        let root = base_dir.join("content");
+        let files = [
+            ("b.txt", vec![b'b'; 20000]),
+            ("a.txt", vec![b'a'; 5]),
+            ("sub/empty", vec![]),
+            ("sub/c.bin", (0..50000).map(|i| i as u8).collect()),
+        ];
+
+        for (path, data) in &files {
+            let path = root.join(path);
+
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, data).unwrap();
+        }
+
+        let built = TorrentBuilder::new(&root, "http://one.example.com/announce")
+            .with_piece_len(16384)
+            .with_private(true)
+            .with_comment("hello")
+            .build()
+            .unwrap();
+        let mut bytes = Vec::new();
+        built.write_to(&mut bytes).unwrap();
+
+        fs::remove_dir_all(&base_dir).unwrap();
+
+        let torrent = Torrent::try_from(bytes.as_slice()).unwrap();
+        let info = &torrent.info;
+
+        assert_eq!(torrent, built);
+        assert_eq!(
+            torrent.announce.as_deref(),
+            Some("http://one.example.com/announce")
+        );
+        assert_eq!(torrent.comment.as_deref(), Some("hello"));
+        assert_eq!(info.name, "content");
+        assert!(info.private && info.multi_file);
+        assert_eq!(
+            info.files
+                .iter()
+                .map(|file| (file.path.clone(), file.length))
+                .collect::<Vec<_>>(),
+            vec![
+                (PathBuf::from("a.txt"), 5),
+                (PathBuf::from("b.txt"), 20000),
+                (PathBuf::from("sub/c.bin"), 50000),
+                (PathBuf::from("sub/empty"), 0),
+            ]
+        );
+        info.check_consistency().unwrap();
+
+        // The content as a whole is the files one after the other, sorted.
+        let content = [&files[1].1, &files[0].1, &files[3].1, &files[2].1]
+            .iter()
+            .flat_map(|data| data.iter().copied())
+            .collect::<Vec<_>>();
+
+        assert!(info.verify_file(&content).into_iter().all(|ok| ok));
+    }
+
+    #[test]
+    fn build_from_file() {
+        let base_dir = base_dir("file");
+        let path = base_dir.join("hello.txt");
+
+        fs::create_dir_all(&base_dir).unwrap();
+        fs::write(&path, b"hello").unwrap();
+
+        let torrent = TorrentBuilder::new(&path, "http://one.example.com/announce").build();
+
+        fs::remove_dir_all(&base_dir).unwrap();
+
+        let info = torrent.unwrap().info;
+
+        assert_eq!(info.name, "hello.txt");
+        assert!(!info.multi_file && !info.private);
+        assert_eq!(info.piece_len, DEFAULT_PIECE_LEN);
+        assert_eq!(info.files[0].path, PathBuf::from("hello.txt"));
+        assert!(info.verify_piece(0, b"hello"));
+    }
+
+    #[test]
+    fn parallel_hashing() {
+        let base_dir = base_dir("parallel");
+        let root = base_dir.join("content");
+
+        // Enough pieces for a few batches, with pieces spanning files.
+        for i in 0..20 {
+            let path = root.join(format!("{:02}", i));
+
+            fs::create_dir_all(&root).unwrap();
+            fs::write(path, vec![i as u8; 30000 + i * 1000]).unwrap();
+        }
+
+        let builder = TorrentBuilder::new(&root, "").with_piece_len(16384);
+        let sequential = builder.clone().build();
+        let parallel = builder.with_parallel(true).build();
+
+        fs::remove_dir_all(&base_dir).unwrap();
+
+        let (sequential, parallel) = (sequential.unwrap().info, parallel.unwrap().info);
+
+        assert_eq!(sequential.num_pieces(), 49);
+        assert_eq!(parallel.pieces, sequential.pieces);
+    }
+
+    #[test]
+    fn piece_len_for() {
+        const KIB: u64 = 1024;
+        const MIB: u64 = 1024 * KIB;
+        const GIB: u64 = 1024 * MIB;
+
+        let cases = [
+            (0, 16 * KIB),
+            (5, 16 * KIB),
+            (10 * MIB, 16 * KIB),
+            (2000 * 16 * KIB, 16 * KIB),
+            (2000 * 16 * KIB + 1, 32 * KIB),
+            (100 * MIB, 64 * KIB),
+            (GIB, MIB),
+            (4 * GIB + 1, 4 * MIB),
+            (50 * GIB, 16 * MIB),
+            (1024 * GIB, 16 * MIB),
+        ];
+
+        for &(total_length, piece_len) in &cases {
+            assert_eq!(
+                TorrentBuilder::piece_len_for(total_length),
+                piece_len,
+                "for {} bytes",
+                total_length
+            );
+
+            let num_pieces = total_length.div_ceil(piece_len);
+
+            assert!(num_pieces <= AUTO_MAX_PIECES || piece_len == AUTO_MAX_PIECE_LEN);
+        }
+    }
+
+    #[test]
+    fn build_with_auto_piece_len() {
+        let base_dir = base_dir("auto");
+        let path = base_dir.join("big");
+
+        fs::create_dir_all(&base_dir).unwrap();
+        File::create(&path)
+            .and_then(|file| file.set_len(2000 * 16384 + 1))
+            .unwrap();
+
+        let torrent = TorrentBuilder::new(&path, "").auto_piece_len().build();
+
+        fs::remove_dir_all(&base_dir).unwrap();
+
+        let info = torrent.unwrap().info;
+
+        assert_eq!(info.piece_len, 32768);
+        assert_eq!(info.num_pieces(), 1001);
+    }
+
+    #[test]
+    fn build_errors() {
+        let base_dir = base_dir("errors");
+
+        fs::create_dir_all(base_dir.join("empty")).unwrap();
+
+        let results = (
+            TorrentBuilder::new(base_dir.join("empty"), "").build(),
+            TorrentBuilder::new(base_dir.join("missing"), "").build(),
+            TorrentBuilder::new(&base_dir, "")
+                .with_piece_len(49152)
+                .build(),
+        );
+
+        fs::remove_dir_all(&base_dir).unwrap();
+
+        assert!(matches!(results.0, Err(BuildError::NoContent { .. })));
+        assert!(matches!(results.1, Err(BuildError::Io { .. })));
+        assert!(matches!(
+            results.2,
+            Err(BuildError::InvalidPieceLen { piece_len: 49152 })
+        ));
+    }
+}
@@ -0,0 +1,263 @@
+use super::peer::PeerMessage;
+use std::{collections::BTreeMap, time::Duration};
+
+/// How many of the peers that upload to us fastest get unchoked, not
+/// counting the optimistic unchoke.
+pub const DEFAULT_UNCHOKE_SLOTS: usize = 4;
+/// How often [`ChokeManager::rechoke`] should be called.
+pub const RECHOKE_INTERVAL: Duration = Duration::from_secs(10);
+/// The optimistic unchoke moves on every this many rechokes, so every 30
+/// seconds.
+const OPTIMISTIC_ROUNDS: u32 = 3;
+
+#[derive(Clone, Copy, Debug, Default)]
+struct PeerState {
+    interested: bool,
+    unchoked: bool,
+    /// Bytes received since the last rechoke.
+    downloaded: u64,
+    /// The download rate over the last full round, in bytes per second.
+    rate: f64,
+}
+
+/// Decides which peers to upload to with BitTorrent's tit-for-tat: the ones
+/// uploading to us the fastest get unchoked, plus one more optimistically so
+/// that new peers get a chance to show what they can do. Peers are
+/// identified by their number.
+#[derive(Clone, Debug)]
+pub struct ChokeManager {
+    slots: usize,
+    peers: BTreeMap<usize, PeerState>,
+    optimistic: Option<usize>,
+    rounds: u32,
+}
+
+impl Default for ChokeManager {
+    fn default() -> Self {
+        Self::new(DEFAULT_UNCHOKE_SLOTS)
+    }
+}
+
+impl ChokeManager {
+    pub fn new(slots: usize) -> Self {
+        Self {
+            slots,
+            peers: BTreeMap::new(),
+            optimistic: None,
+            rounds: 0,
+        }
+    }
+
+    /// Every peer starts out choked, like the protocol has it.
+    pub fn add_peer(&mut self, peer: usize) {
+        self.peers.entry(peer).or_default();
+    }
+
+    pub fn remove_peer(&mut self, peer: usize) {
+        self.peers.remove(&peer);
+
+        if self.optimistic == Some(peer) {
+            self.optimistic = None;
+        }
+    }
+
+    pub fn set_interested(&mut self, peer: usize, interested: bool) {
+        if let Some(state) = self.peers.get_mut(&peer) {
+            state.interested = interested;
+        }
+    }
+
+    pub fn record_download(&mut self, peer: usize, bytes: u64) {
+        if let Some(state) = self.peers.get_mut(&peer) {
+            state.downloaded += bytes;
+        }
+    }
+
+    /// How fast `peer` uploaded to us before the last rechoke.
+    pub fn download_rate(&self, peer: usize) -> Option<f64> {
+        self.peers.get(&peer).map(|state| state.rate)
+    }
+
+    pub fn is_unchoked(&self, peer: usize) -> bool {
+        self.peers.get(&peer).is_some_and(|state| state.unchoked)
+    }
+
+    pub fn unchoked(&self) -> Vec<usize> {
+        self.peers
+            .iter()
+            .filter(|(_, state)| state.unchoked)
+            .map(|(&peer, _)| peer)
+            .collect()
+    }
+
+    pub fn optimistic(&self) -> Option<usize> {
+        self.optimistic
+    }
+
+    /// Works out who to unchoke based on how much each peer sent us since the
+    /// last call, returning a choke or unchoke for each peer whose state
+    /// changed. This should be called every [`RECHOKE_INTERVAL`].
+    pub fn rechoke(&mut self) -> Vec<(usize, PeerMessage)> {
+        for state in self.peers.values_mut() {
+            state.rate = state.downloaded as f64 / RECHOKE_INTERVAL.as_secs_f64();
+            state.downloaded = 0;
+        }
+
+        let mut interested = self
+            .peers
+            .iter()
+            .filter(|(_, state)| state.interested)
+            .map(|(&peer, state)| (peer, state.rate))
+            .collect::<Vec<_>>();
+        // Sorting is stable, so ties go to the lowest number.
+        interested.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+
+        let top = interested
+            .iter()
+            .take(self.slots)
+            .map(|&(peer, _)| peer)
+            .collect::<Vec<_>>();
+        let mut candidates = interested
+            .iter()
+            .map(|&(peer, _)| peer)
+            .filter(|peer| !top.contains(peer))
+            .collect::<Vec<_>>();
+        candidates.sort_unstable();
+
+        let rotate = self.rounds.is_multiple_of(OPTIMISTIC_ROUNDS);
+        self.rounds += 1;
+
+        // Going round in order makes sure every peer gets a turn eventually.
+        match self.optimistic {
+            Some(current) if !rotate && candidates.contains(&current) => {}
+            current => {
+                self.optimistic = candidates
+                    .iter()
+                    .find(|&&peer| current.is_none_or(|current| peer > current))
+                    .or_else(|| candidates.first())
+                    .copied();
+            }
+        }
+
+        let mut messages = Vec::new();
+
+        for (&peer, state) in self.peers.iter_mut() {
+            let unchoke = top.contains(&peer) || self.optimistic == Some(peer);
+
+            if unchoke != state.unchoked {
+                state.unchoked = unchoke;
+                messages.push((
+                    peer,
+                    if unchoke {
+                        PeerMessage::Unchoke
+                    } else {
+                        PeerMessage::Choke
+                    },
+                ));
+            }
+        }
+
+        messages
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Sets up `num_peers` peers, all interested.
+    fn manager(slots: usize, num_peers: usize) -> ChokeManager {
+        let mut manager = ChokeManager::new(slots);
+
+        for peer in 0..num_peers {
+            manager.add_peer(peer);
+            manager.set_interested(peer, true);
+        }
+
+        manager
+    }
+
+    /// Has each peer send us its rate's worth of bytes for one round.
+    fn round(manager: &mut ChokeManager, rates: &[u64]) -> Vec<(usize, PeerMessage)> {
+        for (peer, &rate) in rates.iter().enumerate() {
+            manager.record_download(peer, rate * RECHOKE_INTERVAL.as_secs());
+        }
+
+        manager.rechoke()
+    }
+
+    #[test]
+    fn unchokes_fastest_peers() {
+        let rates = [10, 50, 0, 40, 30];
+        let mut manager = manager(2, rates.len());
+
+        let messages = round(&mut manager, &rates);
+
+        // 1 and 3 are fastest, and 0 is first in line for the optimistic one.
+        assert_eq!(manager.unchoked(), vec![0, 1, 3]);
+        assert_eq!(manager.optimistic(), Some(0));
+        assert_eq!(
+            messages,
+            vec![
+                (0, PeerMessage::Unchoke),
+                (1, PeerMessage::Unchoke),
+                (3, PeerMessage::Unchoke)
+            ]
+        );
+        assert_eq!(manager.download_rate(1), Some(50.0));
+
+        // Peer 4 speeds up and takes peer 3's slot.
+        let rates = [10, 50, 0, 40, 60];
+        let messages = round(&mut manager, &rates);
+
+        assert_eq!(manager.unchoked(), vec![0, 1, 4]);
+        assert_eq!(
+            messages,
+            vec![(3, PeerMessage::Choke), (4, PeerMessage::Unchoke)]
+        );
+    }
+
+    #[test]
+    fn optimistic_rotation() {
+        let rates = [0, 0, 0, 100];
+        let mut manager = manager(1, rates.len());
+
+        let mut optimistic = Vec::new();
+
+        for _ in 0..9 {
+            round(&mut manager, &rates);
+            optimistic.push(manager.optimistic().unwrap());
+            assert!(manager.is_unchoked(3));
+        }
+
+        // It moves on every third round, and skips the peer that's already
+        // unchoked for its rate.
+        assert_eq!(optimistic, vec![0, 0, 0, 1, 1, 1, 2, 2, 2]);
+
+        round(&mut manager, &rates);
+
+        assert_eq!(manager.optimistic(), Some(0));
+        assert_eq!(manager.unchoked(), vec![0, 3]);
+    }
+
+    #[test]
+    fn uninterested_and_removed_peers() {
+        let rates = [100, 50, 10];
+        let mut manager = manager(1, rates.len());
+
+        manager.set_interested(0, false);
+        round(&mut manager, &rates);
+
+        // Peer 0 is fastest, but doesn't want anything from us.
+        assert_eq!(manager.unchoked(), vec![1, 2]);
+        assert_eq!(manager.optimistic(), Some(2));
+
+        // Losing the optimistic unchoke gets a new one straight away.
+        manager.remove_peer(2);
+        manager.set_interested(0, true);
+
+        assert_eq!(round(&mut manager, &rates), vec![(0, PeerMessage::Unchoke)]);
+        assert_eq!(manager.unchoked(), vec![0, 1]);
+        assert_eq!(manager.optimistic(), Some(1));
+    }
+}
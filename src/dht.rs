@@ -0,0 +1,691 @@
+use super::{
+    bencode_parser::{encode, parse_bencode, Bencode},
+    torrent_parser::{SHA1Hash, Torrent},
+    tracker::{parse_compact_peer, parse_compact_peers},
+};
+use bytes::Bytes;
+use snafu::{ensure, OptionExt, ResultExt, Snafu};
+use std::{
+    collections::{BTreeMap, HashSet},
+    convert::TryInto,
+    fmt, io,
+    net::{SocketAddr, SocketAddrV4},
+    time::Duration,
+};
+use tokio::{
+    net::{self, UdpSocket},
+    time::{self, Instant},
+};
+
+/// Well-known nodes that are always up, for finding our first few nodes.
+pub const BOOTSTRAP_NODES: &[&str] = &["router.bittorrent.com:6881"];
+/// How many nodes a lookup keeps track of as the closest to its target,
+/// which BEP 5 calls K.
+pub const LOOKUP_SIZE: usize = 8;
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+const COMPACT_NODE_LEN: usize = 26;
+
+/// Identifies a node, and shares its keyspace with info hashes so that the
+/// nodes closest to a torrent's info hash are the ones that track its peers.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct NodeId(pub [u8; 20]);
+
+impl NodeId {
+    pub fn random() -> Self {
+        Self(rand::random())
+    }
+
+    /// The XOR metric from Kademlia, which compares in the same order as the
+    /// distance it represents.
+    pub fn distance(&self, other: &NodeId) -> [u8; 20] {
+        let mut distance = self.0;
+
+        for (byte, other) in distance.iter_mut().zip(&other.0) {
+            *byte ^= other;
+        }
+
+        distance
+    }
+}
+
+impl From<SHA1Hash> for NodeId {
+    fn from(hash: SHA1Hash) -> Self {
+        Self(hash.0)
+    }
+}
+
+impl fmt::Debug for NodeId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "NodeId({})", SHA1Hash(self.0))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Node {
+    pub id: NodeId,
+    pub addr: SocketAddrV4,
+}
+
+/// Decodes BEP 5's compact node info, which is each node's ID followed by
+/// its address in the compact peer format.
+pub fn parse_compact_nodes(bytes: &[u8]) -> Result<Vec<Node>, DhtError> {
+    let (chunks, remainder) = bytes.as_chunks::<COMPACT_NODE_LEN>();
+
+    ensure!(remainder.is_empty(), MalformedMessage);
+
+    chunks
+        .iter()
+        .map(|chunk| {
+            let addr = parse_compact_peers(&chunk[20..])
+                .ok()
+                .and_then(|addrs| addrs.first().copied())
+                .context(MalformedMessage)?;
+
+            Ok(Node {
+                id: NodeId(chunk[0..20].try_into().unwrap()),
+                addr,
+            })
+        })
+        .collect()
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Query {
+    Ping,
+    FindNode { target: NodeId },
+    GetPeers { info_hash: SHA1Hash },
+}
+
+impl Query {
+    fn name(&self) -> &'static [u8] {
+        match self {
+            Self::Ping => b"ping",
+            Self::FindNode { .. } => b"find_node",
+            Self::GetPeers { .. } => b"get_peers",
+        }
+    }
+
+    /// The KRPC message for this query, as sent by the node `id`.
+    pub fn to_bencode(&self, transaction_id: &[u8], id: NodeId) -> Bencode {
+        let mut args = BTreeMap::new();
+
+        args.insert(
+            Bytes::from_static(b"id"),
+            Bencode::ByteString(Bytes::copy_from_slice(&id.0)),
+        );
+
+        match self {
+            Self::Ping => {}
+            Self::FindNode { target } => {
+                args.insert(
+                    Bytes::from_static(b"target"),
+                    Bencode::ByteString(Bytes::copy_from_slice(&target.0)),
+                );
+            }
+            Self::GetPeers { info_hash } => {
+                args.insert(
+                    Bytes::from_static(b"info_hash"),
+                    Bencode::ByteString(Bytes::copy_from_slice(info_hash.as_ref())),
+                );
+            }
+        }
+
+        let mut dict = BTreeMap::new();
+
+        dict.insert(
+            Bytes::from_static(b"t"),
+            Bencode::ByteString(Bytes::copy_from_slice(transaction_id)),
+        );
+        dict.insert(
+            Bytes::from_static(b"y"),
+            Bencode::ByteString(Bytes::from_static(b"q")),
+        );
+        dict.insert(
+            Bytes::from_static(b"q"),
+            Bencode::ByteString(Bytes::from_static(self.name())),
+        );
+        dict.insert(Bytes::from_static(b"a"), Bencode::Dict(args));
+        // We never answer queries, so per BEP 43 other nodes shouldn't bother
+        // adding us to their routing tables.
+        dict.insert(Bytes::from_static(b"ro"), Bencode::Number(1));
+
+        Bencode::Dict(dict)
+    }
+}
+
+/// What a node answered a query with. Which of these are set depends on the
+/// query: a ping only gets an ID back, and `get_peers` gets either peers or
+/// nodes closer to the info hash along with a token for announcing.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Response {
+    pub id: NodeId,
+    pub nodes: Vec<Node>,
+    pub peers: Vec<SocketAddr>,
+    pub token: Option<Bytes>,
+}
+
+/// Decodes a response or error to the query with `transaction_id`, or
+/// returns `None` if `message` was for some other query.
+pub fn parse_response(message: &[u8], transaction_id: &[u8]) -> Result<Option<Response>, DhtError> {
+    let mut dict = parse_bencode(message)
+        .ok()
+        .and_then(|(_, bencode)| bencode.dict())
+        .context(MalformedMessage)?;
+
+    if dict.get(b"t" as &[u8]).and_then(Bencode::as_bytes) != Some(transaction_id) {
+        return Ok(None);
+    }
+
+    match dict.remove(b"y" as &[u8]).and_then(|val| val.byte_string()) {
+        Some(kind) if kind == b"e" as &[u8] => {
+            // Errors are a list of a code and a message.
+            let error = dict
+                .remove(b"e" as &[u8])
+                .and_then(|val| val.list())
+                .context(MalformedMessage)?;
+
+            ErrorResponse {
+                code: error.first().and_then(Bencode::as_i64).unwrap_or_default(),
+                message: error
+                    .get(1)
+                    .and_then(Bencode::as_bytes)
+                    .map(String::from_utf8_lossy)
+                    .unwrap_or_default(),
+            }
+            .fail()
+        }
+        Some(kind) if kind == b"r" as &[u8] => {
+            let mut values = dict
+                .remove(b"r" as &[u8])
+                .and_then(|val| val.dict())
+                .context(MalformedMessage)?;
+
+            let id = values
+                .remove(b"id" as &[u8])
+                .and_then(|val| val.byte_string())
+                .and_then(|id| id.as_ref().try_into().ok())
+                .map(NodeId)
+                .context(MalformedMessage)?;
+
+            let nodes = match values.remove(b"nodes" as &[u8]) {
+                Some(nodes) => {
+                    parse_compact_nodes(&nodes.byte_string().context(MalformedMessage)?)?
+                }
+                None => vec![],
+            };
+
+            // Unlike trackers, each peer is its own string, and BEP 32 lets
+            // IPv6 peers be mixed in with the IPv4 ones.
+            let peers = match values.remove(b"values" as &[u8]) {
+                Some(peers) => peers
+                    .list()
+                    .context(MalformedMessage)?
+                    .into_iter()
+                    .map(|peer| {
+                        peer.byte_string()
+                            .and_then(|peer| parse_compact_peer(&peer))
+                            .context(MalformedMessage)
+                    })
+                    .collect::<Result<Vec<_>, _>>()?,
+                None => vec![],
+            };
+
+            let token = values
+                .remove(b"token" as &[u8])
+                .and_then(|val| val.byte_string());
+
+            Ok(Some(Response {
+                id,
+                nodes,
+                peers,
+                token,
+            }))
+        }
+        _ => MalformedMessage.fail(),
+    }
+}
+
+/// A DHT client that only ever asks other nodes for things, which is enough
+/// to find peers for trackerless torrents.
+pub struct Dht {
+    socket: UdpSocket,
+    id: NodeId,
+    /// Every node we've heard from or about that hasn't failed to respond.
+    nodes: BTreeMap<NodeId, SocketAddrV4>,
+    timeout: Duration,
+}
+
+impl Dht {
+    pub async fn bind(addr: SocketAddr) -> Result<Self, DhtError> {
+        Ok(Self {
+            socket: UdpSocket::bind(addr).await.context(Socket)?,
+            id: NodeId::random(),
+            nodes: BTreeMap::new(),
+            timeout: QUERY_TIMEOUT,
+        })
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.id
+    }
+
+    pub fn num_nodes(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Checks that the node at `addr` is up, returning its ID.
+    pub async fn ping(&mut self, addr: SocketAddr) -> Result<NodeId, DhtError> {
+        Ok(self.query(addr, &Query::Ping).await?.id)
+    }
+
+    /// Asks the node at `addr` for the nodes it knows closest to `target`.
+    pub async fn find_node(
+        &mut self,
+        addr: SocketAddr,
+        target: NodeId,
+    ) -> Result<Vec<Node>, DhtError> {
+        Ok(self.query(addr, &Query::FindNode { target }).await?.nodes)
+    }
+
+    /// Gets our first nodes by asking `routers` for the ones closest to us.
+    /// It's enough for one of them to respond.
+    pub async fn bootstrap(&mut self, routers: &[&str]) -> Result<(), DhtError> {
+        for &router in routers {
+            let addrs = match net::lookup_host(router).await {
+                Ok(addrs) => addrs,
+                Err(e) => {
+                    log::warn!("Couldn't resolve DHT bootstrap node {}: {}", router, e);
+                    continue;
+                }
+            };
+
+            for addr in addrs.filter(SocketAddr::is_ipv4) {
+                if let Err(e) = self.find_node(addr, self.id).await {
+                    log::warn!("DHT bootstrap node {} failed: {}", router, e);
+                }
+            }
+        }
+
+        ensure!(!self.nodes.is_empty(), BootstrapFailed);
+
+        Ok(())
+    }
+
+    /// Finds peers for `torrent`, which has to be public since BEP 27 has
+    /// private torrents get their peers from their trackers only.
+    pub async fn get_peers(&mut self, torrent: &Torrent) -> Result<Vec<SocketAddr>, DhtError> {
+        ensure!(!torrent.info.private, PrivateTorrent);
+
+        self.get_peers_for(torrent.info_hash()).await
+    }
+
+    /// Repeatedly asks the closest nodes we know to `info_hash` for peers,
+    /// learning about closer nodes as we go, until the closest ones have all
+    /// been asked.
+    pub async fn get_peers_for(
+        &mut self,
+        info_hash: SHA1Hash,
+    ) -> Result<Vec<SocketAddr>, DhtError> {
+        let target = NodeId::from(info_hash);
+        let mut queried = HashSet::new();
+        let mut peers = Vec::new();
+
+        loop {
+            let mut closest = self
+                .nodes
+                .iter()
+                .map(|(&id, &addr)| Node { id, addr })
+                .collect::<Vec<_>>();
+            closest.sort_by_key(|node| node.id.distance(&target));
+            closest.truncate(LOOKUP_SIZE);
+
+            let unqueried = closest
+                .into_iter()
+                .filter(|node| !queried.contains(&node.id))
+                .collect::<Vec<_>>();
+
+            if unqueried.is_empty() {
+                break;
+            }
+
+            for node in unqueried {
+                queried.insert(node.id);
+
+                match self
+                    .query(node.addr.into(), &Query::GetPeers { info_hash })
+                    .await
+                {
+                    Ok(response) => {
+                        for peer in response.peers {
+                            if !peers.contains(&peer) {
+                                peers.push(peer);
+                            }
+                        }
+                    }
+                    Err(e) => log::debug!("DHT node {} failed get_peers: {}", node.addr, e),
+                }
+            }
+        }
+
+        Ok(peers)
+    }
+
+    /// Sends `query` to `addr` and waits for its response, keeping track of
+    /// every node that we learn about along the way.
+    async fn query(&mut self, addr: SocketAddr, query: &Query) -> Result<Response, DhtError> {
+        let transaction_id = rand::random::<[u8; 2]>();
+
+        let result = self
+            .exchange(
+                addr,
+                &encode(&query.to_bencode(&transaction_id, self.id)),
+                &transaction_id,
+            )
+            .await;
+
+        match &result {
+            Ok(response) => {
+                if let SocketAddr::V4(addr) = addr {
+                    self.nodes.insert(response.id, addr);
+                }
+
+                for node in &response.nodes {
+                    if node.id != self.id && !node.addr.ip().is_unspecified() {
+                        self.nodes.insert(node.id, node.addr);
+                    }
+                }
+            }
+            // A node that sent back an error is still up, it just couldn't
+            // help with this query.
+            Err(DhtError::Timeout) => self
+                .nodes
+                .retain(|_, &mut node_addr| SocketAddr::from(node_addr) != addr),
+            Err(_) => {}
+        }
+
+        result
+    }
+
+    async fn exchange(
+        &self,
+        addr: SocketAddr,
+        packet: &[u8],
+        transaction_id: &[u8],
+    ) -> Result<Response, DhtError> {
+        self.socket.send_to(packet, addr).await.context(Socket)?;
+
+        let deadline = Instant::now() + self.timeout;
+        let mut buf = vec![0; 8192];
+
+        loop {
+            let (len, from) =
+                match time::timeout_at(deadline, self.socket.recv_from(&mut buf)).await {
+                    Ok(received) => received.context(Socket)?,
+                    Err(_) => return Timeout.fail(),
+                };
+
+            // Anything else is probably a late response to a query that
+            // already timed out.
+            if from != addr {
+                continue;
+            }
+
+            match parse_response(&buf[..len], transaction_id) {
+                Ok(Some(response)) => return Ok(response),
+                Ok(None) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+#[derive(Debug, Snafu)]
+pub enum DhtError {
+    #[snafu(display("Couldn't use DHT socket: {}", source))]
+    Socket { source: io::Error },
+    #[snafu(display("DHT node sent a malformed message"))]
+    MalformedMessage,
+    #[snafu(display("DHT node returned error {}: {}", code, message))]
+    ErrorResponse { code: i64, message: String },
+    #[snafu(display("DHT node didn't respond"))]
+    Timeout,
+    #[snafu(display("Couldn't reach any DHT bootstrap nodes"))]
+    BootstrapFailed,
+    #[snafu(display("DHT can't be used for private torrents"))]
+    PrivateTorrent,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::{convert::TryFrom, net::Ipv6Addr};
+
+    fn compact_node(node: &Node) -> Vec<u8> {
+        [
+            &node.id.0 as &[u8],
+            &node.addr.ip().octets(),
+            &node.addr.port().to_be_bytes(),
+        ]
+        .concat()
+    }
+
+    /// Answers every query it gets with `id`, `nodes`, and for `get_peers`,
+    /// `peers` too, until the test ends.
+    async fn mock_node(id: NodeId, nodes: Vec<Node>, peers: Vec<SocketAddrV4>) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let mut buf = vec![0; 8192];
+
+            loop {
+                let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+                let (_, query) = parse_bencode(&buf[..len]).unwrap();
+
+                assert_eq!(
+                    query.get(b"y").and_then(Bencode::as_bytes),
+                    Some(b"q" as &[u8])
+                );
+                assert!(query.get(b"a").and_then(|args| args.get(b"id")).is_some());
+
+                let mut values = BTreeMap::new();
+                values.insert(
+                    Bytes::from_static(b"id"),
+                    Bencode::ByteString(Bytes::copy_from_slice(&id.0)),
+                );
+
+                match query.get(b"q").and_then(Bencode::as_bytes) {
+                    Some(b"ping") => {}
+                    Some(b"find_node") => {
+                        values.insert(
+                            Bytes::from_static(b"nodes"),
+                            Bencode::ByteString(nodes.iter().flat_map(compact_node).collect()),
+                        );
+                    }
+                    Some(b"get_peers") => {
+                        values.insert(
+                            Bytes::from_static(b"token"),
+                            Bencode::ByteString(Bytes::from_static(b"tok")),
+                        );
+
+                        if peers.is_empty() {
+                            values.insert(
+                                Bytes::from_static(b"nodes"),
+                                Bencode::ByteString(nodes.iter().flat_map(compact_node).collect()),
+                            );
+                        } else {
+                            values.insert(
+                                Bytes::from_static(b"values"),
+                                Bencode::List(
+                                    peers
+                                        .iter()
+                                        .map(|peer| {
+                                            Bencode::ByteString(
+                                                [
+                                                    &peer.ip().octets() as &[u8],
+                                                    &peer.port().to_be_bytes(),
+                                                ]
+                                                .concat()
+                                                .into(),
+                                            )
+                                        })
+                                        .collect(),
+                                ),
+                            );
+                        }
+                    }
+                    _ => panic!("Unexpected query"),
+                }
+
+                let mut response = BTreeMap::new();
+                response.insert(Bytes::from_static(b"t"), query.get(b"t").unwrap().clone());
+                response.insert(
+                    Bytes::from_static(b"y"),
+                    Bencode::ByteString(Bytes::from_static(b"r")),
+                );
+                response.insert(Bytes::from_static(b"r"), Bencode::Dict(values));
+
+                socket
+                    .send_to(&encode(&Bencode::Dict(response)), from)
+                    .await
+                    .unwrap();
+            }
+        });
+
+        addr
+    }
+
+    fn v4(addr: SocketAddr) -> SocketAddrV4 {
+        match addr {
+            SocketAddr::V4(addr) => addr,
+            SocketAddr::V6(_) => unreachable!(),
+        }
+    }
+
+    async fn dht() -> Dht {
+        Dht::bind("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap()
+            .with_timeout(Duration::from_millis(200))
+    }
+
+    #[test]
+    fn xor_distance() {
+        let a = NodeId([0b1010; 20]);
+        let b = NodeId([0b0110; 20]);
+
+        assert_eq!(a.distance(&b), [0b1100; 20]);
+        assert_eq!(a.distance(&a), [0; 20]);
+    }
+
+    #[test]
+    fn compact_nodes() {
+        let node = Node {
+            id: NodeId([7; 20]),
+            addr: "10.0.0.1:6881".parse().unwrap(),
+        };
+
+        assert_eq!(
+            parse_compact_nodes(&compact_node(&node)).unwrap(),
+            vec![node]
+        );
+        assert!(parse_compact_nodes(&[0; 25]).is_err());
+    }
+
+    #[test]
+    fn error_response() {
+        assert!(matches!(
+            parse_response(b"d1:eli201e13:Generic Errore1:t2:aa1:y1:ee", b"aa"),
+            Err(DhtError::ErrorResponse { code: 201, message }) if message == "Generic Error"
+        ));
+        assert!(matches!(
+            parse_response(b"d1:eli201e13:Generic Errore1:t2:bb1:y1:ee", b"aa"),
+            Ok(None)
+        ));
+    }
+
+    #[test]
+    fn mixed_values() {
+        let mut message = b"d1:rd2:id20:".to_vec();
+        message.extend_from_slice(&[1; 20]);
+        message.extend_from_slice(b"6:valuesl6:\x0a\x00\x00\x01\x1a\xe118:");
+        message.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        message.extend_from_slice(b"\x1a\xe2ee1:t2:aa1:y1:re");
+
+        assert_eq!(
+            parse_response(&message, b"aa").unwrap().unwrap().peers,
+            vec![
+                "10.0.0.1:6881".parse::<SocketAddr>().unwrap(),
+                "[::1]:6882".parse().unwrap(),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn ping_mock_node() {
+        let addr = mock_node(NodeId([1; 20]), vec![], vec![]).await;
+        let mut dht = dht().await;
+
+        assert_eq!(dht.ping(addr).await.unwrap(), NodeId([1; 20]));
+        assert_eq!(dht.num_nodes(), 1);
+    }
+
+    #[tokio::test]
+    async fn bootstrap_and_get_peers() {
+        let info_hash = SHA1Hash([0xf0; 20]);
+        let peer = "10.1.2.3:51413".parse().unwrap();
+
+        let close_node = Node {
+            id: NodeId([0xf1; 20]),
+            addr: v4(mock_node(NodeId([0xf1; 20]), vec![], vec![peer]).await),
+        };
+        let router = mock_node(NodeId([0x01; 20]), vec![close_node], vec![]).await;
+        // Nothing is listening here, so the lookup has to get past it.
+        let dead_node = Node {
+            id: NodeId([0xf2; 20]),
+            addr: v4(UdpSocket::bind("127.0.0.1:0")
+                .await
+                .unwrap()
+                .local_addr()
+                .unwrap()),
+        };
+
+        let mut dht = dht().await;
+        dht.bootstrap(&[&router.to_string()]).await.unwrap();
+        dht.nodes.insert(dead_node.id, dead_node.addr);
+
+        assert_eq!(dht.num_nodes(), 3);
+        assert_eq!(
+            dht.get_peers_for(info_hash).await.unwrap(),
+            vec![SocketAddr::from(peer)]
+        );
+        assert_eq!(dht.num_nodes(), 2);
+    }
+
+    #[tokio::test]
+    async fn bootstrap_with_no_nodes() {
+        let mut dht = dht().await;
+
+        assert!(matches!(
+            dht.bootstrap(&[]).await,
+            Err(DhtError::BootstrapFailed)
+        ));
+    }
+
+    #[tokio::test]
+    async fn refuses_private_torrents() {
+        let mut torrent =
+            Torrent::try_from(&include_bytes!("../tests/fixtures/hybrid.torrent")[..]).unwrap();
+        torrent.info.private = true;
+
+        assert!(matches!(
+            dht().await.get_peers(&torrent).await,
+            Err(DhtError::PrivateTorrent)
+        ));
+    }
+}
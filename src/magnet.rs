@@ -0,0 +1,324 @@
+use super::{
+    torrent_parser::{sha256_from_hex, to_hex, SHA1Hash, SHA1HashParsingError, Torrent},
+    tracker::urlencode_bytes,
+};
+use snafu::{ensure, OptionExt, ResultExt, Snafu};
+use url::Url;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Magnet {
+    /// Links to v2-only torrents only come with the v2 hash, and there's
+    /// always at least one of the two.
+    pub info_hash: Option<SHA1Hash>,
+    pub info_hash_v2: Option<[u8; 32]>,
+    pub name: Option<String>,
+    pub trackers: Vec<String>,
+}
+
+pub fn parse_magnet(uri: &str) -> Result<Magnet, MagnetError> {
+    let url = Url::parse(uri).context(InvalidUri)?;
+
+    ensure!(url.scheme() == "magnet", NotAMagnet);
+
+    let mut info_hash = None;
+    let mut info_hash_v2 = None;
+    let mut name = None;
+    let mut trackers = Vec::new();
+
+    // query_pairs takes care of percent-decoding for us.
+    for (key, value) in url.query_pairs() {
+        match &*key {
+            "xt" => {
+                // Links can carry hashes for other networks alongside ours,
+                // in any order, so those are skipped over. So are multihashes
+                // that aren't a 32 byte SHA-256 digest, which can't be a v2
+                // info hash.
+                if let Some(encoded_hash) = value.strip_prefix("urn:btih:") {
+                    if info_hash.is_none() {
+                        info_hash = Some(match encoded_hash.len() {
+                            32 => decode_base32_hash(encoded_hash)?,
+                            _ => encoded_hash.parse().context(InvalidHexHash)?,
+                        });
+                    }
+                } else if let Some(encoded_hash) = value.strip_prefix("urn:btmh:1220") {
+                    if info_hash_v2.is_none() {
+                        info_hash_v2 = Some(
+                            sha256_from_hex(encoded_hash)
+                                .context(InvalidV2Hash { hash: encoded_hash })?,
+                        );
+                    }
+                }
+            }
+            "dn" => name = Some(value.into_owned()),
+            "tr" => trackers.push(value.into_owned()),
+            _ => {}
+        }
+    }
+
+    ensure!(
+        info_hash.is_some() || info_hash_v2.is_some(),
+        MissingInfoHash
+    );
+
+    Ok(Magnet {
+        info_hash,
+        info_hash_v2,
+        name,
+        trackers,
+    })
+}
+
+impl Torrent {
+    /// A magnet link with the torrent's info hash, name and every one of its
+    /// trackers. v2 torrents get a `urn:btmh:` topic with their SHA-256
+    /// multihash, which comes after the v1 hash for hybrids so that clients
+    /// that only know v1 still find the one they're after first.
+    pub fn to_magnet(&self) -> String {
+        let mut params = vec![];
+
+        if self.info.version().has_v1() {
+            params.push(format!("xt=urn:btih:{}", self.info_hash()));
+        }
+
+        if let Some(hash) = self.info_hash_v2() {
+            // The multihash prefix for a 32 byte SHA-256 digest.
+            params.push(format!("xt=urn:btmh:1220{}", to_hex(&hash)));
+        }
+
+        params.push(format!("dn={}", urlencode_bytes(self.info.name.as_bytes())));
+
+        let mut trackers = Vec::new();
+
+        for tracker in self
+            .announce
+            .iter()
+            .chain(self.announce_list.iter().flatten())
+        {
+            if !trackers.contains(&tracker) {
+                trackers.push(tracker);
+            }
+        }
+
+        params.extend(
+            trackers
+                .into_iter()
+                .map(|tracker| format!("tr={}", urlencode_bytes(tracker.as_bytes()))),
+        );
+
+        format!("magnet:?{}", params.join("&"))
+    }
+}
+
+/// Older magnet links encode the info hash as 32 characters of RFC 4648
+/// base32 rather than 40 hex characters, which works out to exactly the same
+/// 160 bits.
+fn decode_base32_hash(encoded_hash: &str) -> Result<SHA1Hash, MagnetError> {
+    let mut hash = [0; 20];
+    let (mut buffer, mut bits) = (0u64, 0);
+    let mut bytes = hash.iter_mut();
+
+    for c in encoded_hash.chars() {
+        let value = match c.to_ascii_uppercase() {
+            c @ 'A'..='Z' => c as u64 - 'A' as u64,
+            c @ '2'..='7' => c as u64 - '2' as u64 + 26,
+            character => InvalidBase32Character { character }.fail()?,
+        };
+
+        buffer = buffer << 5 | value;
+        bits += 5;
+
+        if bits >= 8 {
+            bits -= 8;
+
+            // The caller guarantees 32 characters, so this never runs past
+            // the end of the 20 byte hash.
+            if let Some(byte) = bytes.next() {
+                *byte = (buffer >> bits) as u8;
+            }
+        }
+    }
+
+    Ok(SHA1Hash(hash))
+}
+
+#[derive(Debug, Snafu)]
+pub enum MagnetError {
+    #[snafu(display("Invalid URI: {}", source))]
+    InvalidUri { source: url::ParseError },
+    #[snafu(display("URI is not a magnet link"))]
+    NotAMagnet,
+    #[snafu(display("Couldn't find an xt parameter with an info hash"))]
+    MissingInfoHash,
+    #[snafu(display("Invalid hex info hash: {}", source))]
+    InvalidHexHash { source: SHA1HashParsingError },
+    #[snafu(display("Invalid v2 info hash {}", hash))]
+    InvalidV2Hash { hash: String },
+    #[snafu(display("Found non-base32 character {:?} in info hash", character))]
+    InvalidBase32Character { character: char },
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{test_util::info, torrent_parser::TorrentInfo};
+    use std::convert::TryFrom;
+
+    const HASH: &str = "36f3cd180287d2b87add927771ce7d9b5fee8928";
+
+    #[test]
+    fn hex_info_hash() {
+        let magnet = parse_magnet(&format!("magnet:?xt=urn:btih:{}", HASH)).unwrap();
+
+        assert_eq!(magnet.info_hash.unwrap().to_string(), HASH);
+        assert_eq!(magnet.name, None);
+        assert!(magnet.trackers.is_empty());
+    }
+
+    #[test]
+    fn base32_info_hash() {
+        let magnet = parse_magnet("magnet:?xt=urn:btih:G3Z42GACQ7JLQ6W5SJ3XDTT5TNP65CJI").unwrap();
+
+        assert_eq!(magnet.info_hash.unwrap().to_string(), HASH);
+    }
+
+    #[test]
+    fn name_and_trackers() {
+        let magnet = parse_magnet(&format!(
+            "magnet:?xt=urn:btih:{}&dn=hello%20world.txt&tr=http%3A%2F%2Fone.example.com%2Fannounce&tr=udp%3A%2F%2Ftwo.example.com%3A80",
+            HASH
+        ))
+        .unwrap();
+
+        assert_eq!(magnet.name.as_deref(), Some("hello world.txt"));
+        assert_eq!(
+            magnet.trackers,
+            vec![
+                "http://one.example.com/announce".to_owned(),
+                "udp://two.example.com:80".to_owned()
+            ]
+        );
+    }
+
+    #[test]
+    fn generated_round_trip() {
+        let mut torrent = Torrent::new(
+            "http://one.example.com/announce?passkey=a&b".to_owned(),
+            info("hello world & 10%.txt", &[], 16384, &[]),
+        );
+        torrent.announce_list = vec![
+            vec!["http://one.example.com/announce?passkey=a&b".to_owned()],
+            vec!["udp://two.example.com:80".to_owned()],
+        ];
+
+        let magnet = parse_magnet(&torrent.to_magnet()).unwrap();
+
+        assert_eq!(magnet.info_hash, Some(torrent.info_hash()));
+        assert_eq!(magnet.info_hash_v2, None);
+        assert_eq!(magnet.name.as_deref(), Some("hello world & 10%.txt"));
+        assert_eq!(
+            magnet.trackers,
+            vec![
+                "http://one.example.com/announce?passkey=a&b".to_owned(),
+                "udp://two.example.com:80".to_owned()
+            ]
+        );
+    }
+
+    #[test]
+    fn hybrid_magnet() {
+        let torrent =
+            Torrent::try_from(&include_bytes!("../tests/fixtures/hybrid.torrent")[..]).unwrap();
+        let uri = torrent.to_magnet();
+        let v2_hash = to_hex(&torrent.info_hash_v2().unwrap());
+
+        assert!(uri.starts_with(&format!("magnet:?xt=urn:btih:{}&", torrent.info_hash())));
+        assert!(uri.contains(&format!("&xt=urn:btmh:1220{}&", v2_hash)));
+
+        let magnet = parse_magnet(&uri).unwrap();
+
+        assert_eq!(magnet.info_hash, Some(torrent.info_hash()));
+        assert_eq!(magnet.info_hash_v2, torrent.info_hash_v2());
+    }
+
+    #[test]
+    fn v2_only_magnet() {
+        let hybrid =
+            Torrent::try_from(&include_bytes!("../tests/fixtures/hybrid.torrent")[..]).unwrap();
+        let torrent = Torrent::new(
+            "http://one.example.com/announce".to_owned(),
+            TorrentInfo {
+                pieces: vec![],
+                ..hybrid.info
+            },
+        );
+        let uri = torrent.to_magnet();
+
+        assert!(!uri.contains("urn:btih:"));
+
+        let magnet = parse_magnet(&uri).unwrap();
+
+        assert_eq!(magnet.info_hash, None);
+        assert!(magnet.info_hash_v2.is_some());
+        assert_eq!(magnet.info_hash_v2, torrent.info_hash_v2());
+        assert_eq!(
+            magnet.trackers,
+            vec!["http://one.example.com/announce".to_owned()]
+        );
+    }
+
+    #[test]
+    fn invalid_v2_hash() {
+        assert!(matches!(
+            parse_magnet("magnet:?xt=urn:btmh:1220abc"),
+            Err(MagnetError::InvalidV2Hash { hash }) if hash == "abc"
+        ));
+        assert!(matches!(
+            parse_magnet(&format!("magnet:?xt=urn:btmh:1220%2Bf{}", "0".repeat(62))),
+            Err(MagnetError::InvalidV2Hash { hash }) if hash.starts_with("+f")
+        ));
+        // Other kinds of multihash are skipped over like any other topic.
+        assert!(matches!(
+            parse_magnet("magnet:?xt=urn:btmh:1114abc"),
+            Err(MagnetError::MissingInfoHash)
+        ));
+    }
+
+    #[test]
+    fn missing_info_hash() {
+        assert!(matches!(
+            parse_magnet("magnet:?dn=hello"),
+            Err(MagnetError::MissingInfoHash)
+        ));
+    }
+
+    #[test]
+    fn other_topics_skipped() {
+        let magnet = parse_magnet(&format!(
+            "magnet:?xt=urn:ed2k:31d6cfe0d16ae931b73c59d7e0c089c0&xt=urn:btih:{}",
+            HASH
+        ))
+        .unwrap();
+
+        assert_eq!(magnet.info_hash.unwrap().to_string(), HASH);
+        assert!(matches!(
+            parse_magnet("magnet:?xt=urn:sha1:3I42H3S6NNFQ2MSVX7XZKYAYSCX5QBYJ"),
+            Err(MagnetError::MissingInfoHash)
+        ));
+    }
+
+    #[test]
+    fn not_a_magnet() {
+        assert!(matches!(
+            parse_magnet("http://example.com/?xt=urn:btih:abc"),
+            Err(MagnetError::NotAMagnet)
+        ));
+    }
+
+    #[test]
+    fn invalid_base32() {
+        assert!(matches!(
+            parse_magnet("magnet:?xt=urn:btih:G3Z42GACQ7JLQ6W5SJ3XDTT5TNP65CJ1"),
+            Err(MagnetError::InvalidBase32Character { character: '1' })
+        ));
+    }
+}
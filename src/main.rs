@@ -1,7 +1,23 @@
 #![feature(slice_as_chunks, with_options)]
+#![cfg_attr(test, feature(test))]
 
 mod bencode_parser;
+mod bitfield;
+mod builder;
+mod choke;
+mod dht;
+mod magnet;
+mod metadata;
+mod peer;
+mod pex;
+mod rate;
+mod resume;
+mod scheduler;
+mod session;
+mod storage;
 mod tcp_peer_communicator;
+#[cfg(test)]
+mod test_util;
 mod torrent_parser;
 mod tracker;
 mod types;
@@ -10,7 +26,6 @@ use bitvec::prelude::*;
 use bytes::BytesMut;
 use indicatif::{ProgressBar, ProgressStyle};
 use rand::{distributions::Bernoulli, prelude::*};
-use sha1::{Digest, Sha1};
 use snafu::{ensure, OptionExt, ResultExt, Snafu};
 use std::{
     task::{Context, Poll, ready},
@@ -29,7 +44,9 @@ use std::{
     },
     time::Duration,
 };
-use tcp_peer_communicator::create_tcp_peer_rw;
+use pex::PeerPool;
+use rate::RateLimiter;
+use tcp_peer_communicator::{create_tcp_peer_rw, max_frame_len};
 use tokio::{
     self,
     io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, AsyncRead, AsyncSeek, AsyncWrite, ReadBuf},
@@ -37,8 +54,8 @@ use tokio::{
     sync::{broadcast, mpsc, Mutex, OwnedMutexGuard, RwLock},
     task, time,
 };
-use torrent_parser::{SHA1Hash, Torrent};
-use tracker::{build_peer_id, build_peerlist, build_tracker_url};
+use torrent_parser::Torrent;
+use tracker::{announce, build_peer_id, AnnounceEvent, AnnounceRequest};
 use types::{Block, BlockMeta, Message, PeerReader, PeerWriter};
 use pin_project_lite::pin_project;
 
@@ -58,23 +75,32 @@ async fn main() -> Result<(), Box<dyn error::Error>> {
             .nth(1)
             .ok_or("Didn't find a torrent file in the first argument")?,
     )?;
-    let torrent = Torrent::try_from(torrent_bytes.as_slice()).map_err(|e| {
-        log::error!("Torrent parsing error: {:?}", e);
+    let torrent = Torrent::try_from(torrent_bytes.as_slice())
+        .and_then(|torrent| torrent.info.check_consistency().map(|_| torrent))
+        .map_err(|e| {
+            log::error!("Torrent parsing error: {:?}", e);
 
-        "Invalid or unsupported torrent file format"
-    })?;
+            "Invalid or unsupported torrent file format"
+        })?;
     log::info!("Parsed torrent file");
 
     // Tracker networking
     let peer_id = build_peer_id();
     log::info!("Our peer ID: {}", peer_id);
-    let tracker_url = build_tracker_url(&torrent, &PORT.to_string(), &peer_id)?;
-    log::info!("Announcing to tracker at URL: {}", tracker_url);
-    let resp = reqwest::get(tracker_url).await?;
-    log::info!("Got response from tracker");
-    let peerlist = build_peerlist(&resp.bytes().await?)
-        .ok_or("Failed to find valid peerlist in tracker response")?;
-    log::info!("Parsed peerlist from tracker response");
+    let announce_request = AnnounceRequest::new(torrent.info_hash(), peer_id.clone(), PORT)
+        .left(torrent.info.total_length())
+        .event(AnnounceEvent::Started);
+    let announce_url = torrent
+        .announce
+        .as_deref()
+        .ok_or("Trackerless torrents aren't supported yet")?;
+    log::info!("Announcing to tracker at {}", announce_url);
+    let announce_response = announce(announce_url, &announce_request).await?;
+    if let Some(warning) = &announce_response.warning {
+        log::warn!("Tracker warning: {}", warning);
+    }
+    let peerlist = announce_response.peers;
+    log::info!("Got peerlist from tracker");
 
     // TODO: reannounce to the tracker
 
@@ -165,19 +191,39 @@ async fn main() -> Result<(), Box<dyn error::Error>> {
         }
     });
 
+    // Both limits are shared by every peer, in bytes per second.
+    let download_limiter = rate_limiter_from_env("MATEY_DOWNLOAD_LIMIT")?;
+    let upload_limiter = rate_limiter_from_env("MATEY_UPLOAD_LIMIT")?;
+
+    let mut peer_pool = PeerPool::new(&torrent.info);
+    peer_pool.add(peerlist);
     let mut num_peers = 0;
 
-    for peer_addr in peerlist {
+    while let Some(peer_addr) = peer_pool.next_peer() {
         if num_peers == MAX_PEERS {
             break;
         }
 
         if let Ok(stream) = TcpStream::connect(peer_addr).await {
-            if let Ok(peer) =
-                create_tcp_peer_rw(stream, torrent.info_hash.as_ref(), peer_id.as_bytes()).await
+            if let Ok((mut peer_reader, mut peer_writer)) = create_tcp_peer_rw(
+                stream,
+                torrent.info_hash(),
+                peer_id.as_bytes().try_into().unwrap(),
+            )
+            .await
             {
                 log::debug!("Starting connection with {}", peer_addr);
 
+                peer_reader = peer_reader
+                    .with_max_frame_len(max_frame_len(torrent.info.pieces.len(), BLOCK_SIZE));
+
+                if let Some(limiter) = &download_limiter {
+                    peer_reader = peer_reader.with_rate_limiter(limiter.clone());
+                }
+                if let Some(limiter) = &upload_limiter {
+                    peer_writer = peer_writer.with_rate_limiter(limiter.clone());
+                }
+
                 num_peers += 1;
 
                 let worker_queue = worker_queue.clone();
@@ -192,7 +238,7 @@ async fn main() -> Result<(), Box<dyn error::Error>> {
                         &bitfield_pieces,
                         &blocks_tx,
                         &mut pieces_rx,
-                        peer,
+                        (peer_reader, peer_writer),
                     )
                     .await;
 
@@ -209,6 +255,23 @@ async fn main() -> Result<(), Box<dyn error::Error>> {
     Ok(())
 }
 
+fn rate_limiter_from_env(var: &str) -> Result<Option<Arc<RateLimiter>>, Box<dyn error::Error>> {
+    match env::var(var) {
+        Ok(limit) => {
+            let limit = limit
+                .parse::<u64>()
+                .ok()
+                .filter(|&limit| limit > 0)
+                .ok_or_else(|| {
+                    format!("{} has to be a positive number of bytes per second", var)
+                })?;
+
+            Ok(Some(Arc::new(RateLimiter::new(limit))))
+        }
+        Err(_) => Ok(None),
+    }
+}
+
 async fn store_blocks(
     torrent: &Torrent,
     mut file_handles: Vec<tokio::fs::File>,
@@ -243,8 +306,9 @@ async fn store_blocks(
             }
         }
 
-        if SHA1Hash(Sha1::digest(&buf).try_into().unwrap())
-            == torrent.info.pieces[block.meta.piece_index as usize]
+        if torrent
+            .info
+            .verify_piece(block.meta.piece_index as usize, &buf)
         {
             log::debug!("Wrote complete piece {}", block.meta.piece_index);
 
@@ -425,6 +489,13 @@ where
                     }
                     Request(_block_meta) => { /* TODO no panic */ }
                     Cancel(_block_meta) => { /* TODO no panic */ }
+                    // We don't advertise the extension protocol, so peers
+                    // shouldn't be sending these anyway.
+                    Extended(..) => continue,
+                    // Same goes for the Fast extension's.
+                    SuggestPiece(_) | HaveAll | HaveNone | RejectRequest(_) | AllowedFast(_) => {
+                        continue
+                    }
                     Piece(received_block) => {
                         let block_queue_read = block_queue.read().await;
 
@@ -680,31 +751,19 @@ fn construct_worker_queue(torrent: &Torrent, block_len: u32) -> WorkerQueue {
         "Piece length is smaller than the block length"
     );
 
-    let mut left_bytes = torrent.info.files.iter().map(|f| f.length).sum::<u64>();
-
     (0..torrent.info.pieces.len() as u32)
         .map(|piece_index| {
-            let mut left_piece_len = cmp::min(left_bytes, torrent.info.piece_len);
-            left_bytes = left_bytes.saturating_sub(torrent.info.piece_len);
-
-            let num_blocks = (left_piece_len as f64 / block_len as f64).ceil() as u32;
+            let blocks = torrent
+                .info
+                .blocks_for_piece(piece_index as usize, block_len)
+                .map(|block_meta| Arc::new(Mutex::new(block_meta)))
+                .collect::<Vec<_>>();
 
             (
                 PieceKey { piece_index },
                 PieceWorkInfo {
-                    available_blocks: Arc::new(AtomicU32::new(num_blocks)),
-                    blocks: (0..num_blocks)
-                        .map(|block_index| {
-                            let this_block_len = cmp::min(left_piece_len, block_len as u64) as u32;
-                            left_piece_len = left_piece_len.saturating_sub(block_len as u64);
-
-                            Arc::new(Mutex::new(BlockMeta {
-                                piece_index,
-                                begin: block_index * block_len,
-                                length: this_block_len,
-                            }))
-                        })
-                        .collect(),
+                    available_blocks: Arc::new(AtomicU32::new(blocks.len() as u32)),
+                    blocks,
                 },
             )
         })
@@ -0,0 +1,397 @@
+use super::{
+    bencode_parser::{encode, parse_bencode, Bencode},
+    torrent_parser::{SHA1Hash, TorrentInfo, TorrentParsingError},
+};
+use bytes::Bytes;
+use sha1::{Digest, Sha1};
+use snafu::{ensure, OptionExt, ResultExt, Snafu};
+use std::{
+    collections::BTreeMap,
+    convert::{TryFrom, TryInto},
+};
+
+/// The name ut_metadata goes by in the extended handshake's `m` dict.
+pub const UT_METADATA: &str = "ut_metadata";
+
+/// Metadata is always sent in pieces of this size, other than the last one.
+pub const METADATA_PIECE_LEN: usize = 16 * 1024;
+
+/// Peers tell us how big the metadata is, so this keeps one from making us
+/// allocate an absurd amount of memory for it.
+pub const MAX_METADATA_SIZE: u64 = 16 * 1024 * 1024;
+
+const MSG_TYPE_REQUEST: i64 = 0;
+const MSG_TYPE_DATA: i64 = 1;
+const MSG_TYPE_REJECT: i64 = 2;
+
+/// The payload of a ut_metadata extended message (BEP 9).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MetadataMessage {
+    Request {
+        piece: u32,
+    },
+    Data {
+        piece: u32,
+        total_size: u64,
+        data: Bytes,
+    },
+    Reject {
+        piece: u32,
+    },
+}
+
+impl MetadataMessage {
+    pub fn encode(&self) -> Vec<u8> {
+        let (msg_type, piece) = match self {
+            MetadataMessage::Request { piece } => (MSG_TYPE_REQUEST, piece),
+            MetadataMessage::Data { piece, .. } => (MSG_TYPE_DATA, piece),
+            MetadataMessage::Reject { piece } => (MSG_TYPE_REJECT, piece),
+        };
+
+        let mut dict = BTreeMap::new();
+
+        dict.insert(Bytes::from_static(b"msg_type"), Bencode::Number(msg_type));
+        dict.insert(Bytes::from_static(b"piece"), Bencode::Number(*piece as i64));
+
+        match self {
+            MetadataMessage::Data {
+                total_size, data, ..
+            } => {
+                dict.insert(
+                    Bytes::from_static(b"total_size"),
+                    Bencode::Number(*total_size as i64),
+                );
+
+                // The piece's data comes straight after the dict, rather than
+                // being a part of it.
+                [encode(&Bencode::Dict(dict)), data.to_vec()].concat()
+            }
+            _ => encode(&Bencode::Dict(dict)),
+        }
+    }
+
+    pub fn decode(payload: &[u8]) -> Result<Self, MetadataError> {
+        let (data, dict) = parse_bencode(payload)
+            .ok()
+            .filter(|(_, dict)| dict.is_dict())
+            .context(InvalidMetadataMessage)?;
+
+        let field = |key: &[u8]| dict.get(key).and_then(Bencode::as_i64);
+
+        let msg_type = field(b"msg_type").context(InvalidMetadataMessage)?;
+        let piece = field(b"piece")
+            .and_then(|piece| u32::try_from(piece).ok())
+            .context(InvalidMetadataMessage)?;
+
+        Ok(match msg_type {
+            MSG_TYPE_REQUEST => MetadataMessage::Request { piece },
+            MSG_TYPE_DATA => MetadataMessage::Data {
+                piece,
+                total_size: field(b"total_size")
+                    .and_then(|total_size| u64::try_from(total_size).ok())
+                    .context(InvalidMetadataMessage)?,
+                data: Bytes::copy_from_slice(data),
+            },
+            MSG_TYPE_REJECT => MetadataMessage::Reject { piece },
+            msg_type => UnknownMessageType { msg_type }.fail()?,
+        })
+    }
+}
+
+/// Collects the pieces of a torrent's metadata as they come in from peers,
+/// then checks the result against the info hash we already know it has.
+#[derive(Clone, Debug)]
+pub struct MetadataAssembler {
+    info_hash: SHA1Hash,
+    total_size: usize,
+    pieces: Vec<Option<Bytes>>,
+}
+
+impl MetadataAssembler {
+    /// `total_size` is the `metadata_size` from a peer's extended handshake.
+    pub fn new(info_hash: SHA1Hash, total_size: u64) -> Result<Self, MetadataError> {
+        ensure!(
+            total_size > 0 && total_size <= MAX_METADATA_SIZE,
+            InvalidMetadataSize { size: total_size }
+        );
+
+        let total_size = total_size as usize;
+
+        Ok(Self {
+            info_hash,
+            total_size,
+            pieces: vec![None; total_size.div_ceil(METADATA_PIECE_LEN)],
+        })
+    }
+
+    pub fn num_pieces(&self) -> usize {
+        self.pieces.len()
+    }
+
+    /// The pieces that still need to be requested, in order.
+    pub fn missing_pieces(&self) -> impl Iterator<Item = u32> + '_ {
+        self.pieces
+            .iter()
+            .enumerate()
+            .filter(|(_, piece)| piece.is_none())
+            .map(|(index, _)| index as u32)
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.pieces.iter().all(Option::is_some)
+    }
+
+    pub fn add_piece(&mut self, piece: u32, data: Bytes) -> Result<(), MetadataError> {
+        let index = piece as usize;
+
+        ensure!(index < self.pieces.len(), InvalidPieceIndex { piece });
+
+        let expected = if index == self.pieces.len() - 1 {
+            self.total_size - index * METADATA_PIECE_LEN
+        } else {
+            METADATA_PIECE_LEN
+        };
+
+        ensure!(
+            data.len() == expected,
+            InvalidPieceLen {
+                piece,
+                len: data.len(),
+                expected
+            }
+        );
+
+        self.pieces[index] = Some(data);
+
+        Ok(())
+    }
+
+    /// Handles a message that a peer sent back in response to a request. A
+    /// data message for metadata of a different size than we were told is an
+    /// error, while rejections are left for the caller to retry elsewhere.
+    pub fn receive(&mut self, message: MetadataMessage) -> Result<(), MetadataError> {
+        match message {
+            MetadataMessage::Data {
+                piece,
+                total_size,
+                data,
+            } => {
+                ensure!(
+                    total_size == self.total_size as u64,
+                    InvalidMetadataSize { size: total_size }
+                );
+
+                self.add_piece(piece, data)
+            }
+            MetadataMessage::Request { .. } | MetadataMessage::Reject { .. } => Ok(()),
+        }
+    }
+
+    /// Puts the pieces together, making sure they hash to the info hash
+    /// before parsing them.
+    pub fn finish(self) -> Result<TorrentInfo, MetadataError> {
+        let missing = self.missing_pieces().count();
+
+        ensure!(missing == 0, IncompleteMetadata { missing });
+
+        let metadata = self
+            .pieces
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>()
+            .concat();
+
+        // Sha1::digest always returns 20 bytes, so this can't fail.
+        let hash = SHA1Hash(Sha1::digest(&metadata).as_slice().try_into().unwrap());
+
+        ensure!(hash == self.info_hash, MetadataHashMismatch);
+
+        parse_bencode(&metadata)
+            .map_err(TorrentParsingError::from)
+            .and_then(|(_, info)| TorrentInfo::try_from(info))
+            .context(InvalidInfoDict)
+    }
+}
+
+#[derive(Debug, Snafu)]
+pub enum MetadataError {
+    #[snafu(display("Received a malformed ut_metadata message"))]
+    InvalidMetadataMessage,
+    #[snafu(display("Received a ut_metadata message with unknown type {}", msg_type))]
+    UnknownMessageType { msg_type: i64 },
+    #[snafu(display("Invalid metadata size {}", size))]
+    InvalidMetadataSize { size: u64 },
+    #[snafu(display("Received metadata piece {} that doesn't exist", piece))]
+    InvalidPieceIndex { piece: u32 },
+    #[snafu(display(
+        "Expected metadata piece {} to be {} bytes, got {}",
+        piece,
+        expected,
+        len
+    ))]
+    InvalidPieceLen {
+        piece: u32,
+        len: usize,
+        expected: usize,
+    },
+    #[snafu(display("Still missing {} metadata pieces", missing))]
+    IncompleteMetadata { missing: usize },
+    #[snafu(display("Metadata doesn't match the info hash"))]
+    MetadataHashMismatch,
+    #[snafu(display("Metadata isn't a valid info dict"))]
+    InvalidInfoDict { source: TorrentParsingError },
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::info;
+
+    fn info_dict() -> (SHA1Hash, Vec<u8>) {
+        let info = TorrentInfo {
+            pieces: (0..2000u32)
+                .map(|i| {
+                    SHA1Hash(
+                        Sha1::digest(&i.to_be_bytes())
+                            .as_slice()
+                            .try_into()
+                            .unwrap(),
+                    )
+                })
+                .collect(),
+            ..info("big", &[], 16384, &[("big", 2000 * 16384)])
+        };
+
+        let metadata = encode(&info.to_bencode());
+        let info_hash = SHA1Hash(Sha1::digest(&metadata).as_slice().try_into().unwrap());
+
+        (info_hash, metadata)
+    }
+
+    fn data_messages(metadata: &[u8]) -> Vec<MetadataMessage> {
+        metadata
+            .chunks(METADATA_PIECE_LEN)
+            .enumerate()
+            .map(|(piece, data)| MetadataMessage::Data {
+                piece: piece as u32,
+                total_size: metadata.len() as u64,
+                data: Bytes::copy_from_slice(data),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn reassemble_metadata() {
+        let (info_hash, metadata) = info_dict();
+        let mut assembler = MetadataAssembler::new(info_hash, metadata.len() as u64).unwrap();
+
+        assert_eq!(assembler.num_pieces(), 3);
+
+        // Pieces can come back in any order.
+        for message in data_messages(&metadata).into_iter().rev() {
+            assert!(!assembler.is_complete());
+
+            let encoded = message.encode();
+            assembler
+                .receive(MetadataMessage::decode(&encoded).unwrap())
+                .unwrap();
+        }
+
+        assert!(assembler.is_complete());
+        assert_eq!(assembler.missing_pieces().count(), 0);
+
+        let info = assembler.finish().unwrap();
+
+        assert_eq!(info.name, "big");
+        assert_eq!(info.pieces.len(), 2000);
+        assert_eq!(encode(&info.to_bencode()), metadata);
+    }
+
+    #[test]
+    fn metadata_hash_mismatch() {
+        let (_, metadata) = info_dict();
+        let mut assembler =
+            MetadataAssembler::new(SHA1Hash([0; 20]), metadata.len() as u64).unwrap();
+
+        for message in data_messages(&metadata) {
+            assembler.receive(message).unwrap();
+        }
+
+        assert!(matches!(
+            assembler.finish(),
+            Err(MetadataError::MetadataHashMismatch)
+        ));
+    }
+
+    #[test]
+    fn metadata_incomplete() {
+        let (info_hash, metadata) = info_dict();
+        let mut assembler = MetadataAssembler::new(info_hash, metadata.len() as u64).unwrap();
+
+        assembler
+            .receive(data_messages(&metadata).remove(1))
+            .unwrap();
+
+        assert_eq!(assembler.missing_pieces().collect::<Vec<_>>(), vec![0, 2]);
+        assert!(matches!(
+            assembler.finish(),
+            Err(MetadataError::IncompleteMetadata { missing: 2 })
+        ));
+    }
+
+    #[test]
+    fn metadata_invalid_pieces() {
+        let (info_hash, metadata) = info_dict();
+        let mut assembler = MetadataAssembler::new(info_hash, metadata.len() as u64).unwrap();
+
+        assert!(matches!(
+            assembler.add_piece(3, Bytes::new()),
+            Err(MetadataError::InvalidPieceIndex { piece: 3 })
+        ));
+        assert!(matches!(
+            assembler.add_piece(0, Bytes::from_static(b"short")),
+            Err(MetadataError::InvalidPieceLen {
+                piece: 0,
+                len: 5,
+                expected: METADATA_PIECE_LEN
+            })
+        ));
+        assert!(matches!(
+            assembler.receive(MetadataMessage::Data {
+                piece: 0,
+                total_size: 5,
+                data: Bytes::from_static(b"short")
+            }),
+            Err(MetadataError::InvalidMetadataSize { size: 5 })
+        ));
+        assert!(matches!(
+            MetadataAssembler::new(info_hash, MAX_METADATA_SIZE + 1),
+            Err(MetadataError::InvalidMetadataSize { .. })
+        ));
+    }
+
+    #[test]
+    fn message_wire_format() {
+        assert_eq!(
+            MetadataMessage::Request { piece: 0 }.encode(),
+            b"d8:msg_typei0e5:piecei0ee"
+        );
+        assert_eq!(
+            MetadataMessage::Data {
+                piece: 1,
+                total_size: 5,
+                data: Bytes::from_static(b"hello")
+            }
+            .encode(),
+            b"d8:msg_typei1e5:piecei1e10:total_sizei5eehello" as &[u8]
+        );
+        assert_eq!(
+            MetadataMessage::decode(b"d8:msg_typei2e5:piecei4ee").unwrap(),
+            MetadataMessage::Reject { piece: 4 }
+        );
+        assert!(matches!(
+            MetadataMessage::decode(b"d8:msg_typei7e5:piecei4ee"),
+            Err(MetadataError::UnknownMessageType { msg_type: 7 })
+        ));
+    }
+}
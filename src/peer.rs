@@ -0,0 +1,962 @@
+use super::{
+    bencode_parser::{encode, parse_bencode, Bencode},
+    torrent_parser::SHA1Hash,
+    types::{Block, BlockMeta, Message},
+};
+use bitvec::prelude::*;
+use bytes::Bytes;
+use rand::{distributions, thread_rng, Rng};
+use snafu::{ensure, OptionExt, Snafu};
+use std::{
+    collections::BTreeMap,
+    convert::{TryFrom, TryInto},
+    fmt, str,
+    str::FromStr,
+};
+
+pub const CLIENT_TAG: &[u8; 2] = b"MS"; // the Matey Ship! 🏴‍☠️
+
+pub const PROTOCOL: &[u8] = b"BitTorrent protocol";
+
+/// The pstr length prefix, the pstr itself, then 8 reserved bytes, the
+/// 20 byte info hash, and the 20 byte peer ID.
+pub const HANDSHAKE_LEN: usize = 1 + PROTOCOL.len() + 8 + 20 + 20;
+
+/// Builds an Azureus-style peer ID, `-MSvvvv-` followed by 12 random
+/// alphanumeric characters. Sticking to alphanumerics keeps the ID printable
+/// and safe to put in a tracker URL without escaping.
+pub fn generate_peer_id(version: [u8; 4]) -> [u8; 20] {
+    let mut peer_id = [0; 20];
+
+    peer_id[0] = b'-';
+    peer_id[1..3].copy_from_slice(CLIENT_TAG);
+    peer_id[3..7].copy_from_slice(&version);
+    peer_id[7] = b'-';
+
+    for (byte, random) in peer_id[8..]
+        .iter_mut()
+        .zip(thread_rng().sample_iter(&distributions::Alphanumeric))
+    {
+        *byte = random;
+    }
+
+    peer_id
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PeerId(pub [u8; 20]);
+
+impl PeerId {
+    /// The two character client tag and four character version of an
+    /// Azureus-style peer ID, like `("MS", "0010")` for `-MS0010-...`.
+    pub fn azureus_client(&self) -> Option<(&str, &str)> {
+        let id = &self.0;
+
+        if id[0] != b'-' || id[7] != b'-' || !id[1..7].iter().all(u8::is_ascii_alphanumeric) {
+            return None;
+        }
+
+        // These are all ASCII, so they're definitely valid UTF-8.
+        Some((
+            str::from_utf8(&id[1..3]).unwrap(),
+            str::from_utf8(&id[3..7]).unwrap(),
+        ))
+    }
+
+    /// The name of the client that made this peer ID, for the handful of
+    /// clients common enough to be worth recognizing.
+    pub fn client_name(&self) -> Option<&'static str> {
+        Some(match self.azureus_client()?.0 {
+            "MS" => "matey",
+            "AZ" => "Vuze",
+            "DE" => "Deluge",
+            "LT" | "lt" => "libtorrent",
+            "qB" => "qBittorrent",
+            "TR" => "Transmission",
+            "UT" => "µTorrent",
+            _ => return None,
+        })
+    }
+}
+
+impl fmt::Display for PeerId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Peer IDs from other clients can be arbitrary bytes, so anything
+        // unprintable is escaped.
+        for &byte in &self.0 {
+            if byte.is_ascii_graphic() {
+                write!(f, "{}", byte as char)?;
+            } else {
+                write!(f, "\\x{:02x}", byte)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Debug for PeerId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl TryFrom<&[u8]> for PeerId {
+    type Error = PeerIdError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        bytes
+            .try_into()
+            .map(Self)
+            .map_err(|_| PeerIdError::InvalidPeerIdLen { len: bytes.len() })
+    }
+}
+
+impl FromStr for PeerId {
+    type Err = PeerIdError;
+
+    fn from_str(peer_id: &str) -> Result<Self, Self::Err> {
+        Self::try_from(peer_id.as_bytes())
+    }
+}
+
+#[derive(Debug, Snafu)]
+pub enum PeerIdError {
+    #[snafu(display("Expected a 20 byte peer ID, got {} bytes", len))]
+    InvalidPeerIdLen { len: usize },
+}
+
+/// The reserved bytes of a handshake, which are used to advertise support for
+/// protocol extensions.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub struct ReservedBits(pub [u8; 8]);
+
+impl ReservedBits {
+    // Each extension is given as (byte, mask), counting from the first byte.
+    const DHT: (usize, u8) = (7, 0x01); // BEP 5
+    const FAST: (usize, u8) = (7, 0x04); // BEP 6
+    const EXTENSION_PROTOCOL: (usize, u8) = (5, 0x10); // BEP 10
+
+    pub fn supports_dht(&self) -> bool {
+        self.has(Self::DHT)
+    }
+
+    pub fn supports_fast(&self) -> bool {
+        self.has(Self::FAST)
+    }
+
+    pub fn supports_extension_protocol(&self) -> bool {
+        self.has(Self::EXTENSION_PROTOCOL)
+    }
+
+    pub fn with_dht(self) -> Self {
+        self.with(Self::DHT)
+    }
+
+    pub fn with_fast(self) -> Self {
+        self.with(Self::FAST)
+    }
+
+    pub fn with_extension_protocol(self) -> Self {
+        self.with(Self::EXTENSION_PROTOCOL)
+    }
+
+    fn has(&self, (byte, mask): (usize, u8)) -> bool {
+        self.0[byte] & mask != 0
+    }
+
+    fn with(mut self, (byte, mask): (usize, u8)) -> Self {
+        self.0[byte] |= mask;
+        self
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Handshake {
+    pub pstr: Bytes,
+    pub reserved: ReservedBits,
+    pub info_hash: SHA1Hash,
+    pub peer_id: [u8; 20],
+}
+
+impl Handshake {
+    pub fn new(info_hash: SHA1Hash, peer_id: [u8; 20]) -> Self {
+        Self {
+            pstr: Bytes::from_static(PROTOCOL),
+            reserved: ReservedBits::default(),
+            info_hash,
+            peer_id,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        [
+            &[self.pstr.len() as u8] as &[u8],
+            &self.pstr,
+            &self.reserved.0,
+            self.info_hash.as_ref(),
+            &self.peer_id,
+        ]
+        .concat()
+    }
+
+    /// Decodes a peer's handshake, making sure it's talking about the same
+    /// torrent we are.
+    pub fn decode(buf: &[u8], info_hash: &SHA1Hash) -> Result<Self, HandshakeError> {
+        ensure!(
+            buf.len() == HANDSHAKE_LEN,
+            InvalidHandshakeLen { len: buf.len() }
+        );
+
+        let pstr_len = buf[0];
+
+        ensure!(
+            pstr_len as usize == PROTOCOL.len(),
+            InvalidPstrLen { len: pstr_len }
+        );
+        ensure!(&buf[1..20] == PROTOCOL, UnsupportedProtocol);
+
+        let handshake = Self {
+            pstr: Bytes::copy_from_slice(&buf[1..20]),
+            reserved: ReservedBits(buf[20..28].try_into().unwrap()),
+            info_hash: SHA1Hash(buf[28..48].try_into().unwrap()),
+            peer_id: buf[48..68].try_into().unwrap(),
+        };
+
+        ensure!(&handshake.info_hash == info_hash, InfoHashMismatch);
+
+        Ok(handshake)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PeerMessage {
+    KeepAlive,
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    Have(u32),
+    Bitfield(Vec<u8>),
+    Request {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    Piece {
+        index: u32,
+        begin: u32,
+        block: Bytes,
+    },
+    Cancel {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    /// A BEP 10 extension message. The ID is 0 for the extended handshake, or
+    /// whatever the peer assigned the extension in theirs.
+    Extended {
+        id: u8,
+        payload: Bytes,
+    },
+    // The rest are from the Fast extension (BEP 6), which both sides have to
+    // set the reserved bit for before sending any of them.
+    SuggestPiece(u32),
+    HaveAll,
+    HaveNone,
+    /// Tells the peer a request won't be answered, which it would otherwise
+    /// be left waiting on.
+    RejectRequest {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    /// A piece the peer can request even while we're choking it.
+    AllowedFast(u32),
+}
+
+/// The message ID that all BEP 10 extension messages share.
+pub const EXTENDED_MESSAGE_ID: u8 = 20;
+pub const EXTENDED_HANDSHAKE_ID: u8 = 0;
+
+impl PeerMessage {
+    pub fn extended_handshake(handshake: &ExtendedHandshake) -> Self {
+        PeerMessage::Extended {
+            id: EXTENDED_HANDSHAKE_ID,
+            payload: encode(&handshake.to_bencode()).into(),
+        }
+    }
+
+    /// Whether this is one of the Fast extension's messages.
+    pub fn is_fast(&self) -> bool {
+        use PeerMessage::*;
+
+        matches!(
+            self,
+            SuggestPiece(_) | HaveAll | HaveNone | RejectRequest { .. } | AllowedFast(_)
+        )
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        use PeerMessage::*;
+
+        let (id, payload) = match self {
+            // Keep-alives are just a length prefix of 0, with no ID.
+            KeepAlive => return 0u32.to_be_bytes().to_vec(),
+            Choke => (0, vec![]),
+            Unchoke => (1, vec![]),
+            Interested => (2, vec![]),
+            NotInterested => (3, vec![]),
+            Have(index) => (4, index.to_be_bytes().to_vec()),
+            Bitfield(bitfield) => (5, bitfield.clone()),
+            Request {
+                index,
+                begin,
+                length,
+            }
+            | Cancel {
+                index,
+                begin,
+                length,
+            }
+            | RejectRequest {
+                index,
+                begin,
+                length,
+            } => (
+                match self {
+                    Request { .. } => 6,
+                    Cancel { .. } => 8,
+                    _ => 16,
+                },
+                [
+                    index.to_be_bytes(),
+                    begin.to_be_bytes(),
+                    length.to_be_bytes(),
+                ]
+                .concat(),
+            ),
+            Piece {
+                index,
+                begin,
+                block,
+            } => (
+                7,
+                [&index.to_be_bytes() as &[u8], &begin.to_be_bytes(), block].concat(),
+            ),
+            Extended { id, payload } => (EXTENDED_MESSAGE_ID, [&[*id] as &[u8], payload].concat()),
+            SuggestPiece(index) => (13, index.to_be_bytes().to_vec()),
+            HaveAll => (14, vec![]),
+            HaveNone => (15, vec![]),
+            AllowedFast(index) => (17, index.to_be_bytes().to_vec()),
+        };
+
+        [
+            &(payload.len() as u32 + 1).to_be_bytes() as &[u8],
+            &[id],
+            &payload,
+        ]
+        .concat()
+    }
+
+    /// Decodes one length-prefixed message from the start of `buf`, returning
+    /// it along with whatever comes after it.
+    pub fn decode(buf: &[u8]) -> Result<(Self, &[u8]), PeerMessageError> {
+        use PeerMessage::*;
+
+        ensure!(buf.len() >= 4, TruncatedMessage { len: buf.len() });
+
+        let len = u32::from_be_bytes(buf[0..4].try_into().unwrap()) as usize;
+
+        ensure!(buf.len() - 4 >= len, TruncatedMessage { len: buf.len() });
+
+        let (frame, rest) = buf[4..].split_at(len);
+
+        let (id, payload) = match frame.split_first() {
+            Some((&id, payload)) => (id, payload),
+            None => return Ok((KeepAlive, rest)),
+        };
+
+        let expect_len = |expected: usize| {
+            ensure!(
+                payload.len() == expected,
+                InvalidPayloadLen {
+                    len: payload.len(),
+                    id
+                }
+            );
+
+            Ok(())
+        };
+
+        let u32_at = |i: usize| u32::from_be_bytes(payload[i..i + 4].try_into().unwrap());
+
+        let message = match id {
+            0 => expect_len(0).map(|_| Choke)?,
+            1 => expect_len(0).map(|_| Unchoke)?,
+            2 => expect_len(0).map(|_| Interested)?,
+            3 => expect_len(0).map(|_| NotInterested)?,
+            4 => expect_len(4).map(|_| Have(u32_at(0)))?,
+            5 => Bitfield(payload.to_vec()),
+            6 | 8 | 16 => {
+                expect_len(12)?;
+
+                let (index, begin, length) = (u32_at(0), u32_at(4), u32_at(8));
+
+                match id {
+                    6 => Request {
+                        index,
+                        begin,
+                        length,
+                    },
+                    8 => Cancel {
+                        index,
+                        begin,
+                        length,
+                    },
+                    _ => RejectRequest {
+                        index,
+                        begin,
+                        length,
+                    },
+                }
+            }
+            7 => {
+                ensure!(
+                    payload.len() >= 8,
+                    InvalidPayloadLen {
+                        len: payload.len(),
+                        id
+                    }
+                );
+
+                Piece {
+                    index: u32_at(0),
+                    begin: u32_at(4),
+                    block: Bytes::copy_from_slice(&payload[8..]),
+                }
+            }
+            EXTENDED_MESSAGE_ID => match payload.split_first() {
+                Some((&extended_id, payload)) => Extended {
+                    id: extended_id,
+                    payload: Bytes::copy_from_slice(payload),
+                },
+                None => InvalidPayloadLen { len: 0usize, id }.fail()?,
+            },
+            13 => expect_len(4).map(|_| SuggestPiece(u32_at(0)))?,
+            14 => expect_len(0).map(|_| HaveAll)?,
+            15 => expect_len(0).map(|_| HaveNone)?,
+            17 => expect_len(4).map(|_| AllowedFast(u32_at(0)))?,
+            id => UnknownMessageId { id }.fail()?,
+        };
+
+        Ok((message, rest))
+    }
+}
+
+impl From<PeerMessage> for Message {
+    fn from(message: PeerMessage) -> Self {
+        match message {
+            PeerMessage::KeepAlive => Message::KeepAlive,
+            PeerMessage::Choke => Message::Choke,
+            PeerMessage::Unchoke => Message::Unchoke,
+            PeerMessage::Interested => Message::Interested,
+            PeerMessage::NotInterested => Message::NotInterested,
+            PeerMessage::Have(index) => Message::Have(index),
+            PeerMessage::Bitfield(bitfield) => Message::BitField(BitVec::from_vec(bitfield)),
+            PeerMessage::Request {
+                index,
+                begin,
+                length,
+            } => Message::Request(BlockMeta {
+                piece_index: index,
+                begin,
+                length,
+            }),
+            PeerMessage::Piece {
+                index,
+                begin,
+                block,
+            } => Message::Piece(Block {
+                meta: BlockMeta {
+                    piece_index: index,
+                    begin,
+                    length: block.len() as u32,
+                },
+                data: block,
+            }),
+            PeerMessage::Cancel {
+                index,
+                begin,
+                length,
+            } => Message::Cancel(BlockMeta {
+                piece_index: index,
+                begin,
+                length,
+            }),
+            PeerMessage::Extended { id, payload } => Message::Extended(id, payload),
+            PeerMessage::SuggestPiece(index) => Message::SuggestPiece(index),
+            PeerMessage::HaveAll => Message::HaveAll,
+            PeerMessage::HaveNone => Message::HaveNone,
+            PeerMessage::RejectRequest {
+                index,
+                begin,
+                length,
+            } => Message::RejectRequest(BlockMeta {
+                piece_index: index,
+                begin,
+                length,
+            }),
+            PeerMessage::AllowedFast(index) => Message::AllowedFast(index),
+        }
+    }
+}
+
+impl From<Message> for PeerMessage {
+    fn from(message: Message) -> Self {
+        match message {
+            Message::KeepAlive => PeerMessage::KeepAlive,
+            Message::Choke => PeerMessage::Choke,
+            Message::Unchoke => PeerMessage::Unchoke,
+            Message::Interested => PeerMessage::Interested,
+            Message::NotInterested => PeerMessage::NotInterested,
+            Message::Have(index) => PeerMessage::Have(index),
+            Message::BitField(bitfield) => PeerMessage::Bitfield(bitfield.into_vec()),
+            Message::Request(meta) => PeerMessage::Request {
+                index: meta.piece_index,
+                begin: meta.begin,
+                length: meta.length,
+            },
+            Message::Piece(block) => PeerMessage::Piece {
+                index: block.meta.piece_index,
+                begin: block.meta.begin,
+                block: block.data,
+            },
+            Message::Cancel(meta) => PeerMessage::Cancel {
+                index: meta.piece_index,
+                begin: meta.begin,
+                length: meta.length,
+            },
+            Message::Extended(id, payload) => PeerMessage::Extended { id, payload },
+            Message::SuggestPiece(index) => PeerMessage::SuggestPiece(index),
+            Message::HaveAll => PeerMessage::HaveAll,
+            Message::HaveNone => PeerMessage::HaveNone,
+            Message::RejectRequest(meta) => PeerMessage::RejectRequest {
+                index: meta.piece_index,
+                begin: meta.begin,
+                length: meta.length,
+            },
+            Message::AllowedFast(index) => PeerMessage::AllowedFast(index),
+        }
+    }
+}
+
+/// The payload of a BEP 10 extended handshake. Keys other than these are
+/// dropped when decoding.
+#[derive(Clone, Debug, PartialEq, Eq, Default)]
+pub struct ExtendedHandshake {
+    /// The `m` dict, mapping the name of each extension supported to the ID
+    /// that its messages should be sent with. An ID of 0 disables it.
+    pub extensions: BTreeMap<String, u8>,
+    /// The `v` string, with the client's name and version.
+    pub client: Option<String>,
+    /// The `p` port, which the peer accepts incoming connections on.
+    pub port: Option<u16>,
+    /// The size of the info dict in bytes, sent along with ut_metadata
+    /// support (BEP 9).
+    pub metadata_size: Option<u64>,
+}
+
+impl ExtendedHandshake {
+    pub fn to_bencode(&self) -> Bencode {
+        let mut dict = BTreeMap::new();
+
+        dict.insert(
+            Bytes::from_static(b"m"),
+            Bencode::Dict(
+                self.extensions
+                    .iter()
+                    .map(|(name, &id)| {
+                        (
+                            Bytes::copy_from_slice(name.as_bytes()),
+                            Bencode::Number(id.into()),
+                        )
+                    })
+                    .collect(),
+            ),
+        );
+
+        if let Some(metadata_size) = self.metadata_size {
+            dict.insert(
+                Bytes::from_static(b"metadata_size"),
+                Bencode::Number(metadata_size as i64),
+            );
+        }
+
+        if let Some(port) = self.port {
+            dict.insert(Bytes::from_static(b"p"), Bencode::Number(port.into()));
+        }
+
+        if let Some(client) = &self.client {
+            dict.insert(
+                Bytes::from_static(b"v"),
+                Bencode::ByteString(Bytes::copy_from_slice(client.as_bytes())),
+            );
+        }
+
+        Bencode::Dict(dict)
+    }
+
+    /// Only `m` is required. Any other field that doesn't make sense is
+    /// treated as if it wasn't sent at all.
+    pub fn decode(payload: &[u8]) -> Result<Self, ExtendedHandshakeError> {
+        let handshake = parse_bencode(payload)
+            .ok()
+            .map(|(_, bencode)| bencode)
+            .filter(Bencode::is_dict)
+            .context(InvalidHandshakeDict)?;
+
+        let extensions = handshake
+            .get(b"m")
+            .and_then(Bencode::as_dict)
+            .context(InvalidExtensionMap)?
+            .iter()
+            .map(|(name, id)| {
+                Some((
+                    str::from_utf8(name).ok()?.to_owned(),
+                    u8::try_from(id.as_i64()?).ok()?,
+                ))
+            })
+            .collect::<Option<_>>()
+            .context(InvalidExtensionMap)?;
+
+        Ok(Self {
+            extensions,
+            client: handshake
+                .get(b"v")
+                .and_then(Bencode::as_str)
+                .map(str::to_owned),
+            port: handshake
+                .get(b"p")
+                .and_then(Bencode::as_i64)
+                .and_then(|port| u16::try_from(port).ok()),
+            metadata_size: handshake
+                .get(b"metadata_size")
+                .and_then(Bencode::as_i64)
+                .and_then(|size| u64::try_from(size).ok()),
+        })
+    }
+}
+
+#[derive(Debug, Snafu)]
+pub enum ExtendedHandshakeError {
+    #[snafu(display("Extended handshake isn't a bencoded dictionary"))]
+    InvalidHandshakeDict,
+    #[snafu(display("Extended handshake has a missing or invalid m dictionary"))]
+    InvalidExtensionMap,
+}
+
+#[derive(Debug, Snafu)]
+pub enum PeerMessageError {
+    #[snafu(display("Message of {} bytes is cut off before its end", len))]
+    TruncatedMessage { len: usize },
+    #[snafu(display("Recieved a message with an unknown ID: {}", id))]
+    UnknownMessageId { id: u8 },
+    #[snafu(display("Recieved a message with an invalid length {} for its ID {}", len, id))]
+    InvalidPayloadLen { len: usize, id: u8 },
+}
+
+#[derive(Debug, Snafu)]
+pub enum HandshakeError {
+    #[snafu(display("Expected a {} byte handshake, got {} bytes", HANDSHAKE_LEN, len))]
+    InvalidHandshakeLen { len: usize },
+    #[snafu(display("Handshake has pstr length {} instead of 19", len))]
+    InvalidPstrLen { len: u8 },
+    #[snafu(display("Handshake isn't for the BitTorrent protocol"))]
+    UnsupportedProtocol,
+    #[snafu(display("Recieved a handshake with a different info hash than was sent"))]
+    InfoHashMismatch,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const INFO_HASH: SHA1Hash = SHA1Hash([0xab; 20]);
+    const PEER_ID: &[u8; 20] = b"-MS0010-abcdefghijkl";
+
+    #[test]
+    fn handshake_round_trip() {
+        let handshake = Handshake::new(INFO_HASH, *PEER_ID);
+        let encoded = handshake.encode();
+
+        assert_eq!(encoded.len(), HANDSHAKE_LEN);
+        assert_eq!(&encoded[..20], b"\x13BitTorrent protocol");
+        assert_eq!(Handshake::decode(&encoded, &INFO_HASH).unwrap(), handshake);
+    }
+
+    #[test]
+    fn handshake_wrong_info_hash() {
+        let encoded = Handshake::new(SHA1Hash([0xcd; 20]), *PEER_ID).encode();
+
+        assert!(matches!(
+            Handshake::decode(&encoded, &INFO_HASH),
+            Err(HandshakeError::InfoHashMismatch)
+        ));
+    }
+
+    #[test]
+    fn handshake_wrong_pstr_len() {
+        let mut encoded = Handshake::new(INFO_HASH, *PEER_ID).encode();
+        encoded[0] = 18;
+
+        assert!(matches!(
+            Handshake::decode(&encoded, &INFO_HASH),
+            Err(HandshakeError::InvalidPstrLen { len: 18 })
+        ));
+    }
+
+    #[test]
+    fn reserved_bit_positions() {
+        assert_eq!(
+            ReservedBits::default().with_dht().0,
+            [0, 0, 0, 0, 0, 0, 0, 0x01]
+        );
+        assert_eq!(
+            ReservedBits::default().with_fast().0,
+            [0, 0, 0, 0, 0, 0, 0, 0x04]
+        );
+        assert_eq!(
+            ReservedBits::default().with_extension_protocol().0,
+            [0, 0, 0, 0, 0, 0x10, 0, 0]
+        );
+
+        let reserved = ReservedBits([0, 0, 0, 0, 0, 0x10, 0, 0x05]);
+
+        assert!(reserved.supports_dht());
+        assert!(reserved.supports_fast());
+        assert!(reserved.supports_extension_protocol());
+        assert!(
+            !ReservedBits([0xff, 0xff, 0xff, 0xff, 0xff, 0xef, 0xff, 0xfa])
+                .supports_extension_protocol()
+        );
+    }
+
+    #[test]
+    fn handshake_reserved_bits() {
+        let mut handshake = Handshake::new(INFO_HASH, *PEER_ID);
+        handshake.reserved = ReservedBits::default().with_dht().with_extension_protocol();
+
+        let encoded = handshake.encode();
+
+        assert_eq!(&encoded[20..28], [0, 0, 0, 0, 0, 0x10, 0, 0x01]);
+
+        let decoded = Handshake::decode(&encoded, &INFO_HASH).unwrap();
+
+        assert!(decoded.reserved.supports_dht());
+        assert!(!decoded.reserved.supports_fast());
+        assert!(decoded.reserved.supports_extension_protocol());
+    }
+
+    #[test]
+    fn generated_peer_id_format() {
+        let peer_id = generate_peer_id(*b"0010");
+
+        assert_eq!(peer_id.len(), 20);
+        assert_eq!(&peer_id[..8], b"-MS0010-");
+        assert!(peer_id[8..].iter().all(u8::is_ascii_alphanumeric));
+        assert_eq!(PeerId(peer_id).azureus_client(), Some(("MS", "0010")));
+    }
+
+    #[test]
+    fn parse_peer_id() {
+        let peer_id = "-qB4250-abcdefghijkl".parse::<PeerId>().unwrap();
+
+        assert_eq!(peer_id.to_string(), "-qB4250-abcdefghijkl");
+        assert_eq!(peer_id.client_name(), Some("qBittorrent"));
+        assert!(matches!(
+            "-qB4250-".parse::<PeerId>(),
+            Err(PeerIdError::InvalidPeerIdLen { len: 8 })
+        ));
+    }
+
+    #[test]
+    fn non_azureus_peer_id() {
+        let peer_id = PeerId::try_from(&[0u8; 20] as &[u8]).unwrap();
+
+        assert_eq!(peer_id.azureus_client(), None);
+        assert_eq!(peer_id.client_name(), None);
+        assert!(peer_id.to_string().starts_with("\\x00\\x00"));
+    }
+
+    fn assert_round_trip(message: PeerMessage) {
+        let encoded = message.encode();
+
+        assert_eq!(
+            PeerMessage::decode(&encoded).unwrap(),
+            (message, &[] as &[u8])
+        );
+    }
+
+    #[test]
+    fn message_round_trips() {
+        assert_round_trip(PeerMessage::KeepAlive);
+        assert_round_trip(PeerMessage::Choke);
+        assert_round_trip(PeerMessage::Unchoke);
+        assert_round_trip(PeerMessage::Interested);
+        assert_round_trip(PeerMessage::NotInterested);
+        assert_round_trip(PeerMessage::Have(42));
+        assert_round_trip(PeerMessage::Bitfield(vec![0b1010_0000, 0xff]));
+        assert_round_trip(PeerMessage::Request {
+            index: 1,
+            begin: 16384,
+            length: 16384,
+        });
+        assert_round_trip(PeerMessage::Piece {
+            index: 1,
+            begin: 16384,
+            block: Bytes::from_static(b"some data"),
+        });
+        assert_round_trip(PeerMessage::Cancel {
+            index: 1,
+            begin: 16384,
+            length: 16384,
+        });
+        assert_round_trip(PeerMessage::Extended {
+            id: 3,
+            payload: Bytes::from_static(b"d8:msg_typei0e5:piecei0ee"),
+        });
+    }
+
+    #[test]
+    fn fast_message_round_trips() {
+        let messages = vec![
+            PeerMessage::SuggestPiece(7),
+            PeerMessage::HaveAll,
+            PeerMessage::HaveNone,
+            PeerMessage::RejectRequest {
+                index: 1,
+                begin: 16384,
+                length: 16384,
+            },
+            PeerMessage::AllowedFast(3),
+        ];
+
+        for message in messages {
+            assert!(message.is_fast());
+            assert_eq!(PeerMessage::from(Message::from(message.clone())), message);
+            assert_round_trip(message);
+        }
+
+        assert!(!PeerMessage::Have(7).is_fast());
+        assert_eq!(PeerMessage::HaveAll.encode(), b"\0\0\0\x01\x0e");
+        assert_eq!(
+            PeerMessage::AllowedFast(1).encode(),
+            b"\0\0\0\x05\x11\0\0\0\x01"
+        );
+        assert!(matches!(
+            PeerMessage::decode(b"\0\0\0\x02\x0f\0"),
+            Err(PeerMessageError::InvalidPayloadLen { len: 1, id: 15 })
+        ));
+    }
+
+    #[test]
+    fn message_wire_format() {
+        assert_eq!(PeerMessage::KeepAlive.encode(), b"\0\0\0\0");
+        assert_eq!(PeerMessage::Have(1).encode(), b"\0\0\0\x05\x04\0\0\0\x01");
+    }
+
+    #[test]
+    fn message_leaves_rest() {
+        let encoded = [PeerMessage::Choke.encode(), PeerMessage::Unchoke.encode()].concat();
+        let (message, rest) = PeerMessage::decode(&encoded).unwrap();
+
+        assert_eq!(message, PeerMessage::Choke);
+        assert_eq!(PeerMessage::decode(rest).unwrap().0, PeerMessage::Unchoke);
+    }
+
+    #[test]
+    fn message_unknown_id() {
+        assert!(matches!(
+            PeerMessage::decode(b"\0\0\0\x01\x15"),
+            Err(PeerMessageError::UnknownMessageId { id: 21 })
+        ));
+    }
+
+    #[test]
+    fn extended_handshake_round_trip() {
+        let handshake = ExtendedHandshake {
+            extensions: vec![("ut_metadata".to_owned(), 3), ("ut_pex".to_owned(), 1)]
+                .into_iter()
+                .collect(),
+            client: Some("Matey 0.1.0".to_owned()),
+            port: Some(6881),
+            metadata_size: Some(31235),
+        };
+
+        let message = PeerMessage::extended_handshake(&handshake);
+        let encoded = message.encode();
+
+        assert_eq!(
+            encoded,
+            [
+                &[0u8, 0, 0, 86, 20, 0] as &[u8],
+                b"d1:md11:ut_metadatai3e6:ut_pexi1ee13:metadata_sizei31235e1:pi6881e1:v11:Matey 0.1.0e"
+            ]
+            .concat()
+        );
+
+        match PeerMessage::decode(&encoded).unwrap() {
+            (PeerMessage::Extended { id, payload }, rest) => {
+                assert_eq!(id, EXTENDED_HANDSHAKE_ID);
+                assert!(rest.is_empty());
+                assert_eq!(ExtendedHandshake::decode(&payload).unwrap(), handshake);
+            }
+            (message, _) => panic!("Expected an extended message, got {:?}", message),
+        }
+    }
+
+    #[test]
+    fn extended_handshake_lenient_fields() {
+        let handshake = ExtendedHandshake::decode(b"d1:md6:ut_pexi1ee1:pi70000e1:vi5ee").unwrap();
+
+        assert_eq!(handshake.extensions.get("ut_pex"), Some(&1));
+        assert_eq!(handshake.client, None);
+        assert_eq!(handshake.port, None);
+        assert_eq!(handshake.metadata_size, None);
+        assert!(matches!(
+            ExtendedHandshake::decode(b"d1:pi6881ee"),
+            Err(ExtendedHandshakeError::InvalidExtensionMap)
+        ));
+        assert!(matches!(
+            ExtendedHandshake::decode(b"i5e"),
+            Err(ExtendedHandshakeError::InvalidHandshakeDict)
+        ));
+    }
+
+    #[test]
+    fn extended_message_without_id() {
+        assert!(matches!(
+            PeerMessage::decode(b"\0\0\0\x01\x14"),
+            Err(PeerMessageError::InvalidPayloadLen { len: 0, id: 20 })
+        ));
+    }
+
+    #[test]
+    fn message_truncated() {
+        let encoded = PeerMessage::Have(1).encode();
+
+        assert!(matches!(
+            PeerMessage::decode(&encoded[..7]),
+            Err(PeerMessageError::TruncatedMessage { len: 7 })
+        ));
+        assert!(matches!(
+            PeerMessage::decode(&encoded[..2]),
+            Err(PeerMessageError::TruncatedMessage { len: 2 })
+        ));
+        assert!(matches!(
+            PeerMessage::decode(b"\0\0\0\x03\x04\0\0"),
+            Err(PeerMessageError::InvalidPayloadLen { len: 2, id: 4 })
+        ));
+    }
+}
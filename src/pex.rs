@@ -0,0 +1,324 @@
+use super::{
+    bencode_parser::{encode, parse_bencode, Bencode},
+    torrent_parser::TorrentInfo,
+    tracker::{parse_compact_peers, parse_compact_peers6},
+};
+use bytes::Bytes;
+use snafu::{OptionExt, Snafu};
+use std::{
+    collections::{BTreeMap, HashSet, VecDeque},
+    net::SocketAddr,
+};
+
+/// The name ut_pex goes by in the extended handshake's `m` dict.
+pub const UT_PEX: &str = "ut_pex";
+
+/// What a peer says about each peer it added, one byte per peer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub struct PexFlags(pub u8);
+
+impl PexFlags {
+    const PREFERS_ENCRYPTION: u8 = 0x01;
+    const SEED: u8 = 0x02;
+    const UTP: u8 = 0x04;
+    const HOLEPUNCH: u8 = 0x08;
+    const REACHABLE: u8 = 0x10;
+
+    pub fn prefers_encryption(&self) -> bool {
+        self.0 & Self::PREFERS_ENCRYPTION != 0
+    }
+
+    pub fn is_seed(&self) -> bool {
+        self.0 & Self::SEED != 0
+    }
+
+    pub fn supports_utp(&self) -> bool {
+        self.0 & Self::UTP != 0
+    }
+
+    pub fn supports_holepunch(&self) -> bool {
+        self.0 & Self::HOLEPUNCH != 0
+    }
+
+    /// Whether the peer that sent this connected to the added peer itself,
+    /// meaning it accepts incoming connections.
+    pub fn is_reachable(&self) -> bool {
+        self.0 & Self::REACHABLE != 0
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PexPeer {
+    pub addr: SocketAddr,
+    pub flags: PexFlags,
+}
+
+/// The payload of a ut_pex extended message (BEP 11), which has the peers the
+/// sender has connected to and disconnected from since its last one.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PexMessage {
+    pub added: Vec<PexPeer>,
+    pub dropped: Vec<SocketAddr>,
+}
+
+impl PexMessage {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut dict = BTreeMap::new();
+
+        for (ipv6, suffix) in [(false, ""), (true, "6")] {
+            let added = self
+                .added
+                .iter()
+                .filter(|peer| peer.addr.is_ipv6() == ipv6)
+                .collect::<Vec<_>>();
+
+            dict.insert(
+                Bytes::from(format!("added{}", suffix)),
+                Bencode::ByteString(added.iter().flat_map(|peer| compact(&peer.addr)).collect()),
+            );
+            dict.insert(
+                Bytes::from(format!("added{}.f", suffix)),
+                Bencode::ByteString(added.iter().map(|peer| peer.flags.0).collect()),
+            );
+            dict.insert(
+                Bytes::from(format!("dropped{}", suffix)),
+                Bencode::ByteString(
+                    self.dropped
+                        .iter()
+                        .filter(|addr| addr.is_ipv6() == ipv6)
+                        .flat_map(compact)
+                        .collect(),
+                ),
+            );
+        }
+
+        encode(&Bencode::Dict(dict))
+    }
+
+    pub fn decode(payload: &[u8]) -> Result<Self, PexError> {
+        let dict = parse_bencode(payload)
+            .ok()
+            .map(|(_, dict)| dict)
+            .filter(Bencode::is_dict)
+            .context(InvalidPexMessage)?;
+
+        // Every key is optional, and anything missing is the same as empty.
+        let bytes = |key: &[u8]| match dict.get(key) {
+            Some(val) => val.as_bytes().context(InvalidPexMessage),
+            None => Ok(&[] as &[u8]),
+        };
+        let addrs = |key: &[u8]| -> Result<Vec<SocketAddr>, PexError> {
+            let bytes = bytes(key)?;
+
+            Ok(if key.ends_with(b"6") {
+                parse_compact_peers6(bytes)
+                    .ok()
+                    .context(InvalidPexMessage)?
+                    .into_iter()
+                    .map(SocketAddr::from)
+                    .collect()
+            } else {
+                parse_compact_peers(bytes)
+                    .ok()
+                    .context(InvalidPexMessage)?
+                    .into_iter()
+                    .map(SocketAddr::from)
+                    .collect()
+            })
+        };
+
+        let mut added = Vec::new();
+
+        for (key, flags_key) in [
+            (b"added" as &[u8], b"added.f" as &[u8]),
+            (b"added6", b"added6.f"),
+        ] {
+            let flags = bytes(flags_key)?;
+
+            // Some clients leave the flags out, which tells us nothing.
+            added.extend(
+                addrs(key)?
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, addr)| PexPeer {
+                        addr,
+                        flags: PexFlags(flags.get(i).copied().unwrap_or_default()),
+                    }),
+            );
+        }
+
+        Ok(Self {
+            added,
+            dropped: [addrs(b"dropped")?, addrs(b"dropped6")?].concat(),
+        })
+    }
+}
+
+fn compact(addr: &SocketAddr) -> Vec<u8> {
+    match addr {
+        SocketAddr::V4(addr) => [&addr.ip().octets() as &[u8], &addr.port().to_be_bytes()].concat(),
+        SocketAddr::V6(addr) => [&addr.ip().octets() as &[u8], &addr.port().to_be_bytes()].concat(),
+    }
+}
+
+/// Every peer we've heard about for a torrent, whether from a tracker or
+/// from other peers, in the order we should try connecting to them.
+#[derive(Clone, Debug)]
+pub struct PeerPool {
+    private: bool,
+    known: HashSet<SocketAddr>,
+    pending: VecDeque<SocketAddr>,
+}
+
+impl PeerPool {
+    pub fn new(info: &TorrentInfo) -> Self {
+        Self {
+            private: info.private,
+            known: HashSet::new(),
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// BEP 27 has private torrents only get peers from their trackers.
+    pub fn pex_enabled(&self) -> bool {
+        !self.private
+    }
+
+    /// Adds any of `peers` we haven't heard of before, returning how many
+    /// there were.
+    pub fn add(&mut self, peers: impl IntoIterator<Item = SocketAddr>) -> usize {
+        let before = self.pending.len();
+
+        for peer in peers {
+            if self.known.insert(peer) {
+                self.pending.push_back(peer);
+            }
+        }
+
+        self.pending.len() - before
+    }
+
+    /// Adds the peers from a ut_pex message, unless PEX is disabled. Dropped
+    /// peers are kept, since they might just have been dropped by the sender.
+    pub fn add_pex(&mut self, message: &PexMessage) -> usize {
+        if !self.pex_enabled() {
+            return 0;
+        }
+
+        self.add(message.added.iter().map(|peer| peer.addr))
+    }
+
+    /// The next peer to try connecting to. Each one is only given out once.
+    pub fn next_peer(&mut self) -> Option<SocketAddr> {
+        self.pending.pop_front()
+    }
+
+    pub fn num_pending(&self) -> usize {
+        self.pending.len()
+    }
+}
+
+#[derive(Debug, Snafu)]
+pub enum PexError {
+    #[snafu(display("Received a malformed ut_pex message"))]
+    InvalidPexMessage,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util;
+
+    fn info(private: bool) -> TorrentInfo {
+        TorrentInfo {
+            private,
+            ..test_util::info("a", &[], 16384, &[])
+        }
+    }
+
+    #[test]
+    fn decode_sample() {
+        let message = PexMessage::decode(
+            b"d5:added12:\x0a\x00\x00\x01\x1a\xe1\x0a\x00\x00\x02\x1a\xe27:added.f2:\x12\x016:added618:\
+\x20\x01\x0d\xb8\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x01\x1a\xe17:dropped6:\x0a\x00\x00\x03\x00\x50e",
+        )
+        .unwrap();
+
+        assert_eq!(
+            message.added,
+            vec![
+                PexPeer {
+                    addr: "10.0.0.1:6881".parse().unwrap(),
+                    flags: PexFlags(0x12),
+                },
+                PexPeer {
+                    addr: "10.0.0.2:6882".parse().unwrap(),
+                    flags: PexFlags(0x01),
+                },
+                PexPeer {
+                    addr: "[2001:db8::1]:6881".parse().unwrap(),
+                    flags: PexFlags(0),
+                },
+            ]
+        );
+        assert_eq!(message.dropped, vec!["10.0.0.3:80".parse().unwrap()]);
+
+        let flags = message.added[0].flags;
+
+        assert!(flags.is_seed() && flags.is_reachable());
+        assert!(!flags.prefers_encryption() && !flags.supports_utp());
+    }
+
+    #[test]
+    fn round_trip() {
+        let message = PexMessage {
+            added: vec![
+                PexPeer {
+                    addr: "10.0.0.1:6881".parse().unwrap(),
+                    flags: PexFlags(0x04),
+                },
+                PexPeer {
+                    addr: "[::1]:6881".parse().unwrap(),
+                    flags: PexFlags(0x08),
+                },
+            ],
+            dropped: vec!["[::2]:80".parse().unwrap()],
+        };
+
+        assert_eq!(PexMessage::decode(&message.encode()).unwrap(), message);
+    }
+
+    #[test]
+    fn invalid_messages() {
+        assert!(PexMessage::decode(b"le").is_err());
+        assert!(PexMessage::decode(b"d5:added5:aaaaae").is_err());
+        assert!(PexMessage::decode(b"d5:addedi1ee").is_err());
+        assert_eq!(PexMessage::decode(b"de").unwrap(), PexMessage::default());
+    }
+
+    #[test]
+    fn peer_pool() {
+        let message = PexMessage {
+            added: vec![PexPeer {
+                addr: "10.0.0.2:6881".parse().unwrap(),
+                flags: PexFlags::default(),
+            }],
+            dropped: vec![],
+        };
+
+        let mut pool = PeerPool::new(&info(false));
+
+        assert_eq!(pool.add(vec!["10.0.0.1:6881".parse().unwrap()]), 1);
+        assert_eq!(pool.add_pex(&message), 1);
+        assert_eq!(pool.add_pex(&message), 0);
+        assert_eq!(pool.next_peer(), Some("10.0.0.1:6881".parse().unwrap()));
+        assert_eq!(pool.next_peer(), Some("10.0.0.2:6881".parse().unwrap()));
+        assert_eq!(pool.next_peer(), None);
+
+        let mut private_pool = PeerPool::new(&info(true));
+
+        assert!(!private_pool.pex_enabled());
+        assert_eq!(private_pool.add_pex(&message), 0);
+        assert_eq!(private_pool.num_pending(), 0);
+    }
+}
@@ -0,0 +1,126 @@
+use std::{sync::Mutex, time::Duration};
+use tokio::time::{self, Instant};
+
+/// Caps how many bytes per second go through it with a token bucket, which
+/// lets up to a second's worth through at once after being idle. Share one
+/// between every peer connection to make the cap global.
+#[derive(Debug)]
+pub struct RateLimiter {
+    bytes_per_sec: u64,
+    bucket: Mutex<Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    /// This goes negative when bytes are taken faster than they're added,
+    /// which is how much whoever took them has to wait for.
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        assert!(bytes_per_sec > 0, "Rate limit can't be 0");
+
+        Self {
+            bytes_per_sec,
+            bucket: Mutex::new(Bucket {
+                tokens: bytes_per_sec as f64,
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn bytes_per_sec(&self) -> u64 {
+        self.bytes_per_sec
+    }
+
+    /// Waits until `bytes` can go through without going over the limit.
+    /// Everyone waits in turn, so a big acquisition can't be starved by lots
+    /// of small ones.
+    pub async fn acquire(&self, bytes: usize) {
+        let rate = self.bytes_per_sec as f64;
+
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let now = Instant::now();
+
+            bucket.tokens = f64::min(
+                rate,
+                bucket.tokens + (now - bucket.last_refill).as_secs_f64() * rate,
+            );
+            bucket.last_refill = now;
+            bucket.tokens -= bytes as f64;
+
+            Duration::from_secs_f64(f64::max(0.0, -bucket.tokens) / rate)
+        };
+
+        if wait > Duration::ZERO {
+            time::sleep(wait).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn burst_goes_through() {
+        time::pause();
+
+        let limiter = RateLimiter::new(1000);
+        let start = Instant::now();
+
+        limiter.acquire(600).await;
+        limiter.acquire(400).await;
+
+        assert_eq!(Instant::now(), start);
+    }
+
+    #[tokio::test]
+    async fn stays_under_cap() {
+        time::pause();
+
+        let limiter = RateLimiter::new(1000);
+        let start = Instant::now();
+
+        for _ in 0..50 {
+            limiter.acquire(100).await;
+        }
+
+        // The first second's worth is the burst, the rest is rate limited.
+        let elapsed = Instant::now() - start;
+
+        assert!(elapsed >= Duration::from_secs(4));
+        assert!(elapsed < Duration::from_millis(4100));
+    }
+
+    #[tokio::test]
+    async fn shared_between_tasks() {
+        time::pause();
+
+        let limiter = Arc::new(RateLimiter::new(1000));
+        let start = Instant::now();
+
+        let tasks = (0..4)
+            .map(|_| {
+                let limiter = limiter.clone();
+
+                tokio::spawn(async move {
+                    for _ in 0..10 {
+                        limiter.acquire(100).await;
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        // 4000 bytes in total, so 3 seconds after the burst.
+        assert!(Instant::now() - start >= Duration::from_secs(3));
+    }
+}
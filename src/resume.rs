@@ -0,0 +1,117 @@
+use super::{bitfield::Bitfield, torrent_parser::TorrentInfo};
+use std::{
+    fs::File,
+    io::{self, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+/// Where each of the torrent's files would be under `base_dir`. Like with
+/// most clients, a multi-file torrent's files go in a directory named after it.
+pub fn file_paths(info: &TorrentInfo, base_dir: &Path) -> Vec<PathBuf> {
+    let root = if info.multi_file {
+        base_dir.join(&info.name)
+    } else {
+        base_dir.to_owned()
+    };
+
+    info.files
+        .iter()
+        .map(|file| root.join(&file.path))
+        .collect()
+}
+
+/// Reads whatever's already been downloaded to `base_dir`, for resuming a
+/// download, and returns which pieces are there and match their hashes.
+/// Missing or short files just mean the pieces in them aren't done yet.
+pub fn check_existing(info: &TorrentInfo, base_dir: &Path) -> Result<Bitfield, io::Error> {
+    let mut files = file_paths(info, base_dir)
+        .into_iter()
+        .map(|path| match File::open(path) {
+            Ok(file) => Ok(Some(file)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut bitfield = Bitfield::new(info.num_pieces());
+    let mut buf = Vec::new();
+
+    'pieces: for piece in info.iter_pieces() {
+        buf.clear();
+        buf.resize(piece.length as usize, 0);
+
+        let mut written = 0;
+
+        for span in info.locate(piece.offset, piece.length) {
+            let file = match &mut files[span.file] {
+                Some(file) => file,
+                None => continue 'pieces,
+            };
+            let part = &mut buf[written..written + span.len as usize];
+
+            file.seek(SeekFrom::Start(span.offset))?;
+
+            match file.read_exact(part) {
+                Ok(()) => written += part.len(),
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => continue 'pieces,
+                Err(e) => return Err(e),
+            }
+        }
+
+        if info.verify_piece(piece.index, &buf) {
+            bitfield.set(piece.index);
+        }
+    }
+
+    Ok(bitfield)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::{base_dir, info};
+    use std::fs;
+
+    #[test]
+    fn partially_complete() {
+        let content = b"abcdefghijklmn";
+        let info = info("resume", content, 4, &[("0", 6), ("1", 5), ("2", 3)]);
+
+        let base_dir = base_dir("resume", "partial");
+        let root = base_dir.join("resume");
+        fs::create_dir_all(&root).unwrap();
+
+        // Pieces 0 and 1 are fine, piece 1 spanning two files. Piece 2 has a
+        // corrupt byte, and the last file is cut off before piece 3.
+        fs::write(root.join("0"), b"abcdef").unwrap();
+        fs::write(root.join("1"), b"ghXjk").unwrap();
+        fs::write(root.join("2"), b"l").unwrap();
+
+        let bitfield = check_existing(&info, &base_dir).unwrap();
+
+        fs::remove_dir_all(&base_dir).unwrap();
+
+        assert_eq!(bitfield.iter_set().collect::<Vec<_>>(), vec![0, 1]);
+        assert_eq!(bitfield.len(), 4);
+    }
+
+    #[test]
+    fn nothing_downloaded() {
+        let content = b"abcdefgh";
+        let info = info("resume", content, 4, &[("0", 8)]);
+
+        let bitfield = check_existing(&info, Path::new("tests/fixtures/nowhere")).unwrap();
+
+        assert_eq!(bitfield.count_set(), 0);
+    }
+
+    #[test]
+    fn single_file_paths() {
+        let info = info("resume", b"abcd", 4, &[("0", 4)]);
+
+        assert_eq!(
+            file_paths(&info, Path::new("downloads")),
+            vec![PathBuf::from("downloads/0")]
+        );
+    }
+}
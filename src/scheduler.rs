@@ -0,0 +1,645 @@
+use super::{bitfield::Bitfield, peer::PeerMessage, torrent_parser::TorrentInfo, types::BlockMeta};
+use snafu::{ensure, Snafu};
+use std::{cmp::Reverse, collections::BTreeMap};
+
+/// Endgame starts once fewer than this many blocks are left to request.
+pub const DEFAULT_ENDGAME_THRESHOLD: usize = 20;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum SelectionMode {
+    /// Prefer the pieces the fewest peers have, so that they don't disappear
+    /// from the swarm before we get them.
+    #[default]
+    RarestFirst,
+    /// Go in order, for playing a file while it's still downloading.
+    Sequential,
+}
+
+/// How much a file is wanted. Higher priorities are downloaded first.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum FilePriority {
+    Skip,
+    #[default]
+    Normal,
+    High,
+}
+
+/// Which of a torrent's files to download, and which of them first.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FileSelection {
+    priorities: Vec<FilePriority>,
+}
+
+impl FileSelection {
+    /// Every file starts out at normal priority.
+    pub fn new(info: &TorrentInfo) -> Self {
+        Self {
+            priorities: vec![FilePriority::Normal; info.files.len()],
+        }
+    }
+
+    pub fn with_priority(
+        mut self,
+        file: usize,
+        priority: FilePriority,
+    ) -> Result<Self, FileSelectionError> {
+        self.set_priority(file, priority)?;
+        Ok(self)
+    }
+
+    pub fn set_priority(
+        &mut self,
+        file: usize,
+        priority: FilePriority,
+    ) -> Result<(), FileSelectionError> {
+        let num_files = self.num_files();
+
+        ensure!(file < num_files, NoSuchFile { file, num_files });
+
+        self.priorities[file] = priority;
+        Ok(())
+    }
+
+    /// `None` for files that aren't in the torrent.
+    pub fn priority(&self, file: usize) -> Option<FilePriority> {
+        self.priorities.get(file).copied()
+    }
+
+    pub fn num_files(&self) -> usize {
+        self.priorities.len()
+    }
+
+    /// Each piece gets the highest priority of the files it overlaps, so a
+    /// piece is only skipped if every file in it is. Pieces that straddle a
+    /// skipped file still have to be downloaded whole to be verified. Fails
+    /// if `info` has a different number of files to the torrent this
+    /// selection was made for.
+    pub fn piece_priorities(
+        &self,
+        info: &TorrentInfo,
+    ) -> Result<Vec<FilePriority>, FileSelectionError> {
+        let (selected, files) = (self.num_files(), info.files.len());
+
+        ensure!(selected == files, MismatchedTorrent { selected, files });
+
+        Ok(info
+            .iter_pieces()
+            .map(|piece| {
+                info.locate(piece.offset, piece.length)
+                    .iter()
+                    .map(|span| self.priorities[span.file])
+                    .max()
+                    .unwrap_or(FilePriority::Skip)
+            })
+            .collect())
+    }
+
+    /// The pieces that aren't skipped.
+    pub fn wanted_pieces(&self, info: &TorrentInfo) -> Result<Bitfield, FileSelectionError> {
+        let mut wanted = Bitfield::new(info.num_pieces());
+
+        for (index, priority) in self.piece_priorities(info)?.into_iter().enumerate() {
+            if priority != FilePriority::Skip {
+                wanted.set(index);
+            }
+        }
+
+        Ok(wanted)
+    }
+}
+
+/// Decides which piece to download next, and keeps track of which blocks
+/// are being requested from which peers for endgame mode.
+#[derive(Clone, Debug)]
+pub struct PieceSelector {
+    mode: SelectionMode,
+    endgame_threshold: usize,
+    /// Each block that's been requested but hasn't arrived, along with every
+    /// peer it's been requested from. Peers are identified by their number.
+    outstanding: BTreeMap<BlockMeta, Vec<usize>>,
+    /// Each piece's priority, if only some files are wanted.
+    piece_priorities: Option<Vec<FilePriority>>,
+}
+
+impl Default for PieceSelector {
+    fn default() -> Self {
+        Self::new(SelectionMode::default())
+    }
+}
+
+impl PieceSelector {
+    pub fn new(mode: SelectionMode) -> Self {
+        Self {
+            mode,
+            endgame_threshold: DEFAULT_ENDGAME_THRESHOLD,
+            outstanding: BTreeMap::new(),
+            piece_priorities: None,
+        }
+    }
+
+    pub fn with_endgame_threshold(mut self, endgame_threshold: usize) -> Self {
+        self.endgame_threshold = endgame_threshold;
+        self
+    }
+
+    /// Only selects pieces with something `selection` wants in them, going
+    /// by priority first and then by the selection mode. Fails if
+    /// `selection` wasn't made for `info`'s torrent.
+    pub fn with_file_selection(
+        mut self,
+        info: &TorrentInfo,
+        selection: &FileSelection,
+    ) -> Result<Self, FileSelectionError> {
+        self.piece_priorities = Some(selection.piece_priorities(info)?);
+        Ok(self)
+    }
+
+    pub fn mode(&self) -> SelectionMode {
+        self.mode
+    }
+
+    /// Near the end of a download, the last few blocks can be stuck waiting
+    /// on slow peers, so once there are only a few left that nobody has been
+    /// asked for, it's worth asking everyone for the ones still outstanding.
+    pub fn is_endgame(&self, unrequested_blocks: usize) -> bool {
+        unrequested_blocks < self.endgame_threshold
+    }
+
+    pub fn block_requested(&mut self, block: BlockMeta, peer: usize) {
+        let peers = self.outstanding.entry(block).or_default();
+
+        if !peers.contains(&peer) {
+            peers.push(peer);
+        }
+    }
+
+    /// The outstanding blocks that haven't been requested from `peer` yet,
+    /// which it should be sent requests for during endgame.
+    pub fn endgame_requests(&self, peer: usize) -> Vec<BlockMeta> {
+        self.outstanding
+            .iter()
+            .filter(|(_, peers)| !peers.contains(&peer))
+            .map(|(&block, _)| block)
+            .collect()
+    }
+
+    /// Stops tracking a block once it's arrived from `from`, returning a
+    /// cancel for each other peer that it was requested from.
+    pub fn block_received(&mut self, block: BlockMeta, from: usize) -> Vec<(usize, PeerMessage)> {
+        self.outstanding
+            .remove(&block)
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|&peer| peer != from)
+            .map(|peer| {
+                (
+                    peer,
+                    PeerMessage::Cancel {
+                        index: block.piece_index,
+                        begin: block.begin,
+                        length: block.length,
+                    },
+                )
+            })
+            .collect()
+    }
+
+    /// Forgets the request for `block` made to `peer` once it's said it won't
+    /// send it, so that the block can be requested from someone else.
+    pub fn block_rejected(&mut self, block: BlockMeta, peer: usize) {
+        if let Some(peers) = self.outstanding.get_mut(&block) {
+            peers.retain(|&requested_from| requested_from != peer);
+
+            if peers.is_empty() {
+                self.outstanding.remove(&block);
+            }
+        }
+    }
+
+    /// Forgets every request made to `peer`, like when it chokes us or
+    /// disconnects, since those requests won't be answered.
+    pub fn peer_gone(&mut self, peer: usize) {
+        for peers in self.outstanding.values_mut() {
+            peers.retain(|&requested_from| requested_from != peer);
+        }
+
+        self.outstanding.retain(|_, peers| !peers.is_empty());
+    }
+
+    /// Returns a piece that we don't have yet but at least one peer does, or
+    /// `None` if there isn't one. Ties in rarity go to the lowest index.
+    /// Pieces that are skipped by the file selection are never returned.
+    pub fn select<'a>(
+        &self,
+        ours: &Bitfield,
+        peers: impl IntoIterator<Item = &'a Bitfield>,
+    ) -> Option<usize> {
+        let availability = availability(ours.len(), peers);
+        let priority = |index: usize| match &self.piece_priorities {
+            Some(priorities) => priorities.get(index).copied().unwrap_or(FilePriority::Skip),
+            None => FilePriority::Normal,
+        };
+        let needed = (0..ours.len()).filter(|&index| {
+            !ours.has(index) && availability[index] > 0 && priority(index) != FilePriority::Skip
+        });
+
+        match self.mode {
+            SelectionMode::RarestFirst => {
+                needed.min_by_key(|&index| (Reverse(priority(index)), availability[index]))
+            }
+            SelectionMode::Sequential => needed.min_by_key(|&index| Reverse(priority(index))),
+        }
+    }
+}
+
+/// How many of `peers` have each piece.
+pub fn availability<'a>(
+    num_pieces: usize,
+    peers: impl IntoIterator<Item = &'a Bitfield>,
+) -> Vec<usize> {
+    let mut availability = vec![0; num_pieces];
+
+    for peer in peers {
+        for index in peer.iter_set().filter(|&index| index < num_pieces) {
+            availability[index] += 1;
+        }
+    }
+
+    availability
+}
+
+/// How many peers have each piece, kept up to date as peers come and go and
+/// tell us about new pieces, rather than counted again from every peer's
+/// bitfield each time a piece is picked.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Availability {
+    counts: Vec<usize>,
+}
+
+impl Availability {
+    pub fn new(num_pieces: usize) -> Self {
+        Self {
+            counts: vec![0; num_pieces],
+        }
+    }
+
+    pub fn add_peer(&mut self, bitfield: &Bitfield) {
+        for index in bitfield.iter_set() {
+            if let Some(count) = self.counts.get_mut(index) {
+                *count += 1;
+            }
+        }
+    }
+
+    /// Takes away a peer that's gone. `bitfield` has to be everything the
+    /// peer had, haves included, for the counts to stay right.
+    pub fn remove_peer(&mut self, bitfield: &Bitfield) {
+        for index in bitfield.iter_set() {
+            if let Some(count) = self.counts.get_mut(index) {
+                *count = count.saturating_sub(1);
+            }
+        }
+    }
+
+    /// Counts a have from a peer whose bitfield didn't have the piece yet.
+    pub fn add_have(&mut self, index: usize) {
+        if let Some(count) = self.counts.get_mut(index) {
+            *count += 1;
+        }
+    }
+
+    pub fn count(&self, index: usize) -> usize {
+        self.counts.get(index).copied().unwrap_or(0)
+    }
+
+    pub fn counts(&self) -> &[usize] {
+        &self.counts
+    }
+
+    /// The piece out of `candidates` that the fewest peers have, not counting
+    /// ones nobody has. Ties go to the lowest index.
+    pub fn rarest_among(&self, candidates: &Bitfield) -> Option<usize> {
+        candidates
+            .iter_set()
+            .filter(|&index| self.count(index) > 0)
+            .min_by_key(|&index| self.count(index))
+    }
+}
+
+#[derive(Debug, Snafu)]
+pub enum FileSelectionError {
+    #[snafu(display("There's no file {}, only {} of them", file, num_files))]
+    NoSuchFile { file: usize, num_files: usize },
+    #[snafu(display(
+        "File selection is for {} files, but the torrent has {}",
+        selected,
+        files
+    ))]
+    MismatchedTorrent { selected: usize, files: usize },
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn bitfield(pieces: &[usize]) -> Bitfield {
+        let mut bitfield = Bitfield::new(6);
+
+        for &index in pieces {
+            bitfield.set(index);
+        }
+
+        bitfield
+    }
+
+    #[test]
+    fn piece_availability() {
+        let peers = [bitfield(&[0, 1, 2]), bitfield(&[1, 2]), bitfield(&[2])];
+
+        assert_eq!(availability(6, &peers), vec![1, 2, 3, 0, 0, 0]);
+    }
+
+    #[test]
+    fn incremental_availability() {
+        let peers = [bitfield(&[0, 1, 2]), bitfield(&[1, 2]), bitfield(&[2])];
+        let mut tracked = Availability::new(6);
+
+        for peer in &peers {
+            tracked.add_peer(peer);
+        }
+
+        assert_eq!(tracked.counts(), availability(6, &peers).as_slice());
+
+        // The third peer gets piece 4, and then leaves with it.
+        tracked.add_have(4);
+        assert_eq!(tracked.counts(), [1, 2, 3, 0, 1, 0]);
+
+        tracked.remove_peer(&bitfield(&[2, 4]));
+        assert_eq!(tracked.counts(), [1, 2, 2, 0, 0, 0]);
+
+        tracked.remove_peer(&peers[0]);
+        assert_eq!(tracked.counts(), [0, 1, 1, 0, 0, 0]);
+
+        tracked.add_have(6);
+        assert_eq!(tracked.count(6), 0);
+    }
+
+    #[test]
+    fn rarest_among() {
+        let mut tracked = Availability::new(6);
+
+        for peer in &[
+            bitfield(&[0, 1, 2, 3]),
+            bitfield(&[0, 2]),
+            bitfield(&[2, 3]),
+        ] {
+            tracked.add_peer(peer);
+        }
+
+        // What the first peer has that we don't, which is everything but 0.
+        let candidates = &bitfield(&[0, 1, 2, 3]) & &!bitfield(&[0]);
+
+        assert_eq!(tracked.rarest_among(&candidates), Some(1));
+        assert_eq!(tracked.rarest_among(&bitfield(&[2, 3])), Some(3));
+        assert_eq!(tracked.rarest_among(&bitfield(&[4, 5])), None);
+    }
+
+    #[test]
+    fn rarest_first() {
+        let selector = PieceSelector::new(SelectionMode::RarestFirst);
+        let peers = [
+            bitfield(&[0, 1, 2, 3]),
+            bitfield(&[0, 1, 2]),
+            bitfield(&[0, 2, 3]),
+        ];
+
+        // 1 and 3 are both held by two peers, 0 and 2 by all three.
+        assert_eq!(selector.select(&bitfield(&[]), &peers), Some(1));
+        assert_eq!(selector.select(&bitfield(&[1]), &peers), Some(3));
+        assert_eq!(selector.select(&bitfield(&[1, 3]), &peers), Some(0));
+    }
+
+    #[test]
+    fn skips_pieces_we_have() {
+        let selector = PieceSelector::default();
+        let peers = [bitfield(&[0, 1]), bitfield(&[1])];
+
+        assert_eq!(selector.select(&bitfield(&[0]), &peers), Some(1));
+        assert_eq!(selector.select(&bitfield(&[0, 1]), &peers), None);
+        assert_eq!(selector.select(&bitfield(&[]), &[] as &[Bitfield]), None);
+    }
+
+    fn block(begin: u32) -> BlockMeta {
+        BlockMeta {
+            piece_index: 5,
+            begin,
+            length: 16384,
+        }
+    }
+
+    #[test]
+    fn endgame() {
+        let mut selector = PieceSelector::default().with_endgame_threshold(2);
+
+        selector.block_requested(block(0), 0);
+        selector.block_requested(block(16384), 1);
+
+        assert!(!selector.is_endgame(2));
+        assert!(selector.is_endgame(1));
+        assert!(selector.is_endgame(0));
+
+        // Every peer gets asked for everything it hasn't been asked for yet.
+        assert_eq!(selector.endgame_requests(2), vec![block(0), block(16384)]);
+        assert_eq!(selector.endgame_requests(0), vec![block(16384)]);
+
+        for block in selector.endgame_requests(2) {
+            selector.block_requested(block, 2);
+        }
+
+        assert!(selector.endgame_requests(2).is_empty());
+
+        assert_eq!(
+            selector.block_received(block(0), 2),
+            vec![(
+                0,
+                PeerMessage::Cancel {
+                    index: 5,
+                    begin: 0,
+                    length: 16384
+                }
+            )]
+        );
+        assert_eq!(
+            selector.block_received(block(16384), 1),
+            vec![(
+                2,
+                PeerMessage::Cancel {
+                    index: 5,
+                    begin: 16384,
+                    length: 16384
+                }
+            )]
+        );
+
+        // A duplicate arriving after the cancel went out has nothing to cancel.
+        assert!(selector.block_received(block(0), 0).is_empty());
+        assert!(selector.endgame_requests(3).is_empty());
+    }
+
+    #[test]
+    fn endgame_peer_gone() {
+        let mut selector = PieceSelector::default();
+
+        selector.block_requested(block(0), 0);
+        selector.block_requested(block(0), 1);
+        selector.block_requested(block(16384), 1);
+        selector.peer_gone(1);
+
+        assert_eq!(selector.endgame_requests(1), vec![block(0)]);
+        assert!(selector.block_received(block(0), 0).is_empty());
+    }
+
+    #[test]
+    fn rejected_requests() {
+        let mut selector = PieceSelector::default();
+
+        selector.block_requested(block(0), 0);
+        selector.block_requested(block(0), 1);
+        selector.block_requested(block(16384), 1);
+        selector.block_rejected(block(0), 1);
+
+        // Peer 1 could be asked again, and peer 0 still has it outstanding.
+        assert_eq!(selector.endgame_requests(1), vec![block(0)]);
+        assert_eq!(selector.endgame_requests(2), vec![block(0), block(16384)]);
+
+        // Once nobody's left, it's not outstanding at all.
+        selector.block_rejected(block(0), 0);
+
+        assert_eq!(selector.endgame_requests(2), vec![block(16384)]);
+        assert!(selector.block_received(block(0), 0).is_empty());
+    }
+
+    #[test]
+    fn selects_from_stored_pieces() {
+        use crate::{
+            storage::{verified_pieces, MemStorage, Storage},
+            test_util::info,
+        };
+
+        let content = b"abcdefghijklmnopqrstuvwx";
+        let mut storage = MemStorage::new(info("a", content, 4, &[("a", content.len() as u64)]));
+        let selector = PieceSelector::new(SelectionMode::Sequential);
+        let peers = [bitfield(&[0, 1, 2, 3, 4, 5])];
+
+        storage.write_piece(0, b"abcd").unwrap();
+        storage.write_piece(2, b"ijkl").unwrap();
+
+        let ours = verified_pieces(&mut storage).unwrap();
+
+        assert_eq!(selector.select(&ours, &peers), Some(1));
+
+        storage.write_piece(1, b"efgh").unwrap();
+
+        assert_eq!(
+            selector.select(&verified_pieces(&mut storage).unwrap(), &peers),
+            Some(3)
+        );
+    }
+
+    #[test]
+    fn file_selection() {
+        use crate::test_util::info;
+
+        // Pieces of 4 bytes: 0 is all a, 1 is a and b, 2 is all b, 3 is all c.
+        let info = info("a", b"aaaaaabbbbbbcc", 4, &[("a", 6), ("b", 6), ("c", 2)]);
+        let selection = FileSelection::new(&info)
+            .with_priority(1, FilePriority::Skip)
+            .unwrap()
+            .with_priority(2, FilePriority::High)
+            .unwrap();
+
+        assert_eq!(selection.priority(1), Some(FilePriority::Skip));
+        assert_eq!(selection.priority(3), None);
+        assert!(matches!(
+            selection.clone().with_priority(3, FilePriority::High),
+            Err(FileSelectionError::NoSuchFile {
+                file: 3,
+                num_files: 3
+            })
+        ));
+
+        assert_eq!(
+            selection.piece_priorities(&info).unwrap(),
+            vec![
+                FilePriority::Normal,
+                FilePriority::Normal,
+                FilePriority::Skip,
+                FilePriority::High
+            ]
+        );
+        assert_eq!(
+            selection
+                .wanted_pieces(&info)
+                .unwrap()
+                .iter_set()
+                .collect::<Vec<_>>(),
+            vec![0, 1, 3]
+        );
+
+        let seed = [bitfield(&[0, 1, 2, 3])];
+        let selector = PieceSelector::new(SelectionMode::Sequential)
+            .with_file_selection(&info, &selection)
+            .unwrap();
+        let mut ours = Bitfield::new(4);
+
+        // The high priority piece goes first, and skipped piece 2 never does.
+        let mut order = Vec::new();
+
+        while let Some(index) = selector.select(&ours, &seed) {
+            order.push(index);
+            ours.set(index);
+        }
+
+        assert_eq!(order, vec![3, 0, 1]);
+    }
+
+    #[test]
+    fn mismatched_file_selection() {
+        use crate::test_util::info;
+
+        let selection = FileSelection::new(&info(
+            "a",
+            b"aaaaaabbbbbbcc",
+            4,
+            &[("a", 6), ("b", 6), ("c", 2)],
+        ));
+        let fewer = info("b", b"aaaaaabb", 4, &[("a", 6), ("b", 2)]);
+        let mismatched = |result| {
+            matches!(
+                result,
+                Err(FileSelectionError::MismatchedTorrent {
+                    selected: 3,
+                    files: 2
+                })
+            )
+        };
+
+        assert!(mismatched(selection.piece_priorities(&fewer).map(drop)));
+        assert!(mismatched(selection.wanted_pieces(&fewer).map(drop)));
+        assert!(mismatched(
+            PieceSelector::default()
+                .with_file_selection(&fewer, &selection)
+                .map(drop)
+        ));
+    }
+
+    #[test]
+    fn sequential() {
+        let selector = PieceSelector::new(SelectionMode::Sequential);
+        let peers = [bitfield(&[0, 1, 2, 3]), bitfield(&[3])];
+
+        assert_eq!(selector.select(&bitfield(&[]), &peers), Some(0));
+        assert_eq!(selector.select(&bitfield(&[0, 1]), &peers), Some(2));
+        assert_eq!(selector.select(&bitfield(&[0, 1, 2, 3]), &peers), None);
+    }
+}
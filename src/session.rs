@@ -0,0 +1,1771 @@
+use super::{
+    bitfield::{Bitfield, BitfieldError},
+    peer::{ExtendedHandshake, Handshake, PeerMessage, ReservedBits},
+    pex::{PeerPool, PexMessage, UT_PEX},
+    scheduler::{Availability, FilePriority, FileSelection, FileSelectionError},
+    storage::{verified_pieces, Storage, StorageError},
+    tcp_peer_communicator::{create_tcp_peer_rw_with, max_frame_len, TcpPeerError},
+    torrent_parser::{SHA1Hash, Torrent, TorrentInfo},
+    tracker::{AnnounceLoop, AnnounceRequest, DEFAULT_TRACKER_TIMEOUT},
+    types::{Block, BlockMeta, PeerReader, PeerWriter},
+};
+use snafu::{ensure, OptionExt, ResultExt, Snafu};
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, BTreeSet},
+    io, mem,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::{broadcast, mpsc, watch},
+    task,
+    time::{self, Instant},
+};
+
+pub const DEFAULT_MAX_PEERS: usize = 20;
+/// Peers that don't send anything for this long get dropped.
+pub const PEER_TIMEOUT: Duration = Duration::from_secs(120);
+/// How long we go without sending anything before sending a keep-alive. It's
+/// a bit under [`PEER_TIMEOUT`], so that peers that drop us just as quickly
+/// don't.
+pub const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(90);
+/// How big a block we ask each peer for at a time, unless configured
+/// otherwise.
+const BLOCK_LEN: u32 = 16 * 1024;
+/// The biggest block we'll send when asked, which is what most clients cap
+/// it at too.
+const MAX_REQUEST_LEN: u32 = 128 * 1024;
+/// How many requests we keep going to each peer.
+const PIPELINE_LEN: usize = 5;
+/// How often [`PeerManager::run`] tries more peers from its pool when it's
+/// been under the limit.
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+/// How many events can be waiting for a slow subscriber before it misses
+/// some.
+const EVENTS_CAPACITY: usize = 64;
+/// The ID we have peers send ut_pex messages with.
+const UT_PEX_ID: u8 = 1;
+/// How many PEX messages can be waiting for [`PeerManager::run`] before the
+/// oldest are dropped.
+const PEX_CAPACITY: usize = 64;
+
+/// How a [`PeerManager`] deals with its peers. The defaults are the same as
+/// mainline's.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SessionConfig {
+    /// How long we wait for a TCP connection to a peer to go through.
+    pub connect_timeout: Duration,
+    /// How long a peer gets to send its handshake once we're connected.
+    pub handshake_timeout: Duration,
+    /// How long we wait for a block we asked for before asking someone else.
+    pub request_timeout: Duration,
+    /// Peers that don't send anything for this long get dropped.
+    pub peer_timeout: Duration,
+    pub max_peers: usize,
+    /// How big a block we ask each peer for at a time. Most clients won't
+    /// send anything over 16 KiB, and it can't be over 128 KiB.
+    pub block_len: u32,
+    /// How long an announce gets before it's given up on and tried again
+    /// later, for [`PeerManager::announce_loop`].
+    pub tracker_timeout: Duration,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            connect_timeout: Duration::from_secs(10),
+            handshake_timeout: Duration::from_secs(10),
+            request_timeout: Duration::from_secs(60),
+            peer_timeout: PEER_TIMEOUT,
+            max_peers: DEFAULT_MAX_PEERS,
+            block_len: BLOCK_LEN,
+            tracker_timeout: DEFAULT_TRACKER_TIMEOUT,
+        }
+    }
+}
+
+impl SessionConfig {
+    /// Makes sure none of the timeouts are zero, which would drop every peer
+    /// straight away, and that blocks are a size peers will send.
+    fn check(&self) -> Result<(), SessionError> {
+        let timeouts = [
+            ("connect_timeout", self.connect_timeout),
+            ("handshake_timeout", self.handshake_timeout),
+            ("request_timeout", self.request_timeout),
+            ("peer_timeout", self.peer_timeout),
+            ("tracker_timeout", self.tracker_timeout),
+        ];
+
+        for &(field, timeout) in &timeouts {
+            ensure!(timeout > Duration::ZERO, InvalidConfig { field });
+        }
+
+        ensure!(
+            (1..=MAX_REQUEST_LEN).contains(&self.block_len),
+            InvalidConfig { field: "block_len" }
+        );
+
+        Ok(())
+    }
+}
+
+/// Downloads and seeds one torrent over up to `max_peers` TCP connections at
+/// once. Each connection gets its own task, which handshakes, swaps
+/// bitfields and then trades blocks until either side hangs up. Finished
+/// pieces go straight to the storage, which checks them against their hashes.
+///
+/// It's cheap to clone, and every clone shares the same connections.
+pub struct PeerManager<S> {
+    inner: Arc<Inner<S>>,
+    config: SessionConfig,
+}
+
+impl<S> Clone for PeerManager<S> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            config: self.config,
+        }
+    }
+}
+
+struct Inner<S> {
+    info: TorrentInfo,
+    info_hash: SHA1Hash,
+    peer_id: [u8; 20],
+    /// Kept apart from `state`, so that nothing else waits on disk IO.
+    storage: Mutex<S>,
+    state: Mutex<SwarmState>,
+    num_peers: Arc<AtomicUsize>,
+    next_peer: AtomicUsize,
+    /// Every piece we finish, so each connection can send a have for it.
+    completed: broadcast::Sender<u32>,
+    /// How many pieces we have.
+    progress: watch::Sender<usize>,
+    progress_rx: watch::Receiver<usize>,
+    events: broadcast::Sender<PeerEvent>,
+    /// What peers tell us about other peers, for whoever's connecting to them.
+    pex: broadcast::Sender<PexMessage>,
+}
+
+/// Something that happened to one of the connections, by peer number.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PeerEvent {
+    Connected {
+        peer: usize,
+        addr: SocketAddr,
+    },
+    /// The peer went its timeout without sending anything, so it's being
+    /// dropped.
+    Timeout {
+        peer: usize,
+    },
+    Disconnected {
+        peer: usize,
+    },
+}
+
+/// Everything the connections share, which is only ever locked for as long as
+/// it takes to update it.
+struct SwarmState {
+    have: Bitfield,
+    /// The pieces each connected peer has, by their number.
+    peers: BTreeMap<usize, Bitfield>,
+    /// How many of the connected peers have each piece, which has to be kept
+    /// in line with `peers`.
+    availability: Availability,
+    downloads: BTreeMap<u32, PieceDownload>,
+    /// Each piece's priority, from the file selection.
+    priorities: Vec<FilePriority>,
+}
+
+/// A piece we've started requesting blocks for. It stays around while it's
+/// being written, with every block received, so it doesn't get started again.
+struct PieceDownload {
+    data: Vec<u8>,
+    blocks: Vec<(BlockMeta, BlockState)>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum BlockState {
+    Missing,
+    Requested(usize),
+    Received,
+}
+
+impl<S: Storage + Send + 'static> PeerManager<S> {
+    /// Goes through everything already in `storage` first, so that only the
+    /// pieces that are missing get downloaded.
+    pub fn new(torrent: &Torrent, mut storage: S, peer_id: [u8; 20]) -> Result<Self, SessionError> {
+        let have = verified_pieces(&mut storage).context(StorageFailed)?;
+        let (progress, progress_rx) = watch::channel(have.count_set());
+        let (completed, _) = broadcast::channel(torrent.info.num_pieces().max(1));
+
+        Ok(Self {
+            inner: Arc::new(Inner {
+                info: torrent.info.clone(),
+                info_hash: torrent.info_hash(),
+                peer_id,
+                storage: Mutex::new(storage),
+                state: Mutex::new(SwarmState {
+                    have,
+                    peers: BTreeMap::new(),
+                    availability: Availability::new(torrent.info.num_pieces()),
+                    downloads: BTreeMap::new(),
+                    priorities: vec![FilePriority::Normal; torrent.info.num_pieces()],
+                }),
+                num_peers: Arc::new(AtomicUsize::new(0)),
+                next_peer: AtomicUsize::new(0),
+                completed,
+                progress,
+                progress_rx,
+                events: broadcast::channel(EVENTS_CAPACITY).0,
+                pex: broadcast::channel(PEX_CAPACITY).0,
+            }),
+            config: SessionConfig::default(),
+        })
+    }
+
+    /// Fails if `config` has a zero timeout or a block size that's out of
+    /// range.
+    pub fn with_config(mut self, config: SessionConfig) -> Result<Self, SessionError> {
+        config.check()?;
+
+        self.config = config;
+        Ok(self)
+    }
+
+    pub fn with_max_peers(mut self, max_peers: usize) -> Self {
+        self.config.max_peers = max_peers;
+        self
+    }
+
+    /// Only downloads the pieces with something `selection` wants in them,
+    /// highest priority first. That's all it takes to be complete, too. Fails
+    /// if `selection` was made for a torrent with a different number of files.
+    pub fn with_file_selection(self, selection: &FileSelection) -> Result<Self, SessionError> {
+        let priorities = selection
+            .piece_priorities(&self.inner.info)
+            .context(InvalidFileSelection)?;
+
+        self.inner.state.lock().unwrap().priorities = priorities;
+        Ok(self)
+    }
+
+    pub fn num_peers(&self) -> usize {
+        self.inner.num_peers.load(Ordering::SeqCst)
+    }
+
+    /// Everything that happens to connections from now on.
+    pub fn events(&self) -> broadcast::Receiver<PeerEvent> {
+        self.inner.events.subscribe()
+    }
+
+    /// The pieces we have so far.
+    pub fn have(&self) -> Bitfield {
+        self.inner.state.lock().unwrap().have.clone()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        let state = self.inner.state.lock().unwrap();
+
+        (0..self.inner.info.num_pieces()).all(|index| !state.needs(index))
+    }
+
+    /// Reads a piece back out of the storage.
+    pub fn read_piece(&self, index: usize) -> Result<Vec<u8>, SessionError> {
+        let mut storage = self.inner.storage.lock().unwrap();
+
+        storage.read_piece(index).context(StorageFailed)
+    }
+
+    /// Waits until every piece we want has been downloaded.
+    pub async fn wait_complete(&self) {
+        let mut progress = self.inner.progress_rx.clone();
+
+        while !self.is_complete() {
+            if progress.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+
+    /// Starts announcing to the tracker at `announce`, giving each announce
+    /// the configured `tracker_timeout`. Whoever it finds can be sent on to
+    /// [`run`](Self::run).
+    pub fn announce_loop(&self, announce: String, request: AnnounceRequest) -> AnnounceLoop {
+        AnnounceLoop::start_with_timeout(announce, request, self.config.tracker_timeout)
+    }
+
+    /// Connects to `addr` and handshakes with it, after which the connection
+    /// carries on in the background.
+    pub async fn connect(&self, addr: SocketAddr) -> Result<(), SessionError> {
+        let slot = self.reserve_slot()?;
+
+        self.dial(addr, slot).await
+    }
+
+    /// Connects to peers as they come in from `new_peers`, which is where
+    /// trackers and the DHT send whoever they find, and to the ones our peers
+    /// tell us about over PEX. Peers that don't fit
+    /// under the limit are kept for when there's room. This returns once
+    /// `new_peers` closes.
+    pub async fn run(&self, mut new_peers: mpsc::Receiver<SocketAddr>) {
+        let mut pool = PeerPool::new(&self.inner.info);
+        let mut pex = self.inner.pex.subscribe();
+        let mut retry = time::interval(RETRY_INTERVAL);
+
+        loop {
+            tokio::select! {
+                addr = new_peers.recv() => match addr {
+                    Some(addr) => {
+                        pool.add(Some(addr));
+                    }
+                    None => return,
+                },
+                // Missing a few of these isn't worth worrying about, there'll
+                // be more.
+                message = pex.recv() => {
+                    if let Ok(message) = message {
+                        pool.add_pex(&message);
+                    }
+                }
+                _ = retry.tick() => {}
+            }
+
+            while self.num_peers() < self.config.max_peers {
+                let addr = match pool.next_peer() {
+                    Some(addr) => addr,
+                    None => break,
+                };
+                let manager = self.clone();
+
+                // Reserving the slot here rather than in the task keeps us
+                // from going over the limit while they're still connecting.
+                if let Ok(slot) = manager.reserve_slot() {
+                    tokio::spawn(async move {
+                        if let Err(e) = manager.dial(addr, slot).await {
+                            log::debug!("{}", e);
+                        }
+                    });
+                }
+            }
+        }
+    }
+
+    /// Accepts incoming connections for as long as `listener` does, turning
+    /// away any that come in while we're at the limit.
+    pub async fn listen(&self, listener: TcpListener) -> Result<(), SessionError> {
+        loop {
+            let (stream, addr) = listener.accept().await.context(Listen)?;
+            let slot = match self.reserve_slot() {
+                Ok(slot) => slot,
+                Err(_) => {
+                    log::debug!("Turning away {}, we're at the peer limit", addr);
+                    continue;
+                }
+            };
+            let manager = self.clone();
+
+            tokio::spawn(async move {
+                if let Err(e) = manager.start(stream, slot).await {
+                    log::warn!("Couldn't start connection from {}: {}", addr, e);
+                }
+            });
+        }
+    }
+
+    fn reserve_slot(&self) -> Result<Slot, SessionError> {
+        let reserved =
+            self.inner
+                .num_peers
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |num_peers| {
+                    Some(num_peers + 1).filter(|&num_peers| num_peers <= self.config.max_peers)
+                });
+
+        ensure!(
+            reserved.is_ok(),
+            TooManyPeers {
+                max_peers: self.config.max_peers
+            }
+        );
+
+        Ok(Slot(self.inner.num_peers.clone()))
+    }
+
+    async fn dial(&self, addr: SocketAddr, slot: Slot) -> Result<(), SessionError> {
+        let stream = time::timeout(self.config.connect_timeout, TcpStream::connect(addr))
+            .await
+            .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into()))
+            .context(Connect { addr })?;
+
+        self.start(stream, slot).await
+    }
+
+    async fn start(&self, stream: TcpStream, slot: Slot) -> Result<(), SessionError> {
+        let addr = stream.peer_addr().context(Listen)?;
+        let handshake = Handshake {
+            reserved: ReservedBits::default()
+                .with_fast()
+                .with_extension_protocol(),
+            ..Handshake::new(self.inner.info_hash, self.inner.peer_id)
+        };
+        let timeout = self.config.handshake_timeout;
+        let (reader, writer) = time::timeout(timeout, create_tcp_peer_rw_with(stream, handshake))
+            .await
+            .ok()
+            .context(HandshakeTimedOut { addr, timeout })?
+            .context(Peer)?;
+        let reader = reader.with_max_frame_len(max_frame_len(
+            self.inner.info.num_pieces(),
+            self.config.block_len,
+        ));
+        let peer = self.inner.next_peer.fetch_add(1, Ordering::SeqCst);
+        let fast = reader.supports_fast();
+        let extended = reader.supports_extension_protocol();
+        let inner = self.inner.clone();
+        let config = self.config;
+
+        log::info!("Connected to peer {} at {}", peer, addr);
+        let _ = inner.events.send(PeerEvent::Connected { peer, addr });
+
+        tokio::spawn(async move {
+            let connection =
+                run_connection(inner.clone(), config, peer, reader, writer, fast, extended);
+
+            if let Err(e) = connection.await {
+                log::warn!("Lost peer {} at {}: {}", peer, addr, e);
+            }
+
+            drop(slot);
+            let _ = inner.events.send(PeerEvent::Disconnected { peer });
+        });
+
+        Ok(())
+    }
+}
+
+/// Frees up a connection's place under the limit when it's dropped.
+struct Slot(Arc<AtomicUsize>);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+async fn run_connection<S, R, W>(
+    inner: Arc<Inner<S>>,
+    config: SessionConfig,
+    peer: usize,
+    mut reader: R,
+    mut writer: W,
+    fast: bool,
+    extended: bool,
+) -> Result<(), SessionError>
+where
+    S: Storage + Send + 'static,
+    R: PeerReader<Error = TcpPeerError> + Send + 'static,
+    W: PeerWriter<Error = TcpPeerError> + Send + 'static,
+{
+    // Reading isn't safe to cancel halfway through a message, so it gets a
+    // task of its own rather than going in the select below.
+    let (incoming_tx, mut incoming) = mpsc::unbounded_channel();
+    let (outgoing, mut outgoing_rx) = mpsc::unbounded_channel::<PeerMessage>();
+
+    let reader_task = tokio::spawn(async move {
+        loop {
+            let message = reader.read().await.map(PeerMessage::from);
+            let failed = message.is_err();
+
+            if incoming_tx.send(message).is_err() || failed {
+                return;
+            }
+        }
+    });
+    let writer_task = tokio::spawn(async move {
+        loop {
+            let message = tokio::select! {
+                message = outgoing_rx.recv() => match message {
+                    Some(message) => message,
+                    None => break,
+                },
+                _ = time::sleep(KEEP_ALIVE_INTERVAL) => PeerMessage::KeepAlive,
+            };
+
+            writer.write(message.into()).await?;
+            writer.flush().await?;
+        }
+
+        Ok::<_, TcpPeerError>(())
+    });
+
+    let mut completed = inner.completed.subscribe();
+    let mut connection = Connection::new(inner.clone(), config, peer, outgoing, fast, extended);
+
+    let result = async {
+        connection.start()?;
+
+        let mut deadline = Instant::now() + config.peer_timeout;
+
+        loop {
+            let request_deadline = connection.request_deadline();
+
+            tokio::select! {
+                message = incoming.recv() => match message {
+                    Some(message) => {
+                        deadline = Instant::now() + config.peer_timeout;
+                        connection.handle(message.context(Peer)?)?;
+                    }
+                    None => return Ok(()),
+                },
+                index = completed.recv() => match index {
+                    Ok(index) => connection.completed(index)?,
+                    // Whatever we missed is still in our bitfield, but it's
+                    // not worth resending.
+                    Err(broadcast::error::RecvError::Lagged(_)) => {}
+                    Err(broadcast::error::RecvError::Closed) => return Ok(()),
+                },
+                _ = time::sleep_until(request_deadline.unwrap_or(deadline)),
+                    if request_deadline.is_some() => connection.requests_timed_out()?,
+                _ = time::sleep_until(deadline) => {
+                    let _ = inner.events.send(PeerEvent::Timeout { peer });
+
+                    return TimedOut {
+                        timeout: config.peer_timeout,
+                    }
+                    .fail();
+                }
+            }
+        }
+    }
+    .await;
+
+    connection.gone();
+    reader_task.abort();
+    drop(connection);
+
+    // If the writer failed, that's why everything else stopped.
+    match writer_task.await {
+        Ok(Err(e)) => Err(e).context(Peer),
+        _ => result,
+    }
+}
+
+/// One peer's side of things, as far as the protocol goes.
+struct Connection<S> {
+    inner: Arc<Inner<S>>,
+    config: SessionConfig,
+    peer: usize,
+    outgoing: mpsc::UnboundedSender<PeerMessage>,
+    /// Whether they're choking us.
+    peer_choking: bool,
+    /// Whether we're choking them.
+    choking: bool,
+    interested: bool,
+    /// What we've asked for and haven't got yet, and when we asked.
+    requested: BTreeMap<BlockMeta, Instant>,
+    /// Whether we can send and receive the Fast extension's messages.
+    fast: bool,
+    /// Whether we can send and receive extended messages (BEP 10).
+    extended: bool,
+    /// Blocks the peer told us it won't send, or took too long to, which we
+    /// don't ask it for again until it next unchokes us.
+    rejected: BTreeSet<BlockMeta>,
+}
+
+impl<S: Storage + Send + 'static> Connection<S> {
+    fn new(
+        inner: Arc<Inner<S>>,
+        config: SessionConfig,
+        peer: usize,
+        outgoing: mpsc::UnboundedSender<PeerMessage>,
+        fast: bool,
+        extended: bool,
+    ) -> Self {
+        Self {
+            inner,
+            config,
+            peer,
+            outgoing,
+            peer_choking: true,
+            choking: true,
+            interested: false,
+            requested: BTreeMap::new(),
+            fast,
+            extended,
+            rejected: BTreeSet::new(),
+        }
+    }
+
+    fn send(&self, message: PeerMessage) -> Result<(), SessionError> {
+        self.outgoing.send(message).ok().context(ConnectionClosed)
+    }
+
+    /// Registers the peer and sends our bitfield, unless it'd be empty. With
+    /// the Fast extension, that's a have all or have none where it can be.
+    /// Peers that support extended messages are asked for PEX first, unless
+    /// the torrent's private.
+    fn start(&mut self) -> Result<(), SessionError> {
+        let have = {
+            let mut state = self.inner.state.lock().unwrap();
+            state.set_peer_pieces(self.peer, Bitfield::new(self.inner.info.num_pieces()));
+
+            state.have.clone()
+        };
+
+        if self.extended && !self.inner.info.private {
+            self.send(PeerMessage::extended_handshake(&ExtendedHandshake {
+                extensions: vec![(UT_PEX.to_owned(), UT_PEX_ID)].into_iter().collect(),
+                ..ExtendedHandshake::default()
+            }))?;
+        }
+
+        match have.count_set() {
+            0 if self.fast => self.send(PeerMessage::HaveNone),
+            0 => Ok(()),
+            _ if self.fast && have.is_complete() => self.send(PeerMessage::HaveAll),
+            _ => self.send(PeerMessage::Bitfield(have.into_bytes())),
+        }
+    }
+
+    fn handle(&mut self, message: PeerMessage) -> Result<(), SessionError> {
+        match message {
+            PeerMessage::Choke => {
+                self.peer_choking = true;
+
+                // The Fast extension has peers reject each request they
+                // won't answer instead, so the rest might still come.
+                if !self.fast {
+                    self.release_requests();
+                }
+            }
+            PeerMessage::Unchoke => {
+                self.peer_choking = false;
+                self.rejected.clear();
+            }
+            // There's no point holding out on anyone, so whoever's interested
+            // gets unchoked.
+            PeerMessage::Interested if self.choking => {
+                self.choking = false;
+                self.send(PeerMessage::Unchoke)?;
+            }
+            PeerMessage::NotInterested if !self.choking => {
+                self.choking = true;
+                self.send(PeerMessage::Choke)?;
+            }
+            PeerMessage::Have(index) => {
+                ensure!(
+                    (index as usize) < self.inner.info.num_pieces(),
+                    InvalidPieceIndex { index }
+                );
+
+                self.inner
+                    .state
+                    .lock()
+                    .unwrap()
+                    .peer_has(self.peer, index as usize);
+            }
+            PeerMessage::HaveAll | PeerMessage::HaveNone => {
+                let mut bitfield = Bitfield::new(self.inner.info.num_pieces());
+
+                if message == PeerMessage::HaveAll {
+                    (0..bitfield.len()).for_each(|index| bitfield.set(index));
+                }
+
+                self.inner
+                    .state
+                    .lock()
+                    .unwrap()
+                    .set_peer_pieces(self.peer, bitfield);
+            }
+            PeerMessage::Bitfield(bytes) => {
+                let bitfield = Bitfield::from_bytes(bytes, self.inner.info.num_pieces())
+                    .context(InvalidBitfield)?;
+
+                self.inner
+                    .state
+                    .lock()
+                    .unwrap()
+                    .set_peer_pieces(self.peer, bitfield);
+            }
+            PeerMessage::Request {
+                index,
+                begin,
+                length,
+            } => self.serve(BlockMeta {
+                piece_index: index,
+                begin,
+                length,
+            })?,
+            PeerMessage::Piece {
+                index,
+                begin,
+                block,
+            } => self.block_received(Block {
+                meta: BlockMeta {
+                    piece_index: index,
+                    begin,
+                    length: block.len() as u32,
+                },
+                data: block,
+            })?,
+            PeerMessage::RejectRequest {
+                index,
+                begin,
+                length,
+            } => {
+                let meta = BlockMeta {
+                    piece_index: index,
+                    begin,
+                    length,
+                };
+
+                if self.requested.remove(&meta).is_some() {
+                    self.rejected.insert(meta);
+                    self.inner.state.lock().unwrap().reject(self.peer, meta);
+                }
+            }
+            PeerMessage::Extended {
+                id: UT_PEX_ID,
+                payload,
+            } if self.extended => match PexMessage::decode(&payload) {
+                // Nobody might be listening, which is fine.
+                Ok(message) => {
+                    let _ = self.inner.pex.send(message);
+                }
+                // It's only a hint, so it's not worth dropping the peer over.
+                Err(e) => log::debug!("Peer {}: {}", self.peer, e),
+            },
+            // Suggestions and allowed fast pieces are only hints, which we
+            // can do without, and so are the extended messages we didn't ask
+            // for.
+            _ => {}
+        }
+
+        self.update_interest()?;
+        self.fill_requests()
+    }
+
+    /// Tells the peer about a piece we just finished.
+    fn completed(&mut self, index: u32) -> Result<(), SessionError> {
+        self.send(PeerMessage::Have(index))?;
+
+        self.update_interest()
+    }
+
+    fn gone(&mut self) {
+        let mut state = self.inner.state.lock().unwrap();
+
+        if let Some(bitfield) = state.peers.remove(&self.peer) {
+            state.availability.remove_peer(&bitfield);
+        }
+
+        state.release_requests(self.peer);
+    }
+
+    fn release_requests(&mut self) {
+        self.requested.clear();
+        self.inner.state.lock().unwrap().release_requests(self.peer);
+    }
+
+    /// When the oldest request we're still waiting on times out.
+    fn request_deadline(&self) -> Option<Instant> {
+        self.requested
+            .values()
+            .min()
+            .map(|&requested| requested + self.config.request_timeout)
+    }
+
+    /// Gives up on every request that's timed out, the same as if the peer
+    /// had rejected it, so someone else can be asked instead.
+    fn requests_timed_out(&mut self) -> Result<(), SessionError> {
+        let now = Instant::now();
+        let timed_out = self
+            .requested
+            .iter()
+            .filter(|&(_, &requested)| requested + self.config.request_timeout <= now)
+            .map(|(&meta, _)| meta)
+            .collect::<Vec<_>>();
+
+        for meta in timed_out {
+            log::debug!("Peer {} took too long to send {:?}", self.peer, meta);
+
+            self.requested.remove(&meta);
+            self.rejected.insert(meta);
+            self.inner.state.lock().unwrap().reject(self.peer, meta);
+        }
+
+        self.fill_requests()
+    }
+
+    fn update_interest(&mut self) -> Result<(), SessionError> {
+        let interested = {
+            let state = self.inner.state.lock().unwrap();
+
+            state
+                .peers
+                .get(&self.peer)
+                .is_some_and(|bitfield| bitfield.iter_set().any(|index| state.needs(index)))
+        };
+
+        if interested != self.interested {
+            self.interested = interested;
+            self.send(if interested {
+                PeerMessage::Interested
+            } else {
+                PeerMessage::NotInterested
+            })?;
+        }
+
+        Ok(())
+    }
+
+    fn fill_requests(&mut self) -> Result<(), SessionError> {
+        if self.peer_choking || !self.interested || self.requested.len() >= PIPELINE_LEN {
+            return Ok(());
+        }
+
+        let blocks = self.inner.state.lock().unwrap().next_requests(
+            &self.inner.info,
+            self.config.block_len,
+            self.peer,
+            PIPELINE_LEN - self.requested.len(),
+            &self.rejected,
+        );
+
+        let now = Instant::now();
+
+        for block in blocks {
+            self.requested.insert(block, now);
+            self.send(PeerMessage::Request {
+                index: block.piece_index,
+                begin: block.begin,
+                length: block.length,
+            })?;
+        }
+
+        Ok(())
+    }
+
+    /// Sends a block back if we have it and we're not choking them. Anything
+    /// else is dropped, like the protocol allows, or rejected if the Fast
+    /// extension is in use. The block is read on a blocking thread and sent
+    /// whenever it's ready, so that this connection can carry on meanwhile.
+    fn serve(&mut self, meta: BlockMeta) -> Result<(), SessionError> {
+        let index = meta.piece_index as usize;
+        let end = meta.begin as u64 + meta.length as u64;
+        let fits = self
+            .inner
+            .info
+            .iter_pieces()
+            .nth(index)
+            .is_some_and(|piece| end <= piece.length);
+        let reject = PeerMessage::RejectRequest {
+            index: meta.piece_index,
+            begin: meta.begin,
+            length: meta.length,
+        };
+
+        if self.choking
+            || meta.length > MAX_REQUEST_LEN
+            || !fits
+            || !self.inner.state.lock().unwrap().have.has(index)
+        {
+            return if self.fast { self.send(reject) } else { Ok(()) };
+        }
+
+        let inner = self.inner.clone();
+        let outgoing = self.outgoing.clone();
+        let fast = self.fast;
+
+        task::spawn_blocking(move || {
+            let read = inner
+                .storage
+                .lock()
+                .unwrap()
+                .read_block(index, meta.begin, meta.length);
+            let message = match read {
+                Ok(block) => PeerMessage::Piece {
+                    index: meta.piece_index,
+                    begin: meta.begin,
+                    block: block.into(),
+                },
+                Err(e) => {
+                    log::error!("Couldn't read {:?} to send it: {}", meta, e);
+
+                    if !fast {
+                        return;
+                    }
+
+                    reject
+                }
+            };
+
+            // The connection might've closed while we were reading.
+            let _ = outgoing.send(message);
+        });
+
+        Ok(())
+    }
+
+    fn block_received(&mut self, block: Block) -> Result<(), SessionError> {
+        // Anything we didn't ask for, or gave up on, isn't worth keeping.
+        if self.requested.remove(&block.meta).is_none() {
+            return Ok(());
+        }
+
+        let index = block.meta.piece_index;
+        let finished = self
+            .inner
+            .state
+            .lock()
+            .unwrap()
+            .block_received(self.peer, &block);
+
+        if let Some(data) = finished {
+            let inner = self.inner.clone();
+
+            task::spawn_blocking(move || inner.write_piece(index, &data));
+        }
+
+        Ok(())
+    }
+}
+
+impl<S: Storage> Inner<S> {
+    /// Writes a finished piece to the storage, which checks its hash, and
+    /// lets every connection know if it was good. Only the storage is locked
+    /// while it's written, so this is best done on a blocking thread.
+    fn write_piece(&self, index: u32, data: &[u8]) {
+        let written = self
+            .storage
+            .lock()
+            .unwrap()
+            .write_piece(index as usize, data);
+        let num_pieces = {
+            let mut state = self.state.lock().unwrap();
+
+            match state.piece_written(index, written) {
+                Ok(true) => state.have.count_set(),
+                Ok(false) => return,
+                Err(e) => {
+                    log::error!("Couldn't write piece {}, starting it over: {}", index, e);
+                    return;
+                }
+            }
+        };
+
+        log::info!("Piece {} done, {} pieces so far", index, num_pieces);
+
+        let _ = self.completed.send(index);
+        let _ = self.progress.send(num_pieces);
+    }
+}
+
+impl SwarmState {
+    /// Whether we still want the piece at `index`.
+    fn needs(&self, index: usize) -> bool {
+        !self.have.has(index) && self.priorities[index] != FilePriority::Skip
+    }
+
+    /// Replaces everything we know `peer` has, like when it sends a
+    /// bitfield.
+    fn set_peer_pieces(&mut self, peer: usize, bitfield: Bitfield) {
+        self.availability.add_peer(&bitfield);
+
+        if let Some(old) = self.peers.insert(peer, bitfield) {
+            self.availability.remove_peer(&old);
+        }
+    }
+
+    fn peer_has(&mut self, peer: usize, index: usize) {
+        if let Some(bitfield) = self.peers.get_mut(&peer) {
+            // A repeated have isn't another peer with the piece.
+            if !bitfield.has(index) {
+                bitfield.set(index);
+                self.availability.add_have(index);
+            }
+        }
+    }
+
+    /// Picks up to `max` blocks of `block_len` for `peer` to send us, other
+    /// than the ones in `skip`. Pieces that have already been started get
+    /// finished first, then new ones are started by priority and then rarest
+    /// first.
+    fn next_requests(
+        &mut self,
+        info: &TorrentInfo,
+        block_len: u32,
+        peer: usize,
+        max: usize,
+        skip: &BTreeSet<BlockMeta>,
+    ) -> Vec<BlockMeta> {
+        let peer_has = match self.peers.get(&peer) {
+            Some(bitfield) => bitfield.clone(),
+            None => return vec![],
+        };
+        let mut blocks = Vec::new();
+
+        loop {
+            for (&index, download) in self.downloads.iter_mut() {
+                if !peer_has.has(index as usize) {
+                    continue;
+                }
+
+                for (meta, state) in download.blocks.iter_mut() {
+                    if blocks.len() == max {
+                        return blocks;
+                    }
+
+                    if *state == BlockState::Missing && !skip.contains(meta) {
+                        *state = BlockState::Requested(peer);
+                        blocks.push(*meta);
+                    }
+                }
+            }
+
+            let next = peer_has
+                .iter_set()
+                .filter(|&index| self.needs(index) && !self.downloads.contains_key(&(index as u32)))
+                .min_by_key(|&index| {
+                    (
+                        Reverse(self.priorities[index]),
+                        self.availability.count(index),
+                    )
+                });
+
+            match next {
+                Some(index) => {
+                    let length = info
+                        .iter_pieces()
+                        .nth(index)
+                        .map_or(0, |piece| piece.length);
+
+                    self.downloads.insert(
+                        index as u32,
+                        PieceDownload {
+                            data: vec![0; length as usize],
+                            blocks: info
+                                .blocks_for_piece(index, block_len)
+                                .map(|meta| (meta, BlockState::Missing))
+                                .collect(),
+                        },
+                    );
+                }
+                None => return blocks,
+            }
+        }
+    }
+
+    /// Puts `block` in place, returning the piece's data if that finished it,
+    /// for writing to the storage.
+    fn block_received(&mut self, peer: usize, block: &Block) -> Option<Vec<u8>> {
+        let index = block.meta.piece_index;
+        let download = self.downloads.get_mut(&index)?;
+        let state = match download
+            .blocks
+            .iter_mut()
+            .find(|(meta, _)| *meta == block.meta)
+        {
+            Some((_, state)) if *state == BlockState::Requested(peer) => state,
+            _ => return None,
+        };
+
+        *state = BlockState::Received;
+
+        let begin = block.meta.begin as usize;
+        download.data[begin..begin + block.data.len()].copy_from_slice(&block.data);
+
+        if download
+            .blocks
+            .iter()
+            .any(|&(_, state)| state != BlockState::Received)
+        {
+            return None;
+        }
+
+        Some(mem::take(&mut download.data))
+    }
+
+    /// Finishes off a piece once it's been written, returning whether it's
+    /// one we have now. A piece that couldn't be written, or that failed its
+    /// hash check, is started over.
+    fn piece_written(
+        &mut self,
+        index: u32,
+        written: Result<(), StorageError>,
+    ) -> Result<bool, StorageError> {
+        self.downloads.remove(&index);
+
+        match written {
+            Ok(()) => {
+                self.have.set(index as usize);
+
+                Ok(true)
+            }
+            Err(StorageError::HashMismatch { .. }) => {
+                log::warn!("Piece {} failed its hash check, starting it over", index);
+
+                Ok(false)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Lets anyone else have a block `peer` said it won't send us.
+    fn reject(&mut self, peer: usize, block: BlockMeta) {
+        let state = self
+            .downloads
+            .get_mut(&block.piece_index)
+            .and_then(|download| download.blocks.iter_mut().find(|(meta, _)| *meta == block));
+
+        if let Some((_, state)) = state {
+            if *state == BlockState::Requested(peer) {
+                *state = BlockState::Missing;
+            }
+        }
+    }
+
+    /// Lets anyone else have the blocks `peer` was going to send us.
+    fn release_requests(&mut self, peer: usize) {
+        for download in self.downloads.values_mut() {
+            for (_, state) in download.blocks.iter_mut() {
+                if *state == BlockState::Requested(peer) {
+                    *state = BlockState::Missing;
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Snafu)]
+pub enum SessionError {
+    #[snafu(display("Couldn't connect to {}: {}", addr, source))]
+    Connect { addr: SocketAddr, source: io::Error },
+    #[snafu(display("Couldn't accept a connection: {}", source))]
+    Listen { source: io::Error },
+    #[snafu(display("Already connected to the most peers we can, {}", max_peers))]
+    TooManyPeers { max_peers: usize },
+    #[snafu(display("{}", source))]
+    Peer { source: TcpPeerError },
+    #[snafu(display("The connection closed"))]
+    ConnectionClosed,
+    #[snafu(display("{} didn't handshake within {:?}", addr, timeout))]
+    HandshakeTimedOut { addr: SocketAddr, timeout: Duration },
+    #[snafu(display("Peer didn't send anything for {:?}", timeout))]
+    TimedOut { timeout: Duration },
+    #[snafu(display("Peer sent an invalid bitfield: {}", source))]
+    InvalidBitfield { source: BitfieldError },
+    #[snafu(display("Peer has piece {}, which doesn't exist", index))]
+    InvalidPieceIndex { index: u32 },
+    #[snafu(display("{}", source))]
+    StorageFailed { source: StorageError },
+    #[snafu(display("Session config has an invalid {}", field))]
+    InvalidConfig { field: &'static str },
+    #[snafu(display("{}", source))]
+    InvalidFileSelection { source: FileSelectionError },
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        peer::HANDSHAKE_LEN, pex::PexPeer, storage::MemStorage, test_util::info,
+        tracker::TrackerError, types::Message,
+    };
+    use async_trait::async_trait;
+    use std::future;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    const PIECE_LEN: u64 = 32 * 1024;
+
+    fn content() -> Vec<u8> {
+        (0..100_000u32).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn torrent(content: &[u8]) -> Torrent {
+        Torrent::new(
+            "http://tracker.invalid/announce".to_owned(),
+            info(
+                "session",
+                content,
+                PIECE_LEN,
+                &[("session", content.len() as u64)],
+            ),
+        )
+    }
+
+    fn state(pieces: &[&[usize]]) -> SwarmState {
+        let num_pieces = torrent(&content()).info.num_pieces();
+        let mut state = SwarmState {
+            have: Bitfield::new(num_pieces),
+            peers: BTreeMap::new(),
+            availability: Availability::new(num_pieces),
+            downloads: BTreeMap::new(),
+            priorities: vec![FilePriority::Normal; num_pieces],
+        };
+
+        for (peer, pieces) in pieces.iter().enumerate() {
+            let mut bitfield = Bitfield::new(num_pieces);
+            pieces.iter().for_each(|&index| bitfield.set(index));
+
+            state.set_peer_pieces(peer, bitfield);
+        }
+
+        state
+    }
+
+    #[test]
+    fn requests_rarest_first() {
+        let info = torrent(&content()).info;
+        let mut state = state(&[&[0, 1, 2, 3], &[0, 1, 3], &[1]]);
+
+        // Piece 2 only has one peer, and each piece is two blocks.
+        let requests = state.next_requests(&info, BLOCK_LEN, 0, 3, &BTreeSet::new());
+
+        assert_eq!(
+            requests,
+            vec![
+                BlockMeta {
+                    piece_index: 2,
+                    begin: 0,
+                    length: BLOCK_LEN
+                },
+                BlockMeta {
+                    piece_index: 2,
+                    begin: BLOCK_LEN,
+                    length: BLOCK_LEN
+                },
+                BlockMeta {
+                    piece_index: 0,
+                    begin: 0,
+                    length: BLOCK_LEN
+                },
+            ]
+        );
+
+        // Someone else gets the rest of piece 0 first, then the leftovers
+        // from whoever leaves.
+        state.release_requests(0);
+
+        assert_eq!(
+            state
+                .next_requests(&info, BLOCK_LEN, 1, 3, &BTreeSet::new())
+                .iter()
+                .map(|block| (block.piece_index, block.begin))
+                .collect::<Vec<_>>(),
+            vec![(0, 0), (0, BLOCK_LEN), (3, 0)]
+        );
+    }
+
+    #[test]
+    fn follows_file_selection() {
+        let info = torrent(&content()).info;
+        let mut state = state(&[&[0, 1, 2, 3]]);
+
+        // There's only the one file, so going by pieces is enough here.
+        state.priorities[0] = FilePriority::Skip;
+        state.priorities[3] = FilePriority::High;
+
+        let pieces = state
+            .next_requests(&info, BLOCK_LEN, 0, 7, &BTreeSet::new())
+            .iter()
+            .map(|block| block.piece_index)
+            .collect::<Vec<_>>();
+
+        assert_eq!(pieces, vec![3, 1, 1, 2, 2]);
+        assert!(!state.needs(0));
+    }
+
+    #[test]
+    fn bad_piece_starts_over() {
+        let info = torrent(&content()).info;
+        let mut state = state(&[&[0]]);
+        let mut storage = MemStorage::new(info.clone());
+        let blocks = state.next_requests(&info, BLOCK_LEN, 0, 2, &BTreeSet::new());
+        let mut finished = None;
+
+        for meta in blocks {
+            let block = Block {
+                meta,
+                data: vec![0xff; meta.length as usize].into(),
+            };
+
+            finished = state.block_received(0, &block);
+        }
+
+        // Nothing gets started over until it's been written.
+        let data = finished.unwrap();
+
+        assert!(state
+            .next_requests(&info, BLOCK_LEN, 0, 2, &BTreeSet::new())
+            .is_empty());
+        assert!(!state
+            .piece_written(0, storage.write_piece(0, &data))
+            .unwrap());
+        assert!(!state.have.has(0));
+        assert_eq!(
+            state
+                .next_requests(&info, BLOCK_LEN, 0, 2, &BTreeSet::new())
+                .len(),
+            2
+        );
+    }
+
+    #[test]
+    fn rejected_blocks_go_elsewhere() {
+        let info = torrent(&content()).info;
+        let mut state = state(&[&[0], &[0]]);
+        let block = |begin| BlockMeta {
+            piece_index: 0,
+            begin,
+            length: BLOCK_LEN,
+        };
+
+        assert_eq!(
+            state.next_requests(&info, BLOCK_LEN, 0, 2, &BTreeSet::new()),
+            vec![block(0), block(BLOCK_LEN)]
+        );
+
+        // Peer 0 won't be asked for the block it turned down, but peer 1 will.
+        state.reject(0, block(BLOCK_LEN));
+
+        let rejected = vec![block(BLOCK_LEN)].into_iter().collect();
+
+        assert!(state
+            .next_requests(&info, BLOCK_LEN, 0, 2, &rejected)
+            .is_empty());
+        assert_eq!(
+            state.next_requests(&info, BLOCK_LEN, 1, 2, &BTreeSet::new()),
+            vec![block(BLOCK_LEN)]
+        );
+
+        // A reject from a peer that wasn't asked for the block changes nothing.
+        state.reject(1, block(0));
+        state.release_requests(1);
+
+        assert_eq!(
+            state.next_requests(&info, BLOCK_LEN, 1, 2, &BTreeSet::new()),
+            vec![block(BLOCK_LEN)]
+        );
+    }
+
+    /// A peer that never says anything.
+    struct SilentPeer;
+
+    #[async_trait]
+    impl PeerReader for SilentPeer {
+        type Error = TcpPeerError;
+
+        async fn read(&mut self) -> Result<Message, Self::Error> {
+            future::pending().await
+        }
+    }
+
+    /// A peer that says each of its messages and then goes quiet.
+    struct ScriptedPeer(Vec<Message>);
+
+    #[async_trait]
+    impl PeerReader for ScriptedPeer {
+        type Error = TcpPeerError;
+
+        async fn read(&mut self) -> Result<Message, Self::Error> {
+            if self.0.is_empty() {
+                future::pending().await
+            } else {
+                Ok(self.0.remove(0))
+            }
+        }
+    }
+
+    /// Hands over everything that's written to it.
+    struct MockWriter(mpsc::UnboundedSender<Message>);
+
+    #[async_trait]
+    impl PeerWriter for MockWriter {
+        type Error = TcpPeerError;
+
+        async fn write(&mut self, message: Message) -> Result<(), Self::Error> {
+            let _ = self.0.send(message);
+
+            Ok(())
+        }
+
+        async fn flush(&mut self) -> Result<(), Self::Error> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn keep_alive_and_timeout() {
+        time::pause();
+
+        let torrent = torrent(&content());
+        let manager = PeerManager::new(
+            &torrent,
+            MemStorage::new(torrent.info.clone()),
+            *b"-MS0010-silentsilent",
+        )
+        .unwrap();
+        let mut events = manager.events();
+        let (sent, mut sent_rx) = mpsc::unbounded_channel();
+        let start = Instant::now();
+
+        let connection = tokio::spawn(run_connection(
+            manager.inner.clone(),
+            SessionConfig::default(),
+            0,
+            SilentPeer,
+            MockWriter(sent),
+            false,
+            false,
+        ));
+
+        // We have nothing to say, so the first thing sent is a keep-alive.
+        assert_eq!(sent_rx.recv().await, Some(Message::KeepAlive));
+
+        // The clock skips ahead to whichever timer is next, which can be a
+        // little past the keep-alive by the time we get to look at it.
+        let elapsed = Instant::now() - start;
+
+        assert!(elapsed >= KEEP_ALIVE_INTERVAL && elapsed < PEER_TIMEOUT);
+
+        assert_eq!(events.recv().await.unwrap(), PeerEvent::Timeout { peer: 0 });
+        assert!(Instant::now() - start >= PEER_TIMEOUT);
+        assert!(matches!(
+            connection.await.unwrap(),
+            Err(SessionError::TimedOut { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn slow_requests_go_elsewhere() {
+        time::pause();
+
+        let torrent = torrent(&content());
+        let manager = PeerManager::new(
+            &torrent,
+            MemStorage::new(torrent.info.clone()),
+            *b"-MS0010-slowslowslow",
+        )
+        .unwrap();
+        let config = SessionConfig {
+            request_timeout: Duration::from_secs(30),
+            ..SessionConfig::default()
+        };
+        let (sent, mut sent_rx) = mpsc::unbounded_channel();
+        let start = Instant::now();
+
+        tokio::spawn(run_connection(
+            manager.inner.clone(),
+            config,
+            0,
+            ScriptedPeer(vec![Message::HaveAll, Message::Unchoke]),
+            MockWriter(sent),
+            true,
+            false,
+        ));
+
+        let mut requests = vec![];
+
+        while requests.len() < 7 {
+            if let Some(Message::Request(meta)) = sent_rx.recv().await {
+                requests.push((meta, Instant::now() - start));
+            }
+        }
+
+        // The pipeline's full until the first five time out, then the last
+        // two blocks get asked for and nothing's asked for twice.
+        assert!(requests[..5]
+            .iter()
+            .all(|&(_, elapsed)| elapsed < config.request_timeout));
+        assert!(requests[5..]
+            .iter()
+            .all(|&(_, elapsed)| elapsed >= config.request_timeout));
+        assert_eq!(
+            requests
+                .iter()
+                .map(|(meta, _)| meta)
+                .collect::<BTreeSet<_>>()
+                .len(),
+            7
+        );
+    }
+
+    #[test]
+    fn invalid_config() {
+        let torrent = torrent(&content());
+        let manager = || {
+            PeerManager::new(
+                &torrent,
+                MemStorage::new(torrent.info.clone()),
+                *b"-MS0010-configconfig",
+            )
+            .unwrap()
+        };
+        let with = |config| manager().with_config(config).err();
+
+        assert!(with(SessionConfig::default()).is_none());
+        assert!(matches!(
+            with(SessionConfig {
+                block_len: 0,
+                ..SessionConfig::default()
+            }),
+            Some(SessionError::InvalidConfig { field: "block_len" })
+        ));
+        assert!(matches!(
+            with(SessionConfig {
+                block_len: MAX_REQUEST_LEN + 1,
+                ..SessionConfig::default()
+            }),
+            Some(SessionError::InvalidConfig { field: "block_len" })
+        ));
+        assert!(matches!(
+            with(SessionConfig {
+                request_timeout: Duration::ZERO,
+                ..SessionConfig::default()
+            }),
+            Some(SessionError::InvalidConfig {
+                field: "request_timeout"
+            })
+        ));
+    }
+
+    #[test]
+    fn mismatched_file_selection() {
+        let torrent = torrent(&content());
+        let manager = || {
+            PeerManager::new(
+                &torrent,
+                MemStorage::new(torrent.info.clone()),
+                *b"-MS0010-selectselect",
+            )
+            .unwrap()
+        };
+        let other = info("other", b"abcd", 2, &[("a", 2), ("b", 2)]);
+
+        assert!(manager()
+            .with_file_selection(&FileSelection::new(&torrent.info))
+            .is_ok());
+        assert!(matches!(
+            manager().with_file_selection(&FileSelection::new(&other)),
+            Err(SessionError::InvalidFileSelection {
+                source: FileSelectionError::MismatchedTorrent {
+                    selected: 2,
+                    files: 1
+                }
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn announces_with_tracker_timeout() {
+        let torrent = torrent(&content());
+        let timeout = Duration::from_millis(100);
+        let manager = PeerManager::new(
+            &torrent,
+            MemStorage::new(torrent.info.clone()),
+            *b"-MS0010-trackertrack",
+        )
+        .unwrap()
+        .with_config(SessionConfig {
+            tracker_timeout: timeout,
+            ..SessionConfig::default()
+        })
+        .unwrap();
+
+        // Accepts the connection, but never answers.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mute = tokio::spawn(async move { listener.accept().await });
+
+        let mut announce_loop = manager.announce_loop(
+            format!("http://{}/announce", addr),
+            AnnounceRequest::new(torrent.info_hash(), "-MS0010-trackertrack".to_owned(), 6881),
+        );
+        let result = time::timeout(Duration::from_secs(5), announce_loop.next())
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert!(matches!(
+            result,
+            Err(TrackerError::TimedOut { timeout: t }) if t == timeout
+        ));
+
+        drop(mute);
+    }
+
+    #[tokio::test]
+    async fn slow_handshake_gets_dropped() {
+        let torrent = torrent(&content());
+        let manager = PeerManager::new(
+            &torrent,
+            MemStorage::new(torrent.info.clone()),
+            *b"-MS0010-patientpatie",
+        )
+        .unwrap()
+        .with_config(SessionConfig {
+            handshake_timeout: Duration::from_millis(100),
+            ..SessionConfig::default()
+        })
+        .unwrap();
+
+        // Accepts the connection and keeps it open, but never says anything.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mute = tokio::spawn(async move { listener.accept().await });
+
+        let result = time::timeout(Duration::from_secs(5), manager.connect(addr))
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            result,
+            Err(SessionError::HandshakeTimedOut { timeout, .. })
+                if timeout == Duration::from_millis(100)
+        ));
+        assert_eq!(manager.num_peers(), 0);
+
+        drop(mute);
+    }
+
+    #[tokio::test]
+    async fn dials_peers_from_pex() {
+        let torrent = torrent(&content());
+        let manager = PeerManager::new(
+            &torrent,
+            MemStorage::new(torrent.info.clone()),
+            *b"-MS0010-gossipgossip",
+        )
+        .unwrap();
+
+        // Nobody hears about this one except through the gossip.
+        let other = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let other_addr = other.local_addr().unwrap();
+
+        let gossip = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let gossip_addr = gossip.local_addr().unwrap();
+        let info_hash = torrent.info_hash();
+        let gossiping = tokio::spawn(async move {
+            let (mut stream, _) = gossip.accept().await.unwrap();
+            let mut handshake = vec![0; HANDSHAKE_LEN];
+            stream.read_exact(&mut handshake).await.unwrap();
+
+            let reply = Handshake {
+                reserved: ReservedBits::default().with_extension_protocol(),
+                ..Handshake::new(info_hash, *b"-MS0010-gossipgossip")
+            };
+            let pex = PexMessage {
+                added: vec![PexPeer {
+                    addr: other_addr,
+                    flags: Default::default(),
+                }],
+                dropped: vec![],
+            };
+
+            stream.write_all(&reply.encode()).await.unwrap();
+            stream
+                .write_all(
+                    &PeerMessage::Extended {
+                        id: UT_PEX_ID,
+                        payload: pex.encode().into(),
+                    }
+                    .encode(),
+                )
+                .await
+                .unwrap();
+
+            // Hangs on to the connection until the test's done with it.
+            stream
+        });
+
+        let (new_peers, new_peers_rx) = mpsc::channel(1);
+        tokio::spawn({
+            let manager = manager.clone();
+            async move { manager.run(new_peers_rx).await }
+        });
+        new_peers.send(gossip_addr).await.unwrap();
+
+        let dialed = time::timeout(Duration::from_secs(5), other.accept()).await;
+
+        assert!(dialed.is_ok());
+        drop(gossiping);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn trades_torrent_end_to_end() {
+        let content = content();
+        let torrent = torrent(&content);
+
+        let mut seed_storage = MemStorage::new(torrent.info.clone());
+        for (index, piece) in content.chunks(PIECE_LEN as usize).enumerate() {
+            seed_storage.write_piece(index, piece).unwrap();
+        }
+
+        let seeder = PeerManager::new(&torrent, seed_storage, *b"-MS0010-seederseeder").unwrap();
+        let direct = PeerManager::new(
+            &torrent,
+            MemStorage::new(torrent.info.clone()),
+            *b"-MS0010-directdirect",
+        )
+        .unwrap();
+        let pooled = PeerManager::new(
+            &torrent,
+            MemStorage::new(torrent.info.clone()),
+            *b"-MS0010-pooledpooled",
+        )
+        .unwrap();
+
+        assert!(seeder.is_complete());
+        assert!(!direct.is_complete());
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn({
+            let seeder = seeder.clone();
+            async move { seeder.listen(listener).await }
+        });
+
+        // One connects straight away, the other hears about the seeder the
+        // way it would from a tracker.
+        direct.connect(addr).await.unwrap();
+
+        let (new_peers, new_peers_rx) = mpsc::channel(1);
+        tokio::spawn({
+            let pooled = pooled.clone();
+            async move { pooled.run(new_peers_rx).await }
+        });
+        new_peers.send(addr).await.unwrap();
+
+        let downloaded = time::timeout(Duration::from_secs(10), async {
+            direct.wait_complete().await;
+            pooled.wait_complete().await;
+        })
+        .await;
+
+        assert!(downloaded.is_ok());
+
+        for leecher in [&direct, &pooled] {
+            let data = (0..torrent.info.num_pieces())
+                .map(|index| leecher.read_piece(index).unwrap())
+                .collect::<Vec<_>>();
+
+            assert_eq!(data.concat(), content);
+            assert_eq!(leecher.num_peers(), 1);
+        }
+
+        assert_eq!(seeder.num_peers(), 2);
+        assert!(matches!(
+            direct.with_max_peers(1).connect(addr).await,
+            Err(SessionError::TooManyPeers { max_peers: 1 })
+        ));
+    }
+}
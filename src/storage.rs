@@ -0,0 +1,486 @@
+use super::{bitfield::Bitfield, resume::file_paths, torrent_parser::TorrentInfo};
+use snafu::{ensure, ResultExt, Snafu};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+/// Somewhere to keep a torrent's pieces while it's downloading.
+///
+/// Pieces are only ever read or written whole, so a piece's data is always
+/// exactly `piece_len` bytes long, or whatever's left for the last piece.
+/// `write_piece` has to reject anything that isn't, along with any piece
+/// that doesn't match its hash, so only verified data is ever stored.
+/// Reading a piece that was never written gives back zeroes rather than an
+/// error, just like reading a sparse file would.
+pub trait Storage {
+    fn info(&self) -> &TorrentInfo;
+
+    fn read_piece(&mut self, index: usize) -> Result<Vec<u8>, StorageError>;
+
+    /// Reads `length` bytes of the piece at `index` starting at `begin`, for
+    /// serving a request. This reads the whole piece unless the storage can
+    /// do better.
+    fn read_block(
+        &mut self,
+        index: usize,
+        begin: u32,
+        length: u32,
+    ) -> Result<Vec<u8>, StorageError> {
+        let piece = self.read_piece(index)?;
+
+        piece
+            .get(begin as usize..begin as usize + length as usize)
+            .map(<[u8]>::to_vec)
+            .ok_or(StorageError::InvalidBlock {
+                index,
+                begin,
+                length,
+            })
+    }
+
+    fn write_piece(&mut self, index: usize, data: &[u8]) -> Result<(), StorageError>;
+
+    /// Whether the piece at `index` has been stored, going by its hash.
+    fn verify(&mut self, index: usize) -> Result<bool, StorageError> {
+        let data = self.read_piece(index)?;
+
+        Ok(self.info().verify_piece(index, &data))
+    }
+}
+
+/// Checks every piece in `storage`, returning which of them are there.
+pub fn verified_pieces(storage: &mut dyn Storage) -> Result<Bitfield, StorageError> {
+    let mut bitfield = Bitfield::new(storage.info().num_pieces());
+
+    for index in 0..bitfield.len() {
+        if storage.verify(index)? {
+            bitfield.set(index);
+        }
+    }
+
+    Ok(bitfield)
+}
+
+/// What every [`Storage`] has to check before writing a piece.
+fn check_piece(info: &TorrentInfo, index: usize, data: &[u8]) -> Result<(), StorageError> {
+    ensure!(
+        info.verify_piece_length(index, data.len() as u64),
+        InvalidPiece { index }
+    );
+    ensure!(info.verify_piece(index, data), HashMismatch { index });
+
+    Ok(())
+}
+
+/// Where a block starts in the torrent's content, as long as it's all within
+/// its piece.
+fn block_offset(
+    info: &TorrentInfo,
+    index: usize,
+    begin: u32,
+    length: u32,
+) -> Result<u64, StorageError> {
+    let piece = info
+        .iter_pieces()
+        .nth(index)
+        .ok_or(StorageError::InvalidPiece { index })?;
+
+    ensure!(
+        begin as u64 + length as u64 <= piece.length,
+        InvalidBlock {
+            index,
+            begin,
+            length
+        }
+    );
+
+    Ok(piece.offset + begin as u64)
+}
+
+/// Writes pieces to the torrent's files under a base directory, laid out the
+/// same way [`file_paths`] has them. Files are only opened once a piece
+/// needs them, but are made their full length straight away, which most
+/// filesystems do sparsely.
+#[derive(Debug)]
+pub struct FileStorage {
+    info: TorrentInfo,
+    paths: Vec<PathBuf>,
+    files: Vec<Option<File>>,
+}
+
+impl FileStorage {
+    /// Creates every directory the torrent needs. Empty files are created
+    /// too, since no piece will ever get them created otherwise.
+    pub fn new(info: TorrentInfo, base_dir: &Path) -> Result<Self, StorageError> {
+        let paths = file_paths(&info, base_dir);
+
+        for (path, file) in paths.iter().zip(&info.files) {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent).context(Io { path: parent })?;
+            }
+
+            if file.length == 0 {
+                File::create(path).context(Io { path })?;
+            }
+        }
+
+        Ok(Self {
+            files: info.files.iter().map(|_| None).collect(),
+            info,
+            paths,
+        })
+    }
+
+    /// Makes sure everything that's been written is actually on disk.
+    pub fn flush(&mut self) -> Result<(), StorageError> {
+        for (file, path) in self.files.iter_mut().zip(&self.paths) {
+            if let Some(file) = file {
+                file.sync_data().context(Io { path })?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn file(&mut self, index: usize) -> Result<(&mut File, &Path), StorageError> {
+        let path = &self.paths[index];
+
+        if self.files[index].is_none() {
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(path)
+                .context(Io { path })?;
+
+            // Anything that's already there is kept, like for resuming.
+            let length = self.info.files[index].length;
+
+            if file.metadata().context(Io { path })?.len() != length {
+                file.set_len(length).context(Io { path })?;
+            }
+
+            self.files[index] = Some(file);
+        }
+
+        Ok((self.files[index].as_mut().unwrap(), path))
+    }
+
+    /// Fills `data` from the torrent's content at `offset`, from however many
+    /// files it spans.
+    fn read_at(&mut self, offset: u64, data: &mut [u8]) -> Result<(), StorageError> {
+        let mut read = 0;
+
+        for span in self.info.locate(offset, data.len() as u64) {
+            let part = &mut data[read..read + span.len as usize];
+            let (file, path) = self.file(span.file)?;
+
+            file.seek(SeekFrom::Start(span.offset))
+                .and_then(|_| file.read_exact(part))
+                .context(Io { path })?;
+
+            read += part.len();
+        }
+
+        Ok(())
+    }
+}
+
+impl Storage for FileStorage {
+    fn info(&self) -> &TorrentInfo {
+        &self.info
+    }
+
+    /// Once it's been checked, the piece is written to each of the files it
+    /// spans.
+    fn write_piece(&mut self, index: usize, data: &[u8]) -> Result<(), StorageError> {
+        check_piece(&self.info, index, data)?;
+
+        let offset = index as u64 * self.info.piece_len;
+        let mut written = 0;
+
+        for span in self.info.locate(offset, data.len() as u64) {
+            let part = &data[written..written + span.len as usize];
+            let (file, path) = self.file(span.file)?;
+
+            file.seek(SeekFrom::Start(span.offset))
+                .and_then(|_| file.write_all(part))
+                .context(Io { path })?;
+
+            written += part.len();
+        }
+
+        Ok(())
+    }
+
+    fn read_piece(&mut self, index: usize) -> Result<Vec<u8>, StorageError> {
+        let piece = self
+            .info
+            .iter_pieces()
+            .nth(index)
+            .ok_or(StorageError::InvalidPiece { index })?;
+
+        let mut data = vec![0; piece.length as usize];
+        self.read_at(piece.offset, &mut data)?;
+
+        Ok(data)
+    }
+
+    fn read_block(
+        &mut self,
+        index: usize,
+        begin: u32,
+        length: u32,
+    ) -> Result<Vec<u8>, StorageError> {
+        let offset = block_offset(&self.info, index, begin, length)?;
+
+        let mut data = vec![0; length as usize];
+        self.read_at(offset, &mut data)?;
+
+        Ok(data)
+    }
+}
+
+/// Keeps all of the torrent's content in memory, for tests and for anything
+/// that doesn't need it on disk.
+#[derive(Clone, Debug)]
+pub struct MemStorage {
+    info: TorrentInfo,
+    data: Vec<u8>,
+}
+
+impl MemStorage {
+    pub fn new(info: TorrentInfo) -> Self {
+        Self {
+            data: vec![0; info.total_length() as usize],
+            info,
+        }
+    }
+
+    /// The torrent's content as a whole, with zeroes where pieces are missing.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+impl Storage for MemStorage {
+    fn info(&self) -> &TorrentInfo {
+        &self.info
+    }
+
+    fn read_piece(&mut self, index: usize) -> Result<Vec<u8>, StorageError> {
+        let piece = self
+            .info
+            .iter_pieces()
+            .nth(index)
+            .ok_or(StorageError::InvalidPiece { index })?;
+        let start = piece.offset as usize;
+
+        Ok(self.data[start..start + piece.length as usize].to_vec())
+    }
+
+    fn read_block(
+        &mut self,
+        index: usize,
+        begin: u32,
+        length: u32,
+    ) -> Result<Vec<u8>, StorageError> {
+        let start = block_offset(&self.info, index, begin, length)? as usize;
+
+        Ok(self.data[start..start + length as usize].to_vec())
+    }
+
+    fn write_piece(&mut self, index: usize, data: &[u8]) -> Result<(), StorageError> {
+        check_piece(&self.info, index, data)?;
+
+        let start = index * self.info.piece_len as usize;
+        self.data[start..start + data.len()].copy_from_slice(data);
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Snafu)]
+pub enum StorageError {
+    #[snafu(display("Couldn't access {}: {}", path.display(), source))]
+    Io { path: PathBuf, source: io::Error },
+    #[snafu(display("Piece {} doesn't exist or has the wrong length", index))]
+    InvalidPiece { index: usize },
+    #[snafu(display("Piece {} doesn't match its hash", index))]
+    HashMismatch { index: usize },
+    #[snafu(display("Block of {} bytes at {} isn't within piece {}", length, begin, index))]
+    InvalidBlock {
+        index: usize,
+        begin: u32,
+        length: u32,
+    },
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util;
+
+    const CONTENT: &[u8] = b"abcdefghijklmn";
+
+    fn info() -> TorrentInfo {
+        test_util::info(
+            "storage",
+            CONTENT,
+            4,
+            &[("a", 6), ("empty", 0), ("dir/b", 5), ("dir/c", 3)],
+        )
+    }
+
+    fn base_dir(name: &str) -> PathBuf {
+        test_util::base_dir("storage", name)
+    }
+
+    #[test]
+    fn write_and_read_back() {
+        let base_dir = base_dir("write");
+        let mut storage = FileStorage::new(info(), &base_dir).unwrap();
+
+        for (index, piece) in CONTENT.chunks(4).enumerate().rev() {
+            storage.write_piece(index, piece).unwrap();
+        }
+        storage.flush().unwrap();
+
+        let root = base_dir.join("storage");
+        let written = [
+            fs::read(root.join("a")).unwrap(),
+            fs::read(root.join("empty")).unwrap(),
+            fs::read(root.join("dir/b")).unwrap(),
+            fs::read(root.join("dir/c")).unwrap(),
+        ];
+        let read_back = (0..4)
+            .map(|index| storage.read_piece(index).unwrap())
+            .collect::<Vec<_>>();
+
+        fs::remove_dir_all(&base_dir).unwrap();
+
+        assert_eq!(written.concat(), CONTENT);
+        assert_eq!(read_back.concat(), CONTENT);
+    }
+
+    #[test]
+    fn read_blocks() {
+        let base_dir = base_dir("blocks");
+        let mut storage = FileStorage::new(info(), &base_dir).unwrap();
+        let mut mem_storage = MemStorage::new(info());
+
+        for (index, piece) in CONTENT.chunks(4).enumerate() {
+            storage.write_piece(index, piece).unwrap();
+            mem_storage.write_piece(index, piece).unwrap();
+        }
+
+        let storages: Vec<&mut dyn Storage> = vec![&mut storage, &mut mem_storage];
+        let results = storages
+            .into_iter()
+            .map(|storage| {
+                (
+                    // This one spans a and dir/b.
+                    storage.read_block(1, 1, 3).unwrap(),
+                    storage.read_block(3, 0, 2).unwrap(),
+                    storage.read_block(3, 1, 2),
+                    storage.read_block(4, 0, 1),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        fs::remove_dir_all(&base_dir).unwrap();
+
+        for (spanning, last, past_end, missing) in results {
+            assert_eq!(spanning, b"fgh");
+            assert_eq!(last, b"mn");
+            assert!(matches!(
+                past_end,
+                Err(StorageError::InvalidBlock {
+                    index: 3,
+                    begin: 1,
+                    length: 2
+                })
+            ));
+            assert!(matches!(
+                missing,
+                Err(StorageError::InvalidPiece { index: 4 })
+            ));
+        }
+    }
+
+    #[test]
+    fn preallocates_files() {
+        let base_dir = base_dir("preallocate");
+        let mut storage = FileStorage::new(info(), &base_dir).unwrap();
+
+        // Piece 1 spans a and dir/b, skipping over the empty file.
+        storage.write_piece(1, b"efgh").unwrap();
+
+        let root = base_dir.join("storage");
+        let lengths = ["a", "empty", "dir/b"]
+            .iter()
+            .map(|path| fs::metadata(root.join(path)).unwrap().len())
+            .collect::<Vec<_>>();
+        let unwritten = storage.read_piece(0).unwrap();
+        let untouched = root.join("dir/c").exists();
+
+        fs::remove_dir_all(&base_dir).unwrap();
+
+        assert_eq!(lengths, vec![6, 0, 5]);
+        assert_eq!(unwritten, vec![0; 4]);
+        assert!(!untouched);
+    }
+
+    #[test]
+    fn mem_storage() {
+        let mut storage = MemStorage::new(info());
+
+        storage.write_piece(2, b"ijkl").unwrap();
+
+        assert_eq!(storage.read_piece(2).unwrap(), b"ijkl");
+        assert_eq!(storage.read_piece(3).unwrap(), vec![0; 2]);
+        assert!(storage.verify(2).unwrap());
+        assert!(!storage.verify(0).unwrap());
+        assert!(matches!(
+            storage.write_piece(1, b"efgX"),
+            Err(StorageError::HashMismatch { index: 1 })
+        ));
+        assert_eq!(
+            verified_pieces(&mut storage)
+                .unwrap()
+                .iter_set()
+                .collect::<Vec<_>>(),
+            vec![2]
+        );
+    }
+
+    #[test]
+    fn rejects_bad_pieces() {
+        let base_dir = base_dir("reject");
+        let mut storage = FileStorage::new(info(), &base_dir).unwrap();
+
+        let results = (
+            storage.write_piece(0, b"abcX"),
+            storage.write_piece(3, b"mnop"),
+            storage.write_piece(4, b"qrst"),
+        );
+
+        fs::remove_dir_all(&base_dir).unwrap();
+
+        assert!(matches!(
+            results.0,
+            Err(StorageError::HashMismatch { index: 0 })
+        ));
+        assert!(matches!(
+            results.1,
+            Err(StorageError::InvalidPiece { index: 3 })
+        ));
+        assert!(matches!(
+            results.2,
+            Err(StorageError::InvalidPiece { index: 4 })
+        ));
+    }
+}
@@ -1,18 +1,46 @@
-use super::types::{Block, BlockMeta, Message, PeerReader, PeerWriter};
+use super::{
+    peer::{Handshake, HandshakeError, PeerMessage, PeerMessageError, HANDSHAKE_LEN},
+    rate::RateLimiter,
+    torrent_parser::SHA1Hash,
+    types::{Message, PeerReader, PeerWriter},
+};
 use async_trait::async_trait;
-use bitvec::prelude::*;
-use bytes::BytesMut;
-use snafu::{ensure, Snafu};
-use std::io;
+use snafu::{ensure, ResultExt, Snafu};
+use std::{cmp, io, sync::Arc};
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt, BufReader, BufWriter},
     net::{tcp, TcpStream},
 };
 
+/// The longest message we'll read unless told otherwise: a piece message
+/// carrying a 16 KiB block, with some slack for extension messages.
+pub const DEFAULT_MAX_FRAME_LEN: u32 = 9 + 16 * 1024 + FRAME_SLACK;
+
+const FRAME_SLACK: u32 = 1024;
+
+/// The longest message a peer should need to send us for a torrent with
+/// `num_pieces`, when we ask for blocks of `block_len`: whichever's longer of
+/// a piece message and a full bitfield.
+pub fn max_frame_len(num_pieces: usize, block_len: u32) -> u32 {
+    let bitfield_len = 1 + num_pieces.div_ceil(8) as u32;
+
+    cmp::max(9 + block_len, bitfield_len) + FRAME_SLACK
+}
+
 pub async fn create_tcp_peer_rw(
     tcp_stream: TcpStream,
-    info_hash: &[u8],
-    peer_id: &[u8],
+    info_hash: SHA1Hash,
+    peer_id: [u8; 20],
+) -> Result<(TcpPeerReader, TcpPeerWriter), TcpPeerError> {
+    create_tcp_peer_rw_with(tcp_stream, Handshake::new(info_hash, peer_id)).await
+}
+
+/// Like [`create_tcp_peer_rw`], but sends `handshake` as it is, so that the
+/// extensions in its reserved bits get negotiated. Messages for an extension
+/// that only one side supports are rejected both ways.
+pub async fn create_tcp_peer_rw_with(
+    tcp_stream: TcpStream,
+    handshake: Handshake,
 ) -> Result<(TcpPeerReader, TcpPeerWriter), TcpPeerError> {
     log::debug!(
         "Opened TcpPeerCommunicator for address: {}",
@@ -23,25 +51,13 @@ pub async fn create_tcp_peer_rw(
     let (mut reader, mut writer) = (BufReader::new(reader), BufWriter::new(writer));
 
     // Send handshake
-    writer
-        .write_all(
-            &[
-                // 0x13 is 19 in decimal, which is the pstrlen. The next eight bytes
-                // after the pstr itself are 0s, which are obviously 0x00. These are
-                // the reserved bytes.
-                b"\x13BitTorrent protocol\x00\x00\x00\x00\x00\x00\x00\x00",
-                info_hash,
-                peer_id,
-            ]
-            .concat(),
-        )
-        .await?;
+    writer.write_all(&handshake.encode()).await?;
 
     writer.flush().await?;
 
     log::debug!("Sent handshake to peer");
 
-    let mut buf = vec![0; 68];
+    let mut buf = vec![0; HANDSHAKE_LEN];
 
     // Recieve handshake
     reader.read_exact(&mut buf).await?;
@@ -51,13 +67,59 @@ pub async fn create_tcp_peer_rw(
         String::from_utf8_lossy(&buf)
     );
 
-    ensure!(&buf[28..48] == info_hash, HandshakeInfoHash);
-
-    Ok((TcpPeerReader { reader }, TcpPeerWriter { writer }))
+    let reply = Handshake::decode(&buf, &handshake.info_hash).context(InvalidHandshake)?;
+    let fast = handshake.reserved.supports_fast() && reply.reserved.supports_fast();
+    let extended = handshake.reserved.supports_extension_protocol()
+        && reply.reserved.supports_extension_protocol();
+
+    Ok((
+        TcpPeerReader {
+            reader,
+            limiter: None,
+            max_frame_len: DEFAULT_MAX_FRAME_LEN,
+            fast,
+            extended,
+        },
+        TcpPeerWriter {
+            writer,
+            limiter: None,
+            fast,
+        },
+    ))
 }
 
 pub struct TcpPeerReader {
     reader: BufReader<tcp::OwnedReadHalf>,
+    limiter: Option<Arc<RateLimiter>>,
+    max_frame_len: u32,
+    fast: bool,
+    extended: bool,
+}
+
+impl TcpPeerReader {
+    /// Holds off on reading each message until `limiter` lets it through.
+    pub fn with_rate_limiter(mut self, limiter: Arc<RateLimiter>) -> Self {
+        self.limiter = Some(limiter);
+        self
+    }
+
+    /// Rejects messages longer than `len`, without reading them, instead of
+    /// [`DEFAULT_MAX_FRAME_LEN`].
+    pub fn with_max_frame_len(mut self, len: u32) -> Self {
+        self.max_frame_len = len;
+        self
+    }
+
+    /// Whether both sides agreed to use the Fast extension.
+    pub fn supports_fast(&self) -> bool {
+        self.fast
+    }
+
+    /// Whether both sides support the extension protocol (BEP 10), which
+    /// extensions like ut_pex are sent over.
+    pub fn supports_extension_protocol(&self) -> bool {
+        self.extended
+    }
 }
 
 #[async_trait]
@@ -65,80 +127,52 @@ impl PeerReader for TcpPeerReader {
     type Error = TcpPeerError;
 
     async fn read(&mut self) -> Result<Message, Self::Error> {
-        use Message::*;
-
         let len = self.reader.read_u32().await?;
 
-        if len == 0 {
-            return Ok(KeepAlive);
-        }
-
-        let id = self.reader.read_u8().await?;
-
-        Ok(match id {
-            0 => Choke,
-            1 => Unchoke,
-            2 => Interested,
-            3 => NotInterested,
-            4 => {
-                ensure!(len == 5, InvalidMessageLen { len, id });
-
-                Have(self.reader.read_u32().await?)
+        // The length comes straight from the peer, so it can't be trusted
+        // with an allocation, or with the limiter, where a bogus one would
+        // hold up every other peer sharing it.
+        ensure!(
+            len <= self.max_frame_len,
+            FrameTooLong {
+                len,
+                max: self.max_frame_len
             }
-            5 => {
-                let mut bitfield = vec![0; (len - 1) as usize];
+        );
 
-                self.reader.read_exact(&mut bitfield).await?;
-
-                BitField(BitVec::from_vec(bitfield))
-            }
-            6 | 8 => {
-                ensure!(len == 13, InvalidMessageLen { len, id });
-
-                let piece_index = self.reader.read_u32().await?;
-                let begin = self.reader.read_u32().await?;
-                let length = self.reader.read_u32().await?;
-
-                let block_meta = BlockMeta {
-                    piece_index,
-                    begin,
-                    length,
-                };
-
-                if id == 6 {
-                    Request(block_meta)
-                } else {
-                    Cancel(block_meta)
-                }
-            }
-            7 => {
-                ensure!(len >= 9, InvalidMessageLen { len, id });
+        if let Some(limiter) = &self.limiter {
+            limiter.acquire(4 + len as usize).await;
+        }
 
-                let piece_index = self.reader.read_u32().await?;
-                let begin = self.reader.read_u32().await?;
+        let mut frame = vec![0; 4 + len as usize];
+        frame[..4].copy_from_slice(&len.to_be_bytes());
 
-                let mut data = BytesMut::new();
+        self.reader.read_exact(&mut frame[4..]).await?;
 
-                data.resize(len as usize - 9, Default::default());
+        let (message, _) = PeerMessage::decode(&frame).context(InvalidMessage)?;
 
-                self.reader.read_exact(&mut data).await?;
+        ensure!(self.fast || !message.is_fast(), FastNotNegotiated);
 
-                Piece(Block {
-                    meta: BlockMeta {
-                        piece_index,
-                        begin,
-                        length: data.len() as u32,
-                    },
-                    data: data.freeze(),
-                })
-            }
-            id => InvalidMessage { id }.fail()?,
-        })
+        Ok(message.into())
     }
 }
 
 pub struct TcpPeerWriter {
     writer: BufWriter<tcp::OwnedWriteHalf>,
+    limiter: Option<Arc<RateLimiter>>,
+    fast: bool,
+}
+
+impl TcpPeerWriter {
+    /// Holds off on writing each message until `limiter` lets it through.
+    pub fn with_rate_limiter(mut self, limiter: Arc<RateLimiter>) -> Self {
+        self.limiter = Some(limiter);
+        self
+    }
+
+    pub fn supports_fast(&self) -> bool {
+        self.fast
+    }
 }
 
 #[async_trait]
@@ -146,60 +180,17 @@ impl PeerWriter for TcpPeerWriter {
     type Error = TcpPeerError;
 
     async fn write(&mut self, message: Message) -> Result<(), Self::Error> {
-        use Message::*;
-
-        let (id, payload) = match message {
-            KeepAlive => {
-                return self
-                    .writer
-                    .write_u32(0)
-                    .await
-                    .map(|_| ())
-                    .map_err(|e| e.into());
-            }
-            Choke => (0, vec![]),
-            Unchoke => (1, vec![]),
-            Interested => (2, vec![]),
-            NotInterested => (3, vec![]),
-            Have(piece_index) => (4, piece_index.to_be_bytes().to_vec()),
-            BitField(bitfield) => (5, bitfield.into_vec()),
-            Request(BlockMeta {
-                piece_index,
-                begin,
-                length,
-            })
-            | Cancel(BlockMeta {
-                piece_index,
-                begin,
-                length,
-            }) => (
-                if let Request { .. } = message { 6 } else { 8 },
-                [
-                    piece_index.to_be_bytes(),
-                    begin.to_be_bytes(),
-                    length.to_be_bytes(),
-                ]
-                .concat(),
-            ),
-            Piece(Block {
-                meta: BlockMeta {
-                    piece_index, begin, ..
-                },
-                data,
-            }) => (
-                7,
-                [
-                    &piece_index.to_be_bytes() as &[u8],
-                    &begin.to_be_bytes(),
-                    &data,
-                ]
-                .concat(),
-            ),
-        };
-
-        self.writer.write_u32(payload.len() as u32 + 1u32).await?;
-        self.writer.write_u8(id).await?;
-        self.writer.write_all(&payload).await?;
+        let message = PeerMessage::from(message);
+
+        ensure!(self.fast || !message.is_fast(), FastNotNegotiated);
+
+        let frame = message.encode();
+
+        if let Some(limiter) = &self.limiter {
+            limiter.acquire(frame.len()).await;
+        }
+
+        self.writer.write_all(&frame).await?;
 
         Ok(())
     }
@@ -215,10 +206,99 @@ impl PeerWriter for TcpPeerWriter {
 pub enum TcpPeerError {
     #[snafu(context(false))]
     StreamError { source: io::Error },
-    #[snafu(display("Recieved a message with an unknown ID: {}", id))]
-    InvalidMessage { id: u8 },
-    #[snafu(display("Recieved a message with an invalid length {} for its ID {}", len, id))]
-    InvalidMessageLen { len: u32, id: u8 },
-    #[snafu(display("Recieved a handshake with a different info hash than was sent"))]
-    HandshakeInfoHash,
+    #[snafu(display("Recieved an invalid message: {}", source))]
+    InvalidMessage { source: PeerMessageError },
+    #[snafu(display("Recieved an invalid handshake: {}", source))]
+    InvalidHandshake { source: HandshakeError },
+    #[snafu(display("Fast extension message sent without both sides supporting it"))]
+    FastNotNegotiated,
+    #[snafu(display("Message of {} bytes is longer than the maximum of {}", len, max))]
+    FrameTooLong { len: u32, max: u32 },
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+    use tokio::{net::TcpListener, time};
+
+    const INFO_HASH: SHA1Hash = SHA1Hash([7; 20]);
+
+    /// Connects to a raw socket on the other end, which answers our handshake
+    /// and then sends `bytes`.
+    async fn reader_receiving(bytes: Vec<u8>) -> TcpPeerReader {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = vec![0; HANDSHAKE_LEN];
+
+            stream.read_exact(&mut buf).await.unwrap();
+            stream
+                .write_all(&Handshake::new(INFO_HASH, [2; 20]).encode())
+                .await
+                .unwrap();
+            stream.write_all(&bytes).await.unwrap();
+            // Hold the connection open so nothing past `bytes` gets read.
+            let _ = stream.read(&mut buf).await;
+        });
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let (reader, _) = create_tcp_peer_rw(stream, INFO_HASH, [1; 20])
+            .await
+            .unwrap();
+
+        reader
+    }
+
+    #[tokio::test]
+    async fn oversized_length_prefix() {
+        let mut reader = reader_receiving(u32::MAX.to_be_bytes().to_vec()).await;
+
+        assert!(matches!(
+            reader.read().await,
+            Err(TcpPeerError::FrameTooLong {
+                len: u32::MAX,
+                max: DEFAULT_MAX_FRAME_LEN
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn oversized_frame_leaves_limiter_alone() {
+        let limiter = Arc::new(RateLimiter::new(1000));
+        let mut reader = reader_receiving(u32::MAX.to_be_bytes().to_vec())
+            .await
+            .with_rate_limiter(limiter.clone());
+
+        assert!(reader.read().await.is_err());
+        // Had the prefix been taken out of the bucket, this would wait for
+        // days.
+        assert!(time::timeout(Duration::from_secs(1), limiter.acquire(1000))
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn custom_max_frame_len() {
+        let message = PeerMessage::from(Message::Have(3)).encode();
+        let mut reader = reader_receiving(message.to_vec())
+            .await
+            .with_max_frame_len(4);
+
+        assert!(matches!(
+            reader.read().await,
+            Err(TcpPeerError::FrameTooLong { len: 5, max: 4 })
+        ));
+    }
+
+    #[test]
+    fn max_frame_len_fits_bitfield() {
+        assert_eq!(max_frame_len(8, 16 * 1024), DEFAULT_MAX_FRAME_LEN);
+        assert_eq!(
+            max_frame_len(8 * 100_000, 16 * 1024),
+            1 + 100_000 + FRAME_SLACK
+        );
+    }
 }
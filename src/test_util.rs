@@ -0,0 +1,42 @@
+use super::torrent_parser::{FileAttr, SHA1Hash, TorrentFile, TorrentInfo};
+use sha1::{Digest, Sha1};
+use std::{collections::BTreeMap, convert::TryInto, path::PathBuf};
+
+/// An info dict for `content`, hashed in pieces of `piece_len`, that's split
+/// up between files of the given paths and lengths. Those normally add up to
+/// the length of `content`, but tests that don't need the pieces can leave
+/// it empty and set `pieces` themselves.
+pub fn info(name: &str, content: &[u8], piece_len: u64, files: &[(&str, u64)]) -> TorrentInfo {
+    TorrentInfo {
+        name: name.to_owned(),
+        files: files
+            .iter()
+            .map(|&(path, length)| TorrentFile {
+                length,
+                path: PathBuf::from(path),
+                md5sum: None,
+                pieces_root: None,
+                raw_path: None,
+                attr: FileAttr::default(),
+                symlink_path: None,
+                extra: BTreeMap::new(),
+            })
+            .collect(),
+        piece_len,
+        pieces: content
+            .chunks(piece_len as usize)
+            .map(|piece| SHA1Hash(Sha1::digest(piece).as_slice().try_into().unwrap()))
+            .collect(),
+        private: false,
+        multi_file: files.len() > 1,
+        meta_version: None,
+        raw_name: None,
+        extra: BTreeMap::new(),
+    }
+}
+
+/// A directory of its own for a test to write to, which is different for
+/// every test run so that runs of the same test don't trip over each other.
+pub fn base_dir(module: &str, name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("{}-test-{}-{}", module, name, std::process::id()))
+}
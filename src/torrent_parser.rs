@@ -1,218 +1,3802 @@
-use super::bencode_parser::{parse_bencode, Bencode};
-use nom::{
-    bytes::complete::{tag, take_until},
-    combinator::recognize,
-    sequence::preceded,
+use super::{
+    bencode_parser::{
+        encode, encode_to, expect_end, parse_bencode_borrowed, parse_bencode_spanned, Bencode,
+        BencodeConversionError, BencodeKind, BencodeParsingError,
+    },
+    bitfield::Bitfield,
+    types::BlockMeta,
 };
+use bytes::Bytes;
+use once_cell::sync::OnceCell;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use sha1::{Digest, Sha1};
+use sha2::Sha256;
 use snafu::{ensure, OptionExt, ResultExt, Snafu};
 use std::{
+    borrow::Cow,
+    cmp,
+    collections::{BTreeMap, HashMap},
     convert::{TryFrom, TryInto},
-    fmt, num,
-    path::PathBuf,
-    str,
+    fmt, fs,
+    hash::{Hash, Hasher},
+    io::{self, Write},
+    num,
+    path::{Path, PathBuf},
+    str::{self, FromStr},
 };
 
+pub const MIN_PIECE_LEN: u64 = 16 * 1024;
+pub const MAX_PIECE_LEN: u64 = 64 * 1024 * 1024;
+/// v2's merkle trees have a leaf for every block this long.
+pub const MERKLE_BLOCK_LEN: u64 = 16 * 1024;
+
+/// Each file's SHA-256 merkle tree at the piece level, by the file's
+/// `pieces root`.
+pub type PieceLayers = HashMap<[u8; 32], Vec<[u8; 32]>>;
+
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Torrent {
-    pub announce: String,
+    /// Only missing for trackerless torrents, which have `nodes` instead.
+    pub announce: Option<String>,
+    pub announce_list: Vec<Vec<String>>,
+    /// DHT nodes to bootstrap from, for trackerless torrents (BEP 5), as host
+    /// and port. Hosts can be IP addresses or hostnames.
+    pub nodes: Vec<(String, u16)>,
+    pub comment: Option<String>,
+    pub created_by: Option<String>,
+    pub creation_date: Option<i64>,
+    pub encoding: Option<String>,
+    pub url_list: Vec<String>,
     pub info: TorrentInfo,
-    pub info_hash: SHA1Hash,
+    /// Only v2 and hybrid torrents have these, and only for files bigger
+    /// than a piece. A smaller file's root is its only piece's hash.
+    #[cfg_attr(feature = "serde", serde(with = "hex_piece_layers"))]
+    pub piece_layers: PieceLayers,
+    /// Empty for torrents that were deserialized rather than parsed, in which
+    /// case the info dict gets rebuilt from `info` when it's hashed.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    info_bytes: Bytes,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    info_hash: OnceCell<SHA1Hash>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    info_hash_v2: OnceCell<[u8; 32]>,
+}
+
+impl Torrent {
+    /// A torrent with nothing but its tracker and info dict, like for one
+    /// that's just been made rather than parsed.
+    pub fn new(announce: String, info: TorrentInfo) -> Self {
+        Self {
+            announce: Some(announce),
+            announce_list: vec![],
+            nodes: vec![],
+            comment: None,
+            created_by: None,
+            creation_date: None,
+            encoding: None,
+            url_list: vec![],
+            info,
+            piece_layers: HashMap::new(),
+            info_bytes: Bytes::new(),
+            info_hash: OnceCell::new(),
+            info_hash_v2: OnceCell::new(),
+        }
+    }
+
+    /// Hashing the info dict is expensive for torrents with a lot of pieces, so
+    /// this is only done the first time it's asked for.
+    pub fn info_hash(&self) -> SHA1Hash {
+        *self.info_hash.get_or_init(|| {
+            // The digest's length is part of its type, so it always converts
+            // straight into a [u8; 20] without needing to be checked.
+            SHA1Hash(Sha1::digest(&self.info_bytes()).into())
+        })
+    }
+
+    /// BitTorrent v2 (BEP 52) identifies torrents by the SHA-256 of the same
+    /// info dict instead, so this is only available for v2 and hybrid torrents.
+    pub fn info_hash_v2(&self) -> Option<[u8; 32]> {
+        if !self.info.version().has_v2() {
+            return None;
+        }
+
+        Some(
+            *self
+                .info_hash_v2
+                .get_or_init(|| Sha256::digest(&self.info_bytes()).into()),
+        )
+    }
+
+    /// The info dict exactly as it was in the torrent, which is what both
+    /// info hashes are hashed from, for hashing or signing it some other way.
+    /// Torrents that weren't parsed have theirs encoded from `info` instead.
+    pub fn info_bytes(&self) -> Cow<'_, [u8]> {
+        if self.info_bytes.is_empty() {
+            Cow::Owned(encode(&self.info.to_bencode()))
+        } else {
+            Cow::Borrowed(&self.info_bytes)
+        }
+    }
+
+    /// Rebuilds the torrent's dictionary from its fields. Unknown keys outside
+    /// of the info dict are lost, but the ones inside it are kept in
+    /// [`TorrentInfo::extra`] and [`TorrentFile::extra`], so the info hash
+    /// stays the same.
+    pub fn to_bencode(&self) -> Bencode {
+        let mut dict = BTreeMap::new();
+
+        if let Some(announce) = &self.announce {
+            dict.insert(key("announce"), string(announce));
+        }
+
+        if !self.announce_list.is_empty() {
+            dict.insert(
+                key("announce-list"),
+                Bencode::List(
+                    self.announce_list
+                        .iter()
+                        .map(|tier| Bencode::List(tier.iter().map(|url| string(url)).collect()))
+                        .collect(),
+                ),
+            );
+        }
+
+        if let Some(comment) = &self.comment {
+            dict.insert(key("comment"), string(comment));
+        }
+
+        if let Some(created_by) = &self.created_by {
+            dict.insert(key("created by"), string(created_by));
+        }
+
+        if let Some(creation_date) = self.creation_date {
+            dict.insert(key("creation date"), Bencode::Number(creation_date));
+        }
+
+        if let Some(encoding) = &self.encoding {
+            dict.insert(key("encoding"), string(encoding));
+        }
+
+        dict.insert(key("info"), self.info.to_bencode());
+
+        if !self.nodes.is_empty() {
+            dict.insert(
+                key("nodes"),
+                Bencode::List(
+                    self.nodes
+                        .iter()
+                        .map(|(host, port)| {
+                            Bencode::List(vec![string(host), Bencode::Number((*port).into())])
+                        })
+                        .collect(),
+                ),
+            );
+        }
+
+        if !self.piece_layers.is_empty() {
+            dict.insert(
+                key("piece layers"),
+                Bencode::Dict(
+                    self.piece_layers
+                        .iter()
+                        .map(|(root, layer)| {
+                            (
+                                Bytes::copy_from_slice(root),
+                                Bencode::ByteString(layer.concat().into()),
+                            )
+                        })
+                        .collect(),
+                ),
+            );
+        }
+
+        if !self.url_list.is_empty() {
+            dict.insert(
+                key("url-list"),
+                Bencode::List(self.url_list.iter().map(|url| string(url)).collect()),
+            );
+        }
+
+        Bencode::Dict(dict)
+    }
+
+    pub fn write_to<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        encode_to(&self.to_bencode(), &mut writer)
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, TorrentError> {
+        let torrent_bytes = fs::read(path).context(Io)?;
+
+        Self::try_from(torrent_bytes.as_slice()).context(Parse)
+    }
+}
+
+/// Torrents are identified by their info hash, so two torrents with different
+/// trackers or comments are still the same torrent.
+impl PartialEq for Torrent {
+    fn eq(&self, other: &Self) -> bool {
+        self.info_hash() == other.info_hash()
+    }
+}
+
+impl Eq for Torrent {}
+
+impl Hash for Torrent {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.info_hash().hash(state);
+    }
+}
+
+fn key(key: &'static str) -> Bytes {
+    Bytes::from_static(key.as_bytes())
+}
+
+fn string(s: &str) -> Bencode {
+    Bencode::ByteString(Bytes::copy_from_slice(s.as_bytes()))
 }
 
 impl TryFrom<&[u8]> for Torrent {
     type Error = TorrentParsingError;
 
     fn try_from(torrent_bytes: &[u8]) -> Result<Self, Self::Error> {
-        let mut torrent_dict = parse_bencode(torrent_bytes)
-            .map_err(|_| TorrentParsingError::InvalidBencode)
-            .and_then(|(_, bencode)| bencode.dict().context(NotADict))?;
-
-        let announce = torrent_dict
-            .remove(b"announce" as &[u8])
-            .and_then(|val| val.byte_string())
-            .context(FieldNotFound { field: "announce" })
-            .and_then(|val| {
-                str::from_utf8(&val)
-                    .context(InvalidString)
-                    .map(|s| s.to_owned())
-            })?;
-
-        let info = torrent_dict
-            .remove(b"info" as &[u8])
-            .context(FieldNotFound { field: "info" })
-            .and_then(TorrentInfo::try_from)?;
-
-        let (_, info_bytes) = preceded(
-            take_until("info"),
-            // take_until does not consume the pattern itself, so we have to do it
-            preceded(tag("info"), recognize(parse_bencode)),
-        )(&torrent_bytes)
-        .map_err(|_| TorrentParsingError::InvalidBencode)?;
-
-        // Sha1::digest does not make use of const generics yet, but we know it always
-        // returns a [u8; 20] specifically, so unwrapping the try_into here is ok.
-        let info_hash = SHA1Hash(Sha1::digest(info_bytes).as_slice().try_into().unwrap());
+        Self::try_from_collecting(torrent_bytes).map_err(|mut errors| errors.remove(0))
+    }
+}
 
-        Ok(Self {
-            announce,
-            info,
-            info_hash,
+impl Torrent {
+    /// Like `try_from`, but carries on past a broken field to report every
+    /// one that it can, in the order they're checked in. The list of errors
+    /// is never empty.
+    pub fn try_from_collecting(torrent_bytes: &[u8]) -> Result<Self, Vec<TorrentParsingError>> {
+        Self::parse(torrent_bytes, &mut Vec::new())
+    }
+
+    /// Like `try_from`, but also returns anything odd about the torrent that
+    /// didn't stop it from being parsed, in the order it was found.
+    pub fn try_from_with_warnings(
+        torrent_bytes: &[u8],
+    ) -> Result<(Self, Vec<ParseWarning>), TorrentParsingError> {
+        let mut warnings = Vec::new();
+
+        Self::parse(torrent_bytes, &mut warnings)
+            .map(|torrent| (torrent, warnings))
+            .map_err(|mut errors| errors.remove(0))
+    }
+
+    fn parse(
+        torrent_bytes: &[u8],
+        warnings: &mut Vec<ParseWarning>,
+    ) -> Result<Self, Vec<TorrentParsingError>> {
+        let (mut torrent_dict, spans) = parse_bencode_spanned(torrent_bytes)
+            .and_then(|(rest, parsed)| expect_end(torrent_bytes, rest).map(|_| parsed))
+            .map_err(TorrentParsingError::from)
+            .and_then(|(bencode, spans)| Ok((bencode.dict().context(NotADict)?, spans)))
+            .map_err(|e| vec![e])?;
+
+        let mut errors = Vec::new();
+
+        let announce_list = torrent_dict
+            .remove(b"announce-list" as &[u8])
+            .map(|val| {
+                val.list()
+                    .context(InvalidAnnounceList)?
+                    .into_iter()
+                    .map(|tier| {
+                        tier.list()
+                            .context(InvalidAnnounceList)?
+                            .into_iter()
+                            .map(|url| {
+                                str::from_utf8(&url.byte_string().context(InvalidAnnounceList)?)
+                                    .context(InvalidString)
+                                    .map(|s| s.to_owned())
+                            })
+                            .collect::<Result<Vec<_>, _>>()
+                    })
+                    .collect::<Result<Vec<_>, _>>()
+            })
+            .transpose()
+            .map(Option::unwrap_or_default);
+        let announce_list = collect(&mut errors, announce_list);
+
+        let nodes = torrent_dict
+            .remove(b"nodes" as &[u8])
+            .map(|val| parse_nodes(val, warnings))
+            .unwrap_or_default();
+
+        // Per BEP 12, clients that support announce-list are supposed to
+        // ignore announce entirely, and a lot of torrents don't bother
+        // including it. We still prefer it when it's there since it's all we
+        // actually use, but otherwise the first tracker in the first tier is
+        // its closest equivalent.
+        // Trackerless torrents don't need either of them, since they have
+        // DHT nodes instead.
+        let announce = match torrent_dict.remove(b"announce" as &[u8]) {
+            Some(val) => collect(&mut errors, required("announce", Some(val))).map(Some),
+            // If announce-list is broken, that's already been reported, and
+            // there's nothing to fall back on anyway.
+            None => announce_list.as_ref().and_then(|announce_list| {
+                match announce_list.iter().flatten().next() {
+                    Some(announce) => Some(Some(announce.clone())),
+                    None if !nodes.is_empty() => Some(None),
+                    None => collect(&mut errors, FieldNotFound { field: "announce" }.fail()),
+                }
+            }),
+        };
+
+        if let (Some(Some(announce)), Some(announce_list)) = (&announce, &announce_list) {
+            if !announce_list.is_empty()
+                && !announce_list.iter().flatten().any(|url| url == announce)
+            {
+                warnings.push(ParseWarning::IgnoredAnnounce {
+                    announce: announce.clone(),
+                });
+            }
+        }
+
+        // None of these are needed to download anything, so rather than
+        // rejecting the whole torrent over a malformed one, it's just dropped.
+        let mut optional_string = |key: &'static str| {
+            torrent_dict
+                .remove(key.as_bytes())
+                .and_then(|val| malformed(warnings, key, String::try_from(val).ok()))
+        };
+
+        let comment = optional_string("comment");
+        let created_by = optional_string("created by");
+        let encoding = optional_string("encoding");
+
+        let creation_date = torrent_dict
+            .remove(b"creation date" as &[u8])
+            .and_then(|val| malformed(warnings, "creation date", val.number()));
+
+        // BEP 19 allows a single web seed to be given as just a string.
+        let url_list = match torrent_dict.remove(b"url-list" as &[u8]) {
+            Some(Bencode::ByteString(url)) => Ok(vec![url]),
+            Some(Bencode::List(urls)) => urls
+                .into_iter()
+                .map(|url| url.byte_string().context(InvalidUrlList))
+                .collect(),
+            Some(_) => InvalidUrlList.fail(),
+            None => Ok(vec![]),
+        }
+        .and_then(|urls| {
+            urls.into_iter()
+                // Plenty of torrents have an empty url-list string when there
+                // are no web seeds instead of leaving it out.
+                .filter(|url| !url.is_empty())
+                .map(|url| {
+                    str::from_utf8(&url)
+                        .context(InvalidString)
+                        .map(|s| s.to_owned())
+                })
+                .collect()
+        });
+        let url_list = collect(&mut errors, url_list);
+
+        let piece_layers = torrent_dict
+            .remove(b"piece layers" as &[u8])
+            .map(parse_piece_layers)
+            .transpose()
+            .map(Option::unwrap_or_default);
+        let piece_layers = collect(&mut errors, piece_layers);
+
+        let info = match torrent_dict.remove(b"info" as &[u8]) {
+            Some(info) => TorrentInfo::parse(info, &mut errors, warnings),
+            None => collect(&mut errors, FieldNotFound { field: "info" }.fail()),
+        };
+
+        unknown_keys(warnings, &torrent_dict, |key| key.to_owned());
+
+        if let (Some(info), Some(piece_layers)) = (&info, &piece_layers) {
+            collect(&mut errors, info.check_piece_layers(piece_layers));
+        }
+
+        match (announce, announce_list, url_list, piece_layers, info) {
+            (
+                Some(announce),
+                Some(announce_list),
+                Some(url_list),
+                Some(piece_layers),
+                Some(info),
+            ) if errors.is_empty() => {
+                let info_bytes = &torrent_bytes[spans[b"info" as &[u8]].clone()];
+
+                Ok(Self {
+                    announce,
+                    announce_list,
+                    nodes,
+                    comment,
+                    created_by,
+                    creation_date,
+                    encoding,
+                    url_list,
+                    info,
+                    piece_layers,
+                    info_bytes: Bytes::copy_from_slice(info_bytes),
+                    info_hash: OnceCell::new(),
+                    info_hash_v2: OnceCell::new(),
+                })
+            }
+            _ => Err(errors),
+        }
+    }
+}
+
+/// Finds the info dict in a torrent, exactly as it was encoded, without
+/// parsing anything into a [`Bencode`] tree. Going through the torrent's keys
+/// one at a time makes sure it's the real `info` key, not those bytes turning
+/// up earlier in, say, the announce URL. If there's more than one, the last
+/// one wins, like it does when parsing.
+fn info_dict_bytes(torrent_bytes: &[u8]) -> Result<&[u8], TorrentParsingError> {
+    let mut rest = torrent_bytes.strip_prefix(b"d").context(NotADict)?;
+    let mut info_bytes = None;
+
+    while !rest.starts_with(b"e") {
+        let (value_start, key) = parse_bencode_borrowed(rest)?;
+        let (value_end, _) = parse_bencode_borrowed(value_start)?;
+
+        if key.as_bytes() == Some(b"info") {
+            info_bytes = Some(&value_start[..value_start.len() - value_end.len()]);
+        }
+
+        rest = value_end;
+    }
+
+    expect_end(torrent_bytes, &rest[1..])?;
+
+    info_bytes.context(FieldNotFound { field: "info" })
+}
+
+/// Hashes a torrent's info dict without parsing anything else, which is much
+/// quicker for when the info hash is all that's needed.
+pub fn info_hash_only(torrent_bytes: &[u8]) -> Result<SHA1Hash, TorrentParsingError> {
+    let info_bytes = info_dict_bytes(torrent_bytes)?;
+
+    Ok(SHA1Hash(Sha1::digest(info_bytes).into()))
+}
+
+/// Takes the addresses out of a `nodes` list of `[host, port]` pairs. Hosts
+/// are kept as they are, since looking up hostnames is up to whoever
+/// bootstraps from them, and anything else in there gets a warning.
+fn parse_nodes(val: Bencode, warnings: &mut Vec<ParseWarning>) -> Vec<(String, u16)> {
+    let nodes = match malformed(warnings, "nodes", val.list()) {
+        Some(nodes) => nodes,
+        None => return vec![],
+    };
+    let mut any_malformed = false;
+
+    let nodes = nodes
+        .into_iter()
+        .filter_map(|node| {
+            let node = node.list().and_then(|node| match node.as_slice() {
+                [host, port] => Some((
+                    host.as_str().filter(|host| !host.is_empty())?.to_owned(),
+                    u16::try_from(port.as_i64()?).ok()?,
+                )),
+                _ => None,
+            });
+
+            any_malformed |= node.is_none();
+
+            node
         })
+        .collect();
+
+    if any_malformed {
+        warnings.push(ParseWarning::MalformedField {
+            field: "nodes".to_owned(),
+        });
+    }
+
+    nodes
+}
+
+/// Records a warning for an optional field that's there but couldn't be
+/// parsed, which is `None`, passing `val` on either way.
+fn malformed<T>(warnings: &mut Vec<ParseWarning>, field: &str, val: Option<T>) -> Option<T> {
+    if val.is_none() {
+        warnings.push(ParseWarning::MalformedField {
+            field: field.to_owned(),
+        });
     }
+
+    val
+}
+
+/// Records a warning for every key left in `dict` once everything we know
+/// about has been taken out of it.
+fn unknown_keys(
+    warnings: &mut Vec<ParseWarning>,
+    dict: &BTreeMap<Bytes, Bencode>,
+    field: impl Fn(&str) -> String,
+) {
+    warnings.extend(dict.keys().map(|key| ParseWarning::UnknownKey {
+        field: field(&String::from_utf8_lossy(key)),
+    }));
+}
+
+/// Records the error from a field that failed to parse, so that parsing can
+/// carry on and report every broken field at once.
+fn collect<T>(
+    errors: &mut Vec<TorrentParsingError>,
+    result: Result<T, TorrentParsingError>,
+) -> Option<T> {
+    result.map_err(|e| errors.push(e)).ok()
 }
 
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct TorrentInfo {
+    /// Decoded lossily if it wasn't UTF-8, so it's only really good for
+    /// showing and for making files with. [`TorrentInfo::name_bytes`] has
+    /// what was actually in the torrent.
     pub name: String,
     pub files: Vec<TorrentFile>,
     pub piece_len: u64,
     pub pieces: Vec<SHA1Hash>,
+    pub private: bool,
+    /// Whether the info dict had info[files] rather than info[length]. A
+    /// multi-file torrent can still have only one file in it.
+    pub multi_file: bool,
+    /// 2 for v2 and hybrid torrents, and usually absent otherwise.
+    pub meta_version: Option<i64>,
+    /// The torrent's `name` exactly as it was, in the same cases as
+    /// [`TorrentFile::raw_path`].
+    pub raw_name: Option<Vec<u8>>,
+    /// Every key in the info dict that isn't one of the above, so that it can
+    /// be encoded again without changing the info hash. Known keys that can't
+    /// be encoded again from the fields above, like a `private` other than 1
+    /// or a `name.utf-8`, end up here too.
+    #[cfg_attr(feature = "serde", serde(with = "bencoded_extra"))]
+    pub extra: BTreeMap<Vec<u8>, Bencode>,
 }
 
-impl TryFrom<Bencode> for TorrentInfo {
-    type Error = TorrentParsingError;
+/// Which of BitTorrent's two metadata formats a torrent has. Hybrid torrents
+/// have both, so they can join the swarm for either of their info hashes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TorrentVersion {
+    V1,
+    V2,
+    Hybrid,
+}
 
-    fn try_from(info_bencode: Bencode) -> Result<Self, Self::Error> {
-        let mut torrent_info_dict = info_bencode.dict().context(NotADict)?;
+impl TorrentVersion {
+    pub fn has_v1(self) -> bool {
+        self != Self::V2
+    }
 
-        let name = torrent_info_dict
-            .remove(b"name" as &[u8])
-            .and_then(|val| val.byte_string())
-            .context(FieldNotFound {
-                field: "info[name]",
-            })
-            .and_then(|val| {
-                str::from_utf8(&val)
-                    .context(InvalidString)
-                    .map(|s| s.to_owned())
-            })?;
+    pub fn has_v2(self) -> bool {
+        self != Self::V1
+    }
+}
 
-        let files = if let Some(multiple_files) = torrent_info_dict
-            .remove(b"files" as &[u8])
-            .and_then(|val| val.list())
-        {
-            multiple_files
-                .into_iter()
-                .map(TorrentFile::try_from)
-                .collect::<Result<_, _>>()?
+impl TorrentInfo {
+    /// The torrent's name as it was, whatever encoding that was in.
+    pub fn name_bytes(&self) -> &[u8] {
+        self.raw_name.as_deref().unwrap_or(self.name.as_bytes())
+    }
+
+    /// Makes sure every file that needs a piece layer has one, with a hash for
+    /// each of its pieces. Unlike v1, v2 pieces never span files.
+    fn check_piece_layers(&self, piece_layers: &PieceLayers) -> Result<(), TorrentParsingError> {
+        for file in &self.files {
+            let pieces_root = match file.pieces_root {
+                Some(pieces_root) if file.length > self.piece_len => pieces_root,
+                _ => continue,
+            };
+
+            let layer = piece_layers
+                .get(&pieces_root)
+                .context(MissingPieceLayer { path: &file.path })?;
+
+            ensure!(
+                layer.len() as u64 == file.length.div_ceil(self.piece_len),
+                InvalidPieceLayers
+            );
+        }
+
+        Ok(())
+    }
+
+    /// v2-only torrents are the only ones without v1 piece hashes.
+    pub fn version(&self) -> TorrentVersion {
+        match (self.meta_version == Some(2), self.pieces.is_empty()) {
+            (false, _) => TorrentVersion::V1,
+            (true, true) => TorrentVersion::V2,
+            (true, false) => TorrentVersion::Hybrid,
+        }
+    }
+
+    pub fn to_bencode(&self) -> Bencode {
+        // Whatever the fields below say takes precedence over `extra`.
+        let mut dict = self
+            .extra
+            .iter()
+            .map(|(key, val)| (Bytes::copy_from_slice(key), val.clone()))
+            .collect::<BTreeMap<_, _>>();
+
+        let version = self.version();
+        let (v1, v2) = (version.has_v1(), version.has_v2());
+
+        if v2 {
+            dict.insert(key("file tree"), self.file_tree());
+        }
+
+        // v2-only torrents keep all of this in the file tree instead.
+        if v1 && self.multi_file {
+            dict.insert(
+                key("files"),
+                Bencode::List(self.files.iter().map(TorrentFile::to_bencode).collect()),
+            );
+        } else if let Some(file) = self.files.first().filter(|_| v1) {
+            dict.insert(key("length"), Bencode::Number(file.length as i64));
+
+            if let Some(md5sum) = &file.md5sum {
+                dict.insert(key("md5sum"), string(md5sum));
+            }
+
+            file.insert_attr(&mut dict);
+        }
+
+        dict.insert(
+            key("name"),
+            Bencode::ByteString(Bytes::copy_from_slice(self.name_bytes())),
+        );
+
+        // `name` came from name.utf-8 if it had one we could use, so that's
+        // the one to keep up to date with it.
+        if self.raw_name.is_some() && dict.get(b"name.utf-8" as &[u8]).is_some_and(is_utf8_hint) {
+            dict.insert(key("name.utf-8"), string(&self.name));
+        }
+        dict.insert(key("piece length"), Bencode::Number(self.piece_len as i64));
+
+        if v1 {
+            dict.insert(
+                key("pieces"),
+                Bencode::ByteString(self.pieces.iter().flat_map(|piece| piece.0).collect()),
+            );
+        }
+
+        if self.private {
+            dict.insert(key("private"), Bencode::Number(1));
+        }
+
+        if let Some(meta_version) = self.meta_version {
+            dict.insert(key("meta version"), Bencode::Number(meta_version));
+        }
+
+        Bencode::Dict(dict)
+    }
+
+    fn file_tree(&self) -> Bencode {
+        let mut tree = BTreeMap::new();
+
+        // Padding files from the v1 file list of a hybrid torrent aren't in the
+        // tree, and they're the only non-empty files without a root.
+        for file in &self.files {
+            if file.pieces_root.is_none() && file.length > 0 {
+                continue;
+            }
+
+            let mut file_dict = BTreeMap::new();
+
+            file_dict.insert(key("length"), Bencode::Number(file.length as i64));
+
+            if let Some(pieces_root) = file.pieces_root {
+                file_dict.insert(
+                    key("pieces root"),
+                    Bencode::ByteString(Bytes::copy_from_slice(&pieces_root)),
+                );
+            }
+
+            file.insert_attr(&mut file_dict);
+
+            // A hybrid torrent's files only have the unknown keys from its v1
+            // file list, which aren't necessarily meant for the tree.
+            if !self.version().has_v1() {
+                file.insert_extra(&mut file_dict);
+            }
+
+            let mut node = &mut tree;
+
+            for component in file.path.iter() {
+                let component = Bytes::copy_from_slice(component.to_string_lossy().as_bytes());
+
+                node = match node
+                    .entry(component)
+                    .or_insert_with(|| Bencode::Dict(BTreeMap::new()))
+                {
+                    Bencode::Dict(dict) => dict,
+                    _ => unreachable!("file tree nodes are always dicts"),
+                };
+            }
+
+            node.insert(Bytes::new(), Bencode::Dict(file_dict));
+        }
+
+        Bencode::Dict(tree)
+    }
+
+    pub fn total_length(&self) -> u64 {
+        self.files.iter().map(|f| f.length).sum()
+    }
+
+    pub fn num_pieces(&self) -> usize {
+        self.pieces.len()
+    }
+
+    pub fn iter_pieces(&self) -> impl Iterator<Item = PieceInfo> + '_ {
+        let total_length = self.total_length();
+
+        self.pieces.iter().enumerate().map(move |(index, &hash)| {
+            let offset = index as u64 * self.piece_len;
+
+            PieceInfo {
+                index,
+                hash,
+                offset,
+                // The last piece is whatever's left over after all the others.
+                length: cmp::min(self.piece_len, total_length.saturating_sub(offset)),
+            }
+        })
+    }
+
+    /// Splits the piece at `index` into requests of at most `block_len` bytes,
+    /// which is how peers expect pieces to be asked for. There are none for
+    /// an index past the last piece, or for a `block_len` of 0.
+    pub fn blocks_for_piece(
+        &self,
+        index: usize,
+        block_len: u32,
+    ) -> impl Iterator<Item = BlockMeta> {
+        let piece_len = if index < self.num_pieces() && block_len > 0 {
+            cmp::min(
+                self.piece_len,
+                self.total_length()
+                    .saturating_sub(index as u64 * self.piece_len),
+            )
         } else {
-            vec![TorrentFile {
-                length: torrent_info_dict
-                    .remove(b"length" as &[u8])
-                    .and_then(|val| val.number())
-                    .context(FieldNotFound {
-                        field: "info[length]",
-                    })
-                    .and_then(|val| u64::try_from(val).context(InvalidFileLen))?,
-                path: name.clone().into(),
-            }]
+            0
         };
+        // A 0 has already left the piece empty, so this only keeps the
+        // division below from panicking.
+        let block_len = cmp::max(block_len, 1) as u64;
 
-        let piece_len = torrent_info_dict
-            .remove(b"piece length" as &[u8])
-            .and_then(|val| val.number())
-            .context(FieldNotFound {
-                field: "info[piece length]",
-            })
-            .and_then(|val| u64::try_from(val).context(InvalidPieceLen))?;
+        (0..piece_len.div_ceil(block_len)).map(move |block| {
+            let begin = block * block_len;
 
-        let all_pieces = torrent_info_dict
-            .remove(b"pieces" as &[u8])
-            .and_then(|val| val.byte_string())
-            .context(FieldNotFound {
-                field: "info[pieces]",
-            })?;
+            BlockMeta {
+                piece_index: index as u32,
+                begin: begin as u32,
+                length: cmp::min(block_len, piece_len - begin) as u32,
+            }
+        })
+    }
 
-        let (pieces, remainder) = all_pieces.as_chunks();
+    /// Makes sure there are exactly as many piece hashes as it takes to cover
+    /// all of the content, since anything else means the torrent is corrupt.
+    pub fn check_consistency(&self) -> Result<(), TorrentParsingError> {
+        ensure!(self.piece_len > 0, ZeroPieceLen);
 
-        ensure!(remainder.is_empty(), MismatchedPieceLength);
+        let expected = self.total_length().div_ceil(self.piece_len);
 
-        let pieces = pieces.iter().copied().map(SHA1Hash).collect();
+        ensure!(
+            expected == self.num_pieces() as u64,
+            InconsistentPieceCount {
+                expected,
+                found: self.num_pieces()
+            }
+        );
 
-        Ok(Self {
-            name,
-            files,
-            piece_len,
-            pieces,
-        })
+        Ok(())
     }
-}
 
-#[derive(Clone, Debug)]
-pub struct TorrentFile {
-    pub length: u64,
-    pub path: PathBuf,
-}
+    /// Checks `data` against the expected hash of the piece at `index`. Any
+    /// index past the last piece never verifies.
+    pub fn verify_piece(&self, index: usize, data: &[u8]) -> bool {
+        let hash = SHA1Hash(Sha1::digest(data).into());
 
-impl TryFrom<Bencode> for TorrentFile {
-    type Error = TorrentParsingError;
+        self.pieces.get(index) == Some(&hash)
+    }
 
-    fn try_from(file_bencode: Bencode) -> Result<Self, Self::Error> {
-        let mut file_dict = file_bencode.dict().context(NotADict)?;
+    /// Checks `data` against the v2 hash of the piece at `piece_index` in the
+    /// file at `file_index`, since v2 pieces never span files. The hashes of
+    /// each 16 KiB block are the leaves of a merkle tree, so the piece is
+    /// good if the root of its part of the tree matches its hash in the
+    /// piece layer. A file that fits in one piece has its `pieces root` as
+    /// that hash instead.
+    pub fn verify_piece_v2(
+        &self,
+        piece_layers: &PieceLayers,
+        file_index: usize,
+        piece_index: usize,
+        data: &[u8],
+    ) -> bool {
+        let file = match self.files.get(file_index) {
+            Some(file) => file,
+            None => return false,
+        };
+        let (pieces_root, offset) = match file.pieces_root {
+            Some(pieces_root) => (pieces_root, piece_index as u64 * self.piece_len),
+            None => return false,
+        };
 
-        let length = file_dict
-            .remove(b"length" as &[u8])
-            .and_then(|val| val.number())
-            .context(FieldNotFound {
-                field: "file[length]",
-            })
-            .and_then(|val| u64::try_from(val).context(InvalidFileLen))?;
+        if offset >= file.length
+            || data.len() as u64 != cmp::min(self.piece_len, file.length - offset)
+        {
+            return false;
+        }
 
-        let path = file_dict
-            .remove(b"path" as &[u8])
-            .and_then(|val| val.list())
-            .context(FieldNotFound {
-                field: "file[path]",
-            })?
-            .into_iter()
-            .map(|val| {
-                str::from_utf8(&val.byte_string().context(InvalidPath)?)
-                    .context(InvalidString)
-                    .map(|s| s.to_owned())
-            })
-            .collect::<Result<_, _>>()?;
+        let leaves = data
+            .chunks(MERKLE_BLOCK_LEN as usize)
+            .map(|block| Sha256::digest(block).into())
+            .collect::<Vec<_>>();
+
+        if file.length <= self.piece_len {
+            // The tree is only as big as it needs to be for a small file.
+            merkle_root(leaves, leaves_len_for(file.length)) == pieces_root
+        } else {
+            let expected = piece_layers
+                .get(&pieces_root)
+                .and_then(|layer| layer.get(piece_index));
 
-        Ok(Self { length, path })
+            // A short last piece is padded out to a whole piece's worth.
+            expected == Some(&merkle_root(leaves, leaves_len_for(self.piece_len)))
+        }
     }
-}
 
-#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
-pub struct SHA1Hash(pub [u8; 20]);
+    /// Checks all of the torrent's content at once, like when rechecking a
+    /// download, returning whether each piece matches its hash. Any piece
+    /// that `data` is too short to contain doesn't. With the `rayon` feature,
+    /// the pieces are hashed in parallel.
+    pub fn verify_file(&self, data: &[u8]) -> Vec<bool> {
+        let verify = |piece: &PieceInfo| {
+            let start = piece.offset as usize;
 
-impl AsRef<[u8; 20]> for SHA1Hash {
-    fn as_ref(&self) -> &[u8; 20] {
-        &self.0
+            data.get(start..start + piece.length as usize)
+                .is_some_and(|piece_data| {
+                    Sha1::digest(piece_data).as_slice() == piece.hash.as_ref()
+                })
+        };
+
+        let pieces = self.iter_pieces().collect::<Vec<_>>();
+
+        #[cfg(feature = "rayon")]
+        {
+            use rayon::prelude::*;
+
+            pieces.par_iter().map(verify).collect()
+        }
+
+        #[cfg(not(feature = "rayon"))]
+        {
+            pieces.iter().map(verify).collect()
+        }
     }
-}
 
-impl fmt::Debug for SHA1Hash {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        for byte in &self.0 {
-            write!(f, "{:02x}", byte)?;
+    /// Every piece is exactly `piece_len` long, except for the last one,
+    /// which has whatever's left over.
+    pub fn verify_piece_length(&self, index: usize, len: u64) -> bool {
+        let num_pieces = self.num_pieces();
+
+        if index + 1 < num_pieces {
+            len == self.piece_len
+        } else if index + 1 == num_pieces {
+            self.total_length()
+                .checked_sub(self.piece_len * index as u64)
+                == Some(len)
+        } else {
+            false
         }
+    }
 
-        Ok(())
+    /// Pieces are laid out across all the files back to back, so a range of
+    /// the torrent's content can be split between any number of them. This
+    /// finds each part of the range that lands in a different file, in order.
+    /// Ranges that run off the end are cut short there, however long they are.
+    pub fn locate(&self, global_offset: u64, len: u64) -> Vec<FileSpan> {
+        let end = global_offset.saturating_add(len);
+        let mut file_start = 0u64;
+        let mut spans = Vec::new();
+
+        for (file, torrent_file) in self.files.iter().enumerate() {
+            let file_end = file_start.saturating_add(torrent_file.length);
+
+            if torrent_file.length > 0 && file_end > global_offset && file_start < end {
+                let offset = global_offset.saturating_sub(file_start);
+
+                spans.push(FileSpan {
+                    file,
+                    offset,
+                    len: cmp::min(file_end, end) - (file_start + offset),
+                });
+            }
+
+            if file_end >= end {
+                break;
+            }
+
+            file_start = file_end;
+        }
+
+        spans
     }
-}
 
-#[non_exhaustive]
-#[derive(Debug, Snafu)]
-pub enum TorrentParsingError {
-    #[snafu(display("Expected a dictionary, but didn't find it"))]
-    NotADict,
-    #[snafu(display("Attempted to decode an invalid string"))]
+    /// Where each file starts and ends in the torrent's content as a whole,
+    /// in the same order as `files`. This goes the other way to [`locate`],
+    /// and empty files start and end in the same place.
+    ///
+    /// [`locate`]: TorrentInfo::locate
+    pub fn file_offsets(&self) -> Vec<(u64, u64)> {
+        let mut start = 0;
+
+        self.files
+            .iter()
+            .map(|file| {
+                let offsets = (start, start + file.length);
+                start += file.length;
+
+                offsets
+            })
+            .collect()
+    }
+
+    /// The fraction of each file that's covered by the `completed` pieces. A
+    /// piece that spans several files counts towards each of them by however
+    /// much of it lands there, and empty files are always complete.
+    pub fn file_progress(&self, completed: &Bitfield) -> Vec<f64> {
+        let mut have = vec![0; self.files.len()];
+
+        for piece in self
+            .iter_pieces()
+            .filter(|piece| completed.has(piece.index))
+        {
+            for span in self.locate(piece.offset, piece.length) {
+                have[span.file] += span.len;
+            }
+        }
+
+        self.files
+            .iter()
+            .zip(have)
+            .map(|(file, have)| match file.length {
+                0 => 1.0,
+                length => have as f64 / length as f64,
+            })
+            .collect()
+    }
+
+    /// Each file's path with anything that can't be used as a filename on
+    /// Windows replaced, for writing files there. `files` is left untouched.
+    pub fn sanitized_paths(&self) -> Vec<PathBuf> {
+        self.files
+            .iter()
+            .map(|file| {
+                file.path
+                    .iter()
+                    .map(|component| sanitize_component(&component.to_string_lossy()))
+                    .collect()
+            })
+            .collect()
+    }
+}
+
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+fn sanitize_component(component: &str) -> String {
+    let mut sanitized = component
+        .chars()
+        .map(|c| match c {
+            '<' | '>' | ':' | '"' | '/' | '\\' | '|' | '?' | '*' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect::<String>();
+
+    // Windows quietly strips trailing dots and spaces, which could make two
+    // different files end up with the same name.
+    if sanitized.ends_with(&['.', ' '][..]) {
+        sanitized.pop();
+        sanitized.push('_');
+    }
+
+    // Device names are reserved with any extension too, like CON.txt.
+    let stem = sanitized.split('.').next().unwrap_or_default();
+
+    if RESERVED_NAMES
+        .iter()
+        .any(|reserved| stem.eq_ignore_ascii_case(reserved))
+    {
+        sanitized.insert(0, '_');
+    }
+
+    sanitized
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PieceInfo {
+    pub index: usize,
+    pub hash: SHA1Hash,
+    /// Where the piece starts in the torrent's content as a whole.
+    pub offset: u64,
+    pub length: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FileSpan {
+    pub file: usize,
+    pub offset: u64,
+    pub len: u64,
+}
+
+impl TryFrom<Bencode> for TorrentInfo {
+    type Error = TorrentParsingError;
+
+    fn try_from(info_bencode: Bencode) -> Result<Self, Self::Error> {
+        let mut errors = Vec::new();
+
+        match Self::parse(info_bencode, &mut errors, &mut Vec::new()) {
+            Some(info) if errors.is_empty() => Ok(info),
+            _ => Err(errors.remove(0)),
+        }
+    }
+}
+
+impl TorrentInfo {
+    /// Adds an error to `errors` for each field that couldn't be parsed,
+    /// returning `None` if any of them were needed.
+    fn parse(
+        info_bencode: Bencode,
+        errors: &mut Vec<TorrentParsingError>,
+        warnings: &mut Vec<ParseWarning>,
+    ) -> Option<Self> {
+        let errors_before = errors.len();
+
+        let mut torrent_info_dict = collect(errors, info_bencode.dict().context(NotADict))?;
+
+        // Known keys whose values we can't make sense of, or that have to be
+        // encoded again just as they were, are put back into `extra` once the
+        // unknown ones have been warned about.
+        let mut kept = BTreeMap::new();
+
+        let raw_name =
+            required::<Vec<u8>>("info[name]", torrent_info_dict.remove(b"name" as &[u8]));
+        let utf8_name = torrent_info_dict
+            .remove(b"name.utf-8" as &[u8])
+            .and_then(|val| {
+                kept.insert(b"name.utf-8".to_vec(), val.clone());
+                String::try_from(val).ok()
+            });
+
+        // Same as with file paths, name.utf-8 is preferred but only a hint.
+        let (name, raw_name) = match (collect(errors, raw_name), utf8_name) {
+            (Some(raw_name), Some(name)) => (Some(name), Some(raw_name)),
+            (Some(raw_name), None) => match String::from_utf8(raw_name) {
+                Ok(name) => (Some(name), None),
+                Err(e) => (
+                    Some(String::from_utf8_lossy(e.as_bytes()).into_owned()),
+                    Some(e.into_bytes()),
+                ),
+            },
+            (None, _) => (None, None),
+        };
+
+        let meta_version = match torrent_info_dict.remove(b"meta version" as &[u8]) {
+            Some(Bencode::Number(meta_version)) => Some(meta_version),
+            Some(val) => {
+                malformed::<()>(warnings, "info[meta version]", None);
+                kept.insert(b"meta version".to_vec(), val);
+                None
+            }
+            None => None,
+        };
+
+        let file_tree = torrent_info_dict
+            .remove(b"file tree" as &[u8])
+            .map(|tree| {
+                let mut files = Vec::new();
+
+                parse_file_tree(tree, &mut Vec::new(), &mut files).map(|_| files)
+            })
+            .transpose();
+        let file_tree = collect(errors, file_tree);
+
+        // BEP 52 has v2 torrents always list their files in a file tree.
+        if meta_version == Some(2) && matches!(file_tree, Some(None)) {
+            collect::<()>(
+                errors,
+                FieldNotFound {
+                    field: "info[file tree]",
+                }
+                .fail(),
+            );
+        }
+
+        // BEP 3 distinguishes single and multi-file torrents purely by whether
+        // info[length] or info[files] is present, so exactly one of them has to be.
+        // v2-only torrents have neither, and just a file tree instead.
+        let mut files = match (
+            torrent_info_dict.remove(b"files" as &[u8]),
+            torrent_info_dict.remove(b"length" as &[u8]),
+        ) {
+            (Some(multiple_files), None) => collect(
+                errors,
+                required::<Vec<Bencode>>("info[files]", Some(multiple_files)),
+            )
+            .map(|multiple_files| {
+                (
+                    multiple_files
+                        .into_iter()
+                        .filter_map(|file| collect(errors, TorrentFile::try_from(file)))
+                        .collect(),
+                    true,
+                )
+            }),
+            (None, Some(length)) => {
+                let length = required::<i64>("info[length]", Some(length))
+                    .and_then(|val| u64::try_from(val).context(InvalidFileLen));
+                let length = collect(errors, length);
+                // The name is the whole path of a single file.
+                let path = name.as_ref().and_then(|name| {
+                    collect(errors, check_path_component(name).map(|_| name.into()))
+                });
+                let md5sum = collect(
+                    errors,
+                    parse_md5sum(torrent_info_dict.remove(b"md5sum" as &[u8])),
+                );
+                let (attr, symlink_path) = parse_attr(&mut torrent_info_dict);
+
+                match (length, path, md5sum) {
+                    (Some(length), Some(path), Some(md5sum)) => Some((
+                        vec![TorrentFile {
+                            length,
+                            path,
+                            md5sum,
+                            pieces_root: None,
+                            raw_path: None,
+                            attr,
+                            symlink_path,
+                            // Any of the file's other keys are in the info dict.
+                            extra: BTreeMap::new(),
+                        }],
+                        false,
+                    )),
+                    _ => None,
+                }
+            }
+            (Some(_), Some(_)) => collect(errors, AmbiguousFileMode.fail()),
+            (None, None) => match &file_tree {
+                Some(Some(tree_files)) => {
+                    let single_file =
+                        tree_files.len() == 1 && tree_files[0].path.components().count() == 1;
+
+                    Some((tree_files.clone(), !single_file))
+                }
+                Some(None) => collect(errors, MissingFileMode.fail()),
+                // A broken file tree has already been reported.
+                None => None,
+            },
+        };
+
+        // Hybrid torrents have both, in which case the v1 file list is still
+        // used, since it includes padding files, but the roots are worth keeping.
+        // Both halves have to describe the same files, or the two info hashes
+        // would be for different content.
+        if let (Some((files, _)), Some(Some(tree_files))) = (&mut files, &file_tree) {
+            for tree_file in tree_files {
+                match files
+                    .iter_mut()
+                    .find(|file| file.path == tree_file.path && file.length == tree_file.length)
+                {
+                    Some(file) => file.pieces_root = tree_file.pieces_root,
+                    None => {
+                        collect::<()>(
+                            errors,
+                            MismatchedFileTree {
+                                path: tree_file.path.clone(),
+                            }
+                            .fail(),
+                        );
+                    }
+                }
+            }
+        }
+
+        // Each file's length fits in an i64, but they can still add up to more
+        // than a u64 can hold, which everything that works with offsets into
+        // the torrent would overflow on.
+        if let Some((files, _)) = &files {
+            if files
+                .iter()
+                .try_fold(0u64, |total, file| total.checked_add(file.length))
+                .is_none()
+            {
+                collect::<()>(errors, TotalLengthOverflow.fail());
+            }
+        }
+
+        let piece_len = required::<i64>(
+            "info[piece length]",
+            torrent_info_dict.remove(b"piece length" as &[u8]),
+        )
+        .and_then(|val| u64::try_from(val).context(InvalidPieceLen))
+        .and_then(|piece_len| {
+            // BEP 52 requires this, and practically every v1 torrent
+            // follows it too, so anything else is likely corrupt.
+            ensure!(
+                piece_len.is_power_of_two() && (MIN_PIECE_LEN..=MAX_PIECE_LEN).contains(&piece_len),
+                InvalidPieceLenValue { piece_len }
+            );
+
+            Ok(piece_len)
+        });
+        let piece_len = collect(errors, piece_len);
+
+        // v2 moves the piece hashes out of the info dict into piece layers, so
+        // info[pieces] is only there for v1 and hybrid torrents.
+        let pieces = match torrent_info_dict.remove(b"pieces" as &[u8]) {
+            None if meta_version == Some(2) => Ok(vec![]),
+            all_pieces => required::<Vec<u8>>("info[pieces]", all_pieces).and_then(|all_pieces| {
+                let (pieces, remainder) = all_pieces.as_chunks();
+
+                ensure!(remainder.is_empty(), MismatchedPieceLength);
+                ensure!(!pieces.is_empty(), NoPieces);
+
+                Ok(pieces.iter().copied().map(SHA1Hash).collect())
+            }),
+        };
+        let pieces = collect(errors, pieces);
+
+        // BEP 27 only defines private=1, so anything else is as good as absent.
+        let private = match torrent_info_dict.remove(b"private" as &[u8]) {
+            Some(Bencode::Number(1)) => true,
+            Some(val) => {
+                if !matches!(val, Bencode::Number(_)) {
+                    malformed::<()>(warnings, "info[private]", None);
+                }
+                kept.insert(b"private".to_vec(), val);
+                false
+            }
+            None => false,
+        };
+
+        unknown_keys(warnings, &torrent_info_dict, |key| format!("info[{}]", key));
+
+        let mut extra = into_extra(torrent_info_dict);
+        extra.extend(kept);
+
+        match (name, files, piece_len, pieces) {
+            (Some(name), Some((files, multi_file)), Some(piece_len), Some(pieces))
+                if errors.len() == errors_before =>
+            {
+                Some(Self {
+                    name,
+                    files,
+                    piece_len,
+                    pieces,
+                    private,
+                    multi_file,
+                    meta_version,
+                    raw_name,
+                    extra,
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct TorrentFile {
+    pub length: u64,
+    pub path: PathBuf,
+    pub md5sum: Option<String>,
+    /// The root of the file's SHA-256 merkle tree in v2 torrents. Empty files
+    /// don't have one.
+    #[cfg_attr(feature = "serde", serde(with = "hex_pieces_root"))]
+    pub pieces_root: Option<[u8; 32]>,
+    /// The file's `path` exactly as it was in the torrent, when `path` above
+    /// didn't come straight from it. That's either because there was a
+    /// `path.utf-8` as well, or because it wasn't UTF-8 and had to be decoded
+    /// lossily. Older clients used whatever encoding the system had.
+    pub raw_path: Option<Vec<Vec<u8>>>,
+    pub attr: FileAttr,
+    /// Where the file links to, for symlinks (BEP 47). This is as the torrent
+    /// has it, relative to the torrent's root, and isn't checked like `path`
+    /// is, so it's up to whoever makes the link to make sure it's safe.
+    pub symlink_path: Option<PathBuf>,
+    /// Every key in the file's dict that isn't one of the above, like the
+    /// `sha1` some torrents have, plus any `path.utf-8`, the same as
+    /// [`TorrentInfo::extra`]. The files of a hybrid torrent only keep the ones
+    /// from its v1 file list.
+    #[cfg_attr(feature = "serde", serde(with = "bencoded_extra"))]
+    pub extra: BTreeMap<Vec<u8>, Bencode>,
+}
+
+/// BEP 47's `attr` string for a file, one flag per character. It's kept
+/// exactly as the torrent has it, characters we don't know about and all,
+/// since newer ones are only ever hints and it goes back out unchanged.
+#[derive(Clone, Debug, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct FileAttr(pub Vec<u8>);
+
+impl FileAttr {
+    pub fn parse(attr: &[u8]) -> Self {
+        Self(attr.to_vec())
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.has(b'l')
+    }
+
+    pub fn is_executable(&self) -> bool {
+        self.has(b'x')
+    }
+
+    pub fn is_hidden(&self) -> bool {
+        self.has(b'h')
+    }
+
+    pub fn is_padding(&self) -> bool {
+        self.has(b'p')
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn has(&self, flag: u8) -> bool {
+        self.0.contains(&flag)
+    }
+}
+
+/// Takes BEP 47's `attr` and `symlink path` out of a file's dict. Neither of
+/// them is needed to download the file, so they're ignored if malformed, and
+/// left in `dict` to be kept with the file's unknown keys. So is an empty
+/// `attr`, which says nothing but should still be there when the torrent's
+/// written back out.
+fn parse_attr(dict: &mut BTreeMap<Bytes, Bencode>) -> (FileAttr, Option<PathBuf>) {
+    let attr = match dict.remove(b"attr" as &[u8]) {
+        Some(Bencode::ByteString(attr)) if !attr.is_empty() => FileAttr::parse(&attr),
+        Some(val) => {
+            dict.insert(key("attr"), val);
+            FileAttr::default()
+        }
+        None => FileAttr::default(),
+    };
+
+    let symlink_path = dict.remove(b"symlink path" as &[u8]).and_then(|val| {
+        let symlink_path = val.clone().list().and_then(|components| {
+            components
+                .into_iter()
+                .map(|val| String::try_from(val).ok())
+                .collect::<Option<PathBuf>>()
+        });
+
+        if symlink_path.is_none() {
+            dict.insert(key("symlink path"), val);
+        }
+
+        symlink_path
+    });
+
+    (attr, symlink_path)
+}
+
+impl TryFrom<Bencode> for TorrentFile {
+    type Error = TorrentParsingError;
+
+    fn try_from(file_bencode: Bencode) -> Result<Self, Self::Error> {
+        let mut file_dict = file_bencode.dict().context(NotADict)?;
+
+        let length = required::<i64>("file[length]", file_dict.remove(b"length" as &[u8]))
+            .and_then(|val| u64::try_from(val).context(InvalidFileLen))?;
+
+        let raw_path = required::<Vec<Bencode>>("file[path]", file_dict.remove(b"path" as &[u8]))?
+            .into_iter()
+            .map(|val| Vec::try_from(val).ok().context(InvalidPath))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // This is only ever a hint, so if it's malformed we just go by path.
+        // Either way it's left to be kept with the unknown keys.
+        let utf8_path = file_dict
+            .get(b"path.utf-8" as &[u8])
+            .cloned()
+            .and_then(|val| val.list())
+            .and_then(|components| {
+                components
+                    .into_iter()
+                    .map(|val| String::try_from(val).ok())
+                    .collect::<Option<Vec<_>>>()
+            });
+
+        let (components, raw_path) = match utf8_path {
+            Some(components) => (components, Some(raw_path)),
+            None => match raw_path
+                .iter()
+                .map(|component| String::from_utf8(component.clone()))
+                .collect::<Result<Vec<_>, _>>()
+            {
+                Ok(components) => (components, None),
+                Err(_) => (
+                    raw_path
+                        .iter()
+                        .map(|component| String::from_utf8_lossy(component).into_owned())
+                        .collect(),
+                    Some(raw_path),
+                ),
+            },
+        };
+
+        for component in &components {
+            check_path_component(component)?;
+        }
+
+        let path = components.iter().collect();
+
+        let md5sum = parse_md5sum(file_dict.remove(b"md5sum" as &[u8]))?;
+        let (attr, symlink_path) = parse_attr(&mut file_dict);
+
+        Ok(Self {
+            length,
+            path,
+            md5sum,
+            pieces_root: None,
+            raw_path,
+            attr,
+            symlink_path,
+            extra: into_extra(file_dict),
+        })
+    }
+}
+
+/// In a v2 file tree, each key is one path component, down to a dict with an
+/// empty key holding the file's details.
+fn parse_file_tree(
+    tree: Bencode,
+    path: &mut Vec<String>,
+    files: &mut Vec<TorrentFile>,
+) -> Result<(), TorrentParsingError> {
+    for (name, node) in tree.dict().context(InvalidFileTree)? {
+        if name.is_empty() {
+            ensure!(!path.is_empty(), InvalidFileTree);
+
+            let mut file_dict = node.dict().context(InvalidFileTree)?;
+
+            let length = required::<i64>("file tree[length]", file_dict.remove(b"length" as &[u8]))
+                .and_then(|val| u64::try_from(val).context(InvalidFileLen))?;
+
+            let pieces_root = file_dict
+                .remove(b"pieces root" as &[u8])
+                .map(|val| {
+                    val.byte_string()
+                        .and_then(|root| root.as_ref().try_into().ok())
+                        .context(InvalidFileTree)
+                })
+                .transpose()?;
+            let (attr, symlink_path) = parse_attr(&mut file_dict);
+
+            files.push(TorrentFile {
+                length,
+                path: path.iter().collect(),
+                md5sum: None,
+                pieces_root,
+                raw_path: None,
+                attr,
+                symlink_path,
+                extra: into_extra(file_dict),
+            });
+        } else {
+            let component = str::from_utf8(&name).context(InvalidString)?;
+
+            check_path_component(component)?;
+            path.push(component.to_owned());
+            parse_file_tree(node, path, files)?;
+            path.pop();
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether a `name.utf-8` or `path.utf-8` is one we'd have gone by.
+fn is_utf8_hint(hint: &Bencode) -> bool {
+    match hint {
+        Bencode::ByteString(hint) => str::from_utf8(hint).is_ok(),
+        Bencode::List(components) => components.iter().all(is_utf8_hint_component),
+        _ => false,
+    }
+}
+
+fn is_utf8_hint_component(component: &Bencode) -> bool {
+    matches!(component, Bencode::ByteString(component) if str::from_utf8(component).is_ok())
+}
+
+fn into_extra(dict: BTreeMap<Bytes, Bencode>) -> BTreeMap<Vec<u8>, Bencode> {
+    dict.into_iter()
+        .map(|(key, val)| (key.to_vec(), val))
+        .collect()
+}
+
+/// Paths come straight from the torrent, so anything that could make one
+/// point outside of the download directory gets rejected.
+fn check_path_component(component: &str) -> Result<(), TorrentParsingError> {
+    ensure!(
+        !matches!(component, "" | "." | "..") && !component.contains(&['/', '\\', '\0'][..]),
+        UnsafePath { component }
+    );
+
+    Ok(())
+}
+
+impl TorrentFile {
+    fn to_bencode(&self) -> Bencode {
+        let mut dict = BTreeMap::new();
+
+        self.insert_extra(&mut dict);
+        dict.insert(key("length"), Bencode::Number(self.length as i64));
+
+        if let Some(md5sum) = &self.md5sum {
+            dict.insert(key("md5sum"), string(md5sum));
+        }
+
+        self.insert_attr(&mut dict);
+
+        // Paths are only ever built from UTF-8 strings in the first place.
+        let path = Bencode::List(
+            self.path
+                .iter()
+                .map(|component| string(&component.to_string_lossy()))
+                .collect(),
+        );
+
+        match &self.raw_path {
+            Some(raw_path) => {
+                dict.insert(
+                    key("path"),
+                    Bencode::List(
+                        raw_path
+                            .iter()
+                            .map(|component| Bencode::ByteString(component.clone().into()))
+                            .collect(),
+                    ),
+                );
+
+                if dict.get(b"path.utf-8" as &[u8]).is_some_and(is_utf8_hint) {
+                    dict.insert(key("path.utf-8"), path);
+                }
+            }
+            None => {
+                dict.insert(key("path"), path);
+            }
+        }
+
+        Bencode::Dict(dict)
+    }
+
+    fn insert_extra(&self, dict: &mut BTreeMap<Bytes, Bencode>) {
+        for (key, val) in &self.extra {
+            dict.insert(Bytes::copy_from_slice(key), val.clone());
+        }
+    }
+
+    fn insert_attr(&self, dict: &mut BTreeMap<Bytes, Bencode>) {
+        if !self.attr.is_empty() {
+            dict.insert(
+                key("attr"),
+                Bencode::ByteString(Bytes::copy_from_slice(&self.attr.0)),
+            );
+        }
+
+        if let Some(symlink_path) = &self.symlink_path {
+            dict.insert(
+                key("symlink path"),
+                Bencode::List(
+                    symlink_path
+                        .iter()
+                        .map(|component| string(&component.to_string_lossy()))
+                        .collect(),
+                ),
+            );
+        }
+    }
+}
+
+/// Converts a field that has to be there, telling apart one that's missing
+/// from one that's there but has the wrong type.
+fn required<T>(field: &str, val: Option<Bencode>) -> Result<T, TorrentParsingError>
+where
+    T: TryFrom<Bencode, Error = BencodeConversionError>,
+{
+    T::try_from(val.context(FieldNotFound { field })?).map_err(|e| match e {
+        BencodeConversionError::UnexpectedType { expected, found } => {
+            TorrentParsingError::WrongType {
+                field: field.to_owned(),
+                expected,
+                found,
+            }
+        }
+        BencodeConversionError::NotUtf8 { source } => TorrentParsingError::InvalidString {
+            source: source.utf8_error(),
+        },
+        BencodeConversionError::NumberTooBig { .. } => TorrentParsingError::NumberTooBig {
+            field: field.to_owned(),
+        },
+    })
+}
+
+/// How many leaves the merkle tree for `len` bytes has, which is always a
+/// power of two.
+fn leaves_len_for(len: u64) -> usize {
+    len.div_ceil(MERKLE_BLOCK_LEN).next_power_of_two() as usize
+}
+
+/// Builds a merkle tree up from `leaves`, padded out to `len` leaves with
+/// zeroes like BEP 52 has it, returning its root.
+fn merkle_root(mut layer: Vec<[u8; 32]>, len: usize) -> [u8; 32] {
+    layer.resize(len, [0; 32]);
+
+    while layer.len() > 1 {
+        layer = layer
+            .chunks(2)
+            .map(|pair| {
+                Sha256::new()
+                    .chain(pair[0])
+                    .chain(pair[1])
+                    .finalize()
+                    .into()
+            })
+            .collect();
+    }
+
+    layer[0]
+}
+
+fn parse_piece_layers(piece_layers: Bencode) -> Result<PieceLayers, TorrentParsingError> {
+    piece_layers
+        .dict()
+        .context(InvalidPieceLayers)?
+        .into_iter()
+        .map(|(root, layer)| {
+            let root = root.as_ref().try_into().ok().context(InvalidPieceLayers)?;
+            let layer = layer.byte_string().context(InvalidPieceLayers)?;
+            let (hashes, remainder) = layer.as_chunks();
+
+            ensure!(
+                remainder.is_empty() && !hashes.is_empty(),
+                InvalidPieceLayers
+            );
+
+            Ok((root, hashes.to_vec()))
+        })
+        .collect()
+}
+
+fn parse_md5sum(md5sum: Option<Bencode>) -> Result<Option<String>, TorrentParsingError> {
+    md5sum
+        .map(|val| {
+            let md5sum = val.byte_string().context(InvalidMd5sum)?;
+
+            ensure!(
+                md5sum.len() == 32 && md5sum.iter().all(u8::is_ascii_hexdigit),
+                InvalidMd5sum
+            );
+
+            // Only ASCII hex digits are left, so this is always valid UTF-8.
+            Ok(String::from_utf8(md5sum.to_vec()).unwrap())
+        })
+        .transpose()
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct SHA1Hash(pub [u8; 20]);
+
+impl AsRef<[u8; 20]> for SHA1Hash {
+    fn as_ref(&self) -> &[u8; 20] {
+        &self.0
+    }
+}
+
+impl fmt::Display for SHA1Hash {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for byte in &self.0 {
+            write!(f, "{:02x}", byte)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Debug for SHA1Hash {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl FromStr for SHA1Hash {
+    type Err = SHA1HashParsingError;
+
+    fn from_str(hash_str: &str) -> Result<Self, Self::Err> {
+        let digits = hash_str
+            .chars()
+            .map(|c| c.to_digit(16).context(InvalidHexCharacter { character: c }))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        ensure!(digits.len() == 40, InvalidHashLength { len: digits.len() });
+
+        let mut hash = [0; 20];
+
+        for (byte, pair) in hash.iter_mut().zip(digits.chunks(2)) {
+            *byte = (pair[0] << 4 | pair[1]) as u8;
+        }
+
+        Ok(Self(hash))
+    }
+}
+
+/// Lowercase hex, which is how hashes are written down everywhere that isn't
+/// bencode.
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// A SHA-256 hash back from the 64 hex characters `to_hex` makes of it.
+pub fn sha256_from_hex(hex: &str) -> Option<[u8; 32]> {
+    let digits = hex
+        .chars()
+        .map(|c| c.to_digit(16))
+        .collect::<Option<Vec<_>>>()?;
+
+    if digits.len() != 64 {
+        return None;
+    }
+
+    let mut hash = [0; 32];
+
+    for (byte, pair) in hash.iter_mut().zip(digits.chunks(2)) {
+        *byte = (pair[0] << 4 | pair[1]) as u8;
+    }
+
+    Some(hash)
+}
+
+#[cfg(feature = "serde")]
+impl Serialize for SHA1Hash {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> Deserialize<'de> for SHA1Hash {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// Serializes v2 pieces roots as hex, the same way as `SHA1Hash`.
+#[cfg(feature = "serde")]
+mod hex_pieces_root {
+    use super::*;
+    use serde::de::Error;
+
+    pub fn serialize<S: Serializer>(
+        pieces_root: &Option<[u8; 32]>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        pieces_root
+            .as_ref()
+            .map(|hash| to_hex(hash))
+            .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<[u8; 32]>, D::Error> {
+        Option::<String>::deserialize(deserializer)?
+            .map(|hex| from_hex(&hex))
+            .transpose()
+    }
+
+    pub fn from_hex<E: Error>(hex: &str) -> Result<[u8; 32], E> {
+        sha256_from_hex(hex).ok_or_else(|| E::custom("expected 64 hex characters"))
+    }
+}
+
+/// Unknown keys could have anything in them, so they're kept as bencode.
+#[cfg(feature = "serde")]
+mod bencoded_extra {
+    use super::*;
+    use crate::bencode_parser::parse_bencode;
+    use serde::de::Error;
+
+    pub fn serialize<S: Serializer>(
+        extra: &BTreeMap<Vec<u8>, Bencode>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let dict = extra
+            .iter()
+            .map(|(key, val)| (Bytes::copy_from_slice(key), val.clone()))
+            .collect();
+
+        encode(&Bencode::Dict(dict)).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<BTreeMap<Vec<u8>, Bencode>, D::Error> {
+        let bytes = Vec::<u8>::deserialize(deserializer)?;
+        let dict = parse_bencode(&bytes)
+            .ok()
+            .and_then(|(_, bencode)| bencode.dict())
+            .ok_or_else(|| D::Error::custom("expected a bencoded dict"))?;
+
+        Ok(dict
+            .into_iter()
+            .map(|(key, val)| (key.to_vec(), val))
+            .collect())
+    }
+}
+
+/// JSON only has string keys, so the roots and hashes are all hex.
+#[cfg(feature = "serde")]
+mod hex_piece_layers {
+    use super::{hex_pieces_root::from_hex, *};
+
+    pub fn serialize<S: Serializer>(
+        piece_layers: &PieceLayers,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        piece_layers
+            .iter()
+            .map(|(root, layer)| {
+                (
+                    to_hex(root),
+                    layer.iter().map(|hash| to_hex(hash)).collect::<Vec<_>>(),
+                )
+            })
+            .collect::<BTreeMap<_, _>>()
+            .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<PieceLayers, D::Error> {
+        BTreeMap::<String, Vec<String>>::deserialize(deserializer)?
+            .into_iter()
+            .map(|(root, layer)| {
+                Ok((
+                    from_hex(&root)?,
+                    layer
+                        .iter()
+                        .map(|hash| from_hex(hash))
+                        .collect::<Result<_, _>>()?,
+                ))
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Snafu)]
+pub enum SHA1HashParsingError {
+    #[snafu(display("Expected 40 hex characters, found {}", len))]
+    InvalidHashLength { len: usize },
+    #[snafu(display("Found non-hex character {:?}", character))]
+    InvalidHexCharacter { character: char },
+}
+
+/// Something about a torrent that's unusual, but not enough so to stop it
+/// from being used.
+#[non_exhaustive]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ParseWarning {
+    /// A key we don't know about, which is just ignored.
+    UnknownKey { field: String },
+    /// An optional field that had the wrong type, so it was left out.
+    MalformedField { field: String },
+    /// There's an announce-list, so clients that support it will never use
+    /// this announce URL.
+    IgnoredAnnounce { announce: String },
+}
+
+impl fmt::Display for ParseWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnknownKey { field } => write!(f, "Unknown field {}", field),
+            Self::MalformedField { field } => write!(f, "Ignored malformed field {}", field),
+            Self::IgnoredAnnounce { announce } => {
+                write!(
+                    f,
+                    "announce {} isn't in announce-list, so it'll be ignored",
+                    announce
+                )
+            }
+        }
+    }
+}
+
+#[derive(Debug, Snafu)]
+pub enum TorrentError {
+    #[snafu(display("Couldn't read torrent file"))]
+    Io { source: io::Error },
+    #[snafu(display("Couldn't parse torrent file"))]
+    Parse { source: TorrentParsingError },
+}
+
+#[non_exhaustive]
+#[derive(Debug, Snafu)]
+pub enum TorrentParsingError {
+    #[snafu(display("Expected a dictionary, but didn't find it"))]
+    NotADict,
+    #[snafu(display("Attempted to decode an invalid string"))]
     InvalidString { source: str::Utf8Error },
     #[snafu(display("Couldn't find field {}", field))]
     FieldNotFound { field: String },
+    #[snafu(display("Expected field {} to be {}, found {}", field, expected, found))]
+    WrongType {
+        field: String,
+        expected: BencodeKind,
+        found: BencodeKind,
+    },
+    #[snafu(display("Field {} is too big a number", field))]
+    NumberTooBig { field: String },
     #[snafu(display("Invalid piece length"))]
     InvalidPieceLen { source: num::TryFromIntError },
+    #[snafu(display(
+        "Piece length {} isn't a power of two between {} and {}",
+        piece_len,
+        MIN_PIECE_LEN,
+        MAX_PIECE_LEN
+    ))]
+    InvalidPieceLenValue { piece_len: u64 },
     #[snafu(display("Invalid file length"))]
     InvalidFileLen { source: num::TryFromIntError },
+    #[snafu(display("Files add up to more bytes than fit in a u64"))]
+    TotalLengthOverflow,
     #[snafu(display("Invalid file path: not a list of strings"))]
     InvalidPath,
     #[snafu(display("Found a piece with length < 20"))]
     MismatchedPieceLength,
-    #[snafu(display("Provided bytes aren't valid bencode"))]
-    InvalidBencode,
+    #[snafu(display("Torrent has no pieces"))]
+    NoPieces,
+    #[snafu(display("Invalid bencode: expected {} at byte {}", expected, offset))]
+    InvalidBencode {
+        offset: usize,
+        expected: &'static str,
+    },
+    #[snafu(display("Found both info[files] and info[length], can't tell if this is a single or multi-file torrent"))]
+    AmbiguousFileMode,
+    #[snafu(display("Couldn't find either info[files] or info[length]"))]
+    MissingFileMode,
+    #[snafu(display("Invalid announce-list: not a list of lists of strings"))]
+    InvalidAnnounceList,
+    #[snafu(display("Invalid file tree"))]
+    InvalidFileTree,
+    #[snafu(display("Invalid md5sum: not 32 hex characters"))]
+    InvalidMd5sum,
+    #[snafu(display("Invalid url-list: not a string or list of strings"))]
+    InvalidUrlList,
+    #[snafu(display("Piece length is 0"))]
+    ZeroPieceLen,
+    #[snafu(display(
+        "Expected {} pieces for the torrent's length, found {}",
+        expected,
+        found
+    ))]
+    InconsistentPieceCount { expected: u64, found: usize },
+    #[snafu(display("Unsafe path component {:?}", component))]
+    UnsafePath { component: String },
+    #[snafu(display(
+        "File {} in info[file tree] isn't the same in info[files]",
+        path.display()
+    ))]
+    MismatchedFileTree { path: PathBuf },
+    #[snafu(display(
+        "Invalid piece layers: not a dict of 32-byte roots to hashes for each piece"
+    ))]
+    InvalidPieceLayers,
+    #[snafu(display("No piece layer for {}'s pieces root", path.display()))]
+    MissingPieceLayer { path: PathBuf },
+}
+
+impl From<nom::Err<BencodeParsingError>> for TorrentParsingError {
+    fn from(e: nom::Err<BencodeParsingError>) -> Self {
+        let (offset, expected) = match e {
+            nom::Err::Error(e) | nom::Err::Failure(e) => match e {
+                BencodeParsingError::InvalidBencode { offset, expected } => (offset, expected),
+                BencodeParsingError::InvalidNumber { offset, .. } => (offset, "a valid number"),
+                BencodeParsingError::TooDeep { offset } => (offset, "a less deeply nested value"),
+                BencodeParsingError::TrailingData { offset } => (offset, "the end of the torrent"),
+                _ => (0, "valid bencode"),
+            },
+            // We only use complete parsers, which never ask for more input.
+            nom::Err::Incomplete(_) => (0, "more input"),
+        };
+
+        Self::InvalidBencode { offset, expected }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{bencode_parser::parse_bencode, test_util};
+
+    const SINGLE_FILE_TORRENT: &[u8] = include_bytes!("../tests/fixtures/single_file.torrent");
+    const HYBRID_TORRENT: &[u8] = include_bytes!("../tests/fixtures/hybrid.torrent");
+
+    #[test]
+    fn single_file() {
+        let torrent = Torrent::try_from(SINGLE_FILE_TORRENT).unwrap();
+
+        assert_eq!(torrent.info.name, "hello.txt");
+        assert_eq!(torrent.info.files.len(), 1);
+        assert_eq!(torrent.info.files[0].length, 40000);
+        assert_eq!(torrent.info.files[0].path, PathBuf::from("hello.txt"));
+        assert_eq!(torrent.info.pieces.len(), 3);
+    }
+
+    fn hashed_info(data: &[u8], piece_len: usize) -> TorrentInfo {
+        test_util::info("a", data, piece_len as u64, &[("a", data.len() as u64)])
+    }
+
+    #[test]
+    fn verify_piece() {
+        let data = (0..100u8).collect::<Vec<_>>();
+        let info = hashed_info(&data, 32);
+
+        assert!(info.verify_piece(0, &data[..32]));
+        assert!(info.verify_piece(3, &data[96..]));
+        assert!(!info.verify_piece(1, &data[..32]));
+        assert!(!info.verify_piece(4, &data[96..]));
+
+        let mut corrupt = data[32..64].to_vec();
+        corrupt[5] ^= 1;
+
+        assert!(!info.verify_piece(1, &corrupt));
+    }
+
+    fn sha256(data: &[u8]) -> [u8; 32] {
+        Sha256::digest(data).into()
+    }
+
+    fn sha256_pair(left: [u8; 32], right: [u8; 32]) -> [u8; 32] {
+        sha256(&[left, right].concat())
+    }
+
+    #[test]
+    fn verify_piece_v2() {
+        const BLOCK: usize = MERKLE_BLOCK_LEN as usize;
+
+        // 5 blocks in 32 KiB pieces, so the last piece is only half there.
+        let big = (0..5 * BLOCK).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        // 3 blocks, the last one short, all in one 64 KiB piece.
+        let small = (0..2 * BLOCK + 100)
+            .map(|i| (i % 241) as u8)
+            .collect::<Vec<_>>();
+
+        let blocks = big.chunks(BLOCK).map(sha256).collect::<Vec<_>>();
+        let layer = vec![
+            sha256_pair(blocks[0], blocks[1]),
+            sha256_pair(blocks[2], blocks[3]),
+            sha256_pair(blocks[4], [0; 32]),
+        ];
+        let big_root = [1; 32];
+
+        let small_blocks = small.chunks(BLOCK).map(sha256).collect::<Vec<_>>();
+        let small_root = sha256_pair(
+            sha256_pair(small_blocks[0], small_blocks[1]),
+            sha256_pair(small_blocks[2], [0; 32]),
+        );
+
+        let mut info = TorrentInfo {
+            meta_version: Some(2),
+            ..test_util::info(
+                "a",
+                &[],
+                2 * BLOCK as u64,
+                &[("big", big.len() as u64), ("small", small.len() as u64)],
+            )
+        };
+        info.files[0].pieces_root = Some(big_root);
+        info.files[1].pieces_root = Some(small_root);
+        let piece_layers = vec![(big_root, layer)].into_iter().collect();
+
+        for (index, piece) in big.chunks(2 * BLOCK).enumerate() {
+            assert!(info.verify_piece_v2(&piece_layers, 0, index, piece));
+        }
+
+        let mut corrupt = big[..2 * BLOCK].to_vec();
+        corrupt[BLOCK + 7] ^= 1;
+
+        assert!(!info.verify_piece_v2(&piece_layers, 0, 0, &corrupt));
+        assert!(!info.verify_piece_v2(&piece_layers, 0, 1, &big[..2 * BLOCK]));
+        assert!(!info.verify_piece_v2(&piece_layers, 0, 2, &big[4 * BLOCK..4 * BLOCK + 1]));
+        assert!(!info.verify_piece_v2(&piece_layers, 0, 3, &[]));
+        assert!(!info.verify_piece_v2(&PieceLayers::new(), 0, 0, &big[..2 * BLOCK]));
+
+        // The small file's root is the hash of its only piece.
+        let info = TorrentInfo {
+            piece_len: 4 * BLOCK as u64,
+            ..info
+        };
+
+        assert!(info.verify_piece_v2(&PieceLayers::new(), 1, 0, &small));
+        assert!(!info.verify_piece_v2(&PieceLayers::new(), 1, 0, &small[..small.len() - 1]));
+        assert!(!info.verify_piece_v2(&PieceLayers::new(), 2, 0, &small));
+
+        // The fixture's pieces are one block each, so its piece layer is the
+        // tree's leaves, padded with zeroes the same way.
+        let hybrid = Torrent::try_from(HYBRID_TORRENT).unwrap();
+        let root = hybrid.info.files[0].pieces_root.unwrap();
+
+        assert_eq!(merkle_root(hybrid.piece_layers[&root].clone(), 4), root);
+    }
+
+    #[test]
+    fn iter_pieces() {
+        let data = (0..100u8).collect::<Vec<_>>();
+        let info = hashed_info(&data, 32);
+        let pieces = info.iter_pieces().collect::<Vec<_>>();
+
+        assert_eq!(
+            pieces
+                .iter()
+                .map(|piece| (piece.index, piece.offset, piece.length))
+                .collect::<Vec<_>>(),
+            vec![(0, 0, 32), (1, 32, 32), (2, 64, 32), (3, 96, 4)]
+        );
+        assert!(pieces
+            .iter()
+            .all(|piece| piece.hash == info.pieces[piece.index]));
+    }
+
+    #[test]
+    fn iter_pieces_exact_multiple() {
+        let info = hashed_info(&[0; 64], 32);
+
+        assert_eq!(
+            info.iter_pieces()
+                .map(|piece| piece.length)
+                .collect::<Vec<_>>(),
+            vec![32, 32]
+        );
+    }
+
+    #[test]
+    fn blocks_for_full_piece() {
+        let info = TorrentInfo {
+            piece_len: 1 << 20,
+            pieces: vec![SHA1Hash([0; 20]); 2],
+            ..multi_file_info(&[(1 << 20) + 20000])
+        };
+        let blocks = info.blocks_for_piece(0, 16384).collect::<Vec<_>>();
+
+        assert_eq!(blocks.len(), 64);
+        assert!(blocks.iter().enumerate().all(|(i, block)| *block
+            == BlockMeta {
+                piece_index: 0,
+                begin: i as u32 * 16384,
+                length: 16384
+            }));
+    }
+
+    #[test]
+    fn blocks_for_short_final_piece() {
+        let info = TorrentInfo {
+            piece_len: 1 << 20,
+            pieces: vec![SHA1Hash([0; 20]); 2],
+            ..multi_file_info(&[(1 << 20) + 20000])
+        };
+
+        assert_eq!(
+            info.blocks_for_piece(1, 16384).collect::<Vec<_>>(),
+            vec![
+                BlockMeta {
+                    piece_index: 1,
+                    begin: 0,
+                    length: 16384
+                },
+                BlockMeta {
+                    piece_index: 1,
+                    begin: 16384,
+                    length: 20000 - 16384
+                }
+            ]
+        );
+        assert_eq!(info.blocks_for_piece(2, 16384).count(), 0);
+        assert_eq!(info.blocks_for_piece(1, 0).count(), 0);
+    }
+
+    #[test]
+    fn verify_file() {
+        let mut data = (0..100u8).collect::<Vec<_>>();
+        let info = hashed_info(&data, 32);
+
+        assert_eq!(info.verify_file(&data), vec![true; 4]);
+
+        data[40] ^= 0xff;
+
+        assert_eq!(info.verify_file(&data), vec![true, false, true, true]);
+        assert_eq!(
+            info.verify_file(&data[..98]),
+            vec![true, false, true, false]
+        );
+    }
+
+    #[test]
+    fn verify_piece_length() {
+        let info = hashed_info(&[0; 100], 32);
+
+        assert!(info.verify_piece_length(0, 32));
+        assert!(!info.verify_piece_length(2, 4));
+        assert!(info.verify_piece_length(3, 4));
+        assert!(!info.verify_piece_length(3, 32));
+        assert!(!info.verify_piece_length(4, 32));
+    }
+
+    fn multi_file_info(lengths: &[u64]) -> TorrentInfo {
+        let paths = (0..lengths.len())
+            .map(|i| i.to_string())
+            .collect::<Vec<_>>();
+        let files = paths
+            .iter()
+            .map(String::as_str)
+            .zip(lengths.iter().copied())
+            .collect::<Vec<_>>();
+
+        TorrentInfo {
+            multi_file: true,
+            ..test_util::info("a", &[], 10, &files)
+        }
+    }
+
+    #[test]
+    fn total_length_and_num_pieces() {
+        let torrent = Torrent::try_from(SINGLE_FILE_TORRENT).unwrap();
+
+        assert_eq!(torrent.info.total_length(), 40000);
+        assert_eq!(torrent.info.num_pieces(), 3);
+        assert!(torrent.info.check_consistency().is_ok());
+        assert_eq!(multi_file_info(&[4, 0, 3, 5]).total_length(), 12);
+    }
+
+    #[test]
+    fn inconsistent_piece_count() {
+        let mut info = hashed_info(&[0; 100], 32);
+        info.pieces.pop();
+
+        assert!(matches!(
+            info.check_consistency(),
+            Err(TorrentParsingError::InconsistentPieceCount {
+                expected: 4,
+                found: 3
+            })
+        ));
+
+        info.piece_len = 0;
+
+        assert!(matches!(
+            info.check_consistency(),
+            Err(TorrentParsingError::ZeroPieceLen)
+        ));
+    }
+
+    fn parse_piece_len(piece_len: i64) -> Result<u64, TorrentParsingError> {
+        let info = parse_bencode(
+            format!(
+                "d6:lengthi5e4:name1:a12:piece lengthi{}e6:pieces20:aaaaaaaaaaaaaaaaaaaae",
+                piece_len
+            )
+            .as_bytes(),
+        )
+        .unwrap()
+        .1;
+
+        TorrentInfo::try_from(info).map(|info| info.piece_len)
+    }
+
+    #[test]
+    fn piece_len_range() {
+        assert_eq!(parse_piece_len(16384).unwrap(), 16384);
+        assert_eq!(parse_piece_len(1 << 26).unwrap(), 1 << 26);
+
+        for &piece_len in &[0, 8192, 1 << 27, 1 << 40] {
+            assert!(matches!(
+                parse_piece_len(piece_len),
+                Err(TorrentParsingError::InvalidPieceLenValue { piece_len: found }) if found == piece_len as u64
+            ));
+        }
+    }
+
+    #[test]
+    fn piece_len_not_power_of_two() {
+        assert!(matches!(
+            parse_piece_len(16384 * 3),
+            Err(TorrentParsingError::InvalidPieceLenValue { piece_len: 49152 })
+        ));
+    }
+
+    #[test]
+    fn negative_piece_len() {
+        assert!(matches!(
+            parse_piece_len(-16384),
+            Err(TorrentParsingError::InvalidPieceLen { .. })
+        ));
+    }
+
+    #[test]
+    fn locate_within_file() {
+        let info = multi_file_info(&[10, 10]);
+
+        assert_eq!(
+            info.locate(10, 10),
+            vec![FileSpan {
+                file: 1,
+                offset: 0,
+                len: 10
+            }]
+        );
+    }
+
+    #[test]
+    fn locate_across_file_boundary() {
+        let info = multi_file_info(&[10, 10]);
+
+        assert_eq!(
+            info.locate(5, 10),
+            vec![
+                FileSpan {
+                    file: 0,
+                    offset: 5,
+                    len: 5
+                },
+                FileSpan {
+                    file: 1,
+                    offset: 0,
+                    len: 5
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn locate_across_three_files() {
+        let info = multi_file_info(&[4, 0, 3, 5]);
+
+        assert_eq!(
+            info.locate(2, 8),
+            vec![
+                FileSpan {
+                    file: 0,
+                    offset: 2,
+                    len: 2
+                },
+                FileSpan {
+                    file: 2,
+                    offset: 0,
+                    len: 3
+                },
+                FileSpan {
+                    file: 3,
+                    offset: 0,
+                    len: 3
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn locate_huge_ranges() {
+        let info = multi_file_info(&[10, 10]);
+
+        assert_eq!(
+            info.locate(5, u64::MAX),
+            vec![
+                FileSpan {
+                    file: 0,
+                    offset: 5,
+                    len: 5
+                },
+                FileSpan {
+                    file: 1,
+                    offset: 0,
+                    len: 10
+                }
+            ]
+        );
+        assert!(info.locate(u64::MAX, 1).is_empty());
+        assert!(info.locate(u64::MAX, u64::MAX).is_empty());
+    }
+
+    #[test]
+    fn file_offsets() {
+        let info = multi_file_info(&[4, 0, 3, 5]);
+        let offsets = info.file_offsets();
+
+        assert_eq!(offsets, vec![(0, 4), (4, 4), (4, 7), (7, 12)]);
+        assert!(offsets.windows(2).all(|pair| pair[0].1 == pair[1].0));
+        assert_eq!(offsets.last().unwrap().1, info.total_length());
+
+        for (file, &(start, end)) in offsets.iter().enumerate().filter(|(_, (s, e))| s != e) {
+            assert_eq!(
+                info.locate(start, end - start),
+                vec![FileSpan {
+                    file,
+                    offset: 0,
+                    len: end - start
+                }]
+            );
+        }
+    }
+
+    #[test]
+    fn file_progress() {
+        // With 10 byte pieces, these cover 0..10, 10..20, 20..30 and 30..32.
+        let mut info = multi_file_info(&[15, 0, 12, 5]);
+        info.pieces = vec![SHA1Hash([0; 20]); 4];
+
+        let mut completed = Bitfield::new(4);
+
+        assert_eq!(info.file_progress(&completed), vec![0.0, 1.0, 0.0, 0.0]);
+
+        completed.set(1);
+        completed.set(3);
+
+        // Piece 1 is split between the first and third files, and piece 3 is
+        // the end of the last one.
+        assert_eq!(
+            info.file_progress(&completed),
+            vec![5.0 / 15.0, 1.0, 5.0 / 12.0, 2.0 / 5.0]
+        );
+
+        completed.set(0);
+        completed.set(2);
+
+        assert_eq!(info.file_progress(&completed), vec![1.0; 4]);
+    }
+
+    #[test]
+    fn write_round_trip() {
+        let torrent = Torrent::try_from(SINGLE_FILE_TORRENT).unwrap();
+        let mut written = Vec::new();
+
+        torrent.write_to(&mut written).unwrap();
+
+        assert_eq!(written, SINGLE_FILE_TORRENT);
+        assert_eq!(
+            Torrent::try_from(written.as_slice()).unwrap().info_hash(),
+            torrent.info_hash()
+        );
+    }
+
+    #[test]
+    fn write_round_trip_multi_file() {
+        let torrent_bytes = b"d8:announce31:http://one.example.com/announce4:infod5:filesld6:lengthi5e4:pathl3:dir1:aeed6:lengthi7e6:md5sum32:5d41402abc4b2a76b9719d911017c5924:pathl1:beee4:name1:a12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaa7:privatei1eee" as &[u8];
+        let torrent = Torrent::try_from(torrent_bytes).unwrap();
+        let mut written = Vec::new();
+
+        torrent.write_to(&mut written).unwrap();
+
+        assert_eq!(written, torrent_bytes);
+        assert_eq!(
+            Torrent::try_from(written.as_slice()).unwrap().info_hash(),
+            torrent.info_hash()
+        );
+    }
+
+    #[test]
+    fn file_attrs() {
+        let torrent_bytes = b"d8:announce31:http://one.example.com/announce4:infod5:filesld4:attr1:x6:lengthi5e4:pathl3:runeed4:attr1:l6:lengthi0e4:pathl4:linke12:symlink pathl3:runeee4:name1:a12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaaee" as &[u8];
+        let torrent = Torrent::try_from(torrent_bytes).unwrap();
+        let (run, link) = (&torrent.info.files[0], &torrent.info.files[1]);
+
+        assert!(run.attr.is_executable() && !run.attr.is_symlink());
+        assert_eq!(run.symlink_path, None);
+        assert!(link.attr.is_symlink() && !link.attr.is_executable());
+        assert_eq!(link.symlink_path, Some(PathBuf::from("run")));
+
+        let mut written = Vec::new();
+        torrent.write_to(&mut written).unwrap();
+
+        assert_eq!(written, torrent_bytes);
+
+        let attr = FileAttr::parse(b"hpz?");
+
+        assert!(attr.is_hidden() && attr.is_padding());
+        assert!(!attr.is_symlink() && !attr.is_executable());
+        assert!(FileAttr::parse(b"").is_empty());
+
+        // Flags we don't know and the order they're in both survive.
+        let torrent_bytes = b"d8:announce31:http://one.example.com/announce4:infod5:filesld4:attr3:zxh6:lengthi5e4:pathl3:runeed4:attr0:6:lengthi0e4:pathl5:emptyeee4:name1:a12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaaee" as &[u8];
+        let torrent = Torrent::try_from(torrent_bytes).unwrap();
+
+        assert_eq!(torrent.info.files[0].attr, FileAttr(b"zxh".to_vec()));
+        assert!(torrent.info.files[0].attr.is_executable());
+        assert!(torrent.info.files[1].attr.is_empty());
+
+        let mut written = Vec::new();
+        torrent.write_to(&mut written).unwrap();
+
+        assert_eq!(written, torrent_bytes);
+    }
+
+    /// Torrents come from anywhere, so nothing about one should be able to
+    /// crash us, no matter how it's been mangled.
+    #[test]
+    fn mangled_torrents_never_panic() {
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+
+        let mut rng = StdRng::seed_from_u64(0);
+
+        for _ in 0..2000 {
+            let mut torrent_bytes = if rng.gen() {
+                SINGLE_FILE_TORRENT.to_vec()
+            } else {
+                HYBRID_TORRENT.to_vec()
+            };
+
+            for _ in 0..rng.gen_range(1..8) {
+                let at = rng.gen_range(0..torrent_bytes.len());
+
+                match rng.gen_range(0..4) {
+                    0 => torrent_bytes[at] = rng.gen(),
+                    // Digits and structure are what's most likely to trip
+                    // up the parser.
+                    1 => torrent_bytes[at] = b"0123456789-ilde:"[rng.gen_range(0..16)],
+                    2 => torrent_bytes.truncate(at + 1),
+                    _ => torrent_bytes.insert(at, rng.gen()),
+                }
+            }
+
+            let _ = parse_bencode(&torrent_bytes);
+            let _ = crate::bencode_parser::from_reader(torrent_bytes.as_slice());
+            let _ = info_hash_only(&torrent_bytes);
+
+            if let Ok(torrent) = Torrent::try_from(torrent_bytes.as_slice()) {
+                let _ = torrent.info_hash();
+                let _ = torrent.info_hash_v2();
+                let _ = torrent.info.check_consistency();
+                let _ = torrent.info.file_offsets();
+                let _ = encode(&torrent.to_bencode());
+            }
+        }
+    }
+
+    #[test]
+    fn trailing_garbage() {
+        let with = |suffix: &[u8]| [SINGLE_FILE_TORRENT, suffix].concat();
+        let end = SINGLE_FILE_TORRENT.len();
+
+        assert!(Torrent::try_from(&with(b"\n")[..]).is_ok());
+        assert!(info_hash_only(&with(b"\n")).is_ok());
+
+        for torrent_bytes in [with(b"garbage"), with(SINGLE_FILE_TORRENT)] {
+            assert!(matches!(
+                Torrent::try_from(&torrent_bytes[..]),
+                Err(TorrentParsingError::InvalidBencode { offset, .. }) if offset == end
+            ));
+            assert!(matches!(
+                info_hash_only(&torrent_bytes),
+                Err(TorrentParsingError::InvalidBencode { offset, .. }) if offset == end
+            ));
+        }
+    }
+
+    #[test]
+    fn total_length_overflow() {
+        let file = "d6:lengthi9223372036854775807e4:pathl1:aee";
+
+        // Two of the biggest files a torrent can have still fit.
+        assert!(Torrent::try_from(
+            format!(
+                "d8:announce3:abc4:infod5:filesl{}{}e4:name1:a12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaaee",
+                file,
+                file.replace("1:a", "1:b"),
+            )
+            .as_bytes()
+        )
+        .is_ok());
+        assert!(matches!(
+            Torrent::try_from(
+                format!(
+                    "d8:announce3:abc4:infod5:filesl{}{}{}e4:name1:a12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaaee",
+                    file,
+                    file.replace("1:a", "1:b"),
+                    file.replace("1:a", "1:c"),
+                )
+                .as_bytes()
+            ),
+            Err(TorrentParsingError::TotalLengthOverflow)
+        ));
+    }
+
+    #[test]
+    fn info_hash() {
+        let torrent = Torrent::try_from(SINGLE_FILE_TORRENT).unwrap();
+
+        assert_eq!(
+            format!("{:?}", torrent.info_hash()),
+            "36f3cd180287d2b87add927771ce7d9b5fee8928"
+        );
+    }
+
+    #[test]
+    fn info_hash_without_parsing() {
+        for torrent_bytes in [SINGLE_FILE_TORRENT, HYBRID_TORRENT] {
+            assert_eq!(
+                info_hash_only(torrent_bytes).unwrap(),
+                Torrent::try_from(torrent_bytes).unwrap().info_hash()
+            );
+        }
+
+        assert!(matches!(
+            info_hash_only(b"d8:announce3:urle"),
+            Err(TorrentParsingError::FieldNotFound { .. })
+        ));
+    }
+
+    #[test]
+    fn info_span() {
+        let (_, (_, spans)) = parse_bencode_spanned(SINGLE_FILE_TORRENT).unwrap();
+        let info_bytes = &SINGLE_FILE_TORRENT[spans[b"info" as &[u8]].clone()];
+
+        assert_eq!(
+            SHA1Hash(Sha1::digest(info_bytes).as_slice().try_into().unwrap()),
+            Torrent::try_from(SINGLE_FILE_TORRENT).unwrap().info_hash()
+        );
+        assert_eq!(info_bytes, info_dict_bytes(SINGLE_FILE_TORRENT).unwrap());
+    }
+
+    #[test]
+    fn info_bytes() {
+        let torrent = Torrent::try_from(HYBRID_TORRENT).unwrap();
+        let info_bytes = torrent.info_bytes();
+
+        assert_eq!(
+            SHA1Hash(Sha1::digest(&info_bytes).as_slice().try_into().unwrap()),
+            torrent.info_hash()
+        );
+        assert_eq!(
+            Sha256::digest(&info_bytes).as_slice(),
+            torrent.info_hash_v2().unwrap()
+        );
+        assert_eq!(
+            info_bytes.as_ref(),
+            info_dict_bytes(HYBRID_TORRENT).unwrap()
+        );
+
+        let made = Torrent::new(torrent.announce.clone().unwrap(), torrent.info.clone());
+
+        assert_eq!(made.info_bytes(), info_bytes);
+    }
+
+    #[test]
+    fn info_in_announce() {
+        // The announce has something that looks just like an info key and its
+        // value, which a plain search for "info" would have hashed instead.
+        let info_bytes =
+            b"d6:lengthi5e4:name1:a12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaae";
+        let torrent_bytes = [
+            b"d8:announce29:http://tracker.info/4:infoi1e4:info" as &[u8],
+            info_bytes,
+            b"e",
+        ]
+        .concat();
+        let expected = SHA1Hash(Sha1::digest(info_bytes).as_slice().try_into().unwrap());
+
+        assert_eq!(
+            Torrent::try_from(torrent_bytes.as_slice())
+                .unwrap()
+                .info_hash(),
+            expected
+        );
+        assert_eq!(info_hash_only(&torrent_bytes).unwrap(), expected);
+
+        // Nor does an info dict nested somewhere else count.
+        assert!(matches!(
+            info_hash_only(b"d1:ad4:infodeee"),
+            Err(TorrentParsingError::FieldNotFound { .. })
+        ));
+        assert!(matches!(
+            info_hash_only(b"l4:infodee"),
+            Err(TorrentParsingError::NotADict)
+        ));
+    }
+
+    #[test]
+    fn v1_has_no_info_hash_v2() {
+        assert_eq!(
+            Torrent::try_from(SINGLE_FILE_TORRENT)
+                .unwrap()
+                .info_hash_v2(),
+            None
+        );
+    }
+
+    #[test]
+    fn hybrid_info_hashes() {
+        let torrent = Torrent::try_from(HYBRID_TORRENT).unwrap();
+
+        assert_eq!(torrent.info.meta_version, Some(2));
+        assert_eq!(
+            torrent.info_hash().to_string(),
+            "3154e75e19341b0d09c4b25265645ad5a336ce9a"
+        );
+        assert_eq!(
+            torrent.info_hash_v2().unwrap(),
+            [
+                0xd5, 0x82, 0xf9, 0x11, 0xe6, 0x85, 0x23, 0x68, 0x0a, 0xa1, 0x18, 0xa4, 0xad, 0x5f,
+                0x5d, 0x0b, 0x1f, 0xa4, 0x8b, 0x10, 0x50, 0xd6, 0x1f, 0x0b, 0x85, 0x79, 0xf5, 0x1e,
+                0xa9, 0x6d, 0xa4, 0x5e
+            ]
+        );
+        assert_eq!(
+            torrent.info.files[0].pieces_root.unwrap()[..4],
+            [0x7d, 0x9f, 0x01, 0xba]
+        );
+
+        let mut written = Vec::new();
+        torrent.write_to(&mut written).unwrap();
+
+        assert_eq!(
+            Torrent::try_from(written.as_slice())
+                .unwrap()
+                .info_hash_v2(),
+            torrent.info_hash_v2()
+        );
+    }
+
+    #[test]
+    fn torrent_versions() {
+        let hybrid = Torrent::try_from(HYBRID_TORRENT).unwrap().info;
+
+        // Everything from both halves is there.
+        assert_eq!(hybrid.version(), TorrentVersion::Hybrid);
+        assert_eq!(hybrid.num_pieces(), 3);
+        assert_eq!(hybrid.files.len(), 1);
+        assert_eq!(hybrid.files[0].length, 40000);
+        assert!(hybrid.files[0].pieces_root.is_some());
+        hybrid.check_consistency().unwrap();
+
+        let v1 = Torrent::try_from(SINGLE_FILE_TORRENT).unwrap().info;
+
+        assert_eq!(v1.version(), TorrentVersion::V1);
+        assert!(v1.version().has_v1() && !v1.version().has_v2());
+
+        let v2 = TorrentInfo::try_from(
+            parse_bencode(
+                format!(
+                    "d9:file treed1:ad0:d6:lengthi5e11:pieces root32:{}eee12:meta versioni2e4:name1:a12:piece lengthi16384ee",
+                    "a".repeat(32)
+                )
+                .as_bytes(),
+            )
+            .unwrap()
+            .1,
+        )
+        .unwrap();
+
+        assert_eq!(v2.version(), TorrentVersion::V2);
+        assert!(!v2.version().has_v1() && v2.version().has_v2());
+    }
+
+    #[test]
+    fn piece_layers() {
+        let torrent = Torrent::try_from(HYBRID_TORRENT).unwrap();
+        let root = torrent.info.files[0].pieces_root.unwrap();
+        let layer = &torrent.piece_layers[&root];
+
+        assert_eq!(torrent.piece_layers.len(), 1);
+        assert_eq!(layer.len(), 3);
+        assert_eq!(layer[0][..4], [0x90, 0xb8, 0x34, 0x66]);
+
+        // They're outside the info dict, so they don't change the info hash.
+        let mut written = Vec::new();
+        torrent.write_to(&mut written).unwrap();
+        let reparsed = Torrent::try_from(written.as_slice()).unwrap();
+
+        assert_eq!(reparsed.piece_layers, torrent.piece_layers);
+        assert_eq!(reparsed.info_hash_v2(), torrent.info_hash_v2());
+    }
+
+    #[test]
+    fn invalid_piece_layers() {
+        let root = "a".repeat(32);
+        let parse = |length: u64, piece_layers: &str| {
+            Torrent::try_from(
+                format!(
+                    "d8:announce31:http://one.example.com/announce4:infod9:file treed1:ad0:d6:lengthi{}e11:pieces root32:{}eee12:meta versioni2e4:name1:a12:piece lengthi16384ee{}e",
+                    length, root, piece_layers
+                )
+                .as_bytes(),
+            )
+        };
+
+        // Files that fit in one piece don't need a layer.
+        assert!(parse(16384, "").unwrap().piece_layers.is_empty());
+        assert!(matches!(
+            parse(16385, ""),
+            Err(TorrentParsingError::MissingPieceLayer { path }) if path == Path::new("a")
+        ));
+        assert!(matches!(
+            parse(
+                16385,
+                &format!(
+                    "12:piece layersd32:{}32:{}e",
+                    "b".repeat(32),
+                    "c".repeat(32)
+                )
+            ),
+            Err(TorrentParsingError::MissingPieceLayer { .. })
+        ));
+        assert_eq!(
+            parse(
+                16385,
+                &format!("12:piece layersd32:{}64:{}e", root, "c".repeat(64))
+            )
+            .unwrap()
+            .piece_layers[root.as_bytes()],
+            vec![[b'c'; 32]; 2]
+        );
+
+        // Too few hashes, a partial hash, and a root of the wrong length.
+        for piece_layers in &[
+            format!("12:piece layersd32:{}32:{}e", root, "c".repeat(32)),
+            format!("12:piece layersd32:{}40:{}e", root, "c".repeat(40)),
+            format!("12:piece layersd3:abc32:{}e", "c".repeat(32)),
+            "12:piece layersi1e".to_owned(),
+        ] {
+            assert!(matches!(
+                parse(16385, piece_layers),
+                Err(TorrentParsingError::InvalidPieceLayers)
+            ));
+        }
+    }
+
+    #[test]
+    fn mismatched_hybrid() {
+        let parse = |info: String| TorrentInfo::try_from(parse_bencode(info.as_bytes()).unwrap().1);
+        let root = "a".repeat(32);
+
+        // The file tree has b where info[files] has a.
+        assert!(matches!(
+            parse(format!(
+                "d5:filesld6:lengthi5e4:pathl1:aeee9:file treed1:bd0:d6:lengthi5e11:pieces root32:{}eee12:meta versioni2e4:name1:a12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaae",
+                root
+            )),
+            Err(TorrentParsingError::MismatchedFileTree { path }) if path == Path::new("b")
+        ));
+        // Same path, but different lengths.
+        assert!(matches!(
+            parse(format!(
+                "d5:filesld6:lengthi6e4:pathl1:aeee9:file treed1:ad0:d6:lengthi5e11:pieces root32:{}eee12:meta versioni2e4:name1:a12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaae",
+                root
+            )),
+            Err(TorrentParsingError::MismatchedFileTree { .. })
+        ));
+        // meta version 2 without a file tree at all.
+        assert!(matches!(
+            parse("d6:lengthi5e12:meta versioni2e4:name1:a12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaae".to_owned()),
+            Err(TorrentParsingError::FieldNotFound { field }) if field == "info[file tree]"
+        ));
+    }
+
+    #[test]
+    fn v2_file_tree() {
+        let torrent_bytes = format!(
+            "d8:announce31:http://one.example.com/announce4:infod9:file treed5:c.txtd0:d6:lengthi0eee3:dird5:a.txtd0:d6:lengthi5e11:pieces root32:{}ee3:subd5:b.txtd0:d6:lengthi7e11:pieces root32:{}eeeee12:meta versioni2e4:name1:a12:piece lengthi16384eee",
+            "a".repeat(32),
+            "b".repeat(32)
+        );
+        let torrent = Torrent::try_from(torrent_bytes.as_bytes()).unwrap();
+        let files = &torrent.info.files;
+
+        assert!(torrent.info.multi_file);
+        assert!(torrent.info.pieces.is_empty());
+        assert_eq!(
+            files.iter().map(|f| f.path.clone()).collect::<Vec<_>>(),
+            vec![
+                PathBuf::from("c.txt"),
+                PathBuf::from("dir/a.txt"),
+                PathBuf::from("dir/sub/b.txt")
+            ]
+        );
+        assert_eq!(
+            files.iter().map(|f| f.length).collect::<Vec<_>>(),
+            vec![0, 5, 7]
+        );
+        assert_eq!(files[0].pieces_root, None);
+        assert_eq!(files[1].pieces_root, Some([b'a'; 32]));
+        assert_eq!(files[2].pieces_root, Some([b'b'; 32]));
+
+        let mut written = Vec::new();
+        torrent.write_to(&mut written).unwrap();
+
+        assert_eq!(written, torrent_bytes.as_bytes());
+    }
+
+    #[test]
+    fn sha1_hash_display_round_trip() {
+        let hash = Torrent::try_from(SINGLE_FILE_TORRENT).unwrap().info_hash();
+
+        assert_eq!(hash.to_string().parse::<SHA1Hash>().unwrap(), hash);
+    }
+
+    #[test]
+    fn sha1_hash_from_uppercase() {
+        assert_eq!(
+            "36F3CD180287D2B87ADD927771CE7D9B5FEE8928"
+                .parse::<SHA1Hash>()
+                .unwrap()
+                .to_string(),
+            "36f3cd180287d2b87add927771ce7d9b5fee8928"
+        );
+    }
+
+    #[test]
+    fn sha1_hash_wrong_length() {
+        assert!(matches!(
+            "36f3cd18".parse::<SHA1Hash>(),
+            Err(SHA1HashParsingError::InvalidHashLength { len: 8 })
+        ));
+        assert!(matches!(
+            "36f3cd180287d2b87add927771ce7d9b5fee892800".parse::<SHA1Hash>(),
+            Err(SHA1HashParsingError::InvalidHashLength { len: 42 })
+        ));
+    }
+
+    #[test]
+    fn sha1_hash_non_hex() {
+        assert!(matches!(
+            "36f3cd180287d2b87add927771ce7d9b5fee892g".parse::<SHA1Hash>(),
+            Err(SHA1HashParsingError::InvalidHexCharacter { character: 'g' })
+        ));
+    }
+
+    #[test]
+    fn sha256_hex() {
+        let hash: [u8; 32] = (0..32).collect::<Vec<u8>>().try_into().unwrap();
+        let hex = to_hex(&hash);
+
+        assert_eq!(sha256_from_hex(&hex), Some(hash));
+        assert_eq!(sha256_from_hex(&hex.to_uppercase()), Some(hash));
+        assert_eq!(sha256_from_hex(&hex[2..]), None);
+        // from_str_radix would let a sign through.
+        assert_eq!(sha256_from_hex(&format!("+f{}", &hex[2..])), None);
+    }
+
+    #[test]
+    fn optional_metadata() {
+        let torrent = Torrent::try_from(
+            b"d8:announce31:http://one.example.com/announce7:comment5:hello10:created by6:matey013:creation datei1609459200e8:encoding5:UTF-84:infod6:lengthi5e4:name1:a12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaaee" as &[u8],
+        )
+        .unwrap();
+
+        assert_eq!(torrent.comment.as_deref(), Some("hello"));
+        assert_eq!(torrent.created_by.as_deref(), Some("matey0"));
+        assert_eq!(torrent.creation_date, Some(1609459200));
+        assert_eq!(torrent.encoding.as_deref(), Some("UTF-8"));
+    }
+
+    #[test]
+    fn parse_warnings() {
+        for fixture in &[SINGLE_FILE_TORRENT, HYBRID_TORRENT] {
+            assert_eq!(Torrent::try_from_with_warnings(fixture).unwrap().1, vec![]);
+        }
+
+        let (torrent, warnings) = Torrent::try_from_with_warnings(
+            b"d8:announce31:http://one.example.com/announce13:announce-listll31:http://two.example.com/announceee13:creation date3:now4:infod6:lengthi5e4:name1:a12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaa6:source5:matey7:privatei1ee5:extrai1ee",
+        )
+        .unwrap();
+
+        assert_eq!(torrent.creation_date, None);
+        assert_eq!(torrent.info.name, "a");
+        assert_eq!(
+            warnings,
+            vec![
+                ParseWarning::IgnoredAnnounce {
+                    announce: "http://one.example.com/announce".to_owned()
+                },
+                ParseWarning::MalformedField {
+                    field: "creation date".to_owned()
+                },
+                ParseWarning::UnknownKey {
+                    field: "info[source]".to_owned()
+                },
+                ParseWarning::UnknownKey {
+                    field: "extra".to_owned()
+                },
+            ]
+        );
+        assert_eq!(warnings[3].to_string(), "Unknown field extra");
+
+        // Errors are still errors.
+        assert!(matches!(
+            Torrent::try_from_with_warnings(b"d5:extrai1ee"),
+            Err(TorrentParsingError::FieldNotFound { .. })
+        ));
+    }
+
+    #[test]
+    fn extra_info_keys_round_trip() {
+        let info_bytes = b"d6:lengthi5e4:name1:a12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaa6:source5:matey1:zli1ei2eee" as &[u8];
+        let torrent = Torrent::try_from(
+            [
+                b"d8:announce31:http://one.example.com/announce4:info" as &[u8],
+                info_bytes,
+                b"e",
+            ]
+            .concat()
+            .as_slice(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            torrent.info.extra.get(b"source" as &[u8]),
+            Some(&Bencode::ByteString("matey".into()))
+        );
+        assert_eq!(torrent.info.extra.len(), 2);
+        assert_eq!(encode(&torrent.info.to_bencode()), info_bytes);
+
+        // Editing and saving the torrent keeps it the same torrent.
+        let mut edited = Torrent::new(
+            "http://two.example.com/announce".to_owned(),
+            torrent.info.clone(),
+        );
+        edited.comment = Some("edited".to_owned());
+        let mut written = Vec::new();
+        edited.write_to(&mut written).unwrap();
+
+        assert_eq!(edited.info_hash(), torrent.info_hash());
+        assert_eq!(
+            Torrent::try_from(written.as_slice()).unwrap().info_hash(),
+            torrent.info_hash()
+        );
+
+        #[cfg(feature = "serde")]
+        {
+            let json = serde_json::to_string(&torrent).unwrap();
+            let deserialized: Torrent = serde_json::from_str(&json).unwrap();
+
+            assert_eq!(deserialized.info.extra, torrent.info.extra);
+            assert_eq!(deserialized.info_hash(), torrent.info_hash());
+        }
+    }
+
+    #[test]
+    fn odd_values_round_trip() {
+        let info_bytes = b"d5:filesld4:attri1e6:lengthi5e4:pathl1:ae4:sha120:bbbbbbbbbbbbbbbbbbbbee12:meta version1:24:name1:a12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaa7:privatei0ee" as &[u8];
+        let (torrent, warnings) = Torrent::try_from_with_warnings(
+            [
+                b"d8:announce31:http://one.example.com/announce4:info",
+                info_bytes,
+                b"e",
+            ]
+            .concat()
+            .as_slice(),
+        )
+        .unwrap();
+        let info = torrent.info;
+
+        assert!(!info.private);
+        assert_eq!(info.meta_version, None);
+        assert!(info.files[0].attr.is_empty());
+        assert_eq!(
+            info.files[0].extra.get(b"sha1" as &[u8]),
+            Some(&Bencode::ByteString("bbbbbbbbbbbbbbbbbbbb".into()))
+        );
+        assert_eq!(
+            warnings,
+            vec![ParseWarning::MalformedField {
+                field: "info[meta version]".to_owned()
+            }]
+        );
+        assert_eq!(encode(&info.to_bencode()), info_bytes);
+
+        #[cfg(feature = "serde")]
+        {
+            let json = serde_json::to_string(&info).unwrap();
+            let deserialized: TorrentInfo = serde_json::from_str(&json).unwrap();
+
+            assert_eq!(encode(&deserialized.to_bencode()), info_bytes);
+        }
+    }
+
+    #[test]
+    fn from_file() {
+        let torrent = Torrent::from_file("tests/fixtures/hybrid.torrent").unwrap();
+
+        assert_eq!(torrent, Torrent::try_from(HYBRID_TORRENT).unwrap());
+        assert!(matches!(
+            Torrent::from_file("tests/fixtures/missing.torrent"),
+            Err(TorrentError::Io { .. })
+        ));
+        assert!(matches!(
+            Torrent::from_file("Cargo.toml"),
+            Err(TorrentError::Parse { .. })
+        ));
+    }
+
+    #[test]
+    // The info hash cache is the only interior mutability, and it never changes
+    // what a torrent hashes to.
+    #[allow(clippy::mutable_key_type)]
+    fn equality_by_info_hash() {
+        use std::collections::HashSet;
+
+        let torrent = Torrent::try_from(SINGLE_FILE_TORRENT).unwrap();
+        let mut same = Torrent::try_from(SINGLE_FILE_TORRENT).unwrap();
+        same.announce = Some("http://other.example.com/announce".to_owned());
+        let other = Torrent::try_from(HYBRID_TORRENT).unwrap();
+
+        assert_eq!(torrent, same);
+        assert_ne!(torrent, other);
+
+        let torrents = vec![torrent, same, other]
+            .into_iter()
+            .collect::<HashSet<_>>();
+
+        assert_eq!(torrents.len(), 2);
+    }
+
+    #[test]
+    fn announce_wrong_type() {
+        let error = Torrent::try_from(
+            b"d8:announcei5e4:infod6:lengthi5e4:name1:a12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaaee"
+                as &[u8],
+        )
+        .unwrap_err();
+
+        assert!(matches!(
+            &error,
+            TorrentParsingError::WrongType {
+                field,
+                expected: BencodeKind::ByteString,
+                found: BencodeKind::Integer,
+            } if field == "announce"
+        ));
+        assert_eq!(
+            error.to_string(),
+            "Expected field announce to be a byte string, found an integer"
+        );
+    }
+
+    #[test]
+    fn collects_every_missing_field() {
+        let errors = Torrent::try_from_collecting(
+            b"d4:infod6:lengthi5e12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaaee"
+                as &[u8],
+        )
+        .unwrap_err();
+
+        assert!(matches!(
+            errors.as_slice(),
+            [
+                TorrentParsingError::FieldNotFound { field: announce },
+                TorrentParsingError::FieldNotFound { field: name },
+            ] if announce == "announce" && name == "info[name]"
+        ));
+    }
+
+    #[test]
+    fn collects_errors_from_files() {
+        let errors = Torrent::try_from_collecting(
+            b"d8:announce31:http://one.example.com/announce4:infod5:filesld4:pathl1:aeed6:lengthi5e4:pathl2:..eee4:name1:a6:pieces20:aaaaaaaaaaaaaaaaaaaaee" as &[u8],
+        )
+        .unwrap_err();
+
+        assert_eq!(errors.len(), 3);
+        assert!(matches!(
+            errors[0],
+            TorrentParsingError::FieldNotFound { .. }
+        ));
+        assert!(matches!(errors[1], TorrentParsingError::UnsafePath { .. }));
+        assert!(matches!(
+            errors[2],
+            TorrentParsingError::FieldNotFound { .. }
+        ));
+    }
+
+    #[test]
+    fn collecting_matches_try_from() {
+        assert_eq!(
+            Torrent::try_from_collecting(SINGLE_FILE_TORRENT).unwrap(),
+            Torrent::try_from(SINGLE_FILE_TORRENT).unwrap()
+        );
+    }
+
+    #[test]
+    fn no_optional_metadata() {
+        let torrent = Torrent::try_from(
+            b"d8:announce31:http://one.example.com/announce4:infod6:lengthi5e4:name1:a12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaaee" as &[u8],
+        )
+        .unwrap();
+
+        assert_eq!(torrent.comment, None);
+        assert_eq!(torrent.created_by, None);
+        assert_eq!(torrent.creation_date, None);
+        assert_eq!(torrent.encoding, None);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn json_round_trip() {
+        let torrent = Torrent::try_from(HYBRID_TORRENT).unwrap();
+        let json = serde_json::to_string(&torrent).unwrap();
+
+        assert!(json.contains(&format!("\"{}\"", torrent.info.pieces[0])));
+        assert!(json.contains(&format!(
+            "\"pieces_root\":\"{}\"",
+            to_hex(&torrent.info.files[0].pieces_root.unwrap())
+        )));
+
+        let deserialized: Torrent = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(deserialized.announce, torrent.announce);
+        assert_eq!(deserialized.info.pieces, torrent.info.pieces);
+        assert_eq!(deserialized.info.files[0].path, torrent.info.files[0].path);
+        assert_eq!(
+            deserialized.info.files[0].pieces_root,
+            torrent.info.files[0].pieces_root
+        );
+        assert_eq!(deserialized.piece_layers, torrent.piece_layers);
+        assert_eq!(deserialized.info_hash(), torrent.info_hash());
+        assert_eq!(deserialized.info_hash_v2(), torrent.info_hash_v2());
+    }
+
+    fn parse_url_list(url_list: &str) -> Result<Vec<String>, TorrentParsingError> {
+        Torrent::try_from(
+            format!(
+                "d8:announce31:http://one.example.com/announce4:infod6:lengthi5e4:name1:a12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaae{}e",
+                url_list
+            )
+            .as_bytes(),
+        )
+        .map(|torrent| torrent.url_list)
+    }
+
+    #[test]
+    fn url_list_string() {
+        assert_eq!(
+            parse_url_list("8:url-list24:http://seed.example.com/").unwrap(),
+            vec!["http://seed.example.com/".to_owned()]
+        );
+    }
+
+    #[test]
+    fn url_list_list() {
+        assert_eq!(
+            parse_url_list("8:url-listl22:http://one.example.com22:http://two.example.come")
+                .unwrap(),
+            vec![
+                "http://one.example.com".to_owned(),
+                "http://two.example.com".to_owned()
+            ]
+        );
+    }
+
+    #[test]
+    fn url_list_missing() {
+        assert!(parse_url_list("").unwrap().is_empty());
+        assert!(parse_url_list("8:url-list0:").unwrap().is_empty());
+        assert!(matches!(
+            parse_url_list("8:url-listi1e"),
+            Err(TorrentParsingError::InvalidUrlList)
+        ));
+    }
+
+    #[test]
+    fn announce_list_without_announce() {
+        let torrent = Torrent::try_from(
+            b"d13:announce-listll31:http://one.example.com/announce31:http://two.example.com/announceel26:udp://three.example.com:80ee4:infod6:lengthi5e4:name1:a12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaaee" as &[u8],
+        )
+        .unwrap();
+
+        assert_eq!(
+            torrent.announce.as_deref(),
+            Some("http://one.example.com/announce")
+        );
+        assert_eq!(
+            torrent.announce_list,
+            vec![
+                vec![
+                    "http://one.example.com/announce".to_owned(),
+                    "http://two.example.com/announce".to_owned()
+                ],
+                vec!["udp://three.example.com:80".to_owned()]
+            ]
+        );
+    }
+
+    #[test]
+    fn trackerless() {
+        let torrent_bytes = b"d4:infod6:lengthi5e4:name1:a12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaae5:nodesll9:127.0.0.1i6881eel3:::1i6882eeee" as &[u8];
+        let torrent = Torrent::try_from(torrent_bytes).unwrap();
+
+        assert_eq!(torrent.announce, None);
+        assert_eq!(
+            torrent.nodes,
+            vec![("127.0.0.1".to_owned(), 6881), ("::1".to_owned(), 6882)]
+        );
+
+        let mut written = Vec::new();
+        torrent.write_to(&mut written).unwrap();
+
+        assert_eq!(written, torrent_bytes);
+    }
+
+    #[test]
+    fn trackerless_with_hostnames() {
+        let torrent_bytes = b"d4:infod6:lengthi5e4:name1:a12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaae5:nodesll18:router.example.comi6881eeee" as &[u8];
+        let torrent = Torrent::try_from(torrent_bytes).unwrap();
+
+        assert_eq!(torrent.nodes, vec![("router.example.com".to_owned(), 6881)]);
+
+        let mut written = Vec::new();
+        torrent.write_to(&mut written).unwrap();
+
+        assert_eq!(written, torrent_bytes);
+    }
+
+    #[test]
+    fn malformed_nodes() {
+        let (torrent, warnings) = Torrent::try_from_with_warnings(
+            b"d4:infod6:lengthi5e4:name1:a12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaae5:nodesll9:127.0.0.1i6881eel18:router.example.comi70000eeli1eeee" as &[u8],
+        )
+        .unwrap();
+
+        assert_eq!(torrent.nodes, vec![("127.0.0.1".to_owned(), 6881)]);
+        assert_eq!(
+            warnings,
+            vec![ParseWarning::MalformedField {
+                field: "nodes".to_owned()
+            }]
+        );
+
+        // Without any nodes, there's no way to find peers at all.
+        assert!(matches!(
+            Torrent::try_from(
+                b"d4:infod6:lengthi5e4:name1:a12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaae5:nodeslee" as &[u8]
+            ),
+            Err(TorrentParsingError::FieldNotFound { field }) if field == "announce"
+        ));
+    }
+
+    #[test]
+    fn invalid_bencode_offset() {
+        let mut torrent_bytes = SINGLE_FILE_TORRENT.to_vec();
+        // Corrupt the first digit of info[length] so the number is invalid.
+        let offset = torrent_bytes
+            .windows(8)
+            .position(|w| w == b"lengthi4")
+            .unwrap()
+            + 7;
+        torrent_bytes[offset] = b'x';
+
+        assert!(matches!(
+            Torrent::try_from(torrent_bytes.as_slice()),
+            Err(TorrentParsingError::InvalidBencode { offset: o, expected: "a digit or '-'" }) if o == offset
+        ));
+    }
+
+    fn parse_private(private: &str) -> bool {
+        let info = parse_bencode(
+            format!(
+                "d6:lengthi5e4:name1:a12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaa{}e",
+                private
+            )
+            .as_bytes(),
+        )
+        .unwrap()
+        .1;
+
+        TorrentInfo::try_from(info).unwrap().private
+    }
+
+    #[test]
+    fn private_torrent() {
+        assert!(parse_private("7:privatei1e"));
+    }
+
+    #[test]
+    fn non_private_torrent() {
+        assert!(!parse_private(""));
+        assert!(!parse_private("7:privatei0e"));
+    }
+
+    #[test]
+    fn private_unexpected_value() {
+        assert!(!parse_private("7:privatei2e"));
+        assert!(!parse_private("7:private1:1"));
+    }
+
+    fn parse_file_md5sum(md5sum: &str) -> Result<Option<String>, TorrentParsingError> {
+        let file = parse_bencode(format!("d6:lengthi5e{}4:pathl1:aee", md5sum).as_bytes())
+            .unwrap()
+            .1;
+
+        TorrentFile::try_from(file).map(|file| file.md5sum)
+    }
+
+    #[test]
+    fn md5sum_present() {
+        assert_eq!(
+            parse_file_md5sum("6:md5sum32:5d41402abc4b2a76b9719d911017c592").unwrap(),
+            Some("5d41402abc4b2a76b9719d911017c592".to_owned())
+        );
+    }
+
+    #[test]
+    fn md5sum_absent() {
+        assert_eq!(parse_file_md5sum("").unwrap(), None);
+        assert_eq!(
+            Torrent::try_from(SINGLE_FILE_TORRENT).unwrap().info.files[0].md5sum,
+            None
+        );
+    }
+
+    #[test]
+    fn md5sum_malformed() {
+        assert!(matches!(
+            parse_file_md5sum("6:md5sum5:hello"),
+            Err(TorrentParsingError::InvalidMd5sum)
+        ));
+        assert!(matches!(
+            parse_file_md5sum("6:md5sum32:5d41402abc4b2a76b9719d911017c59z"),
+            Err(TorrentParsingError::InvalidMd5sum)
+        ));
+        assert!(matches!(
+            parse_file_md5sum("6:md5sumi5e"),
+            Err(TorrentParsingError::InvalidMd5sum)
+        ));
+    }
+
+    fn sanitized(components: &[&str]) -> PathBuf {
+        let mut info = multi_file_info(&[5]);
+        info.files[0].path = components.iter().collect();
+
+        info.sanitized_paths().remove(0)
+    }
+
+    #[test]
+    fn sanitized_reserved_names() {
+        assert_eq!(sanitized(&["CON"]), PathBuf::from("_CON"));
+        assert_eq!(
+            sanitized(&["dir", "nul.txt"]),
+            ["dir", "_nul.txt"].iter().collect::<PathBuf>()
+        );
+        assert_eq!(sanitized(&["Com1.tar.gz"]), PathBuf::from("_Com1.tar.gz"));
+        assert_eq!(sanitized(&["CONSOLE"]), PathBuf::from("CONSOLE"));
+        assert_eq!(sanitized(&["COM10"]), PathBuf::from("COM10"));
+    }
+
+    #[test]
+    fn sanitized_illegal_characters() {
+        assert_eq!(
+            sanitized(&["what?", "a<b>:c|d*.txt"]),
+            ["what_", "a_b__c_d_.txt"].iter().collect::<PathBuf>()
+        );
+        assert_eq!(sanitized(&["\"quoted\"\t"]), PathBuf::from("_quoted__"));
+        assert_eq!(sanitized(&["trailing."]), PathBuf::from("trailing_"));
+        assert_eq!(sanitized(&["fine.txt"]), PathBuf::from("fine.txt"));
+    }
+
+    #[test]
+    fn sanitized_paths_keep_raw_paths() {
+        let mut info = multi_file_info(&[5]);
+        info.files[0].path = PathBuf::from("a:b");
+
+        assert_eq!(info.sanitized_paths(), vec![PathBuf::from("a_b")]);
+        assert_eq!(info.files[0].path, PathBuf::from("a:b"));
+    }
+
+    fn parse_file_path(path: &str) -> Result<PathBuf, TorrentParsingError> {
+        let file = parse_bencode(format!("d6:lengthi5e4:pathl{}ee", path).as_bytes())
+            .unwrap()
+            .1;
+
+        TorrentFile::try_from(file).map(|file| file.path)
+    }
+
+    fn is_unsafe_path(result: Result<PathBuf, TorrentParsingError>, expected: &str) -> bool {
+        matches!(result, Err(TorrentParsingError::UnsafePath { component }) if component == expected)
+    }
+
+    #[test]
+    fn safe_path() {
+        assert_eq!(
+            parse_file_path("3:dir5:a.txt").unwrap(),
+            ["dir", "a.txt"].iter().collect::<PathBuf>()
+        );
+    }
+
+    #[test]
+    fn path_traversal() {
+        assert!(is_unsafe_path(
+            parse_file_path("2:..2:..3:etc6:passwd"),
+            ".."
+        ));
+        assert!(is_unsafe_path(parse_file_path("3:dir1:.1:a"), "."));
+        assert!(is_unsafe_path(parse_file_path("3:dir0:"), ""));
+    }
+
+    #[test]
+    fn path_embedded_separators() {
+        assert!(is_unsafe_path(
+            parse_file_path("12:../etc/hosts"),
+            "../etc/hosts"
+        ));
+        assert!(is_unsafe_path(parse_file_path("4:/etc"), "/etc"));
+        assert!(is_unsafe_path(parse_file_path("3:a\\b"), "a\\b"));
+        assert!(is_unsafe_path(parse_file_path("3:a\0b"), "a\0b"));
+    }
+
+    fn parse_file(file: &[u8]) -> TorrentFile {
+        TorrentFile::try_from(parse_bencode(file).unwrap().1).unwrap()
+    }
+
+    #[test]
+    fn utf8_path_only() {
+        let file = parse_file(b"d6:lengthi5e4:pathl3:dir5:a.txtee");
+
+        assert_eq!(file.path, ["dir", "a.txt"].iter().collect::<PathBuf>());
+        assert_eq!(file.raw_path, None);
+    }
+
+    #[test]
+    fn prefers_path_utf8() {
+        // Shift-JIS for "ファイル".
+        let file = parse_file(
+            b"d6:lengthi5e4:pathl8:\x83t\x83@\x83C\x83\x8be10:path.utf-8l12:\xe3\x83\x95\xe3\x82\xa1\xe3\x82\xa4\xe3\x83\xabee",
+        );
+
+        assert_eq!(file.path, PathBuf::from("ファイル"));
+        assert_eq!(
+            file.raw_path,
+            Some(vec![b"\x83t\x83@\x83C\x83\x8b".to_vec()])
+        );
+
+        // It's still preferred when path is valid UTF-8 too.
+        let file = parse_file(b"d6:lengthi5e4:pathl1:ae10:path.utf-8l1:bee");
+
+        assert_eq!(file.path, PathBuf::from("b"));
+        assert_eq!(file.raw_path, Some(vec![b"a".to_vec()]));
+    }
+
+    #[test]
+    fn non_utf8_path_without_path_utf8() {
+        let file = parse_file(b"d6:lengthi5e4:pathl3:dir3:a\xffbee");
+
+        assert_eq!(file.path, ["dir", "a\u{fffd}b"].iter().collect::<PathBuf>());
+        assert_eq!(
+            file.raw_path,
+            Some(vec![b"dir".to_vec(), b"a\xffb".to_vec()])
+        );
+
+        // A malformed path.utf-8 is ignored rather than being an error.
+        let file = parse_file(b"d6:lengthi5e4:pathl3:a\xffbe10:path.utf-8i5ee");
+
+        assert_eq!(file.path, PathBuf::from("a\u{fffd}b"));
+    }
+
+    #[test]
+    fn unsafe_path_utf8() {
+        let file = parse_bencode(b"d6:lengthi5e4:pathl1:ae10:path.utf-8l2:..ee")
+            .unwrap()
+            .1;
+
+        assert!(matches!(
+            TorrentFile::try_from(file),
+            Err(TorrentParsingError::UnsafePath { component }) if component == ".."
+        ));
+    }
+
+    #[test]
+    fn raw_path_round_trip() {
+        for bytes in [
+            b"d6:lengthi5e4:pathl3:a\xffbe10:path.utf-8l1:bee" as &[u8],
+            b"d6:lengthi5e4:pathl3:a\xffbee",
+            b"d6:lengthi5e4:pathl3:a\xffbe10:path.utf-8i5ee",
+        ] {
+            let (_, bencode) = parse_bencode(bytes).unwrap();
+
+            assert_eq!(
+                encode(&TorrentFile::try_from(bencode).unwrap().to_bencode()),
+                bytes
+            );
+        }
+    }
+
+    fn parse_info(info: &[u8]) -> TorrentInfo {
+        TorrentInfo::try_from(parse_bencode(info).unwrap().1).unwrap()
+    }
+
+    #[test]
+    fn non_utf8_name() {
+        // Shift-JIS for "ファイル".
+        let info = parse_info(
+            b"d6:lengthi5e4:name8:\x83t\x83@\x83C\x83\x8b12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaae",
+        );
+
+        assert_eq!(info.name, "\u{fffd}t\u{fffd}@\u{fffd}C\u{fffd}\u{fffd}");
+        assert_eq!(info.name_bytes(), b"\x83t\x83@\x83C\x83\x8b");
+        assert_eq!(info.files[0].path, PathBuf::from(&info.name));
+
+        let info = parse_info(
+            b"d6:lengthi5e4:name8:\x83t\x83@\x83C\x83\x8b10:name.utf-812:\xe3\x83\x95\xe3\x82\xa1\xe3\x82\xa4\xe3\x83\xab12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaae",
+        );
+
+        assert_eq!(info.name, "ファイル");
+        assert_eq!(info.name_bytes(), b"\x83t\x83@\x83C\x83\x8b");
+    }
+
+    #[test]
+    fn raw_name_round_trip() {
+        let plain = b"d6:lengthi5e4:name1:a12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaae";
+        let info = parse_info(plain);
+
+        assert_eq!(info.raw_name, None);
+        assert_eq!(info.name_bytes(), b"a");
+        assert_eq!(encode(&info.to_bencode()), plain);
+
+        // The lossy name isn't made into a name.utf-8 that wasn't there.
+        let non_utf8 =
+            b"d6:lengthi5e4:name3:a\xffb12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaae";
+        let info = parse_info(non_utf8);
+
+        assert_eq!(encode(&info.to_bencode()), non_utf8);
+
+        let hinted = b"d6:lengthi5e4:name3:a\xffb10:name.utf-81:b12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaae";
+        let mut info = parse_info(hinted);
+
+        assert_eq!(info.name, "b");
+        assert_eq!(encode(&info.to_bencode()), hinted);
+
+        // Renaming goes into the name.utf-8 the name came from.
+        info.name = "c".to_owned();
+
+        assert_eq!(
+            encode(&info.to_bencode()),
+            &b"d6:lengthi5e4:name3:a\xffb10:name.utf-81:c12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaae"[..]
+        );
+
+        // A malformed one is kept just as it was.
+        let malformed = b"d6:lengthi5e4:name1:a10:name.utf-8i5e12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaae";
+
+        assert_eq!(encode(&parse_info(malformed).to_bencode()), malformed);
+    }
+
+    #[test]
+    fn unsafe_name_and_file_tree() {
+        assert!(matches!(
+            Torrent::try_from(
+                b"d8:announce31:http://one.example.com/announce4:infod6:lengthi5e4:name2:..12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaaee" as &[u8]
+            ),
+            Err(TorrentParsingError::UnsafePath { .. })
+        ));
+        assert!(matches!(
+            Torrent::try_from(
+                b"d8:announce31:http://one.example.com/announce4:infod9:file treed2:..d5:a.txtd0:d6:lengthi0eeeee12:meta versioni2e4:name1:a12:piece lengthi16384eee" as &[u8]
+            ),
+            Err(TorrentParsingError::UnsafePath { .. })
+        ));
+    }
+
+    #[test]
+    fn empty_pieces() {
+        let info = parse_bencode(b"d6:lengthi5e4:name1:a12:piece lengthi16384e6:pieces0:e")
+            .unwrap()
+            .1;
+
+        assert!(matches!(
+            TorrentInfo::try_from(info),
+            Err(TorrentParsingError::NoPieces)
+        ));
+    }
+
+    #[test]
+    fn both_files_and_length() {
+        let info = parse_bencode(
+            b"d5:filesld6:lengthi5e4:pathl1:aeee6:lengthi5e4:name1:a12:piece lengthi16384e6:pieces0:e",
+        )
+        .unwrap()
+        .1;
+
+        assert!(matches!(
+            TorrentInfo::try_from(info),
+            Err(TorrentParsingError::AmbiguousFileMode)
+        ));
+    }
+
+    #[test]
+    fn neither_files_nor_length() {
+        let info = parse_bencode(b"d4:name1:a12:piece lengthi16384e6:pieces0:e")
+            .unwrap()
+            .1;
+
+        assert!(matches!(
+            TorrentInfo::try_from(info),
+            Err(TorrentParsingError::MissingFileMode)
+        ));
+    }
+}
+
+#[cfg(test)]
+mod bench {
+    extern crate test;
+
+    use super::*;
+    use crate::bencode_parser::{encode, parse_bencode, parse_bencode_borrowed};
+    use bytes::Bytes;
+    use std::collections::BTreeMap;
+    use test::Bencher;
+
+    fn many_pieces_torrent(num_pieces: usize) -> Vec<u8> {
+        let info = vec![
+            ("length".into(), Bencode::Number(num_pieces as i64 * 16384)),
+            ("name".into(), Bencode::ByteString("many_pieces".into())),
+            ("piece length".into(), Bencode::Number(16384)),
+            (
+                "pieces".into(),
+                Bencode::ByteString(Bytes::from(vec![0; num_pieces * 20])),
+            ),
+        ]
+        .into_iter()
+        .collect::<BTreeMap<_, _>>();
+
+        encode(&Bencode::Dict(
+            vec![
+                (
+                    "announce".into(),
+                    Bencode::ByteString("http://tracker.example.com/announce".into()),
+                ),
+                ("info".into(), Bencode::Dict(info)),
+            ]
+            .into_iter()
+            .collect(),
+        ))
+    }
+
+    #[bench]
+    fn parse_lazy_info_hash(b: &mut Bencher) {
+        let torrent_bytes = many_pieces_torrent(10_000);
+
+        b.iter(|| Torrent::try_from(torrent_bytes.as_slice()).unwrap());
+    }
+
+    #[bench]
+    fn parse_eager_info_hash(b: &mut Bencher) {
+        let torrent_bytes = many_pieces_torrent(10_000);
+
+        b.iter(|| {
+            Torrent::try_from(torrent_bytes.as_slice())
+                .unwrap()
+                .info_hash()
+        });
+    }
+
+    // Both parse the same 200 KB pieces string, but only the owned version
+    // has to copy it out.
+    #[bench]
+    fn parse_owned_bencode(b: &mut Bencher) {
+        let torrent_bytes = many_pieces_torrent(10_000);
+
+        b.iter(|| parse_bencode(&torrent_bytes).unwrap());
+    }
+
+    #[bench]
+    fn parse_borrowed_bencode(b: &mut Bencher) {
+        let torrent_bytes = many_pieces_torrent(10_000);
+
+        b.iter(|| parse_bencode_borrowed(&torrent_bytes).unwrap());
+    }
 }
@@ -1,12 +1,23 @@
 use super::bencode_parser::{parse_bencode, Bencode};
-use super::torrent_parser::Torrent;
-use bytes::Bytes;
+use super::peer::generate_peer_id;
+use super::torrent_parser::SHA1Hash;
+use once_cell::sync::OnceCell;
 use percent_encoding::{percent_encode, AsciiSet, NON_ALPHANUMERIC};
-use rand::{distributions, thread_rng, Rng};
-use reqwest::Url;
+use reqwest::{redirect, Client, StatusCode, Url};
+use snafu::{ensure, OptionExt, ResultExt, Snafu};
 use std::{
+    cmp,
+    collections::HashMap,
     convert::{TryFrom, TryInto},
-    net::{IpAddr, SocketAddr},
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
+    time::Duration,
+};
+use tokio::{
+    net::{self, UdpSocket},
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+    time::{self, Instant},
 };
 
 const NEEDS_ESCAPE_BYTES: AsciiSet = NON_ALPHANUMERIC
@@ -15,103 +26,925 @@ const NEEDS_ESCAPE_BYTES: AsciiSet = NON_ALPHANUMERIC
     .remove(b'_')
     .remove(b'~');
 
-pub fn build_tracker_url(
-    torrent: &Torrent,
-    port: &str,
-    peer_id: &str,
-) -> Result<Url, Box<dyn std::error::Error>> {
-    let mut url = Url::parse(&torrent.announce)?;
-
-    url.set_query(Some(&format!(
-        "info_hash={}",
-        &percent_encode(torrent.info_hash.as_ref(), &NEEDS_ESCAPE_BYTES)
-    )));
-
-    url.query_pairs_mut()
-        .append_pair("port", port)
-        .append_pair("uploaded", "0")
-        .append_pair("downloaded", "0")
-        .append_pair("compact", "1")
-        .append_pair("event", "started")
-        .append_pair(
-            "left",
-            &torrent
-                .info
-                .files
-                .iter()
-                .map(|f| f.length)
-                .sum::<u64>()
-                .to_string(),
+const UDP_PROTOCOL_ID: u64 = 0x41727101980;
+const UDP_ACTION_CONNECT: u32 = 0;
+const UDP_ACTION_ANNOUNCE: u32 = 1;
+const UDP_ACTION_ERROR: u32 = 3;
+const UDP_MAX_RETRIES: u32 = 8;
+const UDP_CONNECTION_ID_LIFETIME: Duration = Duration::from_secs(60);
+/// How many redirects an HTTP tracker can send us through before we give up
+/// on it.
+const MAX_REDIRECTS: usize = 5;
+/// The most we'll read of an HTTP tracker's response, after decompressing it.
+/// Even scrapes for thousands of torrents come nowhere near this.
+const MAX_RESPONSE_LEN: usize = 8 * 1024 * 1024;
+
+/// How long to wait between announces when the tracker doesn't say.
+pub const DEFAULT_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(30 * 60);
+/// The soonest we'll announce again, whatever the tracker says, so that a
+/// tracker handing out tiny intervals can't have us hammering it.
+pub const MIN_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(60);
+/// How long to wait before trying again after an announce fails.
+pub const ANNOUNCE_RETRY_INTERVAL: Duration = Duration::from_secs(60);
+/// How long [`AnnounceLoop`] gives each announce, unless told otherwise.
+pub const DEFAULT_TRACKER_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Debug)]
+pub struct AnnounceRequest {
+    pub info_hash: SHA1Hash,
+    pub peer_id: String,
+    pub port: u16,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub left: u64,
+    pub event: AnnounceEvent,
+    /// How many peers we'd like back, or `None` to let the tracker decide.
+    pub numwant: Option<u32>,
+    pub compact: bool,
+    /// Whatever the tracker last gave us as its tracker id, which it wants
+    /// back on every announce after that.
+    pub tracker_id: Option<Vec<u8>>,
+}
+
+impl AnnounceRequest {
+    pub fn new(info_hash: SHA1Hash, peer_id: String, port: u16) -> Self {
+        Self {
+            info_hash,
+            peer_id,
+            port,
+            uploaded: 0,
+            downloaded: 0,
+            left: 0,
+            event: AnnounceEvent::None,
+            numwant: None,
+            compact: true,
+            tracker_id: None,
+        }
+    }
+
+    pub fn uploaded(mut self, uploaded: u64) -> Self {
+        self.uploaded = uploaded;
+        self
+    }
+
+    pub fn downloaded(mut self, downloaded: u64) -> Self {
+        self.downloaded = downloaded;
+        self
+    }
+
+    pub fn left(mut self, left: u64) -> Self {
+        self.left = left;
+        self
+    }
+
+    pub fn event(mut self, event: AnnounceEvent) -> Self {
+        self.event = event;
+        self
+    }
+
+    pub fn numwant(mut self, numwant: u32) -> Self {
+        self.numwant = Some(numwant);
+        self
+    }
+
+    pub fn compact(mut self, compact: bool) -> Self {
+        self.compact = compact;
+        self
+    }
+
+    pub fn tracker_id(mut self, tracker_id: Vec<u8>) -> Self {
+        self.tracker_id = Some(tracker_id);
+        self
+    }
+}
+
+/// `None` is for the regular announces made at the tracker's interval.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum AnnounceEvent {
+    #[default]
+    None,
+    Started,
+    Stopped,
+    Completed,
+}
+
+impl AnnounceEvent {
+    /// The value of the HTTP `event` parameter, which is left out entirely
+    /// for `None`.
+    pub fn as_str(self) -> Option<&'static str> {
+        match self {
+            Self::None => None,
+            Self::Started => Some("started"),
+            Self::Stopped => Some("stopped"),
+            Self::Completed => Some("completed"),
+        }
+    }
+
+    /// BEP 15 numbers the events differently from the order BEP 3 lists them.
+    fn udp_code(self) -> u32 {
+        match self {
+            Self::None => 0,
+            Self::Completed => 1,
+            Self::Started => 2,
+            Self::Stopped => 3,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AnnounceResponse {
+    pub peers: Vec<SocketAddr>,
+    /// How long the tracker would like us to wait before announcing again.
+    pub interval: Option<Duration>,
+    /// How long we have to wait before announcing again, at the very least.
+    pub min_interval: Option<Duration>,
+    /// Something the tracker wants the user to know about, which doesn't
+    /// stop the announce from working.
+    pub warning: Option<String>,
+    /// What to send back as the tracker id from now on.
+    pub tracker_id: Option<Vec<u8>>,
+    /// How many seeders the tracker knows of.
+    pub complete: Option<u64>,
+    /// How many leechers the tracker knows of.
+    pub incomplete: Option<u64>,
+}
+
+impl AnnounceResponse {
+    pub fn next_announce_in(&self) -> Duration {
+        cmp::max(
+            self.interval.unwrap_or(DEFAULT_ANNOUNCE_INTERVAL),
+            self.min_interval.unwrap_or_default(),
         )
-        .append_pair("peer_id", peer_id);
+        .max(MIN_ANNOUNCE_INTERVAL)
+    }
+}
+
+/// Keeps announcing to a tracker in the background, as often as it asks us
+/// to, so that we keep hearing about new peers. The tracker is sent a stopped
+/// announce once this is shut down or dropped.
+pub struct AnnounceLoop {
+    updates: mpsc::Receiver<Result<Vec<SocketAddr>, TrackerError>>,
+    finished: Option<oneshot::Sender<()>>,
+    shutdown: Option<oneshot::Sender<()>>,
+    task: Option<JoinHandle<()>>,
+}
+
+impl AnnounceLoop {
+    /// Starts with a started announce right away. Every announce is made
+    /// with `request`, other than its event.
+    pub fn start(announce: String, request: AnnounceRequest) -> Self {
+        Self::start_with_timeout(announce, request, DEFAULT_TRACKER_TIMEOUT)
+    }
+
+    /// Like [`AnnounceLoop::start`], but gives up on any announce that takes
+    /// longer than `timeout`, which counts as a failed one.
+    pub fn start_with_timeout(
+        announce: String,
+        request: AnnounceRequest,
+        timeout: Duration,
+    ) -> Self {
+        let (updates_tx, updates) = mpsc::channel(1);
+        let (finished, finished_rx) = oneshot::channel();
+        let (shutdown, shutdown_rx) = oneshot::channel();
+
+        let task = tokio::spawn(run_announce_loop(
+            announce,
+            request,
+            timeout,
+            updates_tx,
+            finished_rx,
+            shutdown_rx,
+        ));
+
+        Self {
+            updates,
+            finished: Some(finished),
+            shutdown: Some(shutdown),
+            task: Some(task),
+        }
+    }
+
+    /// Waits for the peers from the next announce, or why it failed.
+    pub async fn next(&mut self) -> Option<Result<Vec<SocketAddr>, TrackerError>> {
+        self.updates.recv().await
+    }
+
+    /// Tells the tracker the download's finished with a completed announce,
+    /// straight away rather than at the next interval. Only the first call
+    /// does anything.
+    pub fn download_finished(&mut self) {
+        if let Some(finished) = self.finished.take() {
+            let _ = finished.send(());
+        }
+    }
+
+    /// Stops announcing, waiting until the tracker's been told that we have.
+    pub async fn shutdown(mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+
+        if let Some(task) = self.task.take() {
+            let _ = task.await;
+        }
+    }
+}
+
+impl Drop for AnnounceLoop {
+    fn drop(&mut self) {
+        // The task sends the stopped announce on its own, we just can't wait
+        // for it here.
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+    }
+}
+
+async fn run_announce_loop(
+    announce_url: String,
+    mut request: AnnounceRequest,
+    timeout: Duration,
+    updates: mpsc::Sender<Result<Vec<SocketAddr>, TrackerError>>,
+    finished: oneshot::Receiver<()>,
+    mut shutdown: oneshot::Receiver<()>,
+) {
+    let mut state = AnnounceState::default();
+    // Taken once it's gone off, since it can't be waited on again.
+    let mut finished = Some(finished);
+
+    'announcing: loop {
+        // The loop stops before this can ever be a stopped announce.
+        request.event = state.next_event().unwrap_or_default();
+
+        let result = tokio::select! {
+            result = announce_within(timeout, &announce_url, &request) => result,
+            _ = &mut shutdown => break,
+        };
+
+        // Until the started announce gets through, we keep retrying it.
+        let wait = match &result {
+            Ok(response) => {
+                if let Some(warning) = &response.warning {
+                    log::warn!("Tracker warning: {}", warning);
+                }
+
+                // This can't be out of order, since it came from the state.
+                let _ = state.sent(request.event);
+
+                // Trackers don't have to send it every time, so the last one
+                // we got is kept until there's a new one.
+                if let Some(tracker_id) = &response.tracker_id {
+                    request.tracker_id = Some(tracker_id.clone());
+                }
+
+                response.next_announce_in()
+            }
+            Err(_) => ANNOUNCE_RETRY_INTERVAL,
+        };
+
+        tokio::select! {
+            sent = updates.send(result.map(|response| response.peers)) => {
+                if sent.is_err() {
+                    break;
+                }
+            }
+            _ = &mut shutdown => break,
+        }
+
+        let sleep = time::sleep(wait);
+        tokio::pin!(sleep);
+
+        loop {
+            tokio::select! {
+                _ = &mut sleep => break,
+                result = async { finished.as_mut().unwrap().await }, if finished.is_some() => {
+                    finished = None;
+
+                    // The sender only goes away without sending once we're
+                    // shutting down anyway.
+                    if result.is_ok() {
+                        state.finish_download();
+                        break;
+                    }
+                }
+                _ = &mut shutdown => break 'announcing,
+            }
+        }
+    }
+
+    // A tracker that never heard we started doesn't need to hear we stopped.
+    if !state.needs_stopped() {
+        return;
+    }
+
+    request.event = AnnounceEvent::Stopped;
+
+    if let Err(e) = announce_within(timeout, &announce_url, &request).await {
+        log::warn!("Couldn't tell tracker that we stopped: {}", e);
+    }
+}
+
+async fn announce_within(
+    timeout: Duration,
+    announce_url: &str,
+    request: &AnnounceRequest,
+) -> Result<AnnounceResponse, TrackerError> {
+    time::timeout(timeout, announce(announce_url, request))
+        .await
+        .unwrap_or_else(|_| TimedOut { timeout }.fail())
+}
+
+/// Which events a tracker has heard from us, so that it only ever hears
+/// `started` first, `completed` at most once and `stopped` last, like BEP 3
+/// wants. Getting these out of order can mess with how a tracker keeps
+/// count of what we've uploaded and downloaded.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct AnnounceState {
+    started: bool,
+    download_finished: bool,
+    completed_sent: bool,
+    stopped: bool,
+}
+
+impl AnnounceState {
+    /// For when the download was already finished before the first
+    /// announce. `completed` is only for downloads that finish while we're
+    /// announcing, so it's never sent for these.
+    pub fn seeding() -> Self {
+        Self {
+            download_finished: true,
+            completed_sent: true,
+            ..Self::default()
+        }
+    }
+
+    /// The event the next announce should have, or `None` once we've
+    /// stopped, since there's nothing left to announce.
+    pub fn next_event(&self) -> Option<AnnounceEvent> {
+        if self.stopped {
+            None
+        } else if !self.started {
+            Some(AnnounceEvent::Started)
+        } else if self.download_finished && !self.completed_sent {
+            Some(AnnounceEvent::Completed)
+        } else {
+            Some(AnnounceEvent::None)
+        }
+    }
+
+    /// Marks the download as finished, so that the next announce after
+    /// `started` is `completed`. Doing this again does nothing.
+    pub fn finish_download(&mut self) {
+        self.download_finished = true;
+    }
+
+    /// Whether there's been a `started` that a `stopped` has to follow.
+    pub fn needs_stopped(&self) -> bool {
+        self.started && !self.stopped
+    }
+
+    /// Records that an announce with `event` got through, unless it's out of
+    /// order, which leaves the state as it was.
+    pub fn sent(&mut self, event: AnnounceEvent) -> Result<(), TrackerError> {
+        let valid = match event {
+            AnnounceEvent::Started => !self.started,
+            AnnounceEvent::None => self.needs_stopped(),
+            AnnounceEvent::Completed => {
+                self.needs_stopped() && self.download_finished && !self.completed_sent
+            }
+            AnnounceEvent::Stopped => self.needs_stopped(),
+        };
+
+        ensure!(valid, InvalidEvent { event });
+
+        match event {
+            AnnounceEvent::Started => self.started = true,
+            AnnounceEvent::Completed => self.completed_sent = true,
+            AnnounceEvent::Stopped => self.stopped = true,
+            AnnounceEvent::None => {}
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ScrapeStats {
+    /// Peers with the whole torrent, AKA seeders.
+    pub complete: u64,
+    /// Peers still downloading, AKA leechers.
+    pub incomplete: u64,
+    /// How many times the tracker has seen the torrent finish downloading.
+    pub downloaded: u64,
+}
+
+/// Announces over whichever protocol the announce URL's scheme asks for.
+pub async fn announce(
+    announce: &str,
+    request: &AnnounceRequest,
+) -> Result<AnnounceResponse, TrackerError> {
+    let url = Url::parse(announce).context(InvalidUrl)?;
+
+    if url.scheme() == "udp" {
+        let host = url.host_str().context(MissingUdpAddress)?;
+        let port = url.port().context(MissingUdpAddress)?;
+
+        let addr = net::lookup_host((host, port))
+            .await
+            .context(Io)?
+            .next()
+            .context(MissingUdpAddress)?;
+
+        announce_udp(addr, request).await
+    } else {
+        announce_http(announce, request).await
+    }
+}
+
+pub async fn announce_http(
+    announce: &str,
+    request: &AnnounceRequest,
+) -> Result<AnnounceResponse, TrackerError> {
+    let url = build_tracker_url(announce, request).context(InvalidUrl)?;
+
+    log::debug!("Announcing to tracker at URL: {}", url);
+
+    let response = http_get(url).await?;
+
+    parse_announce_response(&response)
+}
+
+/// Fetches a tracker's response body, which only counts if the status says
+/// it's a success. Redirects are followed, and gzipped bodies decompressed,
+/// since some trackers do both whether or not we ask. Bodies over
+/// [`MAX_RESPONSE_LEN`] are given up on rather than read into memory.
+async fn http_get(url: Url) -> Result<Vec<u8>, TrackerError> {
+    static CLIENT: OnceCell<Client> = OnceCell::new();
+
+    let client = CLIENT.get_or_try_init(|| {
+        Client::builder()
+            .redirect(redirect::Policy::limited(MAX_REDIRECTS))
+            .build()
+            .context(RequestError)
+    })?;
+    let mut response = match client.get(url).send().await {
+        Ok(response) => response,
+        Err(e) if e.is_redirect() => return TooManyRedirects { max: MAX_REDIRECTS }.fail(),
+        Err(e) => return Err(e).context(RequestError),
+    };
+    let status = response.status();
+
+    ensure!(status.is_success(), Http { status });
+
+    let mut body = Vec::new();
+
+    loop {
+        let chunk = match response.chunk().await {
+            Ok(Some(chunk)) => chunk,
+            Ok(None) => return Ok(body),
+            Err(e) if e.is_decode() => return Err(e).context(Gzip),
+            Err(e) => return Err(e).context(RequestError),
+        };
+
+        ensure!(
+            body.len() + chunk.len() <= MAX_RESPONSE_LEN,
+            ResponseTooLong {
+                max: MAX_RESPONSE_LEN
+            }
+        );
+
+        body.extend_from_slice(&chunk);
+    }
+}
+
+pub fn build_tracker_url(
+    announce: &str,
+    request: &AnnounceRequest,
+) -> Result<Url, url::ParseError> {
+    let mut url = Url::parse(announce)?;
+
+    append_query(
+        &mut url,
+        &format!("info_hash={}", urlencode_bytes(request.info_hash.as_ref())),
+    );
+
+    {
+        let mut query = url.query_pairs_mut();
+
+        query
+            .append_pair("port", &request.port.to_string())
+            .append_pair("uploaded", &request.uploaded.to_string())
+            .append_pair("downloaded", &request.downloaded.to_string())
+            .append_pair("compact", if request.compact { "1" } else { "0" });
+
+        if let Some(event) = request.event.as_str() {
+            query.append_pair("event", event);
+        }
+
+        query.append_pair("left", &request.left.to_string());
+
+        if let Some(numwant) = request.numwant {
+            query.append_pair("numwant", &numwant.to_string());
+        }
+    }
+
+    // query_pairs_mut would form-encode these, which isn't quite what
+    // trackers expect for arbitrary bytes.
+    append_query(
+        &mut url,
+        &format!("peer_id={}", urlencode_bytes(request.peer_id.as_bytes())),
+    );
+
+    if let Some(tracker_id) = &request.tracker_id {
+        append_query(
+            &mut url,
+            &format!("trackerid={}", urlencode_bytes(tracker_id)),
+        );
+    }
 
     Ok(url)
 }
 
-pub fn build_peer_id() -> String {
-    let client_id = "MS"; // the Matey Ship! 🏴‍☠️
+/// Adds already encoded parameters to the end of the query. Private trackers
+/// often put a passkey in the announce URL's query, which has to be kept
+/// rather than replaced.
+fn append_query(url: &mut Url, params: &str) {
+    let query = match url.query() {
+        Some(existing) if !existing.is_empty() => format!("{}&{}", existing, params),
+        _ => params.to_owned(),
+    };
 
-    let mut version_str = env!("CARGO_PKG_VERSION").to_owned().replace(".", "");
+    url.set_query(Some(&query));
+}
 
-    version_str.truncate(4);
+/// Percent-encodes every byte outside of RFC 3986's unreserved set, which is
+/// how trackers expect raw binary values like the info hash to be sent.
+pub fn urlencode_bytes(bytes: &[u8]) -> String {
+    percent_encode(bytes, &NEEDS_ESCAPE_BYTES).to_string()
+}
+
+pub async fn scrape(
+    announce: &str,
+    info_hashes: &[SHA1Hash],
+) -> Result<HashMap<SHA1Hash, ScrapeStats>, TrackerError> {
+    let mut url = scrape_url(announce)?;
 
-    let suffix = thread_rng()
-        .sample_iter(&distributions::Alphanumeric)
-        .take(12)
-        .map(char::from)
-        .collect::<String>();
+    for info_hash in info_hashes {
+        append_query(
+            &mut url,
+            &format!("info_hash={}", urlencode_bytes(info_hash.as_ref())),
+        );
+    }
+
+    log::debug!("Scraping tracker at URL: {}", url);
 
-    format!("-{}{:0>4}-{}", client_id, version_str, suffix)
+    let response = http_get(url).await?;
+
+    parse_scrape_response(&response)
 }
 
-pub fn build_peerlist(response: &[u8]) -> Option<Vec<SocketAddr>> {
-    let (_, response_bencode) = parse_bencode(response).ok()?;
+/// Per BEP 48, a tracker only supports scraping if the last part of its
+/// announce URL's path starts with "announce", in which case that gets
+/// replaced with "scrape".
+pub fn scrape_url(announce: &str) -> Result<Url, TrackerError> {
+    let mut url = Url::parse(announce).context(InvalidUrl)?;
 
-    let mut response_dict = response_bencode.dict()?;
+    let path = url.path();
+    let last_segment_start = path.rfind('/').context(NoScrapeUrl)? + 1;
 
-    let (peers, v6_peers) = (
-        response_dict.remove(b"peers" as &[u8]),
-        response_dict.remove(b"peers6" as &[u8]),
+    ensure!(
+        path[last_segment_start..].starts_with("announce"),
+        NoScrapeUrl
     );
 
-    if peers.is_none() && v6_peers.is_none() {
-        return None;
+    let scrape_path = format!(
+        "{}scrape{}",
+        &path[..last_segment_start],
+        &path[last_segment_start + "announce".len()..]
+    );
+
+    url.set_path(&scrape_path);
+
+    Ok(url)
+}
+
+pub fn parse_scrape_response(
+    response: &[u8],
+) -> Result<HashMap<SHA1Hash, ScrapeStats>, TrackerError> {
+    let mut response_dict = parse_bencode(response)
+        .ok()
+        .and_then(|(_, bencode)| bencode.dict())
+        .context(Decode)?;
+
+    if let Some(reason) = response_dict.remove(b"failure reason" as &[u8]) {
+        return Failure {
+            reason: String::from_utf8_lossy(&reason.byte_string().context(Decode)?),
+        }
+        .fail();
     }
 
-    match (
-        peers.unwrap_or_else(|| Bencode::ByteString(Bytes::new())),
-        v6_peers.unwrap_or_else(|| Bencode::ByteString(Bytes::new())),
-    ) {
-        // compact mode
-        (Bencode::ByteString(v4_peer_bytes), Bencode::ByteString(v6_peer_bytes)) => {
-            let (v4_chunks, v4_remainder) = v4_peer_bytes.as_chunks::<6>();
-            let (v6_chunks, v6_remainder) = v6_peer_bytes.as_chunks::<18>();
+    response_dict
+        .remove(b"files" as &[u8])
+        .and_then(|files| files.dict())
+        .context(Decode)?
+        .into_iter()
+        .map(|(info_hash, stats)| {
+            let info_hash = SHA1Hash(info_hash.as_ref().try_into().ok().context(Decode)?);
+
+            let stat = |key: &[u8]| {
+                stats
+                    .get(key)
+                    .and_then(Bencode::as_i64)
+                    .and_then(|val| u64::try_from(val).ok())
+                    .context(Decode)
+            };
+
+            Ok((
+                info_hash,
+                ScrapeStats {
+                    complete: stat(b"complete")?,
+                    incomplete: stat(b"incomplete")?,
+                    downloaded: stat(b"downloaded")?,
+                },
+            ))
+        })
+        .collect()
+}
+
+pub fn parse_announce_response(response: &[u8]) -> Result<AnnounceResponse, TrackerError> {
+    let mut response_dict = parse_bencode(response)
+        .ok()
+        .and_then(|(_, bencode)| bencode.dict())
+        .context(Decode)?;
+
+    // When this is present, the tracker isn't required to send anything else.
+    if let Some(reason) = response_dict.remove(b"failure reason" as &[u8]) {
+        return Failure {
+            reason: String::from_utf8_lossy(&reason.byte_string().context(Decode)?),
+        }
+        .fail();
+    }
+
+    let peers = build_peerlist(
+        response_dict.remove(b"peers" as &[u8]),
+        response_dict.remove(b"peers6" as &[u8]),
+    )
+    .context(MalformedPeers)?;
+
+    let warning = response_dict
+        .remove(b"warning message" as &[u8])
+        .and_then(|val| val.byte_string())
+        .map(|warning| String::from_utf8_lossy(&warning).into_owned());
+    let tracker_id = response_dict
+        .remove(b"tracker id" as &[u8])
+        .and_then(|val| val.byte_string())
+        .map(|tracker_id| tracker_id.to_vec());
+
+    // None of these are needed, so a bad value isn't fatal.
+    let mut number = |key: &[u8]| {
+        response_dict
+            .remove(key)
+            .and_then(|val| val.number())
+            .and_then(|val| u64::try_from(val).ok())
+    };
+
+    Ok(AnnounceResponse {
+        peers,
+        interval: number(b"interval").map(Duration::from_secs),
+        min_interval: number(b"min interval").map(Duration::from_secs),
+        warning,
+        tracker_id,
+        complete: number(b"complete"),
+        incomplete: number(b"incomplete"),
+    })
+}
 
-            if !(v4_remainder.is_empty() && v6_remainder.is_empty()) {
-                return None;
+pub async fn announce_udp(
+    addr: SocketAddr,
+    request: &AnnounceRequest,
+) -> Result<AnnounceResponse, TrackerError> {
+    announce_udp_with_timeout(addr, request, Duration::from_secs(15)).await
+}
+
+async fn announce_udp_with_timeout(
+    addr: SocketAddr,
+    request: &AnnounceRequest,
+    base_timeout: Duration,
+) -> Result<AnnounceResponse, TrackerError> {
+    let bind_addr = if addr.is_ipv4() {
+        SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))
+    } else {
+        SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0))
+    };
+
+    let socket = UdpSocket::bind(bind_addr).await.context(Io)?;
+    socket.connect(addr).await.context(Io)?;
+
+    let mut connection = None;
+
+    // BEP 15 has us wait 15 * 2 ^ n seconds for a response before trying again
+    // with n incremented, up to n = 8. This counts failures of either step,
+    // since a timed out announce can mean our connection ID is stale.
+    for n in 0..=UDP_MAX_RETRIES {
+        let timeout = base_timeout * 2u32.pow(n);
+
+        let connection_id = match connection {
+            Some((connection_id, obtained_at))
+                if Instant::now() - obtained_at < UDP_CONNECTION_ID_LIFETIME =>
+            {
+                connection_id
             }
+            _ => {
+                let transaction_id = rand::random();
 
-            Some(
-                v4_chunks
-                    .iter()
-                    .map(|&addr_bytes| {
-                        (
-                            IpAddr::from(<[u8; 4]>::try_from(&addr_bytes[0..4]).unwrap()),
-                            u16::from_be_bytes(addr_bytes[4..].try_into().unwrap()),
-                        )
-                    })
-                    .chain(v6_chunks.iter().map(|&addr_bytes| {
-                        (
-                            IpAddr::from(<[u8; 16]>::try_from(&addr_bytes[0..16]).unwrap()),
-                            u16::from_be_bytes(addr_bytes[16..].try_into().unwrap()),
-                        )
-                    }))
+                let packet = [
+                    &UDP_PROTOCOL_ID.to_be_bytes() as &[u8],
+                    &UDP_ACTION_CONNECT.to_be_bytes(),
+                    &u32::to_be_bytes(transaction_id),
+                ]
+                .concat();
+
+                let body = match udp_exchange(
+                    &socket,
+                    &packet,
+                    UDP_ACTION_CONNECT,
+                    transaction_id,
+                    timeout,
+                )
+                .await?
+                {
+                    Some(body) => body,
+                    None => continue,
+                };
+
+                ensure!(body.len() >= 8, MalformedUdpResponse);
+
+                let connection_id = u64::from_be_bytes(body[0..8].try_into().unwrap());
+                connection = Some((connection_id, Instant::now()));
+
+                connection_id
+            }
+        };
+
+        let transaction_id = rand::random();
+
+        let packet = [
+            &connection_id.to_be_bytes() as &[u8],
+            &UDP_ACTION_ANNOUNCE.to_be_bytes(),
+            &u32::to_be_bytes(transaction_id),
+            request.info_hash.as_ref(),
+            request.peer_id.as_bytes(),
+            &request.downloaded.to_be_bytes(),
+            &request.left.to_be_bytes(),
+            &request.uploaded.to_be_bytes(),
+            &request.event.udp_code().to_be_bytes(),
+            // IP address: let the tracker use the one the packet came from
+            &0u32.to_be_bytes(),
+            &rand::random::<u32>().to_be_bytes(),
+            // -1 lets the tracker decide
+            &request
+                .numwant
+                .map_or(-1, |numwant| numwant as i32)
+                .to_be_bytes(),
+            &request.port.to_be_bytes(),
+        ]
+        .concat();
+
+        if let Some(body) = udp_exchange(
+            &socket,
+            &packet,
+            UDP_ACTION_ANNOUNCE,
+            transaction_id,
+            timeout,
+        )
+        .await?
+        {
+            // The peers come after the interval, leecher, and seeder counts.
+            ensure!(body.len() >= 12, MalformedUdpResponse);
+
+            let peers = if addr.is_ipv4() {
+                parse_compact_peers(&body[12..])?
+                    .into_iter()
                     .map(SocketAddr::from)
-                    .collect(),
-            )
+                    .collect()
+            } else {
+                parse_compact_peers6(&body[12..])?
+                    .into_iter()
+                    .map(SocketAddr::from)
+                    .collect()
+            };
+
+            return Ok(AnnounceResponse {
+                peers,
+                interval: Some(Duration::from_secs(
+                    u32::from_be_bytes(body[0..4].try_into().unwrap()).into(),
+                )),
+                min_interval: None,
+                warning: None,
+                tracker_id: None,
+                incomplete: Some(u32::from_be_bytes(body[4..8].try_into().unwrap()).into()),
+                complete: Some(u32::from_be_bytes(body[8..12].try_into().unwrap()).into()),
+            });
+        }
+    }
+
+    UdpTimeout.fail()
+}
+
+/// Sends `packet` and waits until `timeout` for a response to it, returning
+/// everything after the action and transaction ID, or `None` if the timeout
+/// elapsed first.
+async fn udp_exchange(
+    socket: &UdpSocket,
+    packet: &[u8],
+    action: u32,
+    transaction_id: u32,
+    timeout: Duration,
+) -> Result<Option<Vec<u8>>, TrackerError> {
+    socket.send(packet).await.context(Io)?;
+
+    let deadline = Instant::now() + timeout;
+    let mut buf = vec![0; 8192];
+
+    loop {
+        let len = match time::timeout_at(deadline, socket.recv(&mut buf)).await {
+            Ok(len) => len.context(Io)?,
+            Err(_) => return Ok(None),
+        };
+
+        // Anything else is either garbage or a late reply to a request we
+        // already gave up on.
+        if len < 8 || buf[4..8] != transaction_id.to_be_bytes() {
+            continue;
         }
-        // non-compact mode
-        (Bencode::List(peer_list), _) => peer_list
+
+        let response_action = u32::from_be_bytes(buf[0..4].try_into().unwrap());
+
+        if response_action == UDP_ACTION_ERROR {
+            return Failure {
+                reason: String::from_utf8_lossy(&buf[8..len]),
+            }
+            .fail();
+        }
+
+        ensure!(response_action == action, MalformedUdpResponse);
+
+        return Ok(Some(buf[8..len].to_vec()));
+    }
+}
+
+pub fn build_peer_id() -> String {
+    let mut version_str = env!("CARGO_PKG_VERSION").to_owned().replace(".", "");
+
+    version_str.truncate(4);
+
+    let version = format!("{:0>4}", version_str)
+        .as_bytes()
+        .try_into()
+        .unwrap();
+
+    // The generated ID is entirely ASCII.
+    String::from_utf8(generate_peer_id(version).to_vec()).unwrap()
+}
+
+/// Combines `peers` with BEP 7's `peers6`, either of which can be left out,
+/// but not both.
+fn build_peerlist(peers: Option<Bencode>, v6_peers: Option<Bencode>) -> Option<Vec<SocketAddr>> {
+    if peers.is_none() && v6_peers.is_none() {
+        return None;
+    }
+
+    let mut peerlist = match peers {
+        Some(peers) => parse_peers(peers, false)?,
+        None => vec![],
+    };
+
+    if let Some(v6_peers) = v6_peers {
+        peerlist.extend(parse_peers(v6_peers, true)?);
+    }
+
+    Some(peerlist)
+}
+
+fn parse_peers(peers: Bencode, ipv6: bool) -> Option<Vec<SocketAddr>> {
+    match peers {
+        // compact mode
+        Bencode::ByteString(peer_bytes) if ipv6 => Some(
+            parse_compact_peers6(&peer_bytes)
+                .ok()?
+                .into_iter()
+                .map(SocketAddr::from)
+                .collect(),
+        ),
+        Bencode::ByteString(peer_bytes) => Some(
+            parse_compact_peers(&peer_bytes)
+                .ok()?
+                .into_iter()
+                .map(SocketAddr::from)
+                .collect(),
+        ),
+        // non-compact mode, where the IP can be a v4 or v6 address either way
+        Bencode::List(peer_list) => peer_list
             .into_iter()
             .map(|peer| {
                 let mut peer_dict = peer.dict()?;
@@ -132,3 +965,1080 @@ pub fn build_peerlist(response: &[u8]) -> Option<Vec<SocketAddr>> {
         _ => None,
     }
 }
+
+/// Decodes a single compact peer, which is an IPv4 peer if it's 6 bytes long
+/// and an IPv6 one if it's 18.
+pub fn parse_compact_peer(peer_bytes: &[u8]) -> Option<SocketAddr> {
+    match peer_bytes.len() {
+        6 => parse_compact_peers(peer_bytes)
+            .ok()?
+            .pop()
+            .map(SocketAddr::from),
+        18 => parse_compact_peers6(peer_bytes)
+            .ok()?
+            .pop()
+            .map(SocketAddr::from),
+        _ => None,
+    }
+}
+
+/// Decodes the compact peer format from BEP 23, where each peer is 4 bytes of
+/// IPv4 address followed by a 2 byte big-endian port.
+pub fn parse_compact_peers(peer_bytes: &[u8]) -> Result<Vec<SocketAddrV4>, TrackerError> {
+    let (chunks, remainder) = peer_bytes.as_chunks::<6>();
+
+    ensure!(remainder.is_empty(), MalformedPeers);
+
+    Ok(chunks
+        .iter()
+        .map(|addr_bytes| {
+            SocketAddrV4::new(
+                Ipv4Addr::from(<[u8; 4]>::try_from(&addr_bytes[0..4]).unwrap()),
+                u16::from_be_bytes(addr_bytes[4..].try_into().unwrap()),
+            )
+        })
+        .collect())
+}
+
+/// The IPv6 equivalent of [`parse_compact_peers`] from BEP 7, where each peer
+/// is 16 bytes of address followed by the port.
+pub fn parse_compact_peers6(peer_bytes: &[u8]) -> Result<Vec<SocketAddrV6>, TrackerError> {
+    let (chunks, remainder) = peer_bytes.as_chunks::<18>();
+
+    ensure!(remainder.is_empty(), MalformedPeers);
+
+    Ok(chunks
+        .iter()
+        .map(|addr_bytes| {
+            SocketAddrV6::new(
+                Ipv6Addr::from(<[u8; 16]>::try_from(&addr_bytes[0..16]).unwrap()),
+                u16::from_be_bytes(addr_bytes[16..].try_into().unwrap()),
+                0,
+                0,
+            )
+        })
+        .collect())
+}
+
+#[derive(Debug, Snafu)]
+pub enum TrackerError {
+    #[snafu(display("Invalid announce URL: {}", source))]
+    InvalidUrl { source: url::ParseError },
+    #[snafu(display("Couldn't reach tracker: {}", source))]
+    RequestError { source: reqwest::Error },
+    #[snafu(display("Tracker responded with HTTP {}", status))]
+    Http { status: StatusCode },
+    #[snafu(display("Tracker redirected more than {} times", max))]
+    TooManyRedirects { max: usize },
+    #[snafu(display("Couldn't decompress tracker response: {}", source))]
+    Gzip { source: reqwest::Error },
+    #[snafu(display("Tracker response is over {} bytes", max))]
+    ResponseTooLong { max: usize },
+    #[snafu(display("Tracker response isn't a bencoded dictionary"))]
+    Decode,
+    #[snafu(display("Tracker refused announce: {}", reason))]
+    Failure { reason: String },
+    #[snafu(display("Tracker response has a missing or malformed peer list"))]
+    MalformedPeers,
+    #[snafu(display("Tracker's announce URL can't be turned into a scrape URL"))]
+    NoScrapeUrl,
+    #[snafu(display("UDP tracker URL has no host or port"))]
+    MissingUdpAddress,
+    #[snafu(display("Couldn't talk to UDP tracker: {}", source))]
+    Io { source: io::Error },
+    #[snafu(display("UDP tracker sent a response that's too short or has the wrong action"))]
+    MalformedUdpResponse,
+    #[snafu(display("UDP tracker didn't respond after retrying"))]
+    UdpTimeout,
+    #[snafu(display("Tracker didn't respond within {:?}", timeout))]
+    TimedOut { timeout: Duration },
+    #[snafu(display("Can't send a {:?} announce now", event))]
+    InvalidEvent { event: AnnounceEvent },
+}
+
+impl TrackerError {
+    /// Whether announcing again later might work. Anything the tracker
+    /// actually said no to, or that's wrong with the announce URL itself,
+    /// will keep failing the same way.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::RequestError { .. }
+            | Self::Io { .. }
+            | Self::UdpTimeout
+            | Self::TimedOut { .. } => true,
+            Self::Http { status } => {
+                status.is_server_error() || *status == StatusCode::TOO_MANY_REQUESTS
+            }
+            // A tracker can send garbage while it's overloaded.
+            Self::Decode | Self::Gzip { .. } | Self::MalformedUdpResponse => true,
+            Self::InvalidUrl { .. }
+            | Self::TooManyRedirects { .. }
+            | Self::ResponseTooLong { .. }
+            | Self::Failure { .. }
+            | Self::MalformedPeers
+            | Self::NoScrapeUrl
+            | Self::MissingUdpAddress
+            | Self::InvalidEvent { .. } => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    /// Responds to a single HTTP request with `body`, returning the request
+    /// it was sent.
+    async fn mock_http_tracker(body: &'static [u8]) -> (SocketAddr, JoinHandle<String>) {
+        mock_http_tracker_with_status("200 OK", body).await
+    }
+
+    async fn mock_http_tracker_with_status(
+        status: &'static str,
+        body: &'static [u8],
+    ) -> (SocketAddr, JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let tracker = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+
+            let mut buf = vec![0; 4096];
+            let len = stream.read(&mut buf).await.unwrap();
+
+            stream
+                .write_all(
+                    format!(
+                        "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                        status,
+                        body.len()
+                    )
+                    .as_bytes(),
+                )
+                .await
+                .unwrap();
+            stream.write_all(body).await.unwrap();
+
+            String::from_utf8_lossy(&buf[..len]).into_owned()
+        });
+
+        (addr, tracker)
+    }
+
+    /// Responds to a request with each of `bodies` in turn, returning the
+    /// requests it was sent along with when they arrived.
+    async fn mock_http_tracker_sequence(
+        bodies: Vec<&'static [u8]>,
+    ) -> (SocketAddr, JoinHandle<Vec<(Instant, String)>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let tracker = tokio::spawn(async move {
+            let mut requests = Vec::new();
+
+            for body in bodies {
+                let (mut stream, _) = listener.accept().await.unwrap();
+
+                let mut buf = vec![0; 4096];
+                let len = stream.read(&mut buf).await.unwrap();
+                requests.push((
+                    Instant::now(),
+                    String::from_utf8_lossy(&buf[..len]).into_owned(),
+                ));
+
+                stream
+                    .write_all(
+                        format!(
+                            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                            body.len()
+                        )
+                        .as_bytes(),
+                    )
+                    .await
+                    .unwrap();
+                stream.write_all(body).await.unwrap();
+            }
+
+            requests
+        });
+
+        (addr, tracker)
+    }
+
+    /// Sends each of `responses` whole, headers and all, to a request of its
+    /// own, returning the requests it was sent.
+    fn serve_http(listener: TcpListener, responses: Vec<Vec<u8>>) -> JoinHandle<Vec<String>> {
+        tokio::spawn(async move {
+            let mut requests = Vec::new();
+
+            for response in responses {
+                let (mut stream, _) = listener.accept().await.unwrap();
+
+                let mut buf = vec![0; 4096];
+                let len = stream.read(&mut buf).await.unwrap();
+                requests.push(String::from_utf8_lossy(&buf[..len]).into_owned());
+
+                // Clients can hang up partway through a long response.
+                let _ = stream.write_all(&response).await;
+            }
+
+            requests
+        })
+    }
+
+    /// Answers the connect and announce steps of BEP 15, ignoring the first
+    /// `drop_first` packets it gets to simulate them being lost. Returns the
+    /// announce packet it was sent.
+    async fn mock_udp_tracker(socket: UdpSocket, drop_first: usize) -> Vec<u8> {
+        let mut buf = vec![0; 1024];
+
+        for _ in 0..drop_first {
+            socket.recv_from(&mut buf).await.unwrap();
+        }
+
+        let (len, peer) = socket.recv_from(&mut buf).await.unwrap();
+
+        assert_eq!(len, 16);
+        assert_eq!(buf[0..8], UDP_PROTOCOL_ID.to_be_bytes());
+        assert_eq!(buf[8..12], UDP_ACTION_CONNECT.to_be_bytes());
+
+        let connect_response = [
+            &UDP_ACTION_CONNECT.to_be_bytes() as &[u8],
+            &buf[12..16],
+            &0xdeadbeefu64.to_be_bytes(),
+        ]
+        .concat();
+        socket.send_to(&connect_response, peer).await.unwrap();
+
+        let (len, peer) = socket.recv_from(&mut buf).await.unwrap();
+
+        assert_eq!(len, 98);
+        assert_eq!(buf[0..8], 0xdeadbeefu64.to_be_bytes());
+        assert_eq!(buf[8..12], UDP_ACTION_ANNOUNCE.to_be_bytes());
+
+        let announce_response = [
+            &UDP_ACTION_ANNOUNCE.to_be_bytes() as &[u8],
+            &buf[12..16],
+            &1800u32.to_be_bytes(),
+            &1u32.to_be_bytes(),
+            &2u32.to_be_bytes(),
+            b"\x7f\x00\x00\x01\x1a\xe1",
+        ]
+        .concat();
+        socket.send_to(&announce_response, peer).await.unwrap();
+
+        buf[..len].to_vec()
+    }
+
+    fn request() -> AnnounceRequest {
+        AnnounceRequest::new(
+            SHA1Hash([0xab; 20]),
+            "-MS0010-abcdefghijkl".to_owned(),
+            6881,
+        )
+        .uploaded(1)
+        .downloaded(2)
+        .left(3)
+        .event(AnnounceEvent::Started)
+    }
+
+    #[test]
+    fn tracker_url() {
+        let url = build_tracker_url("http://tracker.example.com/announce", &request()).unwrap();
+
+        assert_eq!(
+            url.as_str(),
+            "http://tracker.example.com/announce?info_hash=%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB\
+             &port=6881&uploaded=1&downloaded=2&compact=1&event=started&left=3&peer_id=-MS0010-abcdefghijkl"
+        );
+    }
+
+    fn tracker_query(request: &AnnounceRequest) -> String {
+        build_tracker_url("http://tracker.example.com/announce", request)
+            .unwrap()
+            .query()
+            .unwrap()
+            .split_once('&')
+            .unwrap()
+            .1
+            .to_owned()
+    }
+
+    #[test]
+    fn tracker_url_events() {
+        for (event, param) in &[
+            (AnnounceEvent::Started, "&event=started"),
+            (AnnounceEvent::Stopped, "&event=stopped"),
+            (AnnounceEvent::Completed, "&event=completed"),
+            (AnnounceEvent::None, ""),
+        ] {
+            assert_eq!(
+                tracker_query(&request().event(*event)),
+                format!(
+                    "port=6881&uploaded=1&downloaded=2&compact=1{}&left=3&peer_id=-MS0010-abcdefghijkl",
+                    param
+                )
+            );
+        }
+    }
+
+    #[test]
+    fn tracker_url_defaults() {
+        let request = AnnounceRequest::new(
+            SHA1Hash([0xab; 20]),
+            "-MS0010-abcdefghijkl".to_owned(),
+            6881,
+        );
+
+        assert_eq!(
+            tracker_query(&request),
+            "port=6881&uploaded=0&downloaded=0&compact=1&left=0&peer_id=-MS0010-abcdefghijkl"
+        );
+        assert_eq!(
+            tracker_query(&request.numwant(50).compact(false)),
+            "port=6881&uploaded=0&downloaded=0&compact=0&left=0&numwant=50&peer_id=-MS0010-abcdefghijkl"
+        );
+    }
+
+    #[test]
+    fn urlencode_special_bytes() {
+        assert_eq!(urlencode_bytes(b"\x00\x20\xff"), "%00%20%FF");
+        assert_eq!(urlencode_bytes(b"aZ0-._~"), "aZ0-._~");
+        assert_eq!(urlencode_bytes(b"a+b&c=d"), "a%2Bb%26c%3Dd");
+    }
+
+    #[test]
+    fn tracker_url_raw_bytes() {
+        let mut request = request();
+        request.info_hash = SHA1Hash([0; 20]);
+        request.info_hash.0[..3].copy_from_slice(b"\x00\x20\xff");
+        request.peer_id = "-MS0010-abc def+ghij".to_owned();
+
+        let url = build_tracker_url("http://tracker.example.com/announce", &request).unwrap();
+
+        assert!(url.query().unwrap().starts_with("info_hash=%00%20%FF%00"));
+        assert!(url
+            .query()
+            .unwrap()
+            .ends_with("&peer_id=-MS0010-abc%20def%2Bghij"));
+    }
+
+    #[test]
+    fn tracker_url_with_existing_query() {
+        let url = build_tracker_url(
+            "http://tracker.example.com/announce?passkey=abc&x=1",
+            &request(),
+        )
+        .unwrap();
+
+        assert!(url.as_str().starts_with(
+            "http://tracker.example.com/announce?passkey=abc&x=1&info_hash=%AB%AB%AB"
+        ));
+        assert_eq!(url.query_pairs().count(), 10);
+    }
+
+    #[test]
+    fn compact_response() {
+        assert_eq!(
+            parse_announce_response(
+                b"d8:intervali1800e5:peers12:\x7f\x00\x00\x01\x1a\xe1\x0a\x00\x00\x02\x1a\xe2e"
+            )
+            .unwrap()
+            .peers,
+            vec![
+                "127.0.0.1:6881".parse::<SocketAddr>().unwrap(),
+                "10.0.0.2:6882".parse().unwrap()
+            ]
+        );
+    }
+
+    #[test]
+    fn dictionary_response() {
+        assert_eq!(
+            parse_announce_response(
+                b"d8:intervali1800e5:peersld2:ip9:127.0.0.17:peer id20:-MS0010-abcdefghijkl4:porti6881eeee"
+            )
+            .unwrap()
+            .peers,
+            vec!["127.0.0.1:6881".parse::<SocketAddr>().unwrap()]
+        );
+    }
+
+    #[test]
+    fn response_intervals() {
+        let response =
+            parse_announce_response(b"d8:intervali1800e12:min intervali900e5:peers0:e").unwrap();
+
+        assert_eq!(response.interval, Some(Duration::from_secs(1800)));
+        assert_eq!(response.min_interval, Some(Duration::from_secs(900)));
+        assert_eq!(response.next_announce_in(), Duration::from_secs(1800));
+
+        let response = parse_announce_response(b"d8:intervali-5e5:peers0:e").unwrap();
+
+        assert_eq!(response.interval, None);
+        assert_eq!(response.next_announce_in(), DEFAULT_ANNOUNCE_INTERVAL);
+
+        let response =
+            parse_announce_response(b"d8:intervali1e12:min intervali0e5:peers0:e").unwrap();
+
+        assert_eq!(response.interval, Some(Duration::from_secs(1)));
+        assert_eq!(response.next_announce_in(), MIN_ANNOUNCE_INTERVAL);
+    }
+
+    /// Moves the clock on by `duration`, for an announce loop that's waiting
+    /// to announce again. It isn't left paused, as a paused clock skips
+    /// straight to the tracker timeout whenever we're waiting on the tracker.
+    async fn skip(duration: Duration) {
+        time::pause();
+        time::advance(duration).await;
+        time::resume();
+    }
+
+    #[tokio::test]
+    async fn announce_loop_intervals() {
+        let (addr, tracker) = mock_http_tracker_sequence(vec![
+            b"d8:intervali1e5:peers6:\x7f\x00\x00\x01\x1a\xe1e",
+            // The min interval wins over a shorter interval.
+            b"d8:intervali0e12:min intervali1e5:peers0:e",
+            b"d8:intervali3600e5:peers0:e",
+            b"d8:intervali3600e5:peers0:e",
+        ])
+        .await;
+
+        let mut announce_loop = AnnounceLoop::start(format!("http://{}/announce", addr), request());
+
+        assert_eq!(
+            announce_loop.next().await.unwrap().unwrap(),
+            vec!["127.0.0.1:6881".parse::<SocketAddr>().unwrap()]
+        );
+
+        // A second isn't long enough, however short the tracker wants it.
+        skip(MIN_ANNOUNCE_INTERVAL / 2).await;
+        assert!(
+            time::timeout(Duration::from_millis(100), announce_loop.next())
+                .await
+                .is_err()
+        );
+
+        skip(MIN_ANNOUNCE_INTERVAL / 2).await;
+        assert!(announce_loop.next().await.unwrap().unwrap().is_empty());
+        skip(MIN_ANNOUNCE_INTERVAL).await;
+        assert!(announce_loop.next().await.unwrap().unwrap().is_empty());
+
+        announce_loop.shutdown().await;
+
+        let requests = tracker.await.unwrap();
+        let events = requests
+            .iter()
+            .map(|(_, request)| {
+                ["started", "stopped", "completed"]
+                    .iter()
+                    .find(|event| request.contains(&format!("event={}", event)))
+                    .copied()
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(events, vec![Some("started"), None, None, Some("stopped")]);
+        assert!(requests[1].0 - requests[0].0 >= MIN_ANNOUNCE_INTERVAL);
+        assert!(requests[2].0 - requests[1].0 >= MIN_ANNOUNCE_INTERVAL);
+    }
+
+    #[tokio::test]
+    async fn announce_loop_shorter_interval() {
+        let (addr, tracker) = mock_http_tracker_sequence(vec![
+            b"d8:intervali3600e5:peers0:e",
+            b"d8:intervali120e5:peers0:e",
+            b"d8:intervali3600e5:peers0:e",
+            b"d8:intervali3600e5:peers0:e",
+        ])
+        .await;
+
+        let mut announce_loop = AnnounceLoop::start(format!("http://{}/announce", addr), request());
+
+        announce_loop.next().await.unwrap().unwrap();
+        skip(Duration::from_secs(3600)).await;
+        announce_loop.next().await.unwrap().unwrap();
+
+        // The next one goes by the new interval, not the one before it.
+        skip(Duration::from_secs(60)).await;
+        assert!(
+            time::timeout(Duration::from_millis(100), announce_loop.next())
+                .await
+                .is_err()
+        );
+
+        skip(Duration::from_secs(60)).await;
+        announce_loop.next().await.unwrap().unwrap();
+        announce_loop.shutdown().await;
+
+        let requests = tracker.await.unwrap();
+        let waited = requests[2].0 - requests[1].0;
+
+        assert!(requests[1].0 - requests[0].0 >= Duration::from_secs(3600));
+        assert!(waited >= Duration::from_secs(120) && waited < Duration::from_secs(3600));
+    }
+
+    #[tokio::test]
+    async fn announce_loop_timeout() {
+        // Accepts the connection, but never answers.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mute = tokio::spawn(async move { listener.accept().await });
+
+        let timeout = Duration::from_millis(100);
+        let mut announce_loop = AnnounceLoop::start_with_timeout(
+            format!("http://{}/announce", addr),
+            request(),
+            timeout,
+        );
+        let result = announce_loop.next().await.unwrap();
+
+        assert!(matches!(&result, Err(TrackerError::TimedOut { timeout: t }) if *t == timeout));
+        assert!(result.unwrap_err().is_retryable());
+
+        drop(mute);
+    }
+
+    #[tokio::test]
+    async fn announce_loop_completed() {
+        let (addr, tracker) = mock_http_tracker_sequence(vec![
+            b"d8:intervali3600e5:peers0:e",
+            b"d8:intervali3600e5:peers0:e",
+            b"d8:intervali3600e5:peers0:e",
+        ])
+        .await;
+
+        let mut announce_loop = AnnounceLoop::start(format!("http://{}/announce", addr), request());
+
+        announce_loop.next().await.unwrap().unwrap();
+        announce_loop.download_finished();
+        announce_loop.next().await.unwrap().unwrap();
+        // Finishing again doesn't send another.
+        announce_loop.download_finished();
+        announce_loop.shutdown().await;
+
+        let requests = tracker.await.unwrap();
+        let events = requests
+            .iter()
+            .map(|(_, request)| {
+                ["started", "stopped", "completed"]
+                    .iter()
+                    .find(|event| request.contains(&format!("event={}", event)))
+                    .copied()
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            events,
+            vec![Some("started"), Some("completed"), Some("stopped")]
+        );
+        // That's without waiting the hour the tracker asked for.
+        assert!(requests[1].0 - requests[0].0 < Duration::from_secs(60));
+    }
+
+    #[tokio::test]
+    async fn announce_loop_tracker_id() {
+        let (addr, tracker) = mock_http_tracker_sequence(vec![
+            b"d8:intervali1e5:peers0:10:tracker id4:a&b!e",
+            // Leaving it out doesn't mean it's forgotten.
+            b"d8:intervali1e5:peers0:e",
+            b"d8:intervali3600e5:peers0:e",
+            b"d8:intervali3600e5:peers0:e",
+        ])
+        .await;
+
+        let mut announce_loop = AnnounceLoop::start(format!("http://{}/announce", addr), request());
+
+        announce_loop.next().await.unwrap().unwrap();
+
+        for _ in 0..2 {
+            skip(MIN_ANNOUNCE_INTERVAL).await;
+            announce_loop.next().await.unwrap().unwrap();
+        }
+
+        announce_loop.shutdown().await;
+
+        let with_id = tracker
+            .await
+            .unwrap()
+            .iter()
+            .map(|(_, request)| request.contains("&trackerid=a%26b%21 "))
+            .collect::<Vec<_>>();
+
+        assert_eq!(with_id, vec![false, true, true, true]);
+    }
+
+    #[test]
+    fn announce_lifecycle() {
+        let mut state = AnnounceState::default();
+
+        assert!(!state.needs_stopped());
+        assert_eq!(state.next_event(), Some(AnnounceEvent::Started));
+        assert!(matches!(
+            state.sent(AnnounceEvent::None),
+            Err(TrackerError::InvalidEvent {
+                event: AnnounceEvent::None
+            })
+        ));
+
+        state.sent(AnnounceEvent::Started).unwrap();
+        state.sent(AnnounceEvent::None).unwrap();
+
+        assert_eq!(state.next_event(), Some(AnnounceEvent::None));
+        assert!(state.sent(AnnounceEvent::Completed).is_err());
+
+        state.finish_download();
+        state.finish_download();
+
+        assert_eq!(state.next_event(), Some(AnnounceEvent::Completed));
+
+        state.sent(AnnounceEvent::Completed).unwrap();
+
+        // Only the once, no matter how many times it's asked for.
+        assert_eq!(state.next_event(), Some(AnnounceEvent::None));
+        assert!(state.sent(AnnounceEvent::Completed).is_err());
+        assert!(state.sent(AnnounceEvent::Started).is_err());
+
+        state.sent(AnnounceEvent::Stopped).unwrap();
+
+        assert_eq!(state.next_event(), None);
+        assert!(!state.needs_stopped());
+        assert!(state.sent(AnnounceEvent::None).is_err());
+        assert!(state.sent(AnnounceEvent::Stopped).is_err());
+    }
+
+    #[test]
+    fn announce_already_seeding() {
+        let mut state = AnnounceState::seeding();
+
+        state.sent(state.next_event().unwrap()).unwrap();
+
+        assert_eq!(state.next_event(), Some(AnnounceEvent::None));
+        assert!(state.sent(AnnounceEvent::Completed).is_err());
+    }
+
+    #[test]
+    fn swarm_counts() {
+        let response =
+            parse_announce_response(b"d8:completei12e10:incompletei34e5:peers0:e").unwrap();
+
+        assert_eq!(response.complete, Some(12));
+        assert_eq!(response.incomplete, Some(34));
+
+        let response = parse_announce_response(b"d8:completei-1e5:peers0:e").unwrap();
+
+        assert_eq!(response.complete, None);
+        assert_eq!(response.incomplete, None);
+    }
+
+    #[test]
+    fn warning_response() {
+        let response = parse_announce_response(
+            b"d15:warning message9:ratio low5:peers6:\x7f\x00\x00\x01\x1a\xe1e",
+        )
+        .unwrap();
+
+        assert_eq!(response.warning.as_deref(), Some("ratio low"));
+        assert_eq!(
+            response.peers,
+            vec!["127.0.0.1:6881".parse::<SocketAddr>().unwrap()]
+        );
+        assert_eq!(
+            parse_announce_response(b"d5:peers0:e").unwrap().warning,
+            None
+        );
+    }
+
+    #[test]
+    fn failure_response() {
+        assert!(matches!(
+            parse_announce_response(b"d14:failure reason17:torrent not founde"),
+            Err(TrackerError::Failure { reason }) if reason == "torrent not found"
+        ));
+    }
+
+    #[tokio::test]
+    async fn error_variants() {
+        let (addr, _) =
+            mock_http_tracker_with_status("503 Service Unavailable", b"d5:peers0:e").await;
+        let unavailable = announce(&format!("http://{}/announce", addr), &request()).await;
+
+        let (addr, _) = mock_http_tracker(b"<html>not bencode</html>").await;
+        let garbage = announce(&format!("http://{}/announce", addr), &request()).await;
+
+        let (addr, _) = mock_http_tracker(b"d14:failure reason7:go awaye").await;
+        let refused = announce(&format!("http://{}/announce", addr), &request()).await;
+
+        // Nothing's listening on the port anymore, so the tracker's
+        // unreachable.
+        let closed = UdpSocket::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let unreachable =
+            announce_udp_with_timeout(closed, &request(), Duration::from_millis(100)).await;
+
+        assert!(matches!(
+            &unavailable,
+            Err(TrackerError::Http { status }) if *status == StatusCode::SERVICE_UNAVAILABLE
+        ));
+        assert!(matches!(&garbage, Err(TrackerError::Decode)));
+        assert!(matches!(&refused, Err(TrackerError::Failure { .. })));
+        assert!(matches!(&unreachable, Err(TrackerError::Io { .. })));
+
+        assert!(unavailable.unwrap_err().is_retryable());
+        assert!(garbage.unwrap_err().is_retryable());
+        assert!(!refused.unwrap_err().is_retryable());
+        assert!(unreachable.unwrap_err().is_retryable());
+        assert!(!TrackerError::MalformedPeers.is_retryable());
+        assert!(!TrackerError::Http {
+            status: StatusCode::NOT_FOUND
+        }
+        .is_retryable());
+    }
+
+    fn http_response(head: &str, body: &[u8]) -> Vec<u8> {
+        [
+            format!(
+                "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                head,
+                body.len()
+            )
+            .as_bytes(),
+            body,
+        ]
+        .concat()
+    }
+
+    #[tokio::test]
+    async fn follows_redirects() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let tracker = serve_http(
+            listener,
+            vec![
+                http_response("301 Moved Permanently\r\nLocation: /moved", b""),
+                http_response(
+                    &format!("302 Found\r\nLocation: http://{}/announce?passkey=1", addr),
+                    b"",
+                ),
+                http_response(
+                    "200 OK",
+                    b"d8:intervali1800e5:peers6:\x7f\x00\x00\x01\x1a\xe1e",
+                ),
+            ],
+        );
+
+        let response = announce(&format!("http://{}/announce", addr), &request())
+            .await
+            .unwrap();
+        let requests = tracker.await.unwrap();
+
+        assert_eq!(response.peers, vec!["127.0.0.1:6881".parse().unwrap()]);
+        assert!(requests[1].starts_with("GET /moved "));
+        assert!(requests[2].starts_with("GET /announce?passkey=1 "));
+    }
+
+    #[tokio::test]
+    async fn too_many_redirects() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let _tracker = serve_http(
+            listener,
+            (0..=MAX_REDIRECTS)
+                .map(|_| http_response("302 Found\r\nLocation: /again", b""))
+                .collect(),
+        );
+
+        let result = announce(&format!("http://{}/announce", addr), &request()).await;
+
+        assert!(matches!(
+            &result,
+            Err(TrackerError::TooManyRedirects { max: MAX_REDIRECTS })
+        ));
+        assert!(!result.unwrap_err().is_retryable());
+    }
+
+    #[tokio::test]
+    async fn gzipped_response() {
+        // d8:intervali1800e5:peers6:<127.0.0.1:6881>e, gzipped.
+        const BODY: &[u8] = b"\x1f\x8b\x08\x00\x00\x00\x00\x00\x02\x03\x4b\xb1\xb0\xca\xcc\x2b\x49\x2d\x2a\x4b\xcc\xc9\x34\xb4\x30\x30\x48\x35\xb5\x2a\x48\x4d\x2d\x2a\x36\xb3\xaa\x67\x60\x60\x94\x7a\x98\x0a\x00\xc0\x54\x9b\xa3\x21\x00\x00\x00";
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let tracker = serve_http(
+            listener,
+            vec![
+                http_response("200 OK\r\nContent-Encoding: gzip", BODY),
+                http_response("200 OK\r\nContent-Encoding: gzip", &BODY[..20]),
+            ],
+        );
+
+        let url = format!("http://{}/announce", addr);
+        let response = announce(&url, &request()).await.unwrap();
+        let truncated = announce(&url, &request()).await;
+        let requests = tracker.await.unwrap();
+
+        assert_eq!(response.peers, vec!["127.0.0.1:6881".parse().unwrap()]);
+        assert_eq!(response.interval, Some(Duration::from_secs(1800)));
+        assert!(matches!(truncated, Err(TrackerError::Gzip { .. })));
+        assert!(requests[0]
+            .to_ascii_lowercase()
+            .contains("accept-encoding: gzip"));
+    }
+
+    #[tokio::test]
+    async fn response_too_long() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let tracker = serve_http(
+            listener,
+            vec![http_response("200 OK", &vec![b'a'; MAX_RESPONSE_LEN + 1])],
+        );
+
+        let result = announce(&format!("http://{}/announce", addr), &request()).await;
+
+        assert!(matches!(
+            &result,
+            Err(TrackerError::ResponseTooLong {
+                max: MAX_RESPONSE_LEN
+            })
+        ));
+        assert!(!result.unwrap_err().is_retryable());
+
+        tracker.await.unwrap();
+    }
+
+    #[test]
+    fn missing_peers() {
+        assert!(matches!(
+            parse_announce_response(b"d8:intervali1800ee"),
+            Err(TrackerError::MalformedPeers)
+        ));
+    }
+
+    #[test]
+    fn mixed_peers() {
+        let response = parse_announce_response(
+            b"d5:peers6:\x7f\x00\x00\x01\x1a\xe16:peers618:\
+\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x01\x1a\xe2e",
+        )
+        .unwrap();
+
+        assert_eq!(
+            response.peers,
+            vec![
+                "127.0.0.1:6881".parse::<SocketAddr>().unwrap(),
+                "[::1]:6882".parse().unwrap(),
+            ]
+        );
+
+        let response = parse_announce_response(
+            b"d5:peersld2:ip8:10.0.0.14:porti6881eed2:ip11:2001:db8::14:porti6882eee\
+6:peers618:\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x01\x1a\xe3e",
+        )
+        .unwrap();
+
+        assert_eq!(
+            response.peers,
+            vec![
+                "10.0.0.1:6881".parse::<SocketAddr>().unwrap(),
+                "[2001:db8::1]:6882".parse().unwrap(),
+                "[::1]:6883".parse().unwrap(),
+            ]
+        );
+
+        // Only IPv6 peers is fine too.
+        assert_eq!(
+            parse_announce_response(b"d6:peers60:e").unwrap().peers,
+            vec![]
+        );
+        assert!(matches!(
+            parse_announce_response(b"d5:peers0:6:peers65:aaaaae"),
+            Err(TrackerError::MalformedPeers)
+        ));
+    }
+
+    #[test]
+    fn compact_peer_either_family() {
+        assert_eq!(
+            parse_compact_peer(b"\x7f\x00\x00\x01\x1a\xe1"),
+            Some("127.0.0.1:6881".parse().unwrap())
+        );
+        assert_eq!(
+            parse_compact_peer(
+                b"\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x01\x1a\xe1"
+            ),
+            Some("[::1]:6881".parse().unwrap())
+        );
+        assert_eq!(parse_compact_peer(b"\x7f\x00\x00\x01"), None);
+    }
+
+    #[test]
+    fn compact_peers_empty() {
+        assert_eq!(parse_compact_peers(b"").unwrap(), vec![]);
+        assert_eq!(parse_compact_peers6(b"").unwrap(), vec![]);
+    }
+
+    #[test]
+    fn compact_peers_single() {
+        assert_eq!(
+            parse_compact_peers(b"\x7f\x00\x00\x01\x1a\xe1").unwrap(),
+            vec![SocketAddrV4::new(Ipv4Addr::LOCALHOST, 6881)]
+        );
+        assert_eq!(
+            parse_compact_peers6(
+                b"\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x01\x1a\xe1"
+            )
+            .unwrap(),
+            vec![SocketAddrV6::new(Ipv6Addr::LOCALHOST, 6881, 0, 0)]
+        );
+    }
+
+    #[test]
+    fn compact_peers_trailing_bytes() {
+        assert!(matches!(
+            parse_compact_peers(b"\x7f\x00\x00\x01\x1a\xe1\x7f"),
+            Err(TrackerError::MalformedPeers)
+        ));
+        assert!(matches!(
+            parse_compact_peers6(b"\x7f\x00\x00\x01\x1a\xe1"),
+            Err(TrackerError::MalformedPeers)
+        ));
+    }
+
+    #[tokio::test]
+    async fn announce_to_mock_udp_tracker() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let tracker = tokio::spawn(mock_udp_tracker(socket, 0));
+
+        let response = announce_udp(addr, &request()).await.unwrap();
+
+        assert_eq!(
+            response.peers,
+            vec!["127.0.0.1:6881".parse::<SocketAddr>().unwrap()]
+        );
+        assert_eq!(response.incomplete, Some(1));
+        assert_eq!(response.complete, Some(2));
+
+        let announce_packet = tracker.await.unwrap();
+
+        assert_eq!(announce_packet[16..36], [0xab; 20]);
+        assert_eq!(&announce_packet[36..56], b"-MS0010-abcdefghijkl");
+        assert_eq!(announce_packet[96..98], 6881u16.to_be_bytes());
+    }
+
+    #[tokio::test]
+    async fn udp_retransmits_lost_packets() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let tracker = tokio::spawn(mock_udp_tracker(socket, 2));
+
+        let response = announce_udp_with_timeout(addr, &request(), Duration::from_millis(10))
+            .await
+            .unwrap();
+
+        assert_eq!(response.peers.len(), 1);
+        tracker.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn udp_error_response() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let mut buf = vec![0; 1024];
+            let (_, peer) = socket.recv_from(&mut buf).await.unwrap();
+
+            let error_response = [
+                &UDP_ACTION_ERROR.to_be_bytes() as &[u8],
+                &buf[12..16],
+                b"go away",
+            ]
+            .concat();
+            socket.send_to(&error_response, peer).await.unwrap();
+        });
+
+        assert!(matches!(
+            announce_udp(addr, &request()).await,
+            Err(TrackerError::Failure { reason }) if reason == "go away"
+        ));
+    }
+
+    #[tokio::test]
+    async fn announce_to_mock_tracker() {
+        let (addr, tracker) =
+            mock_http_tracker(b"d8:intervali1800e5:peers6:\x7f\x00\x00\x01\x1a\xe1e").await;
+
+        let response = announce_http(&format!("http://{}/announce", addr), &request())
+            .await
+            .unwrap();
+
+        assert_eq!(
+            response.peers,
+            vec!["127.0.0.1:6881".parse::<SocketAddr>().unwrap()]
+        );
+        assert!(tracker
+            .await
+            .unwrap()
+            .starts_with("GET /announce?info_hash=%AB"));
+    }
+
+    #[test]
+    fn scrape_urls() {
+        assert_eq!(
+            scrape_url("http://example.com/announce").unwrap().as_str(),
+            "http://example.com/scrape"
+        );
+        assert_eq!(
+            scrape_url("http://example.com/x/announce.php?passkey=abc")
+                .unwrap()
+                .as_str(),
+            "http://example.com/x/scrape.php?passkey=abc"
+        );
+        assert!(matches!(
+            scrape_url("http://example.com/a"),
+            Err(TrackerError::NoScrapeUrl)
+        ));
+        assert!(matches!(
+            scrape_url("http://example.com/announce/x"),
+            Err(TrackerError::NoScrapeUrl)
+        ));
+    }
+
+    #[tokio::test]
+    async fn scrape_mock_tracker() {
+        let (addr, tracker) = mock_http_tracker(
+            b"d5:filesd20:\xab\xab\xab\xab\xab\xab\xab\xab\xab\xab\xab\xab\xab\xab\xab\xab\xab\xab\xab\xab\
+              d8:completei5e10:downloadedi50e10:incompletei10eeee",
+        )
+        .await;
+
+        let stats = scrape(
+            &format!("http://{}/announce", addr),
+            &[SHA1Hash([0xab; 20])],
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            stats[&SHA1Hash([0xab; 20])],
+            ScrapeStats {
+                complete: 5,
+                incomplete: 10,
+                downloaded: 50
+            }
+        );
+        assert!(tracker
+            .await
+            .unwrap()
+            .starts_with("GET /scrape?info_hash=%AB%AB"));
+    }
+}
@@ -47,6 +47,12 @@ pub enum Message {
     Request(BlockMeta),
     Piece(Block),
     Cancel(BlockMeta),
+    Extended(u8, Bytes),
+    SuggestPiece(u32),
+    HaveAll,
+    HaveNone,
+    RejectRequest(BlockMeta),
+    AllowedFast(u32),
 }
 
 #[async_trait]